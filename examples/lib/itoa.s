@@ -0,0 +1,8 @@
+# itoa(a0 value, a1 buf, a2 len) writes value as a signed decimal number
+# into the len bytes at buf, without a terminating NUL, and returns the
+# length of the whole number in a0, which is more than len if it did not
+# fit. Clobbers a7.
+
+itoa:   li a7, 256                  # format_signed
+        ecall
+        ret
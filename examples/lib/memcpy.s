@@ -0,0 +1,14 @@
+# memcpy(a0 dst, a1 src, a2 len) copies len bytes from src to dst, which
+# must not overlap, and returns dst. Clobbers a3-a5.
+
+memcpy: li a3, 0
+memcpy_loop:
+        beq a3, a2, memcpy_done
+        add a4, a1, a3
+        lb a4, (a4)
+        add a5, a0, a3
+        sb a4, (a5)
+        add a3, a3, x0, 1
+        j memcpy_loop
+memcpy_done:
+        ret
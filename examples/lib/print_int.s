@@ -0,0 +1,18 @@
+# print_int(a0 value) prints value to stdout as a signed decimal number.
+# Clobbers a0-a2, a6 and a7.
+
+.include "itoa.s"
+
+print_int:
+        mv a6, ra                   # calling itoa overwrites ra
+        jal a1, print_int_digits    # a1 = the address of the buffer
+        .space 12                   # room for "-2147483648"
+print_int_digits:
+        li a2, 12
+        call itoa
+        mv a2, a0                   # write(1, buffer, length)
+        li a0, 1
+        li a7, 64
+        ecall
+        mv ra, a6
+        ret
@@ -0,0 +1,13 @@
+# strlen(a0 str) returns the number of bytes before the NUL that ends the
+# string at str. Clobbers a1 and a2.
+
+strlen: li a1, 0
+strlen_loop:
+        add a2, a0, a1
+        lb a2, (a2)
+        beq a2, x0, strlen_done
+        add a1, a1, x0, 1
+        j strlen_loop
+strlen_done:
+        mv a0, a1
+        ret
@@ -0,0 +1,7 @@
+# Prints 12345 using the guest library.
+
+main:   li a0, 12345
+        call print_int
+        ebreak
+
+.include "lib/print_int.s"
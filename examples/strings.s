@@ -0,0 +1,32 @@
+# Builds the string "hey\n" at 0x1000, copies it to 0x1100 with memcpy and
+# prints the copy, then its length.
+
+main:   li a1, 0x68                 # 'h'
+        sb a1, 0x1000
+        li a1, 0x65                 # 'e'
+        sb a1, 0x1001
+        li a1, 0x79                 # 'y'
+        sb a1, 0x1002
+        li a1, 0x0a                 # '\n', then the NUL already there
+        sb a1, 0x1003
+
+        li a0, 0x1100
+        li a1, 0x1000
+        li a2, 5
+        call memcpy
+
+        call strlen                 # a0 = the copy's length
+        mv a2, a0
+        li a0, 1
+        li a1, 0x1100
+        li a7, 64                   # write
+        ecall
+
+        li a0, 0x1100
+        call strlen
+        call print_int
+        ebreak
+
+.include "lib/memcpy.s"
+.include "lib/strlen.s"
+.include "lib/print_int.s"
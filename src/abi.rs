@@ -0,0 +1,219 @@
+use crate::{Address, Machine, RegisterID, Word};
+use std::{fmt, io::Write};
+
+// The registers a function must hand back to its caller as it found them.
+// a0 to a7 carry arguments and results and are free for the callee to use;
+// the rest, and sp, belong to the caller.
+pub const CALLEE_SAVED: [RegisterID; 5] = [
+    RegisterID::A8,
+    RegisterID::A9,
+    RegisterID::A10,
+    RegisterID::A11,
+    RegisterID::A12,
+];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ViolationKind {
+    // A callee-saved register held a different value on return.
+    Clobbered {
+        reg: RegisterID,
+        before: Word,
+        after: Word,
+    },
+    // sp was not back where it was when the call was made.
+    StackUnbalanced {
+        before: Word,
+        after: Word,
+    },
+}
+
+// A return that broke the calling convention.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AbiViolation {
+    // The function returning, by symbol if one covers it.
+    pub function: String,
+    pub callee: Address,
+    // The address of the call instruction.
+    pub call_site: Address,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for AbiViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, called from {:#010x}, ",
+            self.function, self.call_site
+        )?;
+        match self.kind {
+            ViolationKind::Clobbered { reg, before, after } => write!(
+                f,
+                "returns with {reg} changed from {before:#x} to {after:#x}"
+            ),
+            ViolationKind::StackUnbalanced { before, after } => {
+                write!(f, "returns with sp at {after:#010x}, not {before:#010x}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct AbiCheck {
+    // The callee-saved registers at each call being checked, by its depth
+    // on the shadow call stack.
+    saved: Vec<(usize, [Word; 5])>,
+    violations: Vec<AbiViolation>,
+}
+
+impl<W: Write> Machine<W> {
+    // Checks that every call the guest makes preserves the callee-saved
+    // registers and leaves sp balanced, recording the returns that do not.
+    // Calls and returns are those the shadow call stack tracks, and the
+    // machine's symbols name the functions.
+    pub fn set_abi_check(&mut self, enabled: bool) {
+        self.abi = enabled.then(AbiCheck::default);
+    }
+
+    pub fn abi_violations(&self) -> &[AbiViolation] {
+        self.abi
+            .as_ref()
+            .map_or(&[], |abi| abi.violations.as_slice())
+    }
+
+    // Saves the callee-saved registers for the call just pushed.
+    pub(crate) fn abi_enter(&mut self) {
+        let Some(abi) = &mut self.abi else {
+            return;
+        };
+        let saved = CALLEE_SAVED.map(|reg| self.regs.get(&reg));
+        abi.saved.push((self.call_stack.len() - 1, saved));
+    }
+
+    // Checks the return to the call at `depth` on the shadow call stack,
+    // dropping what was saved for it and for any calls it abandons.
+    pub(crate) fn abi_leave(&mut self, depth: usize) {
+        let Some(abi) = &mut self.abi else {
+            return;
+        };
+        let keep = abi.saved.partition_point(|(at, _)| *at < depth);
+        let Some(&(at, saved)) = abi.saved.get(keep) else {
+            return;
+        };
+        abi.saved.truncate(keep);
+        if at != depth {
+            return;
+        }
+        let frame = self.call_stack[depth];
+        let function = self
+            .symbols
+            .label(frame.callee)
+            .unwrap_or_else(|| format!("{:#010x}", frame.callee));
+        let mut kinds: Vec<ViolationKind> = CALLEE_SAVED
+            .iter()
+            .zip(saved)
+            .filter(|(reg, before)| self.regs.get(reg) != *before)
+            .map(|(&reg, before)| ViolationKind::Clobbered {
+                reg,
+                before,
+                after: self.regs.get(&reg),
+            })
+            .collect();
+        let sp = self.regs.get(&RegisterID::SP);
+        if sp != frame.sp {
+            kinds.push(ViolationKind::StackUnbalanced {
+                before: frame.sp,
+                after: sp,
+            });
+        }
+        abi.violations
+            .extend(kinds.into_iter().map(|kind| AbiViolation {
+                function: function.clone(),
+                callee: frame.callee,
+                call_site: frame.return_addr.wrapping_sub(4),
+                kind,
+            }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, symbols::SymbolTable, HaltReason};
+    use claims::assert_ok_eq;
+
+    // Runs a program with the check on, returning the violations and where
+    // sp started.
+    fn check(program: &str, symbols: SymbolTable) -> (Vec<AbiViolation>, Word) {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&assemble(program).unwrap())
+            .build()
+            .unwrap();
+        machine.set_symbols(symbols);
+        machine.set_abi_check(true);
+        let sp = machine.register(RegisterID::SP);
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        (machine.abi_violations().to_vec(), sp)
+    }
+
+    #[test]
+    fn functions_that_save_what_they_use_pass() {
+        let program = "
+            call save
+            call scratch
+            ebreak
+            save: sub sp, sp, x0, 4
+            sw a8, 0(sp)
+            li a8, 7
+            lw a8, 0(sp)
+            add sp, sp, x0, 4
+            ret
+            scratch: li a7, 1
+            ret";
+
+        assert_eq!(check(program, SymbolTable::new()).0, []);
+    }
+
+    #[test]
+    fn violations_name_the_function_and_call_site() {
+        let program = "
+            li a9, 5
+            call clobber
+            call leak
+            ebreak
+            clobber: li a9, 1
+            ret
+            leak: sub sp, sp, x0, 8
+            ret";
+        let symbols = SymbolTable::from([("clobber", 0x10, 0)]);
+
+        let (violations, sp) = check(program, symbols);
+        assert_eq!(
+            violations,
+            [
+                AbiViolation {
+                    function: "clobber".to_string(),
+                    callee: 0x10,
+                    call_site: 0x4,
+                    kind: ViolationKind::Clobbered {
+                        reg: RegisterID::A9,
+                        before: 5,
+                        after: 1,
+                    },
+                },
+                AbiViolation {
+                    function: "clobber+0x8".to_string(),
+                    callee: 0x18,
+                    call_site: 0x8,
+                    kind: ViolationKind::StackUnbalanced {
+                        before: sp,
+                        after: sp - 8,
+                    },
+                },
+            ]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            "clobber, called from 0x00000004, returns with a9 changed from 0x5 to 0x1"
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{Address, Machine};
+use std::io::Write;
+
+// How many of the loads and stores the machine has made were aligned to
+// their width. Byte accesses are always aligned.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Alignment {
+    pub aligned: u64,
+    pub misaligned: u64,
+}
+
+impl Alignment {
+    pub(crate) fn record(&mut self, misaligned: bool) {
+        if misaligned {
+            self.misaligned += 1;
+        } else {
+            self.aligned += 1;
+        }
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Charges `cycles` extra for every misaligned load or store, as real
+    // hardware splits them into more than one access or traps to emulate
+    // them. No penalty is charged by default.
+    pub fn set_misalignment_penalty(&mut self, cycles: u64) {
+        self.misalignment_penalty = cycles;
+    }
+
+    pub fn misalignment_penalty(&self) -> u64 {
+        self.misalignment_penalty
+    }
+
+    // Counts a `len` byte access at `addr`, charging for it if misaligned.
+    pub(crate) fn count_access(&mut self, addr: Address, len: usize) {
+        let misaligned = !(addr as usize).is_multiple_of(len);
+        if misaligned {
+            self.cycles += self.misalignment_penalty;
+        }
+        self.execution.accesses.record(misaligned);
+        if let Some(counters) = &mut self.run_counters {
+            counters.accesses.record(misaligned);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, HaltReason};
+
+    // A word load at 0x102 and halfword store at 0x101 are misaligned;
+    // the rest are not.
+    const ACCESSES: &str = "
+        lw a1, 0x100(x0)
+        lw a1, 0x102(x0)
+        sh a1, 0x101(x0)
+        sh a1, 0x104(x0)
+        sb a1, 0x103(x0)
+        ebreak
+    ";
+
+    fn machine() -> Machine<Vec<u8>> {
+        Machine::builder()
+            .program(&assemble(ACCESSES).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn accesses_are_counted_by_alignment() {
+        let mut machine = machine();
+
+        let outcome = machine.run_with_stats().unwrap();
+
+        assert_eq!(outcome.reason, HaltReason::Breakpoint);
+        let expected = Alignment {
+            aligned: 3,
+            misaligned: 2,
+        };
+        assert_eq!(machine.execution_stats().accesses, expected);
+        assert_eq!(outcome.stats().accesses, expected);
+        // Without a penalty, every instruction takes a cycle.
+        assert_eq!(outcome.stats().cycles, 6);
+    }
+
+    #[test]
+    fn misaligned_accesses_cost_the_penalty() {
+        let mut machine = machine();
+        machine.set_misalignment_penalty(10);
+
+        let outcome = machine.run_with_stats().unwrap();
+
+        assert_eq!(outcome.stats().cycles, 6 + 2 * 10);
+    }
+}
@@ -0,0 +1,1317 @@
+use crate::{
+    disasm,
+    endian::Endian,
+    link::LinkerScript,
+    loader::{Executable, Segment},
+    symbols::SymbolTable,
+    Address, Error, Instruction, Opcode, RegisterID, Result, Word,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// Assembles source text into a program image. Instructions are one per
+// line, optionally after one or more `label:`s, with `#` starting a
+// comment:
+//
+//   main:   li a0, 42
+//           jal ra, print_int
+//           ebreak
+//   .include "lib/print_int.s"
+//
+// Branch and jump targets are labels or pc-relative offsets, and memory
+// operands are written `offset(base)`. `nop`, `mv rd, rs`, `j target`,
+// `call target` and `ret` stand for the instructions they are short for.
+//
+// Data is laid down with `.byte` and `.word` (big-endian), each taking a
+// list of numbers, `.string "text"`, which adds a NUL, and `.space n` for n
+// zero bytes. `.include "file"` assembles another file in its place,
+// relative to the including file; a file is included at most once, so
+// libraries can include what they need without clashing labels.
+//
+// `li rd, value` loads any 32-bit value, expanding to the fewest
+// instructions that build it when it does not fit li's immediate.
+//
+// `li rd, "text"` loads the address of a NUL-terminated copy of the text,
+// and `lw rd, =value` loads a word constant too large for an immediate.
+// These literals are kept in a rodata section after everything else, each
+// distinct one once however often it is used, and the instructions using
+// them are relocated to its address once it is known. The section must
+// lie within reach of the instructions: below 0x8000 for li and 0x4000
+// for lw.
+//
+// `la rd, label` loads a label's address relative to the pc, as
+// `auipc rd, %pcrel_hi(label)` and `add rd, rd, x0, %pcrel_lo(label)`.
+// `%pcrel_lo` is always relative to an auipc just before it, so the pair
+// can also be written out to load or store at a label directly.
+//
+// `.section .name` switches to laying down statements in the named
+// section, `.text` until the first one. A flat image has the sections one
+// after another in the order they first appear, each on a word boundary,
+// with the literals at the end of `.rodata`.
+pub fn assemble(source: &str) -> Result<Vec<u8>> {
+    let executable = Assembler::new(read_file).assemble(source, None, None)?;
+    Ok(flatten(executable))
+}
+
+// Assembles a file, resolving includes relative to it.
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Vec<u8>> {
+    let path = path.as_ref();
+    let source = read_file(path).map_err(|err| invalid_file(path, &err))?;
+    let executable = Assembler::new(read_file).assemble(&source, Some(path), None)?;
+    Ok(flatten(executable))
+}
+
+// Assembles a file as assemble_file does, along with a symbol for each of
+// its labels, as debug info for running it.
+pub fn assemble_file_with_symbols(path: impl AsRef<Path>) -> Result<(Vec<u8>, SymbolTable)> {
+    let path = path.as_ref();
+    let source = read_file(path).map_err(|err| invalid_file(path, &err))?;
+    let mut assembler = Assembler::new(read_file);
+    let executable = assembler.assemble(&source, Some(path), None)?;
+    Ok((flatten(executable), assembler.symbols()))
+}
+
+// Assembles a program with its sections placed by a linker script, as an
+// executable with a segment for each section that is not empty.
+pub fn assemble_with_script(source: &str, script: &LinkerScript) -> Result<Executable> {
+    Assembler::new(read_file).assemble(source, None, Some(script))
+}
+
+pub fn assemble_file_with_script(
+    path: impl AsRef<Path>,
+    script: &LinkerScript,
+) -> Result<Executable> {
+    let path = path.as_ref();
+    let source = read_file(path).map_err(|err| invalid_file(path, &err))?;
+    Assembler::new(read_file).assemble(&source, Some(path), Some(script))
+}
+
+// Where sections go without a script: one after another from zero.
+fn one_after_another(sections: &[(&str, Word)]) -> Vec<Address> {
+    let mut end: Word = 0;
+    sections
+        .iter()
+        .map(|(_, size)| {
+            let base = end.next_multiple_of(4);
+            end = base.wrapping_add(*size);
+            base
+        })
+        .collect()
+}
+
+// The image of sections placed one after another, with zeros between.
+fn flatten(executable: Executable) -> Vec<u8> {
+    let mut image = Vec::new();
+    for segment in executable.segments {
+        image.resize(segment.addr as usize, 0);
+        image.extend(segment.data);
+    }
+    image
+}
+
+fn read_file(path: &Path) -> io::Result<String> {
+    fs::read_to_string(path)
+}
+
+fn invalid_file(path: &Path, err: &io::Error) -> Error {
+    Error::AsmInvalid(format!("{}: {err}", path.display()))
+}
+
+// Where a line came from, for error messages.
+#[derive(Debug, Clone)]
+struct Location {
+    file: Option<PathBuf>,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, reason: &str) -> Error {
+        Error::AsmInvalid(match &self.file {
+            Some(file) => format!("{}, line {}: {reason}", file.display(), self.line),
+            None => format!("line {}: {reason}", self.line),
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<String>,
+        // The literal whose address the instruction is relocated to.
+        literal: Option<Literal>,
+    },
+    Data(Vec<u8>),
+}
+
+// A constant kept in the rodata section.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+enum Literal {
+    // A string, with its NUL.
+    String(Vec<u8>),
+    Word(Word),
+}
+
+// A named part of the program, laid out from offset zero and placed at an
+// address once every section's size is known.
+struct Section {
+    name: String,
+    statements: Vec<(Location, Word, Statement)>,
+    size: Word,
+}
+
+impl Section {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            statements: Vec::new(),
+            size: 0,
+        }
+    }
+}
+
+struct Assembler<F> {
+    read: F,
+    included: HashSet<PathBuf>,
+    // Each label's section and offset in it, and then, once the sections
+    // are placed, its address.
+    positions: HashMap<String, (usize, Word)>,
+    labels: HashMap<String, Word>,
+    sections: Vec<Section>,
+    // The section statements are being laid down in.
+    current: usize,
+    // The distinct literals, in the order they are first used.
+    literals: Vec<Literal>,
+}
+
+impl<F: FnMut(&Path) -> io::Result<String>> Assembler<F> {
+    fn new(read: F) -> Self {
+        Self {
+            read,
+            included: HashSet::new(),
+            positions: HashMap::new(),
+            labels: HashMap::new(),
+            sections: vec![Section::new(".text")],
+            current: 0,
+            literals: Vec::new(),
+        }
+    }
+
+    // Assembles a program, placing its sections with `script` or, without
+    // one, one after another from address zero.
+    fn assemble(
+        &mut self,
+        source: &str,
+        file: Option<&Path>,
+        script: Option<&LinkerScript>,
+    ) -> Result<Executable> {
+        if let Some(file) = file {
+            self.included.insert(file.to_path_buf());
+        }
+        self.layout(source, file)?;
+        let literals = self.place_literals();
+        let sizes: Vec<(&str, Word)> = self
+            .sections
+            .iter()
+            .map(|section| (section.name.as_str(), section.size))
+            .collect();
+        let bases = match script {
+            Some(script) => script.place(&sizes)?,
+            None => one_after_another(&sizes),
+        };
+        self.labels = self
+            .positions
+            .iter()
+            .map(|(label, &(section, offset))| (label.clone(), bases[section].wrapping_add(offset)))
+            .collect();
+        let literals: HashMap<Literal, Word> = match literals {
+            Some((section, offsets)) => offsets
+                .into_iter()
+                .map(|(literal, offset)| (literal, bases[section].wrapping_add(offset)))
+                .collect(),
+            None => HashMap::new(),
+        };
+
+        let mut segments = Vec::new();
+        for (section, &base) in self.sections.iter().zip(&bases) {
+            if section.size == 0 {
+                continue;
+            }
+            let mut data = Vec::with_capacity(section.size as usize);
+            for (location, offset, statement) in &section.statements {
+                match statement {
+                    Statement::Instruction {
+                        mnemonic,
+                        operands,
+                        literal,
+                    } => {
+                        let relocated;
+                        let operands = match literal {
+                            Some(literal) => {
+                                relocated = relocate(mnemonic, operands, literals[literal])
+                                    .map_err(|reason| location.error(&reason))?;
+                                &relocated
+                            }
+                            None => operands,
+                        };
+                        let instruction = self
+                            .encode(mnemonic, operands, base.wrapping_add(*offset))
+                            .map_err(|reason| location.error(&reason))?;
+                        data.extend(instruction.encode().to_be_bytes());
+                    }
+                    Statement::Data(bytes) => data.extend(bytes),
+                }
+            }
+            segments.push(Segment {
+                addr: base,
+                size: section.size,
+                data,
+            });
+        }
+        let entry = match script.and_then(|script| script.entry.as_ref()) {
+            Some(label) => *self
+                .labels
+                .get(label)
+                .ok_or_else(|| Error::LinkFailed(format!("entry {label} is not a label")))?,
+            None => self
+                .sections
+                .iter()
+                .position(|section| section.name == ".text")
+                .map_or(0, |index| bases[index]),
+        };
+        Ok(Executable {
+            entry,
+            segments,
+            endian: Endian::Big,
+        })
+    }
+
+    // The labels of an assembled program and their addresses. Where labels
+    // share an address the table names it by the last of them alphabetically.
+    fn symbols(&self) -> SymbolTable {
+        let mut labels: Vec<(&String, &Word)> = self.labels.iter().collect();
+        labels.sort_by_key(|&(label, addr)| (*addr, label));
+        let mut symbols = SymbolTable::new();
+        for (label, &addr) in labels {
+            symbols.insert(label, addr, 0);
+        }
+        symbols
+    }
+
+    // Lays out the literals at the end of the .rodata section, on a word
+    // boundary with the words first so that they stay aligned, returning
+    // the section and where in it each literal is.
+    fn place_literals(&mut self) -> Option<(usize, HashMap<Literal, Word>)> {
+        if self.literals.is_empty() {
+            return None;
+        }
+        let index = if let Some(index) = self
+            .sections
+            .iter()
+            .position(|section| section.name == ".rodata")
+        {
+            index
+        } else {
+            self.sections.push(Section::new(".rodata"));
+            self.sections.len() - 1
+        };
+        let section = &mut self.sections[index];
+        let start = section.size.next_multiple_of(4);
+        let (words, strings): (Vec<&Literal>, Vec<&Literal>) = self
+            .literals
+            .iter()
+            .partition(|literal| matches!(literal, Literal::Word(_)));
+        let mut rodata = vec![0; (start - section.size) as usize];
+        let mut offsets = HashMap::new();
+        for literal in words.into_iter().chain(strings) {
+            offsets.insert(literal.clone(), section.size + rodata.len() as Word);
+            match literal {
+                Literal::String(text) => rodata.extend(text),
+                Literal::Word(value) => rodata.extend(value.to_be_bytes()),
+            }
+        }
+        let location = Location {
+            file: None,
+            line: 0,
+        };
+        let len = rodata.len() as Word;
+        section
+            .statements
+            .push((location, section.size, Statement::Data(rodata)));
+        section.size = section.size.wrapping_add(len);
+        Some((index, offsets))
+    }
+
+    // The first pass: expands includes, gives every statement its address
+    // and records where the labels are.
+    fn layout(&mut self, source: &str, file: Option<&Path>) -> Result<()> {
+        for (index, text) in source.lines().enumerate() {
+            let location = Location {
+                file: file.map(Path::to_path_buf),
+                line: index + 1,
+            };
+            let mut rest = strip_comment(text).trim();
+            while let Some((label, after)) = rest.split_once(':') {
+                if !is_identifier(label.trim()) {
+                    break;
+                }
+                let position = (self.current, self.sections[self.current].size);
+                if self
+                    .positions
+                    .insert(label.trim().to_string(), position)
+                    .is_some()
+                {
+                    return Err(location.error(&format!("duplicate label {:?}", label.trim())));
+                }
+                rest = after.trim();
+            }
+            if rest.is_empty() {
+                continue;
+            }
+            let (mnemonic, raw) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let operands = split_operands(raw);
+            let numbers = |max: i64| {
+                operands
+                    .iter()
+                    .map(|operand| {
+                        parse_number(operand)
+                            .filter(|n| (-max..=max).contains(n))
+                            .ok_or_else(|| location.error(&format!("invalid value {operand:?}")))
+                    })
+                    .collect::<Result<Vec<i64>>>()
+            };
+            let statement = match mnemonic {
+                ".include" => {
+                    let name = match operands.as_slice() {
+                        [name] => name.strip_prefix('"').and_then(|n| n.strip_suffix('"')),
+                        _ => None,
+                    }
+                    .ok_or_else(|| location.error(".include needs a quoted file name"))?;
+                    let path = match file.and_then(Path::parent) {
+                        Some(dir) => dir.join(name),
+                        None => PathBuf::from(name),
+                    };
+                    if self.included.insert(path.clone()) {
+                        let source = (self.read)(&path).map_err(|err| {
+                            location.error(&format!("cannot include {}: {err}", path.display()))
+                        })?;
+                        self.layout(&source, Some(&path))?;
+                    }
+                    continue;
+                }
+                ".section" => {
+                    let [name] = operands.as_slice() else {
+                        return Err(location.error(".section needs a name"));
+                    };
+                    if !name.starts_with('.') || !is_identifier(&name[1..]) {
+                        return Err(location.error(&format!("invalid section name {name:?}")));
+                    }
+                    self.current =
+                        if let Some(index) = self.sections.iter().position(|s| s.name == *name) {
+                            index
+                        } else {
+                            self.sections.push(Section::new(name));
+                            self.sections.len() - 1
+                        };
+                    continue;
+                }
+                // la is two instructions, so it is expanded here rather
+                // than with the others.
+                "la" => {
+                    let [rd, label] = operands.as_slice() else {
+                        return Err(location.error("la takes a register and a label"));
+                    };
+                    let pair = [
+                        ("auipc", vec![rd.clone(), format!("%pcrel_hi({label})")]),
+                        (
+                            "add",
+                            vec![
+                                rd.clone(),
+                                rd.clone(),
+                                "x0".to_string(),
+                                format!("%pcrel_lo({label})"),
+                            ],
+                        ),
+                    ];
+                    self.push_expansion(&location, pair);
+                    continue;
+                }
+                // So is li of a number too wide for its immediate.
+                "li" if matches!(operands.as_slice(), [_, imm] if parse_number(imm).is_some()) => {
+                    let (rd, imm) = (&operands[0], &operands[1]);
+                    let value = parse_number(imm)
+                        .filter(|n| (-0x8000_0000..=0xffff_ffff).contains(n))
+                        .ok_or_else(|| {
+                            location.error(&format!("immediate {imm} does not fit in 32 bits"))
+                        })?;
+                    self.push_expansion(&location, load_immediate(rd, value as i32));
+                    continue;
+                }
+                ".space" => match operands.as_slice() {
+                    [len] => Statement::Data(vec![
+                        0;
+                        parse_number(len)
+                            .and_then(|len| usize::try_from(len).ok())
+                            .ok_or_else(|| location.error(&format!(
+                                "invalid size {len:?}"
+                            )))?
+                    ]),
+                    _ => return Err(location.error(".space needs a size")),
+                },
+                ".byte" => Statement::Data(numbers(0xff)?.iter().map(|&n| n as u8).collect()),
+                ".word" => Statement::Data(
+                    numbers(0xffff_ffff)?
+                        .iter()
+                        .flat_map(|&n| (n as Word).to_be_bytes())
+                        .collect(),
+                ),
+                ".string" => {
+                    let mut text = parse_string(raw.trim())
+                        .ok_or_else(|| location.error(".string needs a quoted string"))?
+                        .into_bytes();
+                    text.push(0);
+                    Statement::Data(text)
+                }
+                _ => {
+                    let literal =
+                        literal(mnemonic, &operands).map_err(|reason| location.error(&reason))?;
+                    if let Some(literal) = &literal {
+                        if !self.literals.contains(literal) {
+                            self.literals.push(literal.clone());
+                        }
+                    }
+                    Statement::Instruction {
+                        mnemonic: mnemonic.to_string(),
+                        operands,
+                        literal,
+                    }
+                }
+            };
+            self.push(location, statement);
+        }
+        Ok(())
+    }
+
+    // Lays down the instructions a pseudo-instruction expands to.
+    fn push_expansion(
+        &mut self,
+        location: &Location,
+        instructions: impl IntoIterator<Item = (&'static str, Vec<String>)>,
+    ) {
+        for (mnemonic, operands) in instructions {
+            let statement = Statement::Instruction {
+                mnemonic: mnemonic.to_string(),
+                operands,
+                literal: None,
+            };
+            self.push(location.clone(), statement);
+        }
+    }
+
+    // Lays down a statement at the end of the current section.
+    fn push(&mut self, location: Location, statement: Statement) {
+        let len = match &statement {
+            Statement::Data(bytes) => bytes.len() as Word,
+            Statement::Instruction { .. } => 4,
+        };
+        let section = &mut self.sections[self.current];
+        section.statements.push((location, section.size, statement));
+        section.size = section.size.wrapping_add(len);
+    }
+
+    // The operands of the instruction at `addr` with `%pcrel_hi(label)` and
+    // `%pcrel_lo(label)` replaced by the parts of the label's distance from
+    // an auipc. auipc adds the high part shifted up 15 places and the
+    // instruction after it adds the low part, so the distance is from
+    // `addr` for the high part and from the instruction before for the low.
+    fn relative(
+        &self,
+        operands: &[String],
+        addr: Word,
+    ) -> std::result::Result<Vec<String>, String> {
+        let part = |operand: &String| {
+            for (name, from) in [("%pcrel_hi(", addr), ("%pcrel_lo(", addr.wrapping_sub(4))] {
+                let Some(start) = operand.find(name) else {
+                    continue;
+                };
+                let rest = &operand[start + name.len()..];
+                let (label, after) = rest
+                    .split_once(')')
+                    .ok_or_else(|| format!("invalid operand {operand:?}"))?;
+                let to = self
+                    .labels
+                    .get(label)
+                    .ok_or_else(|| format!("unknown label {label:?}"))?;
+                let distance = i64::from(to.wrapping_sub(from) as i32);
+                let low = (distance << 49) >> 49;
+                let value = if name == "%pcrel_hi(" {
+                    let high = (distance - low) >> 15;
+                    if !(-0x4000..0x4000).contains(&high) {
+                        return Err(format!("{label} is out of reach of auipc"));
+                    }
+                    high
+                } else {
+                    low
+                };
+                return Ok(format!("{}{value}{after}", &operand[..start]));
+            }
+            Ok(operand.clone())
+        };
+        operands.iter().map(part).collect()
+    }
+
+    fn encode(
+        &self,
+        mnemonic: &str,
+        operands: &[String],
+        addr: Word,
+    ) -> std::result::Result<Instruction, String> {
+        let expanded = expand(mnemonic, operands)?;
+        let (mnemonic, operands) = match &expanded {
+            Some((mnemonic, operands)) => (*mnemonic, operands.as_slice()),
+            None => (mnemonic, operands),
+        };
+        let operands = self.relative(operands, addr)?;
+        let opcode = Opcode::ALL
+            .into_iter()
+            .find(|opcode| disasm::mnemonic(opcode) == mnemonic)
+            .ok_or_else(|| format!("unknown mnemonic {mnemonic:?}"))?;
+        let mut instruction = Instruction {
+            opcode,
+            rd: RegisterID::X0,
+            rs1: RegisterID::X0,
+            rs2: RegisterID::X0,
+            imm: 0,
+        };
+        let register = |operand: &String| {
+            operand
+                .parse::<RegisterID>()
+                .map_err(|_| format!("unknown register {operand:?}"))
+        };
+        let target = |operand: &String| match self.labels.get(operand) {
+            Some(&label) => offset(i64::from(label) - i64::from(addr)),
+            None => parse_number(operand)
+                .ok_or_else(|| format!("unknown label {operand:?}"))
+                .and_then(offset),
+        };
+        let wrong = || {
+            format!(
+                "{mnemonic} takes {}",
+                match opcode {
+                    Opcode::LoadImmediate | Opcode::Auipc | Opcode::Lui => {
+                        "a register and an immediate"
+                    }
+                    Opcode::Add
+                    | Opcode::Sub
+                    | Opcode::Mul
+                    | Opcode::Div
+                    | Opcode::Rem
+                    | Opcode::And
+                    | Opcode::Or
+                    | Opcode::Xor
+                    | Opcode::Sll
+                    | Opcode::Srl
+                    | Opcode::Sra
+                    | Opcode::Slt
+                    | Opcode::Sltu => {
+                        "three registers and an optional immediate"
+                    }
+                    Opcode::Jal => "a register and a target",
+                    Opcode::Jalr => "two registers and an optional offset",
+                    Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge => {
+                        "two registers and a target"
+                    }
+                    Opcode::Lb | Opcode::Lh | Opcode::Lw | Opcode::Sb | Opcode::Sh | Opcode::Sw => {
+                        "a register and an `offset(base)` operand"
+                    }
+                    Opcode::ECall | Opcode::EBreak | Opcode::Mret => "no operands",
+                }
+            )
+        };
+        match (opcode, operands.as_slice()) {
+            (Opcode::LoadImmediate, [rd, imm]) => {
+                instruction.rd = register(rd)?;
+                instruction.imm = immediate(imm)?;
+            }
+            (Opcode::Auipc | Opcode::Lui, [rd, imm]) => {
+                instruction.rd = register(rd)?;
+                instruction.imm = signed_immediate(imm)?;
+            }
+            (
+                Opcode::Add
+                | Opcode::Sub
+                | Opcode::Mul
+                | Opcode::Div
+                | Opcode::Rem
+                | Opcode::And
+                | Opcode::Or
+                | Opcode::Xor
+                | Opcode::Sll
+                | Opcode::Srl
+                | Opcode::Sra
+                | Opcode::Slt
+                | Opcode::Sltu,
+                [rd, rs1, rs2, rest @ ..],
+            ) if rest.len() <= 1 => {
+                instruction.rd = register(rd)?;
+                instruction.rs1 = register(rs1)?;
+                instruction.rs2 = register(rs2)?;
+                if let [imm] = rest {
+                    instruction.imm = signed_immediate(imm)?;
+                }
+            }
+            (Opcode::Jal, [rd, to]) => {
+                instruction.rd = register(rd)?;
+                instruction.imm = target(to)?;
+            }
+            (Opcode::Jalr, [rd, rs1, rest @ ..]) if rest.len() <= 1 => {
+                instruction.rd = register(rd)?;
+                instruction.rs1 = register(rs1)?;
+                if let [by] = rest {
+                    let by = parse_number(by).ok_or_else(|| format!("invalid offset {by:?}"))?;
+                    instruction.imm = offset(by)?;
+                }
+            }
+            (Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge, [rs1, rs2, to]) => {
+                instruction.rs1 = register(rs1)?;
+                instruction.rs2 = register(rs2)?;
+                instruction.imm = target(to)?;
+            }
+            (Opcode::Lb | Opcode::Lh | Opcode::Lw, [rd, operand]) => {
+                instruction.rd = register(rd)?;
+                (instruction.rs1, instruction.imm) = memory_operand(operand)?;
+            }
+            (Opcode::Sb | Opcode::Sh | Opcode::Sw, [rs2, operand]) => {
+                instruction.rs2 = register(rs2)?;
+                (instruction.rs1, instruction.imm) = memory_operand(operand)?;
+            }
+            (Opcode::ECall | Opcode::EBreak | Opcode::Mret, []) => {}
+            _ => return Err(wrong()),
+        }
+        Ok(instruction)
+    }
+}
+
+// The instruction `mnemonic` stands for, if it is a pseudo-instruction.
+fn expand(
+    mnemonic: &str,
+    operands: &[String],
+) -> std::result::Result<Option<(&'static str, Vec<String>)>, String> {
+    let with = |operands: &[&str]| operands.iter().map(ToString::to_string).collect();
+    Ok(Some(match (mnemonic, operands) {
+        ("nop", []) => ("add", with(&["x0", "x0", "x0"])),
+        ("mv", [rd, rs]) => ("add", with(&[rd, rs, "x0"])),
+        ("j", [target]) => ("jal", with(&["x0", target])),
+        ("call", [target]) => ("jal", with(&["ra", target])),
+        ("ret", []) => ("jalr", with(&["x0", "ra"])),
+        ("nop" | "ret", _) => return Err(format!("{mnemonic} takes no operands")),
+        ("mv", _) => return Err("mv takes two registers".to_string()),
+        ("j" | "call", _) => return Err(format!("{mnemonic} takes a target")),
+        _ => return Ok(None),
+    }))
+}
+
+// One instruction of a sequence loading a constant into a register.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Load {
+    Li(i32),
+    // add rd, x0, x0, value
+    Negative(i32),
+    Lui(i32),
+    // add rd, rd, x0, value
+    Add(i32),
+    Sll(i32),
+    Srl(i32),
+    Sra(i32),
+    // sub rd, x0, rd, 0
+    Negate,
+}
+
+// What a single instruction can load: li when the value fits its unsigned
+// immediate, an add off x0 for a small negative one and lui for a value
+// with its low 15 bits clear.
+fn single_load(value: i32) -> Option<Load> {
+    if (0..=0x7fff).contains(&value) {
+        Some(Load::Li(value))
+    } else if (-0x4000..0).contains(&value) {
+        Some(Load::Negative(value))
+    } else if value.trailing_zeros() >= 15 && (-0x4000..0x4000).contains(&(value >> 15)) {
+        Some(Load::Lui(value >> 15))
+    } else {
+        None
+    }
+}
+
+// A sequence of at most `len` instructions loading `value`: a single one,
+// or one loading another value followed by a shift, a negation or an add
+// of the low bits.
+fn search_load(value: i32, len: usize) -> Option<Vec<Load>> {
+    if let Some(load) = single_load(value) {
+        return Some(vec![load]);
+    }
+    if len < 2 {
+        return None;
+    }
+    let then = |before: i32, load: Load| {
+        let mut loads = search_load(before, len - 1)?;
+        loads.push(load);
+        Some(loads)
+    };
+    let low = (value << 17) >> 17;
+    if low != 0 {
+        if let Some(loads) = then(value.wrapping_sub(low), Load::Add(low)) {
+            return Some(loads);
+        }
+    }
+    if value != i32::MIN {
+        if let Some(loads) = then(-value, Load::Negate) {
+            return Some(loads);
+        }
+    }
+    (1..32).find_map(|shift| {
+        let mask = ((1u32 << shift) - 1) as i32;
+        let shifted_out = [value << shift | mask, value << shift];
+        let left = (value & mask == 0)
+            .then(|| {
+                [value >> shift, ((value as u32) >> shift) as i32]
+                    .into_iter()
+                    .find_map(|before| then(before, Load::Sll(shift)))
+            })
+            .flatten();
+        let logical = (((value as u32) >> (32 - shift)) == 0)
+            .then(|| {
+                shifted_out
+                    .into_iter()
+                    .find_map(|before| then(before, Load::Srl(shift)))
+            })
+            .flatten();
+        let arithmetic = matches!(value >> (31 - shift), 0 | -1)
+            .then(|| {
+                shifted_out
+                    .into_iter()
+                    .find_map(|before| then(before, Load::Sra(shift)))
+            })
+            .flatten();
+        left.or(logical).or(arithmetic)
+    })
+}
+
+// The instructions loading `value` into `rd`: the fewest a search of up to
+// three single loads, shifts, negations and adds finds, which covers every
+// value within 512M of zero in two. Anything the search misses is built
+// shifted down three places, shifted back up and its low bits added.
+fn load_immediate(rd: &str, value: i32) -> Vec<(&'static str, Vec<String>)> {
+    let with = |operands: &[&str]| operands.iter().map(ToString::to_string).collect();
+    let Some(loads) = (1..=3).find_map(|len| search_load(value, len)) else {
+        let mut instructions = load_immediate(rd, value >> 3);
+        instructions.push(("sll", with(&[rd, rd, "x0", "3"])));
+        if value & 7 != 0 {
+            instructions.push(("add", with(&[rd, rd, "x0", &(value & 7).to_string()])));
+        }
+        return instructions;
+    };
+    loads
+        .into_iter()
+        .map(|load| match load {
+            Load::Li(value) => ("li", with(&[rd, &value.to_string()])),
+            Load::Negative(value) => ("add", with(&[rd, "x0", "x0", &value.to_string()])),
+            Load::Lui(high) => ("lui", with(&[rd, &high.to_string()])),
+            Load::Add(low) => ("add", with(&[rd, rd, "x0", &low.to_string()])),
+            Load::Sll(shift) => ("sll", with(&[rd, rd, "x0", &shift.to_string()])),
+            Load::Srl(shift) => ("srl", with(&[rd, rd, "x0", &shift.to_string()])),
+            Load::Sra(shift) => ("sra", with(&[rd, rd, "x0", &shift.to_string()])),
+            Load::Negate => ("sub", with(&[rd, "x0", rd, "0"])),
+        })
+        .collect()
+}
+
+// The literal an instruction uses, if any: a string loaded by li or an
+// `=value` loaded by lw.
+fn literal(mnemonic: &str, operands: &[String]) -> std::result::Result<Option<Literal>, String> {
+    match (mnemonic, operands) {
+        ("li", [_, text]) if text.starts_with('"') => {
+            let mut text = parse_string(text)
+                .ok_or_else(|| format!("invalid string {text}"))?
+                .into_bytes();
+            text.push(0);
+            Ok(Some(Literal::String(text)))
+        }
+        ("lw", [_, operand]) => match operand.strip_prefix('=') {
+            Some(value) => parse_number(value)
+                .filter(|n| (-0x8000_0000..=0xffff_ffff).contains(n))
+                .map(|n| Some(Literal::Word(n as Word)))
+                .ok_or_else(|| format!("invalid constant {value:?}")),
+            None => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+// The operands of an instruction using the literal at `addr`, with the
+// literal replaced by its address.
+fn relocate(
+    mnemonic: &str,
+    operands: &[String],
+    addr: Word,
+) -> std::result::Result<Vec<String>, String> {
+    let reach = if mnemonic == "li" { 0x8000 } else { 0x4000 };
+    if addr >= reach {
+        return Err(format!(
+            "literal at {addr:#x} is out of reach of {mnemonic}"
+        ));
+    }
+    Ok(vec![operands[0].clone(), addr.to_string()])
+}
+
+// Splits operands at the commas outside quotes.
+fn split_operands(raw: &str) -> Vec<String> {
+    if raw.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in raw.char_indices() {
+        match c {
+            '\\' if quoted => escaped = !escaped,
+            '"' if !escaped => quoted = !quoted,
+            ',' if !quoted => {
+                operands.push(raw[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => escaped = false,
+        }
+    }
+    operands.push(raw[start..].trim().to_string());
+    operands
+}
+
+// A quoted string with `\n`, `\t`, `\0`, `\"` and `\\` escapes, which must
+// be all of `text`.
+fn parse_string(text: &str) -> Option<String> {
+    let mut chars = text.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        value.push(match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                '0' => '\0',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            },
+            '"' => return None,
+            c => c,
+        });
+    }
+    Some(value)
+}
+
+// `offset(base)`, `(base)` for no offset, or a bare address off x0.
+fn memory_operand(operand: &str) -> std::result::Result<(RegisterID, u16), String> {
+    let invalid = || format!("invalid memory operand {operand:?}");
+    let (by, base) = match operand.strip_suffix(')') {
+        Some(rest) => {
+            let (by, base) = rest.split_once('(').ok_or_else(invalid)?;
+            let base = base
+                .trim()
+                .parse()
+                .map_err(|_| format!("unknown register {:?}", base.trim()))?;
+            (by.trim(), base)
+        }
+        None => (operand, RegisterID::X0),
+    };
+    let by = match by {
+        "" => 0,
+        by => parse_number(by).ok_or_else(invalid)?,
+    };
+    Ok((base, offset(by)?))
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// Strips a comment, ignoring `#` inside quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if quoted => escaped = !escaped,
+            '"' if !escaped => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+// A decimal, 0x hexadecimal or 0b binary number, optionally negative.
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+// An unsigned immediate, which must fit the 15-bit field.
+fn immediate(text: &str) -> std::result::Result<u16, String> {
+    parse_number(text)
+        .ok_or_else(|| format!("invalid immediate {text:?}"))?
+        .try_into()
+        .ok()
+        .filter(|&imm| imm <= 0x7fff)
+        .ok_or_else(|| format!("immediate {text} does not fit in 15 bits"))
+}
+
+// A signed immediate, which must fit the 15-bit field as two's complement.
+fn signed_immediate(text: &str) -> std::result::Result<u16, String> {
+    let imm = parse_number(text).ok_or_else(|| format!("invalid immediate {text:?}"))?;
+    if (-0x4000..0x4000).contains(&imm) {
+        Ok((imm & 0x7fff) as u16)
+    } else {
+        Err(format!("immediate {text} does not fit in 15 signed bits"))
+    }
+}
+
+// A signed offset, stored in the 15-bit field as two's complement.
+fn offset(by: i64) -> std::result::Result<u16, String> {
+    if (-0x4000..0x4000).contains(&by) {
+        Ok((by & 0x7fff) as u16)
+    } else {
+        Err(format!("offset {by} does not fit in 15 bits"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HaltReason, Machine};
+    use claims::{assert_err_eq, assert_ok, assert_ok_eq};
+
+    fn words(image: &[u8]) -> Vec<Word> {
+        image
+            .chunks(4)
+            .map(|word| Word::from_be_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn instructions_are_encoded() {
+        use RegisterID::{A0, A1, RA, SP, X0};
+        let image = assemble(
+            "start:  li a0, 0x10     # load\n\
+             \x20       add a1, a0, x0, 2\n\
+             loop:   bne a1, a0, loop\n\
+             \x20       jal ra, start\n\
+             \x20       jalr x0, ra, -4\n\
+             \x20       lw a0, -8(sp)\n\
+             \x20       sb a1, (a0)\n\
+             \x20       ecall\n\
+             \x20       ebreak\n",
+        );
+        let encode = |opcode, rd, rs1, rs2, imm| {
+            Instruction {
+                opcode,
+                rd,
+                rs1,
+                rs2,
+                imm,
+            }
+            .encode()
+        };
+        assert_ok_eq!(
+            image.map(|image| words(&image)),
+            vec![
+                encode(Opcode::LoadImmediate, A0, X0, X0, 0x10),
+                encode(Opcode::Add, A1, A0, X0, 2),
+                encode(Opcode::Bne, X0, A1, A0, 0),
+                encode(Opcode::Jal, RA, X0, X0, 0x7ff4),
+                encode(Opcode::Jalr, X0, RA, X0, 0x7ffc),
+                encode(Opcode::Lw, A0, SP, X0, 0x7ff8),
+                encode(Opcode::Sb, X0, A0, A1, 0),
+                encode(Opcode::ECall, X0, X0, X0, 0),
+                encode(Opcode::EBreak, X0, X0, X0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn pseudo_instructions_and_data_are_laid_down() {
+        let image = assert_ok!(assemble(
+            "start: nop\nmv a0, a1\nj start\ncall start\nret\n\
+             .byte 1, -1\n.word 0x01020304\n.string \"a,\\\"#\\n\"\n.space 2",
+        ));
+        let expected = assert_ok!(assemble(
+            "start: add x0, x0, x0\nadd a0, a1, x0\njal x0, start\njal ra, start\njalr x0, ra"
+        ));
+
+        assert_eq!(image[..20], expected[..]);
+        assert_eq!(
+            image[20..],
+            [1, 0xff, 1, 2, 3, 4, b'a', b',', b'"', b'#', b'\n', 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn wide_immediates_are_built_from_as_few_instructions_as_they_need() {
+        let cases = [
+            (0x7fff_i64, "li a0, 32767"),
+            (-5, "add a0, x0, x0, -5"),
+            (0x8000, "lui a0, 1"),
+            (0x1234_5678, "lui a0, 9321\nadd a0, a0, x0, -10632"),
+            (-0x2000_0000, "lui a0, -16384"),
+            (
+                0xdead_beef,
+                "lui a0, -2133\nadd a0, a0, x0, 14301\nsll a0, a0, x0, 3\nadd a0, a0, x0, 7",
+            ),
+            (0x8000_0000, "lui a0, -16384\nsll a0, a0, x0, 2"),
+            (0x7fff_ffff, "add a0, x0, x0, -1\nsrl a0, a0, x0, 1"),
+            (0x7fff_f001, "add a0, x0, x0, -8189\nsrl a0, a0, x0, 1"),
+            (
+                0xc000_0001,
+                "lui a0, -16384\nsll a0, a0, x0, 1\nadd a0, a0, x0, 1",
+            ),
+            (-0x7fff, "lui a0, -1\nadd a0, a0, x0, 1"),
+        ];
+        for (value, expected) in cases {
+            let source = format!("li a0, {value}\nebreak");
+            let image = assert_ok!(assemble(&source));
+            assert_eq!(image[..image.len() - 4], assert_ok!(assemble(expected)));
+
+            let mut machine: Machine<Vec<u8>> = Machine::builder().program(&image).build().unwrap();
+            assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+            assert_eq!(machine.register(RegisterID::A0), value as Word);
+        }
+        // Labels after an expanded li account for its length.
+        let image = assert_ok!(assemble("li a0, 0x12345678\nend: j end"));
+        assert_eq!(image[8..], assert_ok!(assemble("j 0"))[..]);
+    }
+
+    #[test]
+    fn literals_are_kept_once_after_the_program() {
+        let image = assert_ok!(assemble(
+            "li a0, \"hi, you\"\nlw a1, =0x12345678\nli a2, \"hi, you\"\n\
+             lw a3, =0x12345678\nli a4, \"\"\nebreak\n.byte 7",
+        ));
+        let expected = assert_ok!(assemble(
+            "li a0, 32\nlw a1, 28(x0)\nli a2, 32\nlw a3, 28(x0)\nli a4, 40\nebreak"
+        ));
+
+        assert_eq!(image[..24], expected[..]);
+        // The program's own data, padding to a word, then the rodata.
+        assert_eq!(image[24..32], [7, 0, 0, 0, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(image[32..], *b"hi, you\0\0");
+    }
+
+    #[test]
+    fn position_independent_code_runs_at_any_base() {
+        let image = assert_ok!(assemble(
+            "la a0, near
+             lw a1, 0(a0)
+             auipc a2, %pcrel_hi(far)
+             lw a3, %pcrel_lo(far)(a2)
+             ebreak
+             near: .word 7
+             .space 0x9000
+             far: .word 9",
+        ));
+        // Reaching far takes a high part as well as a low one.
+        assert_eq!(words(&image)[3] >> 17, 1);
+
+        for base in [0, 0x1000, 0x0123_4568] {
+            let mut machine: Machine<Vec<u8>> = Machine::builder()
+                .executable(Executable::flat(&image, base))
+                .build()
+                .unwrap();
+            assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+            assert_eq!(machine.register(RegisterID::A0), base + 24);
+            assert_eq!(machine.register(RegisterID::A1), 7);
+            assert_eq!(machine.register(RegisterID::A3), 9);
+        }
+        let script = assert_ok!(LinkerScript::parse(
+            "MEMORY { rom : ORIGIN = 0, LENGTH = 4K  ram : ORIGIN = 0x80000000, LENGTH = 4K }
+             SECTIONS { .text > rom  .data > ram }"
+        ));
+        assert_err_eq!(
+            assemble_with_script("la a0, far\n.section .data\nfar: .word 0", &script),
+            Error::AsmInvalid("line 1: far is out of reach of auipc".to_string())
+        );
+    }
+
+    #[test]
+    fn sections_are_laid_out_one_after_another() {
+        let image = assert_ok!(assemble(
+            ".section .data\n.byte 1\n.section .text\nback: ebreak\n\
+             .section .data\n.byte 2\n.section .text\nj back",
+        ));
+        let expected = assert_ok!(assemble("back: ebreak\nj back"));
+
+        assert_eq!(image[..8], expected[..]);
+        assert_eq!(image[8..], [1, 2]);
+    }
+
+    #[test]
+    fn linker_scripts_place_sections_in_memory_regions() {
+        let script = assert_ok!(LinkerScript::parse(
+            "ENTRY(_start)
+             MEMORY {
+                 rom : ORIGIN = 0x0, LENGTH = 16K
+                 ram : ORIGIN = 0x80000000, LENGTH = 64K
+             }
+             SECTIONS { .text > rom  .rodata > rom  .data > ram }",
+        ));
+        let executable = assert_ok!(assemble_with_script(
+            ".section .data
+             seven: .word 7
+             .section .text
+             spin: j spin
+             _start: li a1, 1
+             sll a1, a1, x0, 31  # the start of ram
+             lw a0, 0(a1)
+             li a2, \"ok\"
+             lb a3, 0(a2)
+             ebreak",
+            &script,
+        ));
+
+        assert_eq!(executable.entry, 4);
+        let placed: Vec<(Address, u32)> = executable
+            .segments
+            .iter()
+            .map(|segment| (segment.addr, segment.size))
+            .collect();
+        assert_eq!(placed, [(0, 28), (0x8000_0000, 4), (28, 3)]);
+        let mut machine: Machine<Vec<u8>> =
+            Machine::builder().executable(executable).build().unwrap();
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register(RegisterID::A0), 7);
+        assert_eq!(machine.register(RegisterID::A3), u32::from(b'o'));
+
+        let script = assert_ok!(LinkerScript::parse(
+            "MEMORY { rom : ORIGIN = 0, LENGTH = 4 } SECTIONS { .text > rom }"
+        ));
+        assert_err_eq!(
+            assemble_with_script("ebreak\n.section .data\n.word 1", &script),
+            Error::LinkFailed("section .data is not placed by the script".to_string())
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let cases = [
+            ("ebreak\nmov a0, a1\n", "line 2: unknown mnemonic \"mov\""),
+            ("li a13, 1", "line 1: unknown register \"a13\""),
+            (
+                "li a0, 0x100000000",
+                "line 1: immediate 0x100000000 does not fit in 32 bits",
+            ),
+            ("beq a0, a1, nowhere", "line 1: unknown label \"nowhere\""),
+            (
+                "add a0, a1",
+                "line 1: add takes three registers and an optional immediate",
+            ),
+            ("x: ebreak\nx: ebreak", "line 2: duplicate label \"x\""),
+            ("lw a0, 4(a13)", "line 1: unknown register \"a13\""),
+            ("mv a0", "line 1: mv takes two registers"),
+            (
+                "sub a0, a1, x0, 0x4000",
+                "line 1: immediate 0x4000 does not fit in 15 signed bits",
+            ),
+            (
+                "xor a0, a1",
+                "line 1: xor takes three registers and an optional immediate",
+            ),
+            (".byte 256", "line 1: invalid value \"256\""),
+            (".string hello", "line 1: .string needs a quoted string"),
+            (".section data", "line 1: invalid section name \"data\""),
+            ("li a0, \"oops", "line 1: invalid string \"oops"),
+            (
+                "lw a0, =0x100000000",
+                "line 1: invalid constant \"0x100000000\"",
+            ),
+            (
+                "li a0, \"far\"\n.space 0x8000",
+                "line 1: literal at 0x8004 is out of reach of li",
+            ),
+        ];
+        for (source, want) in cases {
+            assert_err_eq!(assemble(source), Error::AsmInvalid(want.to_string()));
+        }
+    }
+
+    #[test]
+    fn files_are_included_once_relative_to_the_includer() {
+        let files = HashMap::from([
+            (
+                PathBuf::from("lib/a.s"),
+                ".include \"b.s\"\na: jalr x0, ra".to_string(),
+            ),
+            (PathBuf::from("lib/b.s"), "b: jalr x0, ra".to_string()),
+        ]);
+        let read = |path: &Path| {
+            files
+                .get(path)
+                .cloned()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        };
+        let source = "jal ra, a\njal ra, b\nebreak\n.include \"lib/a.s\"\n.include \"lib/b.s\"\n";
+
+        let image = flatten(assert_ok!(Assembler::new(read).assemble(source, None, None)));
+
+        // b.s lands before a.s's own code, and only once.
+        assert_eq!(image.len(), 20);
+        assert_eq!(words(&image)[0] >> 17, 16);
+        assert_eq!(words(&image)[1] >> 17, 8);
+        assert_err_eq!(
+            Assembler::new(read).assemble(".include \"c.s\"", None, None),
+            Error::AsmInvalid("line 1: cannot include c.s: entity not found".to_string())
+        );
+    }
+
+    #[test]
+    fn the_guest_library_prints_integers() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let image = assert_ok!(assemble_file(dir.join("print_int.s")));
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .stdout(Vec::new())
+            .program(&image)
+            .build()
+            .unwrap();
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.stdout, Some(b"12345".to_vec()));
+    }
+
+    #[test]
+    fn the_guest_library_copies_and_measures_strings() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let image = assert_ok!(assemble_file(dir.join("strings.s")));
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .stdout(Vec::new())
+            .program(&image)
+            .build()
+            .unwrap();
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.stdout, Some(b"hey\n4".to_vec()));
+    }
+}
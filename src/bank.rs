@@ -0,0 +1,182 @@
+use crate::{Address, Error, Machine, Memory, Result};
+use std::{collections::HashMap, io::Write, ops::Range};
+
+// A window of the address space backed by one of several banks, with the
+// bank chosen by the byte at the `select` address, a memory-mapped
+// register. Writing the register switches banks immediately, the way
+// overlays are swapped in on small machines. Select values wrap around
+// the number of banks.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BankConfig {
+    pub window: Range<Address>,
+    pub select: Address,
+    pub count: u8,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Banks {
+    config: BankConfig,
+    // Each bank's bytes, keyed by offset into the window.
+    contents: Vec<HashMap<Address, u8>>,
+}
+
+impl Banks {
+    pub fn new(config: BankConfig) -> Result<Self> {
+        if config.count == 0 || config.window.is_empty() {
+            return Err(Error::LayoutInvalid(
+                "a banked window needs at least one bank and one byte",
+            ));
+        }
+        if config.window.contains(&config.select) {
+            return Err(Error::LayoutInvalid(
+                "the bank select register must be outside the window",
+            ));
+        }
+        Ok(Self {
+            contents: vec![HashMap::new(); config.count.into()],
+            config,
+        })
+    }
+}
+
+impl Memory {
+    pub(crate) fn set_banks(&mut self, banks: Banks) {
+        self.banks = Some(Box::new(banks));
+    }
+
+    pub(crate) fn selected_bank(&self) -> Option<u8> {
+        let banks = self.banks.as_ref()?;
+        let select = self.stored(banks.config.select);
+        Some(select.unwrap_or_default() % banks.config.count)
+    }
+
+    // Where a banked address lives: the selected bank and the offset into
+    // it. Addresses outside the window are not banked.
+    pub(crate) fn bank_slot(&self, addr: Address) -> Option<(usize, Address)> {
+        let banks = self.banks.as_ref()?;
+        let window = &banks.config.window;
+        window.contains(&addr).then(|| {
+            (
+                self.selected_bank().unwrap_or_default().into(),
+                addr - window.start,
+            )
+        })
+    }
+
+    pub(crate) fn bank_get(&self, addr: Address) -> Option<u8> {
+        let (bank, offset) = self.bank_slot(addr)?;
+        let banks = self.banks.as_ref()?;
+        Some(
+            banks.contents[bank]
+                .get(&offset)
+                .copied()
+                .unwrap_or_default(),
+        )
+    }
+
+    // Stores a banked byte, returning false for addresses outside the
+    // window.
+    pub(crate) fn bank_set(&mut self, addr: Address, value: u8) -> bool {
+        let Some((bank, offset)) = self.bank_slot(addr) else {
+            return false;
+        };
+        if let Some(banks) = &mut self.banks {
+            banks.contents[bank].insert(offset, value);
+        }
+        true
+    }
+
+    // Addresses holding data in the selected bank.
+    pub(crate) fn banked_addresses(&self) -> Vec<Address> {
+        let (Some(banks), Some(bank)) = (&self.banks, self.selected_bank()) else {
+            return Vec::new();
+        };
+        let start = banks.config.window.start;
+        banks.contents[bank as usize]
+            .keys()
+            .map(|offset| start + offset)
+            .collect()
+    }
+
+    // Places `data` at `addr` in a specific bank, whichever is selected,
+    // so a loader can lay out every overlay up front.
+    pub(crate) fn write_bank(&mut self, bank: u8, addr: Address, data: &[u8]) -> Result<()> {
+        let banks = self
+            .banks
+            .as_mut()
+            .ok_or(Error::LayoutInvalid("memory is not bank-switched"))?;
+        let window = &banks.config.window;
+        let end = addr as u64 + data.len() as u64;
+        if addr < window.start || end > window.end as u64 {
+            return Err(Error::LayoutInvalid("bank section is outside the window"));
+        }
+        let contents = banks
+            .contents
+            .get_mut(bank as usize)
+            .ok_or(Error::LayoutInvalid("no such bank"))?;
+        for (offset, byte) in data.iter().enumerate() {
+            contents.insert(addr - window.start + offset as Address, *byte);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn selected_bank(&self) -> Option<u8> {
+        self.mem.selected_bank()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err_eq, assert_ok};
+
+    fn memory() -> Memory {
+        let mut mem = Memory::default();
+        mem.set_banks(
+            Banks::new(BankConfig {
+                window: 0x1000..0x2000,
+                select: 0x3000,
+                count: 2,
+            })
+            .unwrap(),
+        );
+        mem
+    }
+
+    #[test]
+    fn the_select_register_switches_what_the_window_shows() {
+        let mut mem = memory();
+        mem.write(0x1000, b"zero");
+        assert_ok!(mem.write_bank(1, 0x1000, b"one"));
+
+        assert_eq!(mem.read(0x1000, 4), b"zero");
+        mem.set(0x3000, 1);
+        assert_eq!(mem.read(0x1000, 4), b"one\0");
+        mem.set(0x3000, 2);
+        assert_eq!(mem.read(0x1000, 4), b"zero");
+        assert_eq!(mem.selected_bank(), Some(0));
+    }
+
+    #[test]
+    fn bank_layouts_are_validated() {
+        let config = BankConfig {
+            window: 0x1000..0x2000,
+            select: 0x1800,
+            count: 2,
+        };
+        assert_err_eq!(
+            Banks::new(config),
+            Error::LayoutInvalid("the bank select register must be outside the window")
+        );
+        assert_err_eq!(
+            memory().write_bank(0, 0x1ffe, b"abc"),
+            Error::LayoutInvalid("bank section is outside the window")
+        );
+        assert_err_eq!(
+            memory().write_bank(2, 0x1000, b"abc"),
+            Error::LayoutInvalid("no such bank")
+        );
+    }
+}
@@ -0,0 +1,425 @@
+use crate::{json, limits::Limits, pool::MachinePool, Error, HaltReason, Machine, Result};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// What a job may use unless its spec says otherwise, so a runaway program
+// cannot hold up the rest of the batch.
+pub const JOB_LIMITS: Limits = Limits {
+    max_steps: Some(1_000_000_000),
+    max_memory: None,
+    max_output_bytes: Some(16 << 20),
+    max_open_files: None,
+    wall_clock: Some(Duration::from_mins(1)),
+};
+
+// One program of a batch. Paths are relative to the batch file.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Job {
+    pub name: String,
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    // A file whose contents are the program's stdin.
+    pub input: Option<PathBuf>,
+    // A file holding the output the program must produce to pass.
+    pub expected: Option<PathBuf>,
+    pub limits: Limits,
+}
+
+// A set of programs to run, read from a file in a small subset of TOML:
+//
+//   parallel = 4
+//
+//   [[program]]
+//   name = "hello"
+//   path = "hello.bin"
+//   args = ["-v"]
+//   input = "hello.in"
+//   expected = "hello.out"
+//   max_steps = 1000000
+//   max_output_bytes = 4096
+//   wall_clock_ms = 2000
+//
+// A job's limits are JOB_LIMITS with any it sets in its place.
+// Values are strings, integers or one-line arrays of strings; `#` starts a
+// comment.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Batch {
+    pub parallel: usize,
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Value {
+    Text(String),
+    Integer(u64),
+    List(Vec<String>),
+}
+
+fn invalid(line: usize, reason: &str) -> Error {
+    Error::BatchInvalid(format!("line {line}: {reason}"))
+}
+
+// Parses a basic string starting at the opening quote, returning it and
+// what follows the closing quote.
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &text[i + 2..])),
+            '\\' => value.push(match chars.next()?.1 {
+                'n' => '\n',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            }),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+fn parse_value(text: &str) -> Option<Value> {
+    if text.starts_with('"') {
+        let (value, rest) = parse_string(text)?;
+        return rest.trim().is_empty().then_some(Value::Text(value));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(end) = rest.strip_prefix(']') {
+                return end.trim().is_empty().then_some(Value::List(items));
+            }
+            let (item, after) = parse_string(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    }
+    text.replace('_', "").parse().ok().map(Value::Integer)
+}
+
+// Strips a comment, ignoring `#` inside strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if quoted => escaped = !escaped,
+            '"' if !escaped => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+impl Batch {
+    pub fn parse(text: &str, base: &Path) -> Result<Self> {
+        let mut top = HashMap::new();
+        let mut tables: Vec<(usize, HashMap<String, Value>)> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[[program]]" {
+                tables.push((number, HashMap::new()));
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(number, "expected `key = value`"))?;
+            let value =
+                parse_value(value.trim()).ok_or_else(|| invalid(number, "unsupported value"))?;
+            let table = match tables.last_mut() {
+                Some((_, table)) => table,
+                None => &mut top,
+            };
+            if table.insert(key.trim().to_string(), value).is_some() {
+                return Err(invalid(number, "duplicate key"));
+            }
+        }
+
+        let parallel = match top.remove("parallel") {
+            Some(Value::Integer(n)) => n as usize,
+            None => 1,
+            Some(_) => return Err(invalid(1, "parallel must be an integer")),
+        };
+        if let Some(key) = top.keys().next() {
+            return Err(Error::BatchInvalid(format!("unknown setting {key:?}")));
+        }
+        let mut jobs = Vec::new();
+        for (line, mut table) in tables {
+            let mut text = |key: &str| match table.remove(key) {
+                Some(Value::Text(text)) => Ok(Some(text)),
+                None => Ok(None),
+                Some(_) => Err(invalid(line, &format!("{key} must be a string"))),
+            };
+            let program = text("path")?.ok_or_else(|| invalid(line, "program has no path"))?;
+            let name = text("name")?.unwrap_or_else(|| program.clone());
+            let input = text("input")?.map(|path| base.join(path));
+            let expected = text("expected")?.map(|path| base.join(path));
+            let args = match table.remove("args") {
+                Some(Value::List(args)) => args,
+                None => Vec::new(),
+                Some(_) => return Err(invalid(line, "args must be a list of strings")),
+            };
+            let mut integer = |key: &str| match table.remove(key) {
+                Some(Value::Integer(n)) => Ok(Some(n)),
+                None => Ok(None),
+                Some(_) => Err(invalid(line, &format!("{key} must be an integer"))),
+            };
+            let limits = Limits {
+                max_steps: integer("max_steps")?.or(JOB_LIMITS.max_steps),
+                max_output_bytes: integer("max_output_bytes")?.or(JOB_LIMITS.max_output_bytes),
+                wall_clock: integer("wall_clock_ms")?
+                    .map(Duration::from_millis)
+                    .or(JOB_LIMITS.wall_clock),
+                ..JOB_LIMITS
+            };
+            if let Some(key) = table.keys().next() {
+                return Err(invalid(line, &format!("unknown key {key:?}")));
+            }
+            jobs.push(Job {
+                name,
+                program: base.join(program),
+                args,
+                input,
+                expected,
+                limits,
+            });
+        }
+        Ok(Self { parallel, jobs })
+    }
+
+    // Runs every job, `parallel` at a time.
+    pub fn run(&self) -> Vec<JobReport> {
+        MachinePool::new(self.parallel).run(self.jobs.clone(), Job::run)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Status {
+    // The program stopped at a breakpoint.
+    Finished,
+    // The program halted for another reason, shown here.
+    Halted(String),
+    // The program was still running when it used up its steps.
+    StepLimit,
+    // The program was still running when it used up its time.
+    TimeLimit,
+    // The program could not be loaded or failed with an error.
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct JobReport {
+    pub name: String,
+    pub status: Status,
+    pub retired: u64,
+    pub output: Vec<u8>,
+    // Whether the output matched the expected output, if there was one.
+    pub passed: Option<bool>,
+}
+
+impl Job {
+    pub fn run(self) -> JobReport {
+        let (status, retired, output) = match self.execute() {
+            Ok(outcome) => outcome,
+            Err(err) => (Status::Failed(err), 0, Vec::new()),
+        };
+        let passed = self.expected.as_ref().map(|path| {
+            status == Status::Finished && fs::read(path).is_ok_and(|expected| expected == output)
+        });
+        JobReport {
+            name: self.name,
+            status,
+            retired,
+            output,
+            passed,
+        }
+    }
+
+    fn execute(&self) -> std::result::Result<(Status, u64, Vec<u8>), String> {
+        let read = |path: &Path| fs::read(path).map_err(|err| format!("{}: {err}", path.display()));
+        let image = read(&self.program)?;
+        let input = match &self.input {
+            Some(path) => read(path)?,
+            None => Vec::new(),
+        };
+        let argv = std::iter::once(self.program.display().to_string()).chain(self.args.clone());
+        let mut machine = Machine::builder()
+            .stdout(Vec::new())
+            .stdin(Cursor::new(input))
+            .program(&image)
+            .args(argv)
+            .build()
+            .map_err(|err| format!("{err}"))?;
+        machine.set_limits(Some(self.limits));
+        let status = match machine.run() {
+            Ok(HaltReason::Breakpoint | HaltReason::Exit { code: 0 }) => Status::Finished,
+            Ok(HaltReason::StepLimit { .. }) => Status::StepLimit,
+            Ok(HaltReason::WallClockLimit { .. }) => Status::TimeLimit,
+            Ok(reason) => Status::Halted(reason.to_string()),
+            Err(err) => Status::Failed(format!("{err}")),
+        };
+        let output = machine.stdout.take().unwrap_or_default();
+        Ok((status, machine.retired, output))
+    }
+}
+
+// A JSON report of a batch: a summary and one object per program, in the
+// order of the batch file.
+pub fn report(reports: &[JobReport]) -> String {
+    let passed = reports.iter().filter(|r| r.passed == Some(true)).count();
+    let failed = reports.iter().filter(|r| r.passed == Some(false)).count();
+    let mut out = format!(
+        "{{\"total\": {}, \"passed\": {passed}, \"failed\": {failed}, \"programs\": [",
+        reports.len()
+    );
+    for (i, report) in reports.iter().enumerate() {
+        let (status, detail) = match &report.status {
+            Status::Finished => ("finished", None),
+            Status::Halted(reason) => ("halted", Some(reason)),
+            Status::StepLimit => ("step-limit", None),
+            Status::TimeLimit => ("time-limit", None),
+            Status::Failed(err) => ("error", Some(err)),
+        };
+        let separator = if i == 0 { "" } else { ", " };
+        let _ = write!(
+            out,
+            "{separator}{{\"name\": {}, \"status\": \"{status}\", \"detail\": {}, \"retired\": {}, \"output\": {}, \"passed\": {}}}",
+            json::string(&report.name),
+            detail.map_or("null".to_string(), |detail| json::string(detail)),
+            report.retired,
+            json::string(&String::from_utf8_lossy(&report.output)),
+            report.passed.map_or("null".to_string(), |passed| passed.to_string()),
+        );
+    }
+    out.push_str("]}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    #[test]
+    fn batch_files_are_parsed() {
+        let text = r#"
+            parallel = 2  # two at a time
+
+            [[program]]
+            name = "hello # world"
+            path = "hello.bin"
+            args = ["-v", "a \"b\""]
+            max_steps = 1_000
+
+            [[program]]
+            path = "other.bin"
+            expected = "other.out"
+            max_output_bytes = 64
+            wall_clock_ms = 1500
+        "#;
+        assert_ok_eq!(
+            Batch::parse(text, Path::new("jobs")),
+            Batch {
+                parallel: 2,
+                jobs: vec![
+                    Job {
+                        name: "hello # world".to_string(),
+                        program: PathBuf::from("jobs/hello.bin"),
+                        args: vec!["-v".to_string(), "a \"b\"".to_string()],
+                        limits: Limits {
+                            max_steps: Some(1000),
+                            ..JOB_LIMITS
+                        },
+                        ..Default::default()
+                    },
+                    Job {
+                        name: "other.bin".to_string(),
+                        program: PathBuf::from("jobs/other.bin"),
+                        expected: Some(PathBuf::from("jobs/other.out")),
+                        limits: Limits {
+                            max_output_bytes: Some(64),
+                            wall_clock: Some(Duration::from_millis(1500)),
+                            ..JOB_LIMITS
+                        },
+                        ..Default::default()
+                    },
+                ],
+            }
+        );
+        assert_err_eq!(
+            Batch::parse("[[program]]\nname = \"x\"\n", Path::new("")),
+            Error::BatchInvalid("line 1: program has no path".to_string())
+        );
+        assert_err_eq!(
+            Batch::parse("[[program]]\npath = x.bin\n", Path::new("")),
+            Error::BatchInvalid("line 2: unsupported value".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_are_json() {
+        let reports = [
+            JobReport {
+                name: "a".to_string(),
+                status: Status::Finished,
+                retired: 3,
+                output: b"hi\n".to_vec(),
+                passed: Some(true),
+            },
+            JobReport {
+                name: "b".to_string(),
+                status: Status::Failed("unknown opcode 0x0".to_string()),
+                retired: 0,
+                output: Vec::new(),
+                passed: None,
+            },
+        ];
+        assert_eq!(
+            report(&reports),
+            concat!(
+                r#"{"total": 2, "passed": 1, "failed": 0, "programs": ["#,
+                r#"{"name": "a", "status": "finished", "detail": null, "retired": 3, "output": "hi\n", "passed": true}, "#,
+                r#"{"name": "b", "status": "error", "detail": "unknown opcode 0x0", "retired": 0, "output": "", "passed": null}"#,
+                "]}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn jobs_are_limited_to_their_steps_and_time() {
+        let dir = std::env::temp_dir().join(format!("rmachine-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // beq x0, x0, 0 loops forever; a second program stops at once.
+        fs::write(dir.join("loop.bin"), [0, 0, 0, 0b0000_1111]).unwrap();
+        fs::write(dir.join("stop.bin"), [0, 0, 0, 0b0001_1000]).unwrap();
+        let batch = Batch::parse(
+            "parallel = 2\n[[program]]\npath = \"loop.bin\"\nmax_steps = 50\n[[program]]\npath = \"stop.bin\"\n[[program]]\npath = \"loop.bin\"\nwall_clock_ms = 20\n",
+            &dir,
+        )
+        .unwrap();
+
+        let reports = batch.run();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            (reports[0].status.clone(), reports[0].retired),
+            (Status::StepLimit, 50)
+        );
+        assert_eq!(reports[1].status, Status::Finished);
+        assert_eq!(reports[2].status, Status::TimeLimit);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{
+    heap::AccessKind, Address, Instruction, Machine, Memory, Opcode, RegisterID, Registers, Word,
+};
+use std::io::Write;
+
+// The length of the boot ROM: three instructions and the entry point.
+pub const BOOT_ROM_LEN: usize = 16;
+
+// The flag for the reset syscall and register that keeps memory as it is.
+pub const RESET_KEEP_MEMORY: Word = 1 << 0;
+
+// The state the hart comes out of reset in. The program counter starts at
+// the reset vector, which is the entry point itself unless a boot ROM there
+// jumps to it, and the registers hold the initial stack pointer and the
+// program's arguments. `mem` is memory as it was loaded, for cold resets,
+// and `register` the address of the reset register, if there is one.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Boot {
+    pub vector: Address,
+    pub regs: Registers,
+    pub mem: Memory,
+    pub register: Option<Address>,
+}
+
+// A boot ROM for `vector` that jumps to `entry`. It finds its own address
+// with a jal, loads the entry point stored after the code and jumps there,
+// leaving the entry point in a12 and the other registers untouched:
+//
+//   jal a12, 8           # a12 = vector + 4, the entry point's address
+//   .word entry
+//   lw a12, 0(a12)
+//   jalr x0, a12
+pub fn boot_rom(entry: Address) -> Vec<u8> {
+    let instruction = |opcode, rd, rs1, imm| {
+        Instruction {
+            opcode,
+            rd,
+            rs1,
+            rs2: RegisterID::X0,
+            imm,
+        }
+        .encode()
+    };
+    [
+        instruction(Opcode::Jal, RegisterID::A12, RegisterID::X0, 8),
+        entry,
+        instruction(Opcode::Lw, RegisterID::A12, RegisterID::A12, 0),
+        instruction(Opcode::Jalr, RegisterID::X0, RegisterID::A12, 0),
+    ]
+    .iter()
+    .flat_map(|word: &Word| word.to_be_bytes())
+    .collect()
+}
+
+impl<W: Write> Machine<W> {
+    pub fn reset_vector(&self) -> Address {
+        self.boot.vector
+    }
+
+    // Puts the hart back in its reset state. Memory is left alone.
+    pub fn reset(&mut self) {
+        self.pc = self.boot.vector;
+        self.regs = self.boot.regs.clone();
+        self.sync_register_bank();
+        self.call_stack.clear();
+    }
+
+    // Resets the machine the way a guest asks to, with the reset syscall or
+    // a write to the reset register. A warm reset keeps memory; a cold one
+    // also puts memory back as it was loaded and forgets the heap break and
+    // any libraries. Either way the exit, interrupt and signal handlers are
+    // dropped, since the program that registered them has gone, along with
+    // any interrupts or signals pending or being handled and any profiled
+    // regions left open.
+    pub fn reboot(&mut self, flags: Word) {
+        if flags & RESET_KEEP_MEMORY == 0 {
+            self.mem = self.boot.mem.clone();
+            self.heap.reset();
+            self.libraries.unload_all();
+        }
+        self.exit_handlers.clear();
+        self.irqs.reset();
+        self.signals.reset();
+        self.profile.close_all();
+        self.exit_code = None;
+        self.reset();
+    }
+
+    // Resets the machine if a store just wrote to the reset register, which
+    // takes the same flags as the syscall.
+    pub(crate) fn check_reset_register(
+        &mut self,
+        addr: Address,
+        len: usize,
+        kind: AccessKind,
+    ) -> bool {
+        let Some(register) = self.boot.register else {
+            return false;
+        };
+        let end = u64::from(addr) + len as u64;
+        if kind != AccessKind::Write
+            || end <= u64::from(register)
+            || u64::from(register) + 4 <= u64::from(addr)
+        {
+            return false;
+        }
+        let flags = self
+            .endian
+            .word(self.mem.read(register, 4).try_into().unwrap());
+        self.reboot(flags);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, HaltReason, Layout};
+    use claims::{assert_err, assert_ok, assert_ok_eq};
+
+    fn build(program: &str, vector: Address, boot_rom: bool) -> crate::Result<Machine<Vec<u8>>> {
+        Machine::builder()
+            .layout(Layout::microcontroller())
+            .load_address(0x100)
+            .program(&assemble(program).unwrap())
+            .reset_vector(vector)
+            .boot_rom(boot_rom)
+            .build()
+    }
+
+    #[test]
+    fn the_boot_rom_jumps_to_the_entry_point() {
+        let mut machine = build("li a0, 7\nebreak", 0, true).unwrap();
+
+        assert_eq!(machine.pc, 0);
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.regs.get(&RegisterID::A0), 7);
+        assert_eq!(machine.regs.get(&RegisterID::SP), 0x4000);
+        // Three boot ROM instructions, then the program.
+        assert_eq!(machine.retired, 5);
+    }
+
+    #[test]
+    fn reset_returns_to_the_reset_vector_keeping_memory() {
+        let mut machine = build("li a0, 7\nsw a0, 0x200\nebreak", 0x100, false).unwrap();
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        machine.reset();
+
+        assert_eq!(machine.pc, 0x100);
+        assert_eq!(machine.regs, Registers::from([(RegisterID::SP, 0x4000)]));
+        assert_eq!(machine.mem.read(0x200, 4), [0, 0, 0, 7]);
+    }
+
+    // Counts boots in the word at 0x200, resetting with `flags` on the first.
+    const REBOOTING: &str = "
+        lw a0, 0x200
+        li a1, 1
+        add a0, a0, a1
+        sw a0, 0x200
+        bne a0, a1, done
+        li a0, FLAGS
+        RESET
+    done:
+        ebreak
+    ";
+
+    fn rebooting(flags: Word, reset: &str) -> Machine<Vec<u8>> {
+        let source = REBOOTING
+            .replace("FLAGS", &flags.to_string())
+            .replace("RESET", reset);
+        Machine::builder()
+            .layout(Layout::microcontroller())
+            .load_address(0x100)
+            .program(&assemble(&source).unwrap())
+            .reset_register(0x300)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn a_warm_reset_restarts_the_program_keeping_memory() {
+        let mut machine = rebooting(RESET_KEEP_MEMORY, "li a7, 266\necall");
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.mem.read(0x200, 4), [0, 0, 0, 2]);
+        assert_eq!(machine.regs.get(&RegisterID::SP), 0x4000);
+    }
+
+    #[test]
+    fn a_cold_reset_also_restores_memory() {
+        let mut machine = rebooting(0, "li a7, 266\necall");
+        machine.set_brk(0x3000);
+        machine.register_exit_handler(0x180);
+
+        // Up to and including the ecall.
+        for _ in 0..8 {
+            assert_ok!(machine.step());
+        }
+
+        // The reset put memory back, so the count starts again from zero.
+        assert_eq!(machine.pc, 0x100);
+        assert_eq!(machine.mem.read(0x200, 4), [0; 4]);
+        assert_eq!(machine.brk(), machine.layout.heap_base);
+        assert!(machine.exit_handlers.is_empty());
+    }
+
+    #[test]
+    fn stores_to_the_reset_register_reset_the_machine() {
+        let mut machine = rebooting(RESET_KEEP_MEMORY, "sw a0, 0x300");
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.mem.read(0x200, 4), [0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn the_boot_rom_must_not_overlap_the_program() {
+        assert_err!(build("ebreak", 0xf8, true));
+        assert_ok!(build("ebreak", 0x104, true));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{
+    loader::Reader,
+    observer::{Event, Observer},
+    Address, Error, Instruction, Opcode, Result,
+};
+use std::collections::VecDeque;
+
+const MAGIC: &[u8; 4] = b"RBT1";
+
+const TARGET: u8 = 0x80;
+const DIVERGED: u8 = 0x81;
+const END: u8 = 0x82;
+
+// Records only where control flow went, leaving the rest of the path to be
+// rebuilt from the program image by `reconstruct`. Direct jumps and
+// straight-line code need no record at all; a conditional branch costs a
+// bit and an indirect jump its target. Branch outcomes, jump targets and
+// divergences are each read back in order, but need not be interleaved in
+// the order they happened, so bits are only written out six at a time. The
+// format, big-endian throughout:
+//
+//   magic     "RBT1"
+//   start     u32 address of the first instruction
+//   packets, each starting with a tag byte:
+//     0x01-0x7f  taken/not-taken bits for up to six conditional branches,
+//                oldest first, below a leading 1 that marks how many
+//     0x80       u32 target of an indirect jump
+//     0x81       u64 index of an instruction followed by something other
+//                than its successor, such as an interrupt or a reset, and
+//                u32 address it went to
+//     0x82       u64 number of instructions retired, ending the trace
+#[derive(Debug, Default, Clone)]
+pub struct BranchTrace {
+    start: Option<Address>,
+    last: Option<(Address, Instruction)>,
+    retired: u64,
+    packets: Vec<u8>,
+    // Branch outcomes not yet written, below a leading 1.
+    pending: u8,
+}
+
+impl BranchTrace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn branch(&mut self, taken: bool) {
+        self.pending = self.pending.max(1) << 1 | u8::from(taken);
+        if self.pending >= 0x40 {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.pending > 1 {
+            self.packets.push(self.pending);
+        }
+        self.pending = 0;
+    }
+
+    fn diverged(&mut self, index: u64, target: Address) {
+        self.packets.push(DIVERGED);
+        self.packets.extend(index.to_be_bytes());
+        self.packets.extend(target.to_be_bytes());
+    }
+
+    // Records how control left the instruction at `pc`, the `index`th of
+    // the trace, given that `next` ran after it.
+    fn transfer(&mut self, index: u64, pc: Address, instruction: &Instruction, next: Address) {
+        let target = pc.wrapping_add_signed(instruction.offset());
+        let successor = pc.wrapping_add(4);
+        match instruction.opcode {
+            Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge => {
+                self.branch(next == target);
+                if next != target && next != successor {
+                    self.diverged(index, next);
+                }
+            }
+            Opcode::Jalr => {
+                self.packets.push(TARGET);
+                self.packets.extend(next.to_be_bytes());
+            }
+            Opcode::Jal if next != target => self.diverged(index, next),
+            Opcode::Jal => {}
+            _ if next != successor => self.diverged(index, next),
+            _ => {}
+        }
+    }
+
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    // The trace so far, ready to be saved.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut trace = self.clone();
+        trace.flush();
+        let mut out = MAGIC.to_vec();
+        out.extend(self.start.unwrap_or_default().to_be_bytes());
+        out.extend(trace.packets);
+        out.push(END);
+        out.extend(self.retired.to_be_bytes());
+        out
+    }
+}
+
+impl Observer for BranchTrace {
+    fn observe(&mut self, event: &Event) {
+        let Event::Retired {
+            pc, instruction, ..
+        } = event
+        else {
+            return;
+        };
+        match self.last {
+            Some((last_pc, last)) => self.transfer(self.retired - 1, last_pc, &last, *pc),
+            None => self.start = Some(*pc),
+        }
+        self.last = Some((*pc, *instruction));
+        self.retired += 1;
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::BranchTraceInvalid(reason.to_string())
+}
+
+#[derive(Debug, Default)]
+struct Packets {
+    start: Address,
+    branches: VecDeque<bool>,
+    targets: VecDeque<Address>,
+    diverged: VecDeque<(u64, Address)>,
+    retired: u64,
+}
+
+impl Packets {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes, invalid };
+        if reader.take(4)? != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let mut packets = Self {
+            start: reader.u32()?,
+            ..Default::default()
+        };
+        loop {
+            match reader.u8()? {
+                TARGET => packets.targets.push_back(reader.u32()?),
+                DIVERGED => packets.diverged.push_back((reader.u64()?, reader.u32()?)),
+                END => break,
+                bits @ 0x02..=0x7f => {
+                    let count = bits.ilog2();
+                    for bit in (0..count).rev() {
+                        packets.branches.push_back(bits >> bit & 1 == 1);
+                    }
+                }
+                _ => return Err(invalid("unknown packet")),
+            }
+        }
+        packets.retired = reader.u64()?;
+        if !reader.bytes.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(packets)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.branches.is_empty() && self.targets.is_empty() && self.diverged.is_empty()
+    }
+}
+
+// Rebuilds the address of every instruction a branch trace covers, in the
+// order they ran, from the program `image` loaded at `base`.
+pub fn reconstruct(trace: &[u8], image: &[u8], base: Address) -> Result<Vec<Address>> {
+    let mut packets = Packets::parse(trace)?;
+    let mut pc = packets.start;
+    let fetch = |pc: Address| {
+        let start = pc.wrapping_sub(base) as usize;
+        let bytes = image
+            .get(start..start + 4)
+            .ok_or_else(|| invalid("the path leaves the program image"))?;
+        Instruction::try_from(Address::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let mut path = Vec::new();
+    for index in 0..packets.retired {
+        path.push(pc);
+        if index + 1 == packets.retired {
+            break;
+        }
+        let instruction = fetch(pc)?;
+        let target = pc.wrapping_add_signed(instruction.offset());
+        let mut next = match instruction.opcode {
+            Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge => {
+                match packets.branches.pop_front() {
+                    Some(true) => target,
+                    Some(false) => pc.wrapping_add(4),
+                    None => return Err(invalid("missing branch outcome")),
+                }
+            }
+            Opcode::Jalr => packets
+                .targets
+                .pop_front()
+                .ok_or_else(|| invalid("missing indirect jump target"))?,
+            Opcode::Jal => target,
+            _ => pc.wrapping_add(4),
+        };
+        if let Some(&(at, target)) = packets.diverged.front() {
+            if at == index {
+                packets.diverged.pop_front();
+                next = target;
+            }
+        }
+        pc = next;
+    }
+    if !packets.is_empty() {
+        return Err(invalid("packets left over"));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, Machine};
+    use claims::{assert_err, assert_ok, assert_ok_eq};
+    use std::{cell::RefCell, rc::Rc};
+
+    // Records a run as both a branch trace and a full path.
+    fn record(program: &[u8]) -> (Vec<u8>, Vec<Address>) {
+        #[derive(Default)]
+        struct Path(Vec<Address>);
+        impl Observer for Path {
+            fn observe(&mut self, event: &Event) {
+                if let Event::Retired { pc, .. } = event {
+                    self.0.push(*pc);
+                }
+            }
+        }
+        let trace = Rc::new(RefCell::new(BranchTrace::new()));
+        let path = Rc::new(RefCell::new(Path::default()));
+        let mut machine: Machine<Vec<u8>> = Machine::builder().program(program).build().unwrap();
+        machine.add_observer(trace.clone());
+        machine.add_observer(path.clone());
+        assert_ok!(machine.run());
+        let trace = trace.borrow().to_bytes();
+        let path = path.borrow().0.clone();
+        (trace, path)
+    }
+
+    #[test]
+    fn the_full_path_is_rebuilt_from_the_trace() {
+        // Counts to ten through a loop and a function call.
+        let program = assemble(
+            "
+                    li a1, 1
+                    li a2, 10
+            loop:   call step
+                    blt a0, a2, loop
+                    ebreak
+            step:   add a0, a0, a1
+                    ret
+            ",
+        )
+        .unwrap();
+
+        let (trace, path) = record(&program);
+
+        assert_eq!(path.len(), 2 + 10 * 4 + 1);
+        assert_ok_eq!(reconstruct(&trace, &program, 0), path);
+        // Ten branch bits and ten return targets rather than 43 addresses.
+        assert_eq!(trace.len(), 8 + 2 + 10 * 5 + 9);
+    }
+
+    #[test]
+    fn changes_of_flow_outside_the_program_are_recorded() {
+        let program = assemble("li a0, 1\nli a0, 2\nli a0, 3\nebreak").unwrap();
+        let mut trace = BranchTrace::new();
+        for pc in [0, 4, 0, 4, 8, 12] {
+            let instruction = Instruction::try_from(Address::from_be_bytes(
+                program[pc as usize..pc as usize + 4].try_into().unwrap(),
+            ))
+            .unwrap();
+            trace.observe(&Event::Retired {
+                pc,
+                instruction,
+                operands: [0, 0],
+            });
+        }
+
+        assert_ok_eq!(
+            reconstruct(&trace.to_bytes(), &program, 0),
+            vec![0, 4, 0, 4, 8, 12]
+        );
+    }
+
+    #[test]
+    fn malformed_traces_are_rejected() {
+        let program = assemble("beq x0, x0, 0\nebreak").unwrap();
+        let trace = [&MAGIC[..], &[0, 0, 0, 0, END], &2u64.to_be_bytes()].concat();
+
+        assert_err!(reconstruct(b"nope", &program, 0));
+        assert_eq!(
+            reconstruct(&trace, &program, 0),
+            Err(invalid("missing branch outcome"))
+        );
+        assert_eq!(
+            reconstruct(&trace[..trace.len() - 1], &program, 0),
+            Err(invalid("truncated"))
+        );
+    }
+}
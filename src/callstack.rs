@@ -0,0 +1,228 @@
+use crate::{
+    observer::Event, Address, Error, HaltReason, Instruction, Machine, Opcode, RegisterID, Result,
+    StepOutcome, Word,
+};
+use std::io::Write;
+
+// An entry on the host-side shadow call stack, pushed when the guest makes
+// a call and popped when it returns.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Frame {
+    pub callee: Address,
+    pub return_addr: Address,
+    pub sp: Word,
+}
+
+impl<W: Write> Machine<W> {
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    // Records a call from the instruction at `caller` to `callee`, which
+    // will return to `return_addr`.
+    pub(crate) fn enter_call(&mut self, caller: Address, callee: Address, return_addr: Address) {
+        self.call_stack.push(Frame {
+            callee,
+            return_addr,
+            sp: self.regs.get(&RegisterID::SP),
+        });
+        self.abi_enter();
+        let symbol = self.symbols.label(callee);
+        self.observers.notify(&Event::Call {
+            callee,
+            symbol,
+            caller,
+            cycle: self.retired,
+        });
+    }
+
+    // Pops the frames a jump to `target` returns from. Jumps that do not
+    // match any frame's return address are not returns.
+    pub(crate) fn leave_call(&mut self, target: Address) {
+        let Some(depth) = self
+            .call_stack
+            .iter()
+            .rposition(|frame| frame.return_addr == target)
+        else {
+            return;
+        };
+        self.abi_leave(depth);
+        for frame in self.call_stack.split_off(depth).into_iter().rev() {
+            let symbol = self.symbols.label(frame.callee);
+            self.observers.notify(&Event::Return {
+                callee: frame.callee,
+                symbol,
+                return_addr: frame.return_addr,
+                cycle: self.retired,
+            });
+        }
+    }
+
+    // Keeps the shadow call stack up to date after the instruction at `pc`
+    // has executed. By convention a jump that links into ra is a call, and
+    // `jalr x0, ra` is a return.
+    pub(crate) fn track_jump(&mut self, pc: Address, instruction: &Instruction) -> StepOutcome {
+        match instruction.opcode {
+            Opcode::Jal | Opcode::Jalr if instruction.rd == RegisterID::RA => {
+                self.enter_call(pc, self.pc, pc.wrapping_add(4));
+                StepOutcome::Continue
+            }
+            Opcode::Jalr
+                if instruction.rd == RegisterID::X0 && instruction.rs1 == RegisterID::RA =>
+            {
+                self.return_to(pc, self.pc)
+            }
+            _ => StepOutcome::Continue,
+        }
+    }
+
+    // In return protection mode a return must go back to the address
+    // recorded by the matching call; any other target halts the machine,
+    // catching a smashed stack before the guest jumps somewhere wild.
+    pub fn set_return_protection(&mut self, enabled: bool) {
+        self.return_protection = enabled;
+    }
+
+    // Handles a return made by the instruction at `pc` to `target`.
+    pub(crate) fn return_to(&mut self, pc: Address, target: Address) -> StepOutcome {
+        if let (true, Some(frame)) = (self.return_protection, self.call_stack.last()) {
+            if frame.return_addr != target {
+                return StepOutcome::Halted(HaltReason::ReturnMismatch {
+                    pc,
+                    expected: frame.return_addr,
+                    target,
+                });
+            }
+        }
+        self.leave_call(target);
+        StepOutcome::Continue
+    }
+
+    // Executes at least one instruction and keeps going until the pc
+    // reaches `addr` or the machine halts.
+    pub fn run_to(&mut self, addr: Address) -> Result<StepOutcome> {
+        loop {
+            let (_, outcome) = self.step()?;
+            if outcome != StepOutcome::Continue || self.pc == addr {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    // Runs until the function executing now returns to its caller, or the
+    // machine halts.
+    pub fn finish(&mut self) -> Result<StepOutcome> {
+        let depth = self.call_stack.len();
+        if depth == 0 {
+            return Err(Error::NoCallFrame);
+        }
+        loop {
+            let (_, outcome) = self.step()?;
+            if outcome != StepOutcome::Continue || self.call_stack.len() < depth {
+                return Ok(outcome);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HaltReason, Memory};
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    // Three Adds bumping a0 followed by an EBreak.
+    fn machine() -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        for i in 0..3 {
+            mem.write(i * 4, &[0b0000_0000, 0b0000_0010, 0b0000_0010, 0b0010_0010]);
+        }
+        mem.write(12, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        Machine {
+            mem,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn run_to_stops_when_the_address_is_reached() {
+        let mut machine = machine();
+        assert_ok_eq!(machine.run_to(8), StepOutcome::Continue);
+        assert_eq!(machine.pc, 8);
+        assert_eq!(machine.retired, 2);
+    }
+
+    #[test]
+    fn run_to_stops_early_if_the_machine_halts() {
+        let mut machine = machine();
+        assert_ok_eq!(
+            machine.run_to(0x100),
+            StepOutcome::Halted(HaltReason::Breakpoint)
+        );
+    }
+
+    #[test]
+    fn returns_pop_every_frame_up_to_the_matching_one() {
+        let mut machine = machine();
+        machine.enter_call(0x00, 0x10, 0x04);
+        machine.enter_call(0x10, 0x20, 0x14);
+        machine.enter_call(0x20, 0x30, 0x24);
+
+        machine.leave_call(0x40);
+        assert_eq!(machine.call_stack().len(), 3);
+
+        machine.leave_call(0x14);
+        assert_eq!(
+            machine.call_stack(),
+            &[Frame {
+                callee: 0x10,
+                return_addr: 0x04,
+                sp: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn protected_returns_must_match_the_shadow_stack() {
+        let mut machine = machine();
+        machine.enter_call(0x00, 0x10, 0x04);
+        machine.enter_call(0x10, 0x20, 0x14);
+
+        assert_eq!(machine.return_to(0x28, 0x04), StepOutcome::Continue);
+        assert_eq!(machine.call_stack().len(), 0);
+
+        machine.set_return_protection(true);
+        machine.enter_call(0x00, 0x10, 0x04);
+        machine.enter_call(0x10, 0x20, 0x14);
+        assert_eq!(machine.return_to(0x28, 0x14), StepOutcome::Continue);
+        assert_eq!(
+            machine.return_to(0x18, 0x44),
+            StepOutcome::Halted(HaltReason::ReturnMismatch {
+                pc: 0x18,
+                expected: 0x04,
+                target: 0x44,
+            })
+        );
+        assert_eq!(machine.call_stack().len(), 1);
+    }
+
+    #[test]
+    fn finish_requires_a_call_frame() {
+        let mut machine = machine();
+        assert_err_eq!(machine.finish(), Error::NoCallFrame);
+    }
+
+    #[test]
+    fn finish_stops_if_the_machine_halts_before_returning() {
+        let mut machine = machine();
+        machine.call_stack.push(Frame {
+            callee: 0,
+            return_addr: 0x40,
+            sp: 0,
+        });
+        assert_ok_eq!(
+            machine.finish(),
+            StepOutcome::Halted(HaltReason::Breakpoint)
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use crate::Machine;
+use std::{fmt, io::Write};
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+// What to do with ANSI escape sequences in captured output.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum Ansi {
+    // Record the bytes as written.
+    #[default]
+    Keep,
+    // Drop escape sequences, leaving only the text.
+    Strip,
+    // Replace each escape sequence with a printable form such as `\e[31m`,
+    // writing the bare reset `\e[m` as `\e[0m`, so differences show up
+    // plainly in a diff.
+    Normalize,
+}
+
+// One write to stdout, stamped with the emulated time it happened at.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Chunk {
+    pub cycle: u64,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+enum State {
+    #[default]
+    Text,
+    Escape,
+    Csi,
+    Osc,
+    OscEscape,
+}
+
+// A record of guest stdout for golden-output tests. Timestamps are machine
+// cycles rather than wall-clock time, so the same program always produces
+// the same capture. Escape sequences may be split across writes; they are
+// recognised as a stream.
+#[derive(Debug, Default, Clone)]
+pub struct Capture {
+    ansi: Ansi,
+    chunks: Vec<Chunk>,
+    state: State,
+    sequence: Vec<u8>,
+}
+
+impl Capture {
+    pub fn new(ansi: Ansi) -> Self {
+        Self {
+            ansi,
+            ..Default::default()
+        }
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    // Everything captured, as one string.
+    pub fn text(&self) -> String {
+        let bytes: Vec<u8> = self.chunks.iter().flat_map(|c| c.bytes.clone()).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn record(&mut self, cycle: u64, data: &[u8]) {
+        let mut bytes = Vec::new();
+        for &byte in data {
+            if self.ansi == Ansi::Keep || self.state == State::Text && byte != ESC {
+                bytes.push(byte);
+                continue;
+            }
+            self.sequence.push(byte);
+            self.state = match (self.state, byte) {
+                (State::Text, _) => State::Escape,
+                (State::Escape, b'[') => State::Csi,
+                (State::Escape, b']') => State::Osc,
+                (State::Csi, 0x20..=0x3f) => State::Csi,
+                (State::Osc, BEL) => State::Text,
+                (State::Osc, ESC) => State::OscEscape,
+                (State::Osc, _) => State::Osc,
+                (State::OscEscape, b'\\') => State::Text,
+                (State::OscEscape, _) => State::Osc,
+                // A two-byte escape, or the final byte of a control sequence.
+                (State::Escape | State::Csi, _) => State::Text,
+            };
+            if self.state == State::Text {
+                if self.ansi == Ansi::Normalize {
+                    bytes.extend(normalize(&self.sequence));
+                }
+                self.sequence.clear();
+            }
+        }
+        if !bytes.is_empty() {
+            self.chunks.push(Chunk { cycle, bytes });
+        }
+    }
+}
+
+fn normalize(sequence: &[u8]) -> Vec<u8> {
+    let body = match &sequence[1..] {
+        b"[m" => b"[0m".as_slice(),
+        body => body,
+    };
+    let mut text = b"\\e".to_vec();
+    text.extend(body.iter().flat_map(|&b| std::ascii::escape_default(b)));
+    text
+}
+
+// One line per chunk: the cycle, then the text with control characters
+// escaped.
+impl fmt::Display for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in &self.chunks {
+            let text: String = chunk.bytes.escape_ascii().to_string();
+            writeln!(f, "{:>8} {text}", chunk.cycle)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Starts recording everything the guest writes to stdout, in addition
+    // to writing it out.
+    pub fn capture_output(&mut self, ansi: Ansi) {
+        self.capture = Some(Capture::new(ansi));
+    }
+
+    pub fn captured_output(&self) -> Option<&Capture> {
+        self.capture.as_ref()
+    }
+
+    pub fn take_captured_output(&mut self) -> Option<Capture> {
+        self.capture.take()
+    }
+
+    pub(crate) fn capture_write(&mut self, data: &[u8]) {
+        if let Some(capture) = &mut self.capture {
+            capture.record(self.cycles, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(ansi: Ansi, writes: &[&[u8]]) -> Capture {
+        let mut capture = Capture::new(ansi);
+        for (cycle, data) in writes.iter().enumerate() {
+            capture.record(cycle as u64, data);
+        }
+        capture
+    }
+
+    #[test]
+    fn escape_sequences_can_be_kept_stripped_or_normalized() {
+        let writes: &[&[u8]] = &[b"\x1b[1;31mred\x1b", b"[m \x1b]0;title\x07ok\n"];
+
+        assert_eq!(
+            capture(Ansi::Keep, writes).text(),
+            "\x1b[1;31mred\x1b[m \x1b]0;title\x07ok\n"
+        );
+        assert_eq!(capture(Ansi::Strip, writes).text(), "red ok\n");
+        assert_eq!(
+            capture(Ansi::Normalize, writes).text(),
+            "\\e[1;31mred\\e[0m \\e]0;title\\x07ok\n"
+        );
+    }
+
+    #[test]
+    fn chunks_are_stamped_with_the_cycle_they_were_written_at() {
+        let capture = capture(Ansi::Strip, &[b"a\x1b[", b"0m", b"b\n"]);
+
+        assert_eq!(
+            capture.chunks(),
+            &[
+                Chunk {
+                    cycle: 0,
+                    bytes: b"a".to_vec(),
+                },
+                Chunk {
+                    cycle: 2,
+                    bytes: b"b\n".to_vec(),
+                },
+            ]
+        );
+        assert_eq!(capture.to_string(), "       0 a\n       2 b\\n\n");
+    }
+}
@@ -0,0 +1,521 @@
+use crate::{
+    fd::FdTable, irq::Irqs, signal::Signals, Address, Error, Machine, Memory, Registers, Result,
+    StepOutcome, Word,
+};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    io::Write,
+};
+
+// A bounded history of machine states taken every `interval` retired
+// instructions. Each checkpoint only stores the pages dirtied since the
+// previous one; pages evicted with the oldest checkpoint are folded into
+// `base`, so the oldest remaining checkpoint can always be reconstructed.
+//
+// The ring also keeps what every read since the oldest checkpoint gave the
+// guest, so that the reads made again after a rewind are served the same
+// bytes rather than consuming more of the live input.
+#[derive(Debug, Eq, PartialEq)]
+pub struct CheckpointRing {
+    interval: u64,
+    capacity: usize,
+    base: HashMap<Address, Vec<u8>>,
+    ring: VecDeque<Checkpoint>,
+    reads: VecDeque<Read>,
+    // How many reads had been made by the first one in `reads`, and by the
+    // one to be served next.
+    first_read: usize,
+    next_read: usize,
+}
+
+// The bytes a read gave the guest, or the errno it failed with.
+pub(crate) type Read = std::result::Result<Vec<u8>, i32>;
+
+#[derive(Debug, Eq, PartialEq)]
+struct Checkpoint {
+    retired: u64,
+    pc: Word,
+    regs: Registers,
+    pages: HashMap<Address, Vec<u8>>,
+    runtime: Runtime,
+    reads: usize,
+}
+
+// What the machine keeps on the guest's behalf besides its registers and
+// memory, which the guest would see go wrong if a rewind left it as it was.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Runtime {
+    cycles: u64,
+    brk: Option<Address>,
+    exit_code: Option<i32>,
+    signals: Signals,
+    irqs: Irqs,
+    files: FdTable,
+}
+
+impl CheckpointRing {
+    fn new(interval: u64, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            base: HashMap::new(),
+            ring: VecDeque::new(),
+            reads: VecDeque::new(),
+            first_read: 0,
+            next_read: 0,
+        }
+    }
+
+    fn record(
+        &mut self,
+        retired: u64,
+        pc: Word,
+        regs: &Registers,
+        mem: &mut Memory,
+        all: bool,
+        runtime: Runtime,
+    ) {
+        let pages = if all {
+            mem.pages()
+        } else {
+            mem.dirty_pages().into_iter().collect()
+        };
+        mem.clear_dirty();
+        self.ring.push_back(Checkpoint {
+            retired,
+            pc,
+            regs: regs.clone(),
+            pages: pages
+                .into_iter()
+                .map(|page| (page, mem.page(page)))
+                .collect(),
+            runtime,
+            reads: self.next_read,
+        });
+        if self.ring.len() > self.capacity {
+            if let Some(oldest) = self.ring.pop_front() {
+                self.base.extend(oldest.pages);
+            }
+            let kept = self
+                .ring
+                .front()
+                .map_or(self.next_read, |oldest| oldest.reads);
+            self.reads.drain(..kept - self.first_read);
+            self.first_read = kept;
+        }
+    }
+
+    // The read the guest made next before a rewind, if it has not got this
+    // far since.
+    fn recorded_read(&mut self) -> Option<Read> {
+        let read = self.reads.get(self.next_read - self.first_read)?.clone();
+        self.next_read += 1;
+        Some(read)
+    }
+
+    fn record_read(&mut self, read: &Read) {
+        self.reads.push_back(read.clone());
+        self.next_read += 1;
+    }
+
+    // The contents of `page` as of the checkpoint at `index`.
+    fn page_at(&self, index: usize, page: Address) -> Option<&Vec<u8>> {
+        self.ring
+            .iter()
+            .take(index + 1)
+            .rev()
+            .find_map(|checkpoint| checkpoint.pages.get(&page))
+            .or_else(|| self.base.get(&page))
+    }
+
+    // Restores the latest checkpoint taken at or before `target`, discarding
+    // any newer ones, and returns the retired count it was taken at along
+    // with the rest of the state to put back.
+    fn restore(
+        &mut self,
+        target: u64,
+        pc: &mut Word,
+        regs: &mut Registers,
+        mem: &mut Memory,
+    ) -> Result<(u64, Runtime)> {
+        let index = self
+            .ring
+            .iter()
+            .rposition(|checkpoint| checkpoint.retired <= target)
+            .ok_or(Error::CheckpointUnavailable(target))?;
+
+        let mut changed: BTreeSet<Address> = mem.dirty_pages().into_iter().collect();
+        for checkpoint in self.ring.iter().skip(index + 1) {
+            changed.extend(checkpoint.pages.keys());
+        }
+        for page in changed {
+            mem.restore_page(page, self.page_at(index, page).map(Vec::as_slice));
+        }
+        mem.clear_dirty();
+        self.ring.truncate(index + 1);
+
+        let checkpoint = &self.ring[index];
+        self.next_read = checkpoint.reads;
+        *pc = checkpoint.pc;
+        *regs = checkpoint.regs.clone();
+        Ok((checkpoint.retired, checkpoint.runtime.clone()))
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Keeps up to `capacity` checkpoints, one every `interval` instructions,
+    // so that execution can later be rewound with step_back or rewind_to.
+    // Checkpointing takes over the memory's dirty-page tracking, and keeps
+    // the files the guest closes open so that a rewind can give them back.
+    pub fn enable_checkpoints(&mut self, interval: u64, capacity: usize) {
+        let mut ring = CheckpointRing::new(interval, capacity);
+        let runtime = self.runtime();
+        ring.record(
+            self.retired,
+            self.pc,
+            &self.regs,
+            &mut self.mem,
+            true,
+            runtime,
+        );
+        self.checkpoints = Some(ring);
+        self.files.retain_closed(true);
+    }
+
+    pub fn disable_checkpoints(&mut self) {
+        self.checkpoints = None;
+        self.files.retain_closed(false);
+    }
+
+    pub(crate) fn record_checkpoint_if_due(&mut self) {
+        let due = self
+            .checkpoints
+            .as_ref()
+            .is_some_and(|ring| self.retired.is_multiple_of(ring.interval));
+        if !due {
+            return;
+        }
+        let runtime = self.runtime();
+        if let Some(ring) = &mut self.checkpoints {
+            ring.record(
+                self.retired,
+                self.pc,
+                &self.regs,
+                &mut self.mem,
+                false,
+                runtime,
+            );
+        }
+    }
+
+    pub(crate) fn recorded_read(&mut self) -> Option<Read> {
+        self.checkpoints.as_mut()?.recorded_read()
+    }
+
+    pub(crate) fn record_read(&mut self, read: &Read) {
+        if let Some(ring) = &mut self.checkpoints {
+            ring.record_read(read);
+        }
+    }
+
+    fn runtime(&self) -> Runtime {
+        Runtime {
+            cycles: self.cycles,
+            brk: self.heap.brk,
+            exit_code: self.exit_code,
+            signals: self.signals.clone(),
+            irqs: self.irqs.clone(),
+            files: self.files.table(),
+        }
+    }
+
+    fn restore_runtime(&mut self, runtime: Runtime) {
+        self.cycles = runtime.cycles;
+        self.heap.brk = runtime.brk;
+        self.exit_code = runtime.exit_code;
+        self.signals = runtime.signals;
+        self.irqs = runtime.irqs;
+        self.files.restore_table(runtime.files);
+    }
+
+    // Without checkpoints, the most recent instruction can still be undone
+    // when effects are being recorded.
+    pub fn step_back(&mut self) -> Result<()> {
+        if self.checkpoints.is_none() && self.retired > 0 {
+            if let Some(effect) = self.take_last_effect() {
+                self.revert(&effect);
+                self.retired -= 1;
+                return Ok(());
+            }
+        }
+        self.rewind(1)
+    }
+
+    pub fn rewind(&mut self, steps: u64) -> Result<()> {
+        let target = self
+            .retired
+            .checked_sub(steps)
+            .ok_or(Error::CheckpointUnavailable(0))?;
+        self.rewind_to(target)
+    }
+
+    // Returns the machine to the state it was in after `target` instructions
+    // had retired, by restoring the nearest checkpoint and replaying forward
+    // with output suppressed.
+    pub fn rewind_to(&mut self, target: u64) -> Result<()> {
+        if target > self.retired {
+            return Err(Error::CheckpointUnavailable(target));
+        }
+        let ring = self
+            .checkpoints
+            .as_mut()
+            .ok_or(Error::CheckpointUnavailable(target))?;
+        let (retired, runtime) =
+            ring.restore(target, &mut self.pc, &mut self.regs, &mut self.mem)?;
+        self.retired = retired;
+        self.restore_runtime(runtime);
+
+        self.replaying = true;
+        let mut replayed = Ok(());
+        while self.retired < target {
+            let retired = self.retired;
+            match self.step() {
+                Err(err) => replayed = Err(err),
+                // Nothing else can retire the instruction either.
+                Ok((_, StepOutcome::Halted(_))) if self.retired == retired => {
+                    replayed = Err(Error::CheckpointUnavailable(target));
+                }
+                Ok(_) => continue,
+            }
+            break;
+        }
+        self.replaying = false;
+        replayed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        capture::Ansi, output::SharedBuffer, Error, Machine, Memory, RegisterID, Registers,
+    };
+    use claims::{assert_err_eq, assert_ok};
+
+    // Counts a3 up by one and formats it into the buffer at 0x1000, over
+    // and over again.
+    fn counting_machine() -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        for i in 0..16u32 {
+            // add a3, a3, x0, 1
+            mem.write(
+                i * 12,
+                &[0b0000_0000, 0b0000_0010, 0b0000_1000, 0b1000_0010],
+            );
+            // add a0, a3, x0, 0
+            mem.write(
+                i * 12 + 4,
+                &[0b0000_0000, 0b0000_0000, 0b0000_1000, 0b0010_0010],
+            );
+            // ecall (format unsigned)
+            mem.write(
+                i * 12 + 8,
+                &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111],
+            );
+        }
+        mem.clear_dirty();
+        Machine {
+            mem,
+            regs: Registers::from([
+                (RegisterID::A1, 0x1000),
+                (RegisterID::A2, 4),
+                (RegisterID::A7, 257),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn step_back_restores_the_previous_state() {
+        let mut machine = counting_machine();
+        machine.enable_checkpoints(4, 8);
+        for _ in 0..9 {
+            assert_ok!(machine.step());
+        }
+        let snapshot = (
+            machine.pc,
+            machine.regs.clone(),
+            machine.mem.read(0x1000, 4),
+        );
+        assert_ok!(machine.step());
+        assert_ok!(machine.step());
+
+        assert_ok!(machine.step_back());
+        assert_ok!(machine.step_back());
+
+        assert_eq!(machine.retired, 9);
+        assert_eq!(
+            (
+                machine.pc,
+                machine.regs.clone(),
+                machine.mem.read(0x1000, 4)
+            ),
+            snapshot
+        );
+    }
+
+    #[test]
+    fn rewind_to_reconstructs_memory_from_incremental_checkpoints() {
+        let mut machine = counting_machine();
+        machine.enable_checkpoints(2, 4);
+        for _ in 0..30 {
+            assert_ok!(machine.step());
+        }
+        assert_eq!(machine.mem.read(0x1000, 2), b"10");
+
+        assert_ok!(machine.rewind_to(24));
+
+        assert_eq!(machine.pc, 96);
+        assert_eq!(machine.regs.get(&RegisterID::A3), 8);
+        assert_eq!(machine.mem.read(0x1000, 2), vec![b'8', 0]);
+    }
+
+    #[test]
+    fn rewinding_past_the_oldest_checkpoint_returns_an_error() {
+        let mut machine = counting_machine();
+        machine.enable_checkpoints(2, 3);
+        for _ in 0..20 {
+            assert_ok!(machine.step());
+        }
+        assert_err_eq!(machine.rewind_to(3), Error::CheckpointUnavailable(3));
+    }
+
+    #[test]
+    fn runs_stopped_by_fuel_or_a_limit_can_be_rewound() {
+        let mut machine = counting_machine();
+        machine.set_fuel(Some(10));
+        machine.enable_checkpoints(100, 4);
+        assert_ok!(machine.run());
+        assert_eq!(machine.retired, 10);
+
+        assert_ok!(machine.step_back());
+        assert_eq!(machine.retired, 9);
+        assert_eq!(machine.fuel(), Some(0));
+
+        let mut machine = counting_machine();
+        machine.set_limits(Some(crate::limits::Limits {
+            max_steps: Some(10),
+            ..Default::default()
+        }));
+        machine.enable_checkpoints(100, 4);
+        assert_ok!(machine.run());
+
+        assert_ok!(machine.rewind(3));
+        assert_eq!(machine.retired, 7);
+    }
+
+    #[test]
+    fn rewinding_restores_the_state_syscalls_keep() {
+        let mut machine = crate::tests::machine(
+            "
+            li a0, 0x10100
+            li a7, 214
+            ecall
+            li a0, 1
+            li a7, 57
+            ecall
+            li a0, 0x200
+            li a1, 0
+            li a7, 59
+            ecall
+            ebreak
+            ",
+        );
+        let heap_base = machine.layout.heap_base;
+        machine.enable_checkpoints(100, 4);
+        assert_ok!(machine.run());
+        assert_eq!(machine.brk(), 0x10100);
+        let files = machine.files.table();
+
+        assert_ok!(machine.rewind_to(0));
+
+        assert_eq!(machine.brk(), heap_base);
+        assert_eq!(machine.cycles, 0);
+        assert_eq!(
+            machine.files.table(),
+            Machine::<Vec<u8>>::default().files.table()
+        );
+
+        // Running again opens the same pipe rather than a new one.
+        assert_ok!(machine.run());
+        assert_eq!(machine.files.table(), files);
+    }
+
+    #[test]
+    fn instructions_run_again_read_what_they_did_the_first_time() {
+        let stderr = SharedBuffer::new();
+        let mut machine = crate::tests::machine(
+            "
+            li a1, 0x100
+            li a2, 4
+            li a7, 63
+            ecall
+            li a0, 2
+            li a7, 64
+            ecall
+            ebreak
+            ",
+        );
+        machine.set_stdin(&b"abcdefgh"[..]);
+        machine.attach_writer(2, stderr.clone());
+        machine.enable_checkpoints(100, 4);
+        assert_ok!(machine.run());
+
+        // Replaying the write leaves stderr be.
+        assert_ok!(machine.rewind_to(machine.retired));
+        assert_eq!(stderr.contents(), b"abcd");
+
+        assert_ok!(machine.rewind_to(0));
+        assert_ok!(machine.run());
+        assert_eq!(machine.mem.read(0x100, 4), b"abcd");
+        assert_eq!(machine.input_read, 4);
+    }
+
+    #[test]
+    fn rewinding_without_checkpoints_returns_an_error() {
+        let mut machine = counting_machine();
+        assert_ok!(machine.step());
+        assert_err_eq!(machine.step_back(), Error::CheckpointUnavailable(0));
+    }
+
+    #[test]
+    fn replaying_does_not_repeat_output() {
+        let mut machine: Machine<Vec<u8>> = Machine {
+            stdout: Some(Vec::new()),
+            regs: Registers::from([
+                (RegisterID::A0, 1),
+                (RegisterID::A1, 0x100),
+                (RegisterID::A2, 1),
+                (RegisterID::A7, 64),
+            ]),
+            mem: Memory::from([(0x100, b'x')]),
+            ..Default::default()
+        };
+        for i in 0..4u32 {
+            machine.mem.write(i * 4, &[0, 0, 0, 0b0001_0111]);
+        }
+        let tee = SharedBuffer::new();
+        machine.add_stdout_sink(tee.clone());
+        machine.capture_output(Ansi::Keep);
+        machine.enable_checkpoints(10, 2);
+        for _ in 0..3 {
+            assert_ok!(machine.step());
+        }
+
+        assert_ok!(machine.step_back());
+
+        assert_eq!(machine.stdout, Some(b"xxx".to_vec()));
+        assert_eq!(tee.contents(), b"xxx");
+        assert_eq!(machine.captured_output().unwrap().chunks().len(), 3);
+        assert_eq!(machine.pc, 8);
+    }
+}
@@ -0,0 +1,1436 @@
+use crate::{
+    asm,
+    batch::{self, Batch, Status},
+    branches::{self, BranchTrace},
+    clock::Clock,
+    console::{Console, RawMode, TerminalInfo},
+    disasm::Disassembler,
+    energy::Energy,
+    hostfs::HostDir,
+    interrupt::Interrupt,
+    irq::Timer,
+    limits::Limits,
+    link::LinkerScript,
+    loader::Executable,
+    manifest::{Digest, Manifest},
+    minimize::{Failure, Minimizer},
+    perf::Comparison,
+    poll::Pipe,
+    signal::SIGINT,
+    snapshot::Snapshot,
+    summary::RunStats,
+    symbols::SymbolTable,
+    trace::Tracer,
+    tracefile::TraceReader,
+    uninit::UninitCheck,
+    vectors,
+    verify::verify,
+    Address, Error, HaltReason, Layout, Machine, Word,
+};
+use std::{
+    cell::RefCell,
+    env, fs,
+    io::{self, IsTerminal, Read, Stdout, Write},
+    panic,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const USAGE: &str = "usage: rmachine run <program> [--manifest <file>] [--checkpoint <file>] [--checkpoint-every <n>] [--resume <file>] [--branch-trace <file>] [--trace-file <file>] [--stats] [--stats-json <file>] [--energy] [--trace] [--timer <cycles>] [--fuel <instructions>] [--max-output <bytes>] [--misalignment-penalty <cycles>] [--strict-decoding] [--trap-overflow] [--symbols <file>] [--region <name>=<base>:<len>]... [--abi-check] [--uninit <warn|trap>] [--check-image] [--exit-on-idle] [--dir <dir> [--allow <path>]... [--read-only]] [--clock-hz <n> [--clock-epoch <secs>]] [-- <args>...]
+       rmachine replay <manifest>
+       rmachine batch <file> [--parallel <n>]
+       rmachine asm <source> [-o | --output <file>] [-T <linker-script> | --symbols <file>]
+       rmachine disasm <program>
+       rmachine verify <program>
+       rmachine vectors generate [-o | --output <file>]
+       rmachine vectors check <file>
+       rmachine reconstruct <program> <branch-trace>
+       rmachine dump <trace-file>
+       rmachine perf-compare <old-stats> <new-stats> [--threshold <percent>] [--json]
+       rmachine minimize <program> (--trap <kind> | --expect-output <file>) [-o | --output <file>] [-- <args>...]";
+
+// How many instructions retire between snapshots by default.
+const CHECKPOINT_EVERY: u64 = 10_000_000;
+
+// The rate of the cycle clock a run recording a manifest gets when no
+// --clock-hz is given, since its replay could not read the host's clock
+// back.
+const MANIFEST_CLOCK_HZ: u64 = 1_000_000;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct RunOptions {
+    // Where to write a manifest describing the run.
+    manifest: Option<String>,
+    // Where to save snapshots of the run as it goes.
+    checkpoint: Option<String>,
+    checkpoint_every: Option<u64>,
+    // A snapshot to resume from if it exists, and to keep saving to unless
+    // --checkpoint names another file.
+    resume: Option<String>,
+    // Where to write a trace of the run's control flow.
+    branch_trace: Option<String>,
+    // Where to write a full trace of every instruction the run executes.
+    trace_file: Option<String>,
+    // Whether to print a summary of the run.
+    stats: bool,
+    // Where to write the summary as JSON.
+    stats_json: Option<String>,
+    // Whether to print an estimate of the energy the run used.
+    energy: bool,
+    // Whether to log each instruction and its effects to stderr.
+    trace: bool,
+    // The period of a timer raising interrupt line 0, in cycles.
+    timer: Option<u64>,
+    // The most instructions the run may execute.
+    fuel: Option<u64>,
+    // The most bytes of output the guest may write.
+    max_output: Option<u64>,
+    // The extra cycles each misaligned load or store costs.
+    misalignment_penalty: u64,
+    // Whether instructions setting fields they ignore trap.
+    strict_decoding: bool,
+    // Whether signed overflow in add, sub and mul traps.
+    trap_overflow: bool,
+    // A symbol file naming the program's functions.
+    symbols: Option<String>,
+    // Named regions of memory for messages to place addresses in.
+    regions: Vec<(String, Address, u32)>,
+    // Whether to report calls that break the calling convention.
+    abi_check: bool,
+    // What to do when the guest reads a value nothing has written.
+    uninit: Option<UninitCheck>,
+    // Whether to check at the end that no executed code was overwritten.
+    check_image: bool,
+    // Whether to halt when the guest sleeps with nothing left to wake it.
+    exit_on_idle: bool,
+    // A host directory for the guest to open files in.
+    dir: Option<HostDir>,
+    clock: Clock,
+}
+
+// Where and how often a run saves snapshots to resume from.
+struct Checkpointing<'a> {
+    path: &'a str,
+    every: u64,
+    image: &'a [u8],
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    // Run a program image, passing the guest its path and `args` as argv.
+    Run {
+        program: String,
+        args: Vec<String>,
+        options: Box<RunOptions>,
+    },
+    // Repeat the run a manifest describes and check that it matches.
+    Replay {
+        manifest: String,
+    },
+    // Shrink a program to a smaller one that fails the same way.
+    Minimize {
+        program: String,
+        args: Vec<String>,
+        failure: FailureSpec,
+        output: Option<String>,
+    },
+    // Assemble a source file into a program image, or an ELF executable
+    // laid out by a linker script, optionally writing a symbol file for it.
+    Asm {
+        source: String,
+        output: Option<String>,
+        script: Option<String>,
+        symbols: Option<String>,
+    },
+    // List the instructions in a program image.
+    Disasm {
+        program: String,
+    },
+    // Scan a program image for likely mistakes without running it.
+    Verify {
+        program: String,
+    },
+    // Write the suite of per-instruction test vectors, to stdout without
+    // an output file.
+    GenerateVectors {
+        output: Option<String>,
+    },
+    // Run a file of test vectors and report those that fail.
+    CheckVectors {
+        file: String,
+    },
+    // List the path a branch trace of the program took.
+    Reconstruct {
+        program: String,
+        trace: String,
+    },
+    // Print the records of a full trace as text.
+    Dump {
+        trace: String,
+    },
+    // Compare the stats reports of two runs, failing if the new one
+    // regressed by more than `threshold` percent.
+    PerfCompare {
+        old: String,
+        new: String,
+        threshold: f64,
+        // Whether to print the comparison as JSON.
+        json: bool,
+    },
+    // Run every program a batch file lists and report on them as JSON.
+    Batch {
+        file: String,
+        parallel: Option<usize>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FailureSpec {
+    Trap(String),
+    // The file holding the output the program should have produced.
+    ExpectOutput(String),
+}
+
+fn parse(args: &[String]) -> Result<Command, String> {
+    match args {
+        [command, rest @ ..] if command == "run" => parse_run(rest),
+        [command, rest @ ..] if command == "minimize" => parse_minimize(rest),
+        [command] if command == "asm" => Err("missing source file".to_string()),
+        [command, rest @ ..] if command == "asm" => parse_asm(rest),
+        [command, program] if command == "disasm" => Ok(Command::Disasm {
+            program: program.clone(),
+        }),
+        [command] if command == "disasm" => Err("missing program".to_string()),
+        [command, _, unexpected, ..] if command == "disasm" => {
+            Err(format!("unexpected argument {unexpected:?}"))
+        }
+        [command, program] if command == "verify" => Ok(Command::Verify {
+            program: program.clone(),
+        }),
+        [command] if command == "verify" => Err("missing program".to_string()),
+        [command, _, unexpected, ..] if command == "verify" => {
+            Err(format!("unexpected argument {unexpected:?}"))
+        }
+        [command, action] if command == "vectors" && action == "generate" => {
+            Ok(Command::GenerateVectors { output: None })
+        }
+        [command, action, flag, output]
+            if command == "vectors"
+                && action == "generate"
+                && (flag == "-o" || flag == "--output") =>
+        {
+            Ok(Command::GenerateVectors {
+                output: Some(output.clone()),
+            })
+        }
+        [command, action, file] if command == "vectors" && action == "check" => {
+            Ok(Command::CheckVectors { file: file.clone() })
+        }
+        [command, action] if command == "vectors" && action == "check" => {
+            Err("missing vector file".to_string())
+        }
+        [command, ..] if command == "vectors" => {
+            Err("usage: rmachine vectors (generate [-o <file>] | check <file>)".to_string())
+        }
+        [command, program, trace] if command == "reconstruct" => Ok(Command::Reconstruct {
+            program: program.clone(),
+            trace: trace.clone(),
+        }),
+        [command] if command == "reconstruct" => Err("missing program".to_string()),
+        [command, _] if command == "reconstruct" => Err("missing branch trace".to_string()),
+        [command, _, _, unexpected, ..] if command == "reconstruct" => {
+            Err(format!("unexpected argument {unexpected:?}"))
+        }
+        [command, trace] if command == "dump" => Ok(Command::Dump {
+            trace: trace.clone(),
+        }),
+        [command] if command == "dump" => Err("missing trace file".to_string()),
+        [command, _, unexpected, ..] if command == "dump" => {
+            Err(format!("unexpected argument {unexpected:?}"))
+        }
+        [command, rest @ ..] if command == "perf-compare" => parse_perf_compare(rest),
+        [command] if command == "batch" => Err("missing batch file".to_string()),
+        [command, rest @ ..] if command == "batch" => parse_batch(rest),
+        [command, manifest] if command == "replay" => Ok(Command::Replay {
+            manifest: manifest.clone(),
+        }),
+        [command] if command == "replay" => Err("missing manifest".to_string()),
+        [command, _, unexpected, ..] if command == "replay" => {
+            Err(format!("unexpected argument {unexpected:?}"))
+        }
+        [command, ..] => Err(format!("unknown command {command:?}")),
+        [] => Err("missing program".to_string()),
+    }
+}
+
+// A program followed by options, which either take a value or are
+// switches, and then the guest's arguments after a `--`.
+struct ProgramArgs {
+    program: String,
+    options: Vec<(String, String)>,
+    switches: Vec<String>,
+    args: Vec<String>,
+}
+
+fn parse_program_args(
+    args: &[String],
+    known: &[&str],
+    switches: &[&str],
+) -> Result<ProgramArgs, String> {
+    let mut program = None;
+    let mut options = Vec::new();
+    let mut switched = Vec::new();
+    let mut guest_args = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => {
+                guest_args = args.cloned().collect();
+                break;
+            }
+            flag if known.contains(&flag) => {
+                let value = args.next().ok_or_else(|| format!("{flag} needs a value"))?;
+                options.push((flag.to_string(), value.clone()));
+            }
+            flag if switches.contains(&flag) => switched.push(flag.to_string()),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {flag:?}")),
+            _ if program.is_none() => program = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {arg:?}")),
+        }
+    }
+    Ok(ProgramArgs {
+        program: program.ok_or("missing program")?,
+        options,
+        switches: switched,
+        args: guest_args,
+    })
+}
+
+fn parse_run(args: &[String]) -> Result<Command, String> {
+    let flags = [
+        "--manifest",
+        "--checkpoint",
+        "--checkpoint-every",
+        "--resume",
+        "--branch-trace",
+        "--trace-file",
+        "--stats-json",
+        "--timer",
+        "--fuel",
+        "--max-output",
+        "--misalignment-penalty",
+        "--clock-hz",
+        "--clock-epoch",
+        "--symbols",
+        "--region",
+        "--uninit",
+        "--dir",
+        "--allow",
+    ];
+    let switches = [
+        "--stats",
+        "--energy",
+        "--trace",
+        "--strict-decoding",
+        "--trap-overflow",
+        "--abi-check",
+        "--check-image",
+        "--exit-on-idle",
+        "--read-only",
+    ];
+    let parsed = parse_program_args(args, &flags, &switches)?;
+    let switched = |name| parsed.switches.iter().any(|switch| switch == name);
+    let mut options = RunOptions {
+        stats: switched("--stats"),
+        energy: switched("--energy"),
+        trace: switched("--trace"),
+        strict_decoding: switched("--strict-decoding"),
+        trap_overflow: switched("--trap-overflow"),
+        abi_check: switched("--abi-check"),
+        check_image: switched("--check-image"),
+        exit_on_idle: switched("--exit-on-idle"),
+        ..Default::default()
+    };
+    let (mut hz, mut epoch) = (None, 0);
+    let mut allow = Vec::new();
+    for (flag, value) in parsed.options {
+        let number = || {
+            value
+                .parse()
+                .map_err(|_| format!("{flag} needs a number, not {value:?}"))
+        };
+        match flag.as_str() {
+            "--manifest" => options.manifest = Some(value),
+            "--checkpoint" => options.checkpoint = Some(value),
+            "--checkpoint-every" => options.checkpoint_every = Some(number()?),
+            "--timer" => options.timer = Some(number()?),
+            "--fuel" => options.fuel = Some(number()?),
+            "--max-output" => options.max_output = Some(number()?),
+            "--misalignment-penalty" => options.misalignment_penalty = number()?,
+            "--clock-hz" => hz = Some(number()?),
+            "--clock-epoch" => epoch = number()?,
+            "--branch-trace" => options.branch_trace = Some(value),
+            "--trace-file" => options.trace_file = Some(value),
+            "--stats-json" => options.stats_json = Some(value),
+            "--symbols" => options.symbols = Some(value),
+            "--region" => options.regions.push(parse_region(&value)?),
+            "--dir" => options.dir = Some(HostDir::new(value)),
+            "--allow" => allow.push(PathBuf::from(value)),
+            "--uninit" => {
+                options.uninit = Some(match value.as_str() {
+                    "warn" => UninitCheck::Warn,
+                    "trap" => UninitCheck::Trap,
+                    _ => return Err(format!("--uninit needs warn or trap, not {value:?}")),
+                });
+            }
+            _ => options.resume = Some(value),
+        }
+    }
+    if let Some(hz) = hz {
+        options.clock = Clock::Cycles { hz, epoch };
+    }
+    match &mut options.dir {
+        Some(dir) => {
+            dir.allow = allow;
+            dir.read_only = switched("--read-only");
+        }
+        None if !allow.is_empty() || switched("--read-only") => {
+            return Err("--allow and --read-only need --dir".to_string())
+        }
+        None => {}
+    }
+    Ok(Command::Run {
+        program: parsed.program,
+        args: parsed.args,
+        options: Box::new(options),
+    })
+}
+
+// A region given as <name>=<base>:<len>, the numbers in decimal or hex.
+fn parse_region(value: &str) -> Result<(String, Address, u32), String> {
+    let number = |text: &str| match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    };
+    value
+        .split_once('=')
+        .and_then(|(name, range)| {
+            let (base, len) = range.rsplit_once(':')?;
+            Some((name.to_string(), number(base)?, number(len)?))
+        })
+        .filter(|(name, _, _)| !name.is_empty())
+        .ok_or_else(|| format!("--region needs <name>=<base>:<len>, not {value:?}"))
+}
+
+fn parse_minimize(args: &[String]) -> Result<Command, String> {
+    let parsed = parse_program_args(args, &["--trap", "--expect-output", "--output", "-o"], &[])?;
+    let (mut failure, mut output) = (None, None);
+    for (flag, value) in parsed.options {
+        match flag.as_str() {
+            "--trap" => failure = Some(FailureSpec::Trap(value)),
+            "--expect-output" => failure = Some(FailureSpec::ExpectOutput(value)),
+            _ => output = Some(value),
+        }
+    }
+    Ok(Command::Minimize {
+        program: parsed.program,
+        args: parsed.args,
+        failure: failure.ok_or("missing --trap or --expect-output")?,
+        output,
+    })
+}
+
+fn parse_asm(args: &[String]) -> Result<Command, String> {
+    let parsed = parse_program_args(args, &["--output", "-o", "-T", "--symbols"], &[])?;
+    if let Some(arg) = parsed.args.first() {
+        return Err(format!("unexpected argument {arg:?}"));
+    }
+    let (mut output, mut script, mut symbols) = (None, None, None);
+    for (flag, value) in parsed.options {
+        match flag.as_str() {
+            "-T" => script = Some(value),
+            "--symbols" => symbols = Some(value),
+            _ => output = Some(value),
+        }
+    }
+    if script.is_some() && symbols.is_some() {
+        return Err("--symbols cannot be used with -T".to_string());
+    }
+    Ok(Command::Asm {
+        source: parsed.program,
+        output,
+        script,
+        symbols,
+    })
+}
+
+fn parse_batch(args: &[String]) -> Result<Command, String> {
+    let parsed = parse_program_args(args, &["--parallel"], &[])?;
+    if let Some(arg) = parsed.args.first() {
+        return Err(format!("unexpected argument {arg:?}"));
+    }
+    let mut parallel = None;
+    for (flag, value) in parsed.options {
+        parallel = Some(
+            value
+                .parse()
+                .map_err(|_| format!("{flag} needs a number, not {value:?}"))?,
+        );
+    }
+    Ok(Command::Batch {
+        file: parsed.program,
+        parallel,
+    })
+}
+
+fn parse_perf_compare(args: &[String]) -> Result<Command, String> {
+    let mut reports = Vec::new();
+    let mut threshold = 0.0;
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--threshold" => {
+                let value = args.next().ok_or("--threshold needs a value")?;
+                threshold = value
+                    .parse()
+                    .map_err(|_| format!("--threshold needs a number, not {value:?}"))?;
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option {flag:?}")),
+            _ if reports.len() < 2 => reports.push(arg.clone()),
+            _ => return Err(format!("unexpected argument {arg:?}")),
+        }
+    }
+    match <[String; 2]>::try_from(reports) {
+        Ok([old, new]) => Ok(Command::PerfCompare {
+            old,
+            new,
+            threshold,
+            json,
+        }),
+        Err(reports) if reports.is_empty() => Err("missing old stats report".to_string()),
+        Err(_) => Err("missing new stats report".to_string()),
+    }
+}
+
+// Builds a machine for the program with `argv` as its arguments, and
+// returns it with the program image.
+fn launch(
+    program: &str,
+    argv: &[String],
+    layout: Layout,
+    hart_id: Word,
+) -> Result<(Machine<Stdout>, Vec<u8>), String> {
+    let image = fs::read(program).map_err(|err| format!("{program}: {err}"))?;
+    let executable =
+        Executable::parse(&image, layout.load_address).map_err(|err| format!("{err}"))?;
+    let mut machine = Machine::builder()
+        .layout(layout)
+        .hart_id(hart_id)
+        .stdout(io::stdout())
+        .executable(executable)
+        .args(argv.to_vec())
+        .build()
+        .map_err(|err| format!("{err}"))?;
+    machine.attach_writer(2, io::stderr());
+    Ok((machine, image))
+}
+
+// Runs the machine with the host's stdin and stdout as the guest's. When
+// stdin is a terminal it is switched to raw mode for the run, so
+// keystrokes reach the guest as they are typed, and Ctrl-C or, otherwise,
+// SIGINT interrupts the machine. The interrupt is posted to the guest as
+// SIGINT if it has a handler for it, and a second one halts the machine
+// before the guest has handled the first. One the machine has not even
+// taken the first by exits the process.
+// With `stats`, the run is measured and its summary returned along with
+// why it halted.
+fn execute(
+    machine: &mut Machine<Stdout>,
+    checkpointing: Option<&Checkpointing>,
+    stats: bool,
+) -> Result<(HaltReason, Option<RunStats>), String> {
+    machine.forward_interrupts(Some(SIGINT));
+    machine.set_logger(|record| eprintln!("{record}"));
+    let raw_mode = if io::stdin().is_terminal() {
+        let raw_mode = RawMode::enable().map_err(|err| format!("raw mode: {err}"))?;
+        let restore = raw_mode.restorer();
+        let console = Console::new(io::stdin(), machine.interrupt_handle()).on_stuck(move || {
+            restore();
+            sigint::exit()
+        });
+        machine.set_stdin_source(Pipe::new(console));
+        Some(raw_mode)
+    } else {
+        machine.set_stdin_source(Pipe::new(io::stdin()));
+        sigint::catch(machine.interrupt_handle());
+        None
+    };
+    let run = |machine: &mut Machine<Stdout>| match checkpointing {
+        Some(checkpointing) => machine.run_with_snapshots(checkpointing.every, |machine| {
+            save_snapshot(
+                checkpointing.path,
+                &Snapshot::take(machine, checkpointing.image),
+            )
+        }),
+        None => machine.run(),
+    };
+    let halted = if stats {
+        machine
+            .measure(run)
+            .map(|outcome| (outcome.reason.clone(), Some(outcome.stats().clone())))
+    } else {
+        run(machine).map(|reason| (reason, None))
+    };
+    if let Some(stdout) = &mut machine.stdout {
+        stdout.flush().ok();
+    }
+    drop(raw_mode);
+    let (reason, stats) = halted.map_err(|err| match err {
+        Error::Trap { pc, trap } => format!(
+            "guest trapped at pc {pc:#010x}{}: {}",
+            machine.memory_map().annotate(pc),
+            trap.describe(machine.memory_map())
+        ),
+        err => format!("{err}"),
+    })?;
+    Ok((reason, stats))
+}
+
+// Raising the machine interrupt on SIGINT needs the C library's signal(3),
+// there being no way to catch a signal in std. The handler only touches
+// atomics, which is safe in a signal handler. If the machine has not taken
+// the interrupt by the next SIGINT it is stuck outside the interpreter, in a
+// blocking read from the host perhaps, and the process exits at once.
+#[cfg(unix)]
+mod sigint {
+    use super::Interrupt;
+    use std::sync::OnceLock;
+
+    static INTERRUPT: OnceLock<Interrupt> = OnceLock::new();
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+
+    extern "C" fn interrupt(_: i32) {
+        match INTERRUPT.get() {
+            Some(interrupt) if !interrupt.is_raised() => interrupt.raise(),
+            _ => exit(),
+        }
+    }
+
+    pub(super) fn catch(interrupt: Interrupt) {
+        if INTERRUPT.set(interrupt).is_ok() {
+            // SAFETY: signal(3) is given a valid signal number and an
+            // extern "C" handler that only touches atomics and calls the
+            // async-signal-safe _exit(2).
+            unsafe {
+                signal(super::SIGINT as i32, self::interrupt);
+            }
+        }
+    }
+
+    // Exits as SIGINT would have, at once and without unwinding or running
+    // destructors, which neither a signal handler nor a thread racing the
+    // machine's can do safely.
+    pub(super) fn exit() -> ! {
+        // SAFETY: _exit(2) takes any status and is async-signal-safe.
+        unsafe { _exit(130) }
+    }
+}
+
+#[cfg(not(unix))]
+mod sigint {
+    pub(super) fn catch(_: super::Interrupt) {}
+
+    pub(super) fn exit() -> ! {
+        std::process::exit(130)
+    }
+}
+
+// Succeeds if the guest stopped at a breakpoint.
+fn exit_code(reason: &HaltReason) -> ExitCode {
+    match reason {
+        HaltReason::Breakpoint => ExitCode::SUCCESS,
+        HaltReason::Interrupted { .. } => ExitCode::from(130),
+        HaltReason::Exit { code } => ExitCode::from(*code as u8),
+        reason => {
+            eprintln!("rmachine: {reason}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Writes the snapshot alongside its file and then moves it into place, so a
+// crash part way through leaves the previous snapshot intact.
+fn save_snapshot(path: &str, snapshot: &Snapshot) -> io::Result<()> {
+    let partial = format!("{path}.partial");
+    fs::write(&partial, snapshot.to_bytes())?;
+    fs::rename(partial, path)
+}
+
+// Restores the snapshot at `path`, if there is one, skipping the input the
+// guest had already read.
+fn resume(machine: &mut Machine<Stdout>, path: &str, image: &[u8]) -> Result<(), String> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(format!("{path}: {err}")),
+    };
+    let snapshot = Snapshot::parse(&bytes, machine.layout.page_size)
+        .map_err(|err| format!("{path}: {err}"))?;
+    if snapshot.program_hash != Digest::of(image) {
+        return Err(format!("{path}: snapshot is of a different program"));
+    }
+    let skipped = io::copy(
+        &mut io::stdin().lock().take(snapshot.input_read),
+        &mut io::sink(),
+    )
+    .map_err(|err| format!("stdin: {err}"))?;
+    if skipped < snapshot.input_read {
+        return Err(format!(
+            "{path}: stdin ended before the {} bytes the guest had read",
+            snapshot.input_read
+        ));
+    }
+    machine
+        .restore_snapshot(&snapshot)
+        .map_err(|err| format!("{path}: {err}"))?;
+    eprintln!(
+        "rmachine: resumed from {path} after {} instructions",
+        snapshot.retired
+    );
+    Ok(())
+}
+
+fn run(program: &str, args: Vec<String>, options: &RunOptions) -> Result<ExitCode, String> {
+    let argv: Vec<String> = std::iter::once(program.to_string()).chain(args).collect();
+    let (mut machine, image) = launch(program, &argv, Layout::default(), 0)?;
+    machine.set_clock(match options.clock {
+        Clock::Host if options.manifest.is_some() => Clock::Cycles {
+            hz: MANIFEST_CLOCK_HZ,
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        },
+        clock => clock,
+    });
+    machine.set_terminal(TerminalInfo::host());
+    if let Some(period) = options.timer {
+        machine.attach_device(Timer::new(0, period));
+    }
+    if let Some(fuel) = options.fuel {
+        machine.set_fuel(Some(fuel));
+    }
+    if let Some(max) = options.max_output {
+        machine.set_limits(Some(Limits {
+            max_output_bytes: Some(max),
+            ..Limits::default()
+        }));
+    }
+    machine.set_misalignment_penalty(options.misalignment_penalty);
+    machine.set_strict_decoding(options.strict_decoding);
+    machine.set_overflow_trap(options.trap_overflow);
+    if let Some(path) = &options.symbols {
+        let text = fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        let symbols = SymbolTable::parse(&text).map_err(|err| format!("{path}: {err}"))?;
+        machine.set_symbols(symbols);
+    }
+    for (name, base, len) in &options.regions {
+        machine.name_region(name, *base, *len);
+    }
+    machine.set_abi_check(options.abi_check);
+    machine.set_uninit_check(options.uninit);
+    machine.set_image_check(options.check_image);
+    machine.set_exit_on_idle(options.exit_on_idle);
+    if let Some(dir) = &options.dir {
+        machine.mount(dir.clone());
+    }
+    if options.trace {
+        machine.set_tracer(Tracer::new(io::stderr()).show_effects());
+    }
+    if let Some(path) = &options.trace_file {
+        let file = fs::File::create(path).map_err(|err| format!("{path}: {err}"))?;
+        machine
+            .record_trace(io::BufWriter::new(file))
+            .map_err(|err| format!("{path}: {err}"))?;
+    }
+    let branch_trace = options.branch_trace.as_ref().map(|path| {
+        let trace = Rc::new(RefCell::new(BranchTrace::new()));
+        machine.add_observer(trace.clone());
+        (path, trace)
+    });
+    let energy = options.energy.then(|| {
+        let energy = Rc::new(RefCell::new(Energy::default()));
+        machine.add_observer(energy.clone());
+        energy
+    });
+    if let Some(path) = &options.resume {
+        resume(&mut machine, path, &image)?;
+    }
+    let checkpointing = options
+        .checkpoint
+        .as_ref()
+        .or(options.resume.as_ref())
+        .map(|path| Checkpointing {
+            path,
+            every: options.checkpoint_every.unwrap_or(CHECKPOINT_EVERY),
+            image: &image,
+        });
+    let measured = options.stats || options.stats_json.is_some();
+    let (reason, stats) = execute(&mut machine, checkpointing.as_ref(), measured)?;
+    if let Some(stats) = &stats {
+        if options.stats {
+            eprint!("{stats}");
+        }
+        if let Some(path) = &options.stats_json {
+            fs::write(path, stats.to_json()).map_err(|err| format!("{path}: {err}"))?;
+        }
+    }
+    if let Some(energy) = energy {
+        eprint!("{}", energy.borrow());
+    }
+    for violation in machine.abi_violations() {
+        eprintln!("rmachine: calling convention: {violation}");
+    }
+    for read in machine.uninitialized_reads() {
+        eprintln!("rmachine: {read}");
+    }
+    if let (Some(path), Some(writer)) = (&options.trace_file, machine.take_trace_writer()) {
+        writer.finish().map_err(|err| format!("{path}: {err}"))?;
+    }
+    if let Some((path, trace)) = branch_trace {
+        fs::write(path, trace.borrow().to_bytes()).map_err(|err| format!("{path}: {err}"))?;
+    }
+    if let Some(path) = &options.manifest {
+        let manifest = Manifest::record(&machine, program, &image, &argv, &reason.to_string());
+        fs::write(path, manifest.to_string()).map_err(|err| format!("{path}: {err}"))?;
+    }
+    Ok(exit_code(&reason))
+}
+
+fn replay(path: &str) -> Result<ExitCode, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    let expected = Manifest::parse(&text).map_err(|err| format!("{path}: {err}"))?;
+    if expected.clock == Clock::Host {
+        return Err(format!(
+            "{path}: the run used the host's clock, which a replay cannot reproduce"
+        ));
+    }
+    let (mut machine, image) = launch(
+        &expected.program,
+        &expected.args,
+        expected.layout,
+        expected.hart_id,
+    )?;
+    machine.set_clock(expected.clock);
+    let (reason, _) = execute(&mut machine, None, false)?;
+    let actual = Manifest::record(
+        &machine,
+        &expected.program,
+        &image,
+        &expected.args,
+        &reason.to_string(),
+    );
+    let differences = expected.differences(&actual);
+    if differences.is_empty() {
+        eprintln!("rmachine: the run matches {path}");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        eprintln!(
+            "rmachine: the run differs from {path} in {}",
+            differences.join(", ")
+        );
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+// Minimizes the program against the failure, with the host's stdin as the
+// input for every run, and writes the result to `output`, or next to the
+// program with a .min extension.
+fn minimize(
+    program: &str,
+    args: Vec<String>,
+    failure: &FailureSpec,
+    output: Option<String>,
+) -> Result<ExitCode, String> {
+    let image = fs::read(program).map_err(|err| format!("{program}: {err}"))?;
+    let failure = match failure {
+        FailureSpec::Trap(kind) => Failure::Trap(kind.clone()),
+        FailureSpec::ExpectOutput(path) => {
+            Failure::WrongOutput(fs::read(path).map_err(|err| format!("{path}: {err}"))?)
+        }
+    };
+    let mut input = Vec::new();
+    io::stdin()
+        .read_to_end(&mut input)
+        .map_err(|err| format!("stdin: {err}"))?;
+    let argv = std::iter::once(program.to_string()).chain(args);
+    let mut minimizer = Minimizer::new(failure).input(&input).args(argv);
+    // Candidates that make the interpreter panic are expected, so their
+    // messages are not worth printing.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let minimized = minimizer.minimize(&image);
+    panic::set_hook(hook);
+    let minimized = minimized.map_err(|err| format!("{program}: {err}"))?;
+    let output = output.unwrap_or_else(|| format!("{program}.min"));
+    fs::write(&output, &minimized).map_err(|err| format!("{output}: {err}"))?;
+    eprintln!(
+        "rmachine: minimized {program} from {} to {} instructions in {} runs, written to {output}",
+        image.len().div_ceil(4),
+        minimized.len().div_ceil(4),
+        minimizer.runs()
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+// Assembles `source`, writing the image to `output`, or next to the source
+// with a .bin extension. With a linker script the output is an ELF file,
+// with a .elf extension by default. Without one, the labels can be written
+// to a symbol file too.
+fn assemble(
+    source: &str,
+    output: Option<String>,
+    script: Option<&str>,
+    symbols: Option<&str>,
+) -> Result<ExitCode, String> {
+    let (image, extension) = match script {
+        Some(script) => {
+            let text = fs::read_to_string(script).map_err(|err| format!("{script}: {err}"))?;
+            let script = LinkerScript::parse(&text).map_err(|err| format!("{script}: {err}"))?;
+            let executable =
+                asm::assemble_file_with_script(source, &script).map_err(|err| err.to_string())?;
+            (executable.to_elf(), "elf")
+        }
+        None => match symbols {
+            Some(path) => {
+                let (image, symbols) =
+                    asm::assemble_file_with_symbols(source).map_err(|err| err.to_string())?;
+                fs::write(path, symbols.to_string()).map_err(|err| format!("{path}: {err}"))?;
+                (image, "bin")
+            }
+            None => (
+                asm::assemble_file(source).map_err(|err| err.to_string())?,
+                "bin",
+            ),
+        },
+    };
+    let output = output.unwrap_or_else(|| {
+        Path::new(source)
+            .with_extension(extension)
+            .to_string_lossy()
+            .into_owned()
+    });
+    fs::write(&output, &image).map_err(|err| format!("{output}: {err}"))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn disassemble(program: &str) -> Result<ExitCode, String> {
+    let image = fs::read(program).map_err(|err| format!("{program}: {err}"))?;
+    let listing = Disassembler::new().listing(&image, Layout::default().load_address);
+    print!("{listing}");
+    Ok(ExitCode::SUCCESS)
+}
+
+// Lists what the verifier found in a program, one finding per line with
+// the instruction it is about, failing if there is anything to list.
+fn verify_program(program: &str) -> Result<ExitCode, String> {
+    let image = fs::read(program).map_err(|err| format!("{program}: {err}"))?;
+    let base = Layout::default().load_address;
+    let findings = verify(&image, base);
+    let disasm = Disassembler::new();
+    for finding in &findings {
+        let offset = (finding.addr - base) as usize;
+        let mut word = [0; 4];
+        let bytes = &image[offset..image.len().min(offset + 4)];
+        word[..bytes.len()].copy_from_slice(bytes);
+        let instruction = disasm.format_word(Word::from_be_bytes(word), finding.addr);
+        println!("{:#010x}: {instruction}: {}", finding.addr, finding.problem);
+    }
+    Ok(if findings.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+// Prints the path a run took, one instruction per line, from its branch
+// trace.
+fn generate_vectors(output: Option<&str>) -> Result<ExitCode, String> {
+    let text = vectors::to_text(&vectors::generate());
+    match output {
+        Some(path) => fs::write(path, text).map_err(|err| format!("{path}: {err}"))?,
+        None => print!("{text}"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Runs every vector in `file`, printing what differed for each that fails.
+fn check_vectors(file: &str) -> Result<ExitCode, String> {
+    let text = fs::read_to_string(file).map_err(|err| format!("{file}: {err}"))?;
+    let vectors = vectors::parse(&text).map_err(|err| format!("{file}: {err}"))?;
+    let mut failed = 0;
+    for vector in &vectors {
+        let differences = vector.check();
+        if !differences.is_empty() {
+            failed += 1;
+            println!("{}: {}", vector.name, differences.join(", "));
+        }
+    }
+    println!("{} passed, {failed} failed", vectors.len() - failed);
+    Ok(if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn reconstruct(program: &str, trace: &str) -> Result<ExitCode, String> {
+    let image = fs::read(program).map_err(|err| format!("{program}: {err}"))?;
+    let bytes = fs::read(trace).map_err(|err| format!("{trace}: {err}"))?;
+    let base = Layout::default().load_address;
+    let path =
+        branches::reconstruct(&bytes, &image, base).map_err(|err| format!("{trace}: {err}"))?;
+    let disasm = Disassembler::new();
+    let mut out = io::stdout().lock();
+    for pc in path {
+        let offset = (pc - base) as usize;
+        let word = Word::from_be_bytes(image[offset..offset + 4].try_into().unwrap());
+        writeln!(out, "{pc:#010x}: {}", disasm.format_word(word, pc))
+            .map_err(|err| format!("stdout: {err}"))?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Prints each record of a full trace on a line of its own, so that two
+// runs can be compared with diff.
+fn dump(trace: &str) -> Result<ExitCode, String> {
+    let file = fs::File::open(trace).map_err(|err| format!("{trace}: {err}"))?;
+    let reader =
+        TraceReader::new(io::BufReader::new(file)).map_err(|err| format!("{trace}: {err}"))?;
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    for record in reader {
+        let record = record.map_err(|err| format!("{trace}: {err}"))?;
+        writeln!(out, "{record}").map_err(|err| format!("stdout: {err}"))?;
+    }
+    out.flush().map_err(|err| format!("stdout: {err}"))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+// Runs a batch and prints its report, succeeding only if every program
+// finished and produced its expected output, where it had one.
+fn run_batch(file: &str, parallel: Option<usize>) -> Result<ExitCode, String> {
+    let text = fs::read_to_string(file).map_err(|err| format!("{file}: {err}"))?;
+    let base = Path::new(file).parent().unwrap_or(Path::new(""));
+    let mut batch = Batch::parse(&text, base).map_err(|err| format!("{file}: {err}"))?;
+    if let Some(parallel) = parallel {
+        batch.parallel = parallel;
+    }
+    let reports = batch.run();
+    print!("{}", batch::report(&reports));
+    let ok = reports
+        .iter()
+        .all(|report| report.status == Status::Finished && report.passed != Some(false));
+    Ok(if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn perf_compare(old: &str, new: &str, threshold: f64, json: bool) -> Result<ExitCode, String> {
+    let read = |path: &str| fs::read_to_string(path).map_err(|err| format!("{path}: {err}"));
+    let comparison =
+        Comparison::new(&read(old)?, &read(new)?, threshold).map_err(|err| format!("{err}"))?;
+    if json {
+        print!("{}", comparison.to_json());
+    } else {
+        print!("{comparison}");
+    }
+    Ok(if comparison.regressions().next().is_none() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+pub fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match parse(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("rmachine: {message}\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let result = match command {
+        Command::Run {
+            program,
+            args,
+            options,
+        } => run(&program, args, &options),
+        Command::Replay { manifest } => replay(&manifest),
+        Command::Minimize {
+            program,
+            args,
+            failure,
+            output,
+        } => minimize(&program, args, &failure, output),
+        Command::Asm {
+            source,
+            output,
+            script,
+            symbols,
+        } => assemble(&source, output, script.as_deref(), symbols.as_deref()),
+        Command::Disasm { program } => disassemble(&program),
+        Command::Verify { program } => verify_program(&program),
+        Command::GenerateVectors { output } => generate_vectors(output.as_deref()),
+        Command::CheckVectors { file } => check_vectors(&file),
+        Command::Reconstruct { program, trace } => reconstruct(&program, &trace),
+        Command::Dump { trace } => dump(&trace),
+        Command::Batch { file, parallel } => run_batch(&file, parallel),
+        Command::PerfCompare {
+            old,
+            new,
+            threshold,
+            json,
+        } => perf_compare(&old, &new, threshold, json),
+    };
+    result.unwrap_or_else(|message| {
+        eprintln!("rmachine: {message}");
+        ExitCode::FAILURE
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn run_commands_take_guest_arguments_after_a_separator() {
+        struct TestCase {
+            args: Vec<String>,
+            want: Command,
+        }
+        let cases = [
+            TestCase {
+                args: args(&["run", "prog.bin"]),
+                want: Command::Run {
+                    program: "prog.bin".to_string(),
+                    args: Vec::new(),
+                    options: Box::default(),
+                },
+            },
+            TestCase {
+                args: args(&["run", "prog.bin", "--", "-n", "3"]),
+                want: Command::Run {
+                    program: "prog.bin".to_string(),
+                    args: args(&["-n", "3"]),
+                    options: Box::default(),
+                },
+            },
+            TestCase {
+                args: args(&["run", "prog.bin", "--manifest", "run.txt", "--", "x"]),
+                want: Command::Run {
+                    program: "prog.bin".to_string(),
+                    args: args(&["x"]),
+                    options: Box::new(RunOptions {
+                        manifest: Some("run.txt".to_string()),
+                        ..Default::default()
+                    }),
+                },
+            },
+            TestCase {
+                args: args(&[
+                    "run",
+                    "prog.bin",
+                    "--energy",
+                    "--trace",
+                    "--branch-trace",
+                    "run.bt",
+                    "--trace-file",
+                    "run.rtr",
+                ]),
+                want: Command::Run {
+                    program: "prog.bin".to_string(),
+                    args: Vec::new(),
+                    options: Box::new(RunOptions {
+                        branch_trace: Some("run.bt".to_string()),
+                        trace_file: Some("run.rtr".to_string()),
+                        energy: true,
+                        trace: true,
+                        ..Default::default()
+                    }),
+                },
+            },
+            TestCase {
+                args: args(&[
+                    "run",
+                    "prog.bin",
+                    "--resume",
+                    "run.snap",
+                    "--stats",
+                    "--stats-json",
+                    "run.json",
+                    "--checkpoint-every",
+                    "1000",
+                    "--timer",
+                    "500",
+                    "--clock-hz",
+                    "1000000",
+                    "--fuel",
+                    "20000",
+                    "--max-output",
+                    "4096",
+                    "--misalignment-penalty",
+                    "3",
+                    "--strict-decoding",
+                    "--trap-overflow",
+                    "--symbols",
+                    "prog.sym",
+                    "--region",
+                    "mmio:uart=0x10000000:16",
+                    "--abi-check",
+                    "--uninit",
+                    "warn",
+                    "--check-image",
+                    "--exit-on-idle",
+                    "--dir",
+                    "course",
+                    "--allow",
+                    "data",
+                    "--read-only",
+                ]),
+                want: Command::Run {
+                    program: "prog.bin".to_string(),
+                    args: Vec::new(),
+                    options: Box::new(RunOptions {
+                        checkpoint_every: Some(1000),
+                        timer: Some(500),
+                        fuel: Some(20000),
+                        max_output: Some(4096),
+                        misalignment_penalty: 3,
+                        strict_decoding: true,
+                        trap_overflow: true,
+                        symbols: Some("prog.sym".to_string()),
+                        regions: vec![("mmio:uart".to_string(), 0x1000_0000, 16)],
+                        abi_check: true,
+                        uninit: Some(UninitCheck::Warn),
+                        check_image: true,
+                        exit_on_idle: true,
+                        dir: Some(HostDir {
+                            root: PathBuf::from("course"),
+                            allow: vec![PathBuf::from("data")],
+                            read_only: true,
+                        }),
+                        resume: Some("run.snap".to_string()),
+                        stats: true,
+                        stats_json: Some("run.json".to_string()),
+                        clock: Clock::Cycles {
+                            hz: 1_000_000,
+                            epoch: 0,
+                        },
+                        ..Default::default()
+                    }),
+                },
+            },
+            TestCase {
+                args: args(&["replay", "run.txt"]),
+                want: Command::Replay {
+                    manifest: "run.txt".to_string(),
+                },
+            },
+            TestCase {
+                args: args(&["minimize", "prog.bin", "--trap", "OpcodeUnknown"]),
+                want: Command::Minimize {
+                    program: "prog.bin".to_string(),
+                    args: Vec::new(),
+                    failure: FailureSpec::Trap("OpcodeUnknown".to_string()),
+                    output: None,
+                },
+            },
+            TestCase {
+                args: args(&[
+                    "minimize",
+                    "prog.bin",
+                    "--expect-output",
+                    "want.txt",
+                    "--output",
+                    "small.bin",
+                    "--",
+                    "x",
+                ]),
+                want: Command::Minimize {
+                    program: "prog.bin".to_string(),
+                    args: args(&["x"]),
+                    failure: FailureSpec::ExpectOutput("want.txt".to_string()),
+                    output: Some("small.bin".to_string()),
+                },
+            },
+            TestCase {
+                args: args(&["asm", "prog.s", "--output", "prog.img"]),
+                want: Command::Asm {
+                    source: "prog.s".to_string(),
+                    output: Some("prog.img".to_string()),
+                    script: None,
+                    symbols: None,
+                },
+            },
+            TestCase {
+                args: args(&["asm", "prog.s", "-o", "prog.bin", "--symbols", "prog.sym"]),
+                want: Command::Asm {
+                    source: "prog.s".to_string(),
+                    output: Some("prog.bin".to_string()),
+                    script: None,
+                    symbols: Some("prog.sym".to_string()),
+                },
+            },
+            TestCase {
+                args: args(&["asm", "prog.s", "-T", "board.ld"]),
+                want: Command::Asm {
+                    source: "prog.s".to_string(),
+                    output: None,
+                    script: Some("board.ld".to_string()),
+                    symbols: None,
+                },
+            },
+            TestCase {
+                args: args(&["disasm", "prog.bin"]),
+                want: Command::Disasm {
+                    program: "prog.bin".to_string(),
+                },
+            },
+            TestCase {
+                args: args(&["verify", "prog.bin"]),
+                want: Command::Verify {
+                    program: "prog.bin".to_string(),
+                },
+            },
+            TestCase {
+                args: args(&["vectors", "generate", "-o", "isa.txt"]),
+                want: Command::GenerateVectors {
+                    output: Some("isa.txt".to_string()),
+                },
+            },
+            TestCase {
+                args: args(&["vectors", "check", "isa.txt"]),
+                want: Command::CheckVectors {
+                    file: "isa.txt".to_string(),
+                },
+            },
+            TestCase {
+                args: args(&["reconstruct", "prog.bin", "prog.bt"]),
+                want: Command::Reconstruct {
+                    program: "prog.bin".to_string(),
+                    trace: "prog.bt".to_string(),
+                },
+            },
+            TestCase {
+                args: args(&["dump", "run.rtr"]),
+                want: Command::Dump {
+                    trace: "run.rtr".to_string(),
+                },
+            },
+            TestCase {
+                args: args(&[
+                    "perf-compare",
+                    "old.json",
+                    "new.json",
+                    "--threshold",
+                    "2.5",
+                    "--json",
+                ]),
+                want: Command::PerfCompare {
+                    old: "old.json".to_string(),
+                    new: "new.json".to_string(),
+                    threshold: 2.5,
+                    json: true,
+                },
+            },
+            TestCase {
+                args: args(&["batch", "jobs.toml", "--parallel", "4"]),
+                want: Command::Batch {
+                    file: "jobs.toml".to_string(),
+                    parallel: Some(4),
+                },
+            },
+        ];
+        for case in cases {
+            assert_ok_eq!(parse(&case.args), case.want);
+        }
+    }
+
+    #[test]
+    fn invalid_command_lines_are_rejected() {
+        assert_err_eq!(parse(&args(&[])), "missing program".to_string());
+        assert_err_eq!(parse(&args(&["run"])), "missing program".to_string());
+        assert_err_eq!(
+            parse(&args(&["go", "prog.bin"])),
+            "unknown command \"go\"".to_string()
+        );
+        assert_err_eq!(
+            parse(&args(&["run", "prog.bin", "-n"])),
+            "unexpected argument \"-n\"".to_string()
+        );
+        assert_err_eq!(
+            parse(&args(&["run", "prog.bin", "--manifest"])),
+            "--manifest needs a value".to_string()
+        );
+        assert_err_eq!(
+            parse(&args(&["run", "prog.bin", "--fast"])),
+            "unknown option \"--fast\"".to_string()
+        );
+        assert_err_eq!(
+            parse(&args(&["run", "prog.bin", "--checkpoint-every", "often"])),
+            "--checkpoint-every needs a number, not \"often\"".to_string()
+        );
+        assert_err_eq!(
+            parse(&args(&["run", "prog.bin", "--uninit", "loud"])),
+            "--uninit needs warn or trap, not \"loud\"".to_string()
+        );
+        assert_err_eq!(
+            parse(&args(&["run", "prog.bin", "--region", "stack:0x8000"])),
+            "--region needs <name>=<base>:<len>, not \"stack:0x8000\"".to_string()
+        );
+        assert_err_eq!(
+            parse(&args(&["run", "prog.bin", "--read-only"])),
+            "--allow and --read-only need --dir".to_string()
+        );
+        assert_err_eq!(
+            parse(&args(&["minimize", "prog.bin"])),
+            "missing --trap or --expect-output".to_string()
+        );
+        assert_err_eq!(parse(&args(&["batch"])), "missing batch file".to_string());
+        assert_err_eq!(
+            parse(&args(&["reconstruct", "prog.bin"])),
+            "missing branch trace".to_string()
+        );
+        assert_err_eq!(parse(&args(&["dump"])), "missing trace file".to_string());
+        assert_err_eq!(
+            parse(&args(&["perf-compare", "old.txt"])),
+            "missing new stats report".to_string()
+        );
+        assert_err_eq!(
+            parse(&args(&[
+                "asm",
+                "prog.s",
+                "-T",
+                "board.ld",
+                "--symbols",
+                "prog.sym"
+            ])),
+            "--symbols cannot be used with -T".to_string()
+        );
+    }
+}
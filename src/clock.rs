@@ -0,0 +1,133 @@
+use crate::{output::EINVAL, Machine, RegisterID, Result, Word};
+use std::{
+    io::Write,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Clock ids for clock_gettime.
+pub const CLOCK_REALTIME: Word = 0;
+pub const CLOCK_MONOTONIC: Word = 1;
+
+// Where the guest's clocks get the time from.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum Clock {
+    // The host's clocks; the monotonic clock counts from when the machine
+    // was created.
+    #[default]
+    Host,
+    // Time derived from the cycle counter, as though the machine ran at
+    // `hz` cycles per second, so that runs reading the clock stay
+    // reproducible. The real-time clock starts at `epoch` seconds after
+    // 1970, the monotonic one at zero.
+    Cycles {
+        hz: u64,
+        epoch: u64,
+    },
+}
+
+// How long `cycles` take at `hz` cycles per second.
+fn cycle_time(cycles: u64, hz: u64) -> Duration {
+    let nanos = u128::from(cycles) * 1_000_000_000 / u128::from(hz.max(1));
+    Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+}
+
+impl<W: Write> Machine<W> {
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    // The time on the guest clock `id`, or None if there is no such clock.
+    pub fn clock_time(&self, id: Word) -> Option<Duration> {
+        match (self.clock, id) {
+            (Clock::Host, CLOCK_REALTIME) => Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default(),
+            ),
+            (Clock::Host, CLOCK_MONOTONIC) => Some(self.clock_start.elapsed()),
+            (Clock::Cycles { hz, epoch }, CLOCK_REALTIME) => {
+                Some(Duration::from_secs(epoch).saturating_add(cycle_time(self.cycles, hz)))
+            }
+            (Clock::Cycles { hz, .. }, CLOCK_MONOTONIC) => Some(cycle_time(self.cycles, hz)),
+            _ => None,
+        }
+    }
+
+    // clock_gettime(clock, buf) stores the time as a u64 of seconds and u32
+    // of nanoseconds, in the machine's byte order, and returns 0, or -EINVAL for an
+    // unknown clock.
+    pub(crate) fn clock_gettime(&mut self) -> Result<()> {
+        let id = self.regs.get(&RegisterID::A0);
+        let buf = self.regs.get(&RegisterID::A1);
+        let result = match self.clock_time(id) {
+            Some(time) => {
+                let mut secs = time.as_secs().to_be_bytes();
+                self.endian.arrange(&mut secs);
+                let mut record = secs.to_vec();
+                record.extend(self.endian.bytes(time.subsec_nanos()));
+                self.guest_buffer(buf, record.len() as u64)?;
+                self.store(buf, &record);
+                0
+            }
+            None => EINVAL.wrapping_neg() as Word,
+        };
+        self.set_register(RegisterID::A0, result);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HaltReason, Memory, Registers};
+    use claims::assert_ok_eq;
+
+    #[test]
+    fn cycle_clocks_advance_with_the_cycle_counter() {
+        let mut machine: Machine<Vec<u8>> = Machine::default();
+        machine.set_clock(Clock::Cycles {
+            hz: 1_000,
+            epoch: 1_700_000_000,
+        });
+        machine.cycles = 1_500;
+
+        assert_eq!(
+            machine.clock_time(CLOCK_MONOTONIC),
+            Some(Duration::from_millis(1_500))
+        );
+        assert_eq!(
+            machine.clock_time(CLOCK_REALTIME),
+            Some(Duration::from_millis(1_700_000_001_500))
+        );
+        assert_eq!(machine.clock_time(7), None);
+    }
+
+    #[test]
+    fn guests_read_the_clock_with_clock_gettime() {
+        // ecall, then ebreak.
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem: Memory::from([(3, 0b0001_0111), (7, 0b0001_1000)]),
+            regs: Registers::from([
+                (RegisterID::A0, CLOCK_MONOTONIC),
+                (RegisterID::A1, 0x100),
+                (RegisterID::A7, 113),
+            ]),
+            ..Default::default()
+        };
+        machine.set_clock(Clock::Cycles { hz: 4, epoch: 0 });
+        machine.cycles = 6;
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        // Six cycles had passed when the ecall ran: 1.5 seconds at 4 Hz.
+        assert_eq!(machine.regs.get(&RegisterID::A0), 0);
+        assert_eq!(
+            machine.mem.read(0x100, 12),
+            [0, 0, 0, 0, 0, 0, 0, 1, 0x1d, 0xcd, 0x65, 0x00]
+        );
+    }
+}
@@ -0,0 +1,404 @@
+use crate::{
+    semantics::{semantics, AluOp, Condition, Update, Value},
+    state, Address, HaltReason, Machine, RegisterID, Registers, StepOutcome, Word,
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Write,
+};
+
+// A register value as a linear function of the symbolic inputs, modulo
+// 2^32. Values the driver cannot express this way are concretised.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Linear {
+    coeffs: Vec<Word>,
+    constant: Word,
+}
+
+impl Linear {
+    fn constant(inputs: usize, constant: Word) -> Self {
+        Self {
+            coeffs: vec![0; inputs],
+            constant,
+        }
+    }
+
+    fn input(inputs: usize, index: usize) -> Self {
+        let mut input = Self::constant(inputs, 0);
+        input.coeffs[index] = 1;
+        input
+    }
+
+    fn combine(&self, other: &Linear, op: fn(Word, Word) -> Word) -> Linear {
+        Linear {
+            coeffs: self
+                .coeffs
+                .iter()
+                .zip(&other.coeffs)
+                .map(|(a, b)| op(*a, *b))
+                .collect(),
+            constant: op(self.constant, other.constant),
+        }
+    }
+
+    fn scale(&self, by: Word) -> Linear {
+        Linear {
+            coeffs: self
+                .coeffs
+                .iter()
+                .map(|coeff| coeff.wrapping_mul(by))
+                .collect(),
+            constant: self.constant.wrapping_mul(by),
+        }
+    }
+
+    fn is_symbolic(&self) -> bool {
+        self.coeffs.iter().any(|coeff| *coeff != 0)
+    }
+
+    fn eval(&self, inputs: &[Word]) -> Word {
+        self.coeffs
+            .iter()
+            .zip(inputs)
+            .fold(self.constant, |acc, (coeff, input)| {
+                acc.wrapping_add(coeff.wrapping_mul(*input))
+            })
+    }
+}
+
+// A branch decision on the path, in terms of the inputs.
+#[derive(Debug, Clone)]
+struct Constraint {
+    condition: Condition,
+    lhs: Linear,
+    rhs: Linear,
+    taken: bool,
+}
+
+impl Constraint {
+    fn holds(&self, inputs: &[Word]) -> bool {
+        self.condition
+            .holds(self.lhs.eval(inputs), self.rhs.eval(inputs))
+            == self.taken
+    }
+}
+
+// One explored execution: the inputs that drive it, the input-dependent
+// branches it made (address and whether the branch was taken), and how it
+// ended. `halt` is `None` if the run failed or ran out of steps.
+#[derive(Debug)]
+pub struct Path {
+    pub inputs: Vec<Word>,
+    pub branches: Vec<(Address, bool)>,
+    pub halt: Option<HaltReason>,
+    pub regs: Registers,
+}
+
+// A small concolic driver: it runs a program concretely while tracking the
+// chosen input registers symbolically, then negates branch decisions one by
+// one to find inputs that drive execution down paths not yet seen. The
+// solver is deliberately simple and only handles constraints in which an
+// input appears with a coefficient of 1 or -1, which covers the
+// comparisons small programs make.
+pub struct Concolic<F> {
+    make: F,
+    inputs: Vec<RegisterID>,
+    max_paths: usize,
+    max_steps: u64,
+}
+
+impl<W: Write, F: FnMut() -> Machine<W>> Concolic<F> {
+    // `make` builds a fresh machine with the program loaded for each run.
+    pub fn new(make: F, inputs: &[RegisterID]) -> Self {
+        Self {
+            make,
+            inputs: inputs.to_vec(),
+            max_paths: 64,
+            max_steps: 10_000,
+        }
+    }
+
+    pub fn max_paths(mut self, max_paths: usize) -> Self {
+        self.max_paths = max_paths;
+        self
+    }
+
+    pub fn max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    // Explores the program starting from the `seed` input values and
+    // returns every distinct path found, in the order they were found.
+    pub fn explore(&mut self, seed: &[Word]) -> Vec<Path> {
+        let mut paths = Vec::new();
+        // Branch sequences of the paths found, and prefixes of branch
+        // sequences that have been explored or scheduled for exploration.
+        let (mut seen, mut covered) = (HashSet::new(), HashSet::new());
+        let mut pending = VecDeque::from([seed.to_vec()]);
+        while let Some(inputs) = pending.pop_front() {
+            if paths.len() >= self.max_paths {
+                break;
+            }
+            let (path, constraints) = self.run(&inputs);
+            if !seen.insert(path.branches.clone()) {
+                continue;
+            }
+            for len in 1..=path.branches.len() {
+                covered.insert(path.branches[..len].to_vec());
+            }
+            for (index, (pc, taken)) in path.branches.iter().enumerate() {
+                let mut prefix = path.branches[..index].to_vec();
+                prefix.push((*pc, !taken));
+                if !covered.insert(prefix) {
+                    continue;
+                }
+                let mut goal = constraints[..=index].to_vec();
+                goal[index].taken = !taken;
+                if let Some(inputs) = solve(&goal, &inputs) {
+                    pending.push_back(inputs);
+                }
+            }
+            paths.push(path);
+        }
+        paths
+    }
+
+    fn run(&mut self, inputs: &[Word]) -> (Path, Vec<Constraint>) {
+        let mut machine = (self.make)();
+        for (reg, value) in self.inputs.iter().zip(inputs) {
+            machine.regs.set(*reg, *value);
+        }
+        let count = inputs.len();
+        let mut symbolic: Vec<Linear> = (0..16)
+            .map(|id| Linear::constant(count, machine.regs.get(&state::register(id))))
+            .collect();
+        for (index, reg) in self.inputs.iter().enumerate() {
+            if *reg != RegisterID::X0 {
+                symbolic[*reg as usize] = Linear::input(count, index);
+            }
+        }
+
+        let (mut branches, mut constraints, mut halt) = (Vec::new(), Vec::new(), None);
+        for _ in 0..self.max_steps {
+            let Ok((instruction, _)) = machine.next() else {
+                break;
+            };
+            let pc = machine.pc;
+            let mut branch = None;
+            let mut syscall = false;
+            let mut loaded = None;
+            for update in semantics(&instruction) {
+                match update {
+                    Update::SetReg(reg, value) if reg != RegisterID::X0 => {
+                        symbolic[reg as usize] = eval(&value, pc, &symbolic, inputs);
+                    }
+                    Update::Branch {
+                        condition,
+                        lhs,
+                        rhs,
+                        ..
+                    } => {
+                        let (lhs, rhs) = (
+                            eval(&lhs, pc, &symbolic, inputs),
+                            eval(&rhs, pc, &symbolic, inputs),
+                        );
+                        branch = Some((condition, lhs, rhs));
+                    }
+                    Update::Syscall => syscall = true,
+                    // Memory is not tracked symbolically, so loaded values
+                    // are concrete.
+                    Update::Load { rd, .. } if rd != RegisterID::X0 => loaded = Some(rd),
+                    Update::SetReg(..)
+                    | Update::Jump(_)
+                    | Update::Load { .. }
+                    | Update::Store { .. }
+                    | Update::Breakpoint
+                    | Update::InterruptReturn => {}
+                }
+            }
+
+            let outcome = machine.step().map(|(_, outcome)| outcome);
+            if syscall {
+                let a0 = machine.regs.get(&RegisterID::A0);
+                symbolic[RegisterID::A0 as usize] = Linear::constant(count, a0);
+            }
+            if let Some(rd) = loaded {
+                symbolic[rd as usize] = Linear::constant(count, machine.regs.get(&rd));
+            }
+            if let Some((condition, lhs, rhs)) = branch {
+                if lhs.is_symbolic() || rhs.is_symbolic() {
+                    let taken = condition.holds(lhs.eval(inputs), rhs.eval(inputs));
+                    branches.push((pc, taken));
+                    constraints.push(Constraint {
+                        condition,
+                        lhs,
+                        rhs,
+                        taken,
+                    });
+                }
+            }
+            match outcome {
+                Ok(StepOutcome::Continue) => {}
+                Ok(StepOutcome::Halted(reason)) => {
+                    halt = Some(reason);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        let path = Path {
+            inputs: inputs.to_vec(),
+            branches,
+            halt,
+            regs: machine.regs.clone(),
+        };
+        (path, constraints)
+    }
+}
+
+fn eval(value: &Value, pc: Address, symbolic: &[Linear], inputs: &[Word]) -> Linear {
+    let count = inputs.len();
+    match value {
+        Value::Imm(value) => Linear::constant(count, *value),
+        Value::Reg(reg) => symbolic[*reg as usize].clone(),
+        Value::Pc => Linear::constant(count, pc),
+        Value::Add(lhs, rhs) => eval(lhs, pc, symbolic, inputs)
+            .combine(&eval(rhs, pc, symbolic, inputs), Word::wrapping_add),
+        Value::Alu(op, lhs, rhs) => {
+            let (lhs, rhs) = (
+                eval(lhs, pc, symbolic, inputs),
+                eval(rhs, pc, symbolic, inputs),
+            );
+            match op {
+                AluOp::Sub => lhs.combine(&rhs, Word::wrapping_sub),
+                AluOp::Mul if !rhs.is_symbolic() => lhs.scale(rhs.constant),
+                AluOp::Mul if !lhs.is_symbolic() => rhs.scale(lhs.constant),
+                // Anything else is not linear in the inputs, so only its
+                // value on this run is kept.
+                op => Linear::constant(count, op.apply(lhs.eval(inputs), rhs.eval(inputs))),
+            }
+        }
+    }
+}
+
+// Looks for inputs close to `current` satisfying every constraint in
+// `goal`, by moving one input so the last constraint's operands are at or
+// next to each other.
+fn solve(goal: &[Constraint], current: &[Word]) -> Option<Vec<Word>> {
+    let last = goal.last()?;
+    let diff = last.lhs.combine(&last.rhs, Word::wrapping_sub);
+    for (index, coeff) in diff.coeffs.iter().enumerate() {
+        if *coeff != 1 && *coeff != Word::MAX {
+            continue;
+        }
+        // diff = coeff * input + rest, and coeff is its own inverse.
+        let rest = diff
+            .eval(current)
+            .wrapping_sub(coeff.wrapping_mul(current[index]));
+        for delta in [0, 1, -1, 2, -2] {
+            let mut candidate = current.to_vec();
+            candidate[index] = (delta as Word).wrapping_sub(rest).wrapping_mul(*coeff);
+            if goal.iter().all(|constraint| constraint.holds(&candidate)) {
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Memory;
+
+    fn encode(opcode: Word, rd: RegisterID, rs1: RegisterID, rs2: RegisterID, imm: i32) -> [u8; 4] {
+        let imm = (imm as Word) & 0x7fff;
+        let word =
+            imm << 17 | (rs2 as Word) << 13 | (rs1 as Word) << 9 | (rd as Word) << 5 | opcode;
+        word.to_be_bytes()
+    }
+
+    // a0 = 2 if a1 >= 10, otherwise 3 if a1 == 3, otherwise 1.
+    fn machine() -> Machine<Vec<u8>> {
+        use RegisterID::{A0, A1, A2, X0};
+        let program = [
+            encode(0b00001, A2, X0, X0, 10),
+            encode(0b10001, X0, A1, A2, 12),
+            encode(0b00001, A0, X0, X0, 2),
+            encode(0b11000, X0, X0, X0, 0),
+            encode(0b00001, A2, X0, X0, 3),
+            encode(0b01111, X0, A1, A2, 12),
+            encode(0b00001, A0, X0, X0, 1),
+            encode(0b11000, X0, X0, X0, 0),
+            encode(0b00001, A0, X0, X0, 3),
+            encode(0b11000, X0, X0, X0, 0),
+        ];
+        let mut mem = Memory::default();
+        for (i, word) in program.iter().enumerate() {
+            mem.write(i as Address * 4, word);
+        }
+        Machine {
+            mem,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exploration_covers_both_sides_of_every_branch() {
+        let mut driver = Concolic::new(machine, &[RegisterID::A1]);
+
+        let paths = driver.explore(&[50]);
+
+        assert_eq!(paths.len(), 3);
+        let want = [
+            (vec![50], vec![(4, false)], 2),
+            (vec![9], vec![(4, true), (20, false)], 1),
+            (vec![3], vec![(4, true), (20, true)], 3),
+        ];
+        for (path, (inputs, branches, a0)) in paths.iter().zip(want) {
+            assert_eq!(path.inputs, inputs);
+            assert_eq!(path.branches, branches);
+            assert_eq!(path.regs.get(&RegisterID::A0), a0);
+            assert_eq!(path.halt, Some(HaltReason::Breakpoint));
+        }
+    }
+
+    #[test]
+    fn differences_of_inputs_stay_symbolic() {
+        use RegisterID::{A0, A1, A2, A3, X0};
+        // a0 = 2 if 5 - a1 == 0, otherwise 1.
+        let make = || {
+            let program = [
+                encode(0b00001, A3, X0, X0, 5),
+                encode(0b01001, A2, A3, A1, 0),
+                encode(0b01111, X0, A2, X0, 12),
+                encode(0b00001, A0, X0, X0, 1),
+                encode(0b11000, X0, X0, X0, 0),
+                encode(0b00001, A0, X0, X0, 2),
+                encode(0b11000, X0, X0, X0, 0),
+            ];
+            let mut mem = Memory::default();
+            for (i, word) in program.iter().enumerate() {
+                mem.write(i as Address * 4, word);
+            }
+            Machine::<Vec<u8>> {
+                mem,
+                ..Default::default()
+            }
+        };
+        let mut driver = Concolic::new(make, &[RegisterID::A1]);
+
+        let paths = driver.explore(&[0]);
+
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[1].inputs, [5]);
+        assert_eq!(paths[1].regs.get(&RegisterID::A0), 2);
+    }
+
+    #[test]
+    fn exploration_stops_at_the_path_limit() {
+        let mut driver = Concolic::new(machine, &[RegisterID::A1]).max_paths(2);
+        assert_eq!(driver.explore(&[50]).len(), 2);
+    }
+}
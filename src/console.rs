@@ -0,0 +1,265 @@
+use crate::{interrupt::Interrupt, Machine, RegisterID, Result, Word};
+use std::{
+    io::{self, IsTerminal, Read, Write},
+    process::{Command, Stdio},
+};
+
+const CTRL_C: u8 = 0x03;
+
+// The length of the terminfo record in bytes.
+pub const TERMINFO_LEN: usize = 12;
+
+// What the guest is told about the terminal its output goes to. A size of
+// zero means it is not known.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct TerminalInfo {
+    pub width: Word,
+    pub height: Word,
+    pub is_tty: bool,
+}
+
+impl TerminalInfo {
+    // The host's own terminal, as seen from this process's stdout, with the
+    // size stty(1) reports for it.
+    pub fn host() -> Self {
+        if !io::stdout().is_terminal() {
+            return Self::default();
+        }
+        let size = stty(&["size"]).unwrap_or_default();
+        let mut numbers = size.split_whitespace().map(|n| n.parse().unwrap_or(0));
+        let height = numbers.next().unwrap_or(0);
+        Self {
+            width: numbers.next().unwrap_or(0),
+            height,
+            is_tty: true,
+        }
+    }
+}
+
+// Puts the host terminal into a raw-ish mode for as long as it is alive:
+// input is delivered a keystroke at a time without echo, and Ctrl-C is
+// passed through as a byte instead of raising SIGINT. Output processing is
+// left alone so guest newlines still return the cursor. The previous
+// settings are restored on drop.
+//
+// This shells out to stty(1) rather than calling termios directly, since
+// the crate has no dependencies.
+#[derive(Debug)]
+pub struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    pub fn enable() -> io::Result<Self> {
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "-isig", "min", "1", "time", "0"])?;
+        Ok(Self {
+            saved: saved.trim().to_string(),
+        })
+    }
+}
+
+impl RawMode {
+    // Puts the previous settings back from wherever, for a host about to
+    // exit without dropping the RawMode.
+    pub fn restorer(&self) -> impl Fn() + Send + 'static {
+        let saved = self.saved.clone();
+        move || {
+            let _ = stty(&[&saved]);
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "stty exited with {}",
+            output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Guest input from an interactive terminal. A Ctrl-C raises the machine
+// interrupt as soon as it is read, rather than reaching the guest, while
+// the bytes typed around it are still delivered. Read it from a Pipe, whose
+// thread is always reading, so Ctrl-C stops a guest that never reads its
+// input too.
+pub struct Console<R> {
+    input: R,
+    interrupt: Interrupt,
+    stuck: Option<Box<dyn Fn() + Send>>,
+}
+
+impl<R: Read> Console<R> {
+    pub fn new(input: R, interrupt: Interrupt) -> Self {
+        Self {
+            input,
+            interrupt,
+            stuck: None,
+        }
+    }
+
+    // Calls `stuck` on a Ctrl-C that comes while the interrupt raised by
+    // the one before is still pending, the machine being stuck somewhere
+    // it does not check for it, such as a blocking host call. A host would
+    // exit.
+    pub fn on_stuck(mut self, stuck: impl Fn() + Send + 'static) -> Self {
+        self.stuck = Some(Box::new(stuck));
+        self
+    }
+
+    fn ctrl_c(&self) {
+        match &self.stuck {
+            Some(stuck) if self.interrupt.is_raised() => stuck(),
+            _ => self.interrupt.raise(),
+        }
+    }
+}
+
+impl<R: Read> Read for Console<R> {
+    // Only returns 0 at end of file: a read of nothing but Ctrl-Cs reads
+    // again.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let count = self.input.read(buf)?;
+            let mut kept = 0;
+            for at in 0..count {
+                if buf[at] == CTRL_C {
+                    self.ctrl_c();
+                } else {
+                    buf[kept] = buf[at];
+                    kept += 1;
+                }
+            }
+            if kept > 0 || count == 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Sets what the terminfo syscall reports, for the host that knows where
+    // the guest's output goes, or a test pretending to be a terminal.
+    pub fn set_terminal(&mut self, terminal: TerminalInfo) {
+        self.terminal = terminal;
+    }
+
+    pub fn terminal(&self) -> TerminalInfo {
+        self.terminal
+    }
+
+    // terminfo(buf) stores the terminal's width and height in characters
+    // and a word of flags, bit 0 set if it is a terminal at all, as words
+    // in the machine's byte order. Returns 0.
+    pub(crate) fn terminfo_syscall(&mut self) -> Result<()> {
+        let buf = self.regs.get(&RegisterID::A0);
+        let record: Vec<u8> = [
+            self.terminal.width,
+            self.terminal.height,
+            Word::from(self.terminal.is_tty),
+        ]
+        .iter()
+        .flat_map(|word| self.endian.bytes(*word))
+        .collect();
+        self.guest_buffer(buf, record.len() as u64)?;
+        self.store(buf, &record);
+        self.set_register(RegisterID::A0, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, HaltReason};
+    use claims::assert_ok_eq;
+
+    #[test]
+    fn ctrl_c_raises_the_interrupt_instead_of_reaching_the_guest() {
+        let interrupt = Interrupt::default();
+        let mut console = Console::new(&b"ab\x03cd"[..], interrupt.clone());
+        let mut buf = [0; 8];
+
+        assert_ok_eq!(console.read(&mut buf), 4);
+        assert_eq!(&buf[..4], b"abcd");
+
+        let mut machine: crate::Machine<Vec<u8>> = crate::Machine {
+            interrupt,
+            ..Default::default()
+        };
+        assert!(machine.take_interrupt().is_some());
+    }
+
+    #[test]
+    fn a_ctrl_c_is_never_read_as_end_of_file() {
+        let interrupt = Interrupt::default();
+        // A read of nothing but the Ctrl-C reads again, as Pipe's thread
+        // reads a keystroke at a time.
+        let keys = io::Read::chain(&b"\x03"[..], &b"x"[..]);
+        let mut console = Console::new(keys, interrupt.clone());
+        let mut buf = [0; 8];
+
+        assert_ok_eq!(console.read(&mut buf), 1);
+        assert_eq!(buf[0], b'x');
+        assert!(interrupt.is_raised());
+        assert_ok_eq!(console.read(&mut buf), 0);
+    }
+
+    #[test]
+    fn ctrl_c_interrupts_the_machine_before_the_guest_reads() {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&assemble("spin: j spin").unwrap())
+            .build()
+            .unwrap();
+        let console = Console::new(&b"\x03"[..], machine.interrupt_handle());
+        machine.set_stdin_source(crate::poll::Pipe::new(console));
+
+        assert_ok_eq!(machine.run(), HaltReason::Interrupted { pc: 0 });
+    }
+
+    #[test]
+    fn a_second_ctrl_c_before_the_first_is_taken_means_the_machine_is_stuck() {
+        let stuck = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let seen = stuck.clone();
+        let mut console = Console::new(&b"\x03a\x03"[..], Interrupt::default())
+            .on_stuck(move || seen.store(true, std::sync::atomic::Ordering::SeqCst));
+        let mut buf = [0; 8];
+
+        assert_ok_eq!(console.read(&mut buf), 1);
+        assert!(stuck.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn terminfo_reports_the_terminal_the_host_set() {
+        let program = "li a0, 0x100\nli a7, 273\necall\nebreak";
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&assemble(program).unwrap())
+            .build()
+            .unwrap();
+        machine.set_terminal(TerminalInfo {
+            width: 132,
+            height: 43,
+            is_tty: true,
+        });
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register(RegisterID::A0), 0);
+        assert_eq!(
+            machine.mem.read(0x100, TERMINFO_LEN),
+            [0, 0, 0, 132, 0, 0, 0, 43, 0, 0, 0, 1]
+        );
+    }
+}
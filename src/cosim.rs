@@ -0,0 +1,216 @@
+use crate::{effect::Effect, Error, HaltReason, Machine, Result};
+use std::{
+    fmt,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+// A bidirectional byte stream to the co-simulator, such as a TcpStream.
+pub trait Channel: Read + Write {}
+
+impl<T: Read + Write> Channel for T {}
+
+// Streams the effect of every retired instruction to an external model so
+// the two can run in lock-step. Each record is encoded big-endian as:
+//
+//   u64 retired  instructions retired before this one
+//   u32 pc       address of the instruction
+//   u32 next_pc  address of the next instruction
+//   u8  count    register writes, each: u8 register id, u32 old, u32 new
+//   u16 count    memory writes, each: u32 address, u16 length, bytes
+//
+// With acknowledgements on, the machine waits for one byte after each
+// record: zero to continue, anything else to report a divergence.
+pub struct Cosim {
+    channel: Box<dyn Channel>,
+    ack: bool,
+}
+
+impl fmt::Debug for Cosim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cosim")
+            .field("ack", &self.ack)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Cosim {
+    pub fn new(channel: impl Channel + 'static) -> Self {
+        Self {
+            channel: Box::new(channel),
+            ack: false,
+        }
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).map_err(failed)?;
+        stream.set_nodelay(true).map_err(failed)?;
+        Ok(Self::new(stream))
+    }
+
+    pub fn acknowledged(mut self) -> Self {
+        self.ack = true;
+        self
+    }
+
+    // Sends the record for `effect` and returns whether the other side
+    // agreed with it.
+    fn send(&mut self, retired: u64, effect: &Effect) -> Result<bool> {
+        self.channel
+            .write_all(&encode(retired, effect))
+            .and_then(|()| self.channel.flush())
+            .map_err(failed)?;
+        if !self.ack {
+            return Ok(true);
+        }
+        let mut reply = [0];
+        self.channel.read_exact(&mut reply).map_err(failed)?;
+        Ok(reply[0] == 0)
+    }
+}
+
+// Taken by value to be passed to map_err.
+#[allow(clippy::needless_pass_by_value)]
+fn failed(err: std::io::Error) -> Error {
+    Error::CosimFailed(err.to_string())
+}
+
+pub fn encode(retired: u64, effect: &Effect) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.extend(retired.to_be_bytes());
+    record.extend(effect.pc.to_be_bytes());
+    record.extend(effect.next_pc.to_be_bytes());
+    record.push(effect.regs.len() as u8);
+    for write in &effect.regs {
+        record.push(write.reg as u8);
+        record.extend(write.old.to_be_bytes());
+        record.extend(write.new.to_be_bytes());
+    }
+    record.extend((effect.mem.len() as u16).to_be_bytes());
+    for write in &effect.mem {
+        record.extend(write.addr.to_be_bytes());
+        record.extend((write.new.len() as u16).to_be_bytes());
+        record.extend(&write.new);
+    }
+    record
+}
+
+impl<W: Write> Machine<W> {
+    // Starts streaming effects to the co-simulator, which turns on effect
+    // recording.
+    pub fn set_cosim(&mut self, cosim: Cosim) {
+        self.record_effects(true);
+        self.cosim = Some(cosim);
+    }
+
+    pub fn take_cosim(&mut self) -> Option<Cosim> {
+        self.cosim.take()
+    }
+
+    // Streams the effect of the instruction that just retired, returning a
+    // halt reason if the co-simulator disagreed with it.
+    pub(crate) fn stream_effect(&mut self) -> Result<Option<HaltReason>> {
+        let (Some(cosim), Some(effect)) = (&mut self.cosim, self.effects.last()) else {
+            return Ok(None);
+        };
+        let retired = self.retired - 1;
+        if cosim.send(retired, effect)? {
+            Ok(None)
+        } else {
+            Ok(Some(HaltReason::CosimDivergence {
+                pc: effect.pc,
+                retired,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Memory;
+    use claims::{assert_err_eq, assert_ok_eq};
+    use std::{cell::RefCell, io::Cursor, rc::Rc};
+
+    // Replies from a fixed script and keeps what it was sent.
+    struct FakeSimulator {
+        replies: Cursor<Vec<u8>>,
+        received: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for FakeSimulator {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for FakeSimulator {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.received.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // add a0, a0, x0, 1 twice, then ebreak.
+    fn machine(replies: &[u8]) -> (Machine<Vec<u8>>, Rc<RefCell<Vec<u8>>>) {
+        let mut mem = Memory::default();
+        mem.write(0, &[0b0000_0000, 0b0000_0010, 0b0000_0010, 0b0010_0010]);
+        mem.write(4, &[0b0000_0000, 0b0000_0010, 0b0000_0010, 0b0010_0010]);
+        mem.write(8, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        let mut machine = Machine {
+            mem,
+            ..Default::default()
+        };
+        let received = Rc::new(RefCell::new(Vec::new()));
+        machine.set_cosim(
+            Cosim::new(FakeSimulator {
+                replies: Cursor::new(replies.to_vec()),
+                received: received.clone(),
+            })
+            .acknowledged(),
+        );
+        (machine, received)
+    }
+
+    #[test]
+    fn retired_instructions_are_streamed_in_the_documented_format() {
+        let (mut machine, received) = machine(&[0, 0, 0]);
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        let received = received.borrow();
+        assert_eq!(received.len(), 3 * 19 + 2 * 9);
+        assert_eq!(
+            received[..28],
+            [
+                0, 0, 0, 0, 0, 0, 0, 0, // retired
+                0, 0, 0, 0, // pc
+                0, 0, 0, 4, // next pc
+                1, 1, 0, 0, 0, 0, 0, 0, 0, 1, // a0: 0 -> 1
+                0, 0, // no memory writes
+            ]
+        );
+    }
+
+    #[test]
+    fn a_negative_acknowledgement_halts_the_machine() {
+        let (mut machine, _) = machine(&[0, 1]);
+
+        assert_ok_eq!(
+            machine.run(),
+            HaltReason::CosimDivergence { pc: 4, retired: 1 }
+        );
+    }
+
+    #[test]
+    fn a_closed_channel_is_an_error() {
+        let (mut machine, _) = machine(&[]);
+        assert_err_eq!(
+            machine.step(),
+            Error::CosimFailed("failed to fill whole buffer".to_string())
+        );
+    }
+}
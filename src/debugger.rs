@@ -0,0 +1,579 @@
+use crate::{
+    asm::assemble_file_with_symbols, expr::Expr, symbols::SymbolTable, Address, Error, Machine,
+    RegisterID, Result, StepOutcome, Word,
+};
+use std::{collections::BTreeMap, io::Write};
+
+// Drives a machine through textual debugger commands, so that sessions can
+// be scripted and replayed. Every address, value and count a command takes
+// is an expression over machine state as understood by the expr module, so
+// it can name registers and symbols, with offsets such as `table + 8`, as
+// well as numbers. Commands taking two are split at the last space that
+// leaves an expression either side. Commands:
+//
+//   break <expr> [if <cond>]  stop before executing the instruction there
+//   delete <expr>             remove a breakpoint
+//   watch <expr|symbol>       stop after the watched memory changes
+//   display <expr>            show the expression whenever execution stops
+//   continue                  run until a breakpoint, watchpoint or halt
+//   step [count]              execute count instructions (default 1)
+//   until <expr>              run until execution reaches the address
+//   finish                    run until the current function returns
+//   patch <expr> <word>       overwrite the word at an address
+//   unpatch <expr>            undo the latest patch at an address
+//   skip                      move past the next instruction unexecuted
+//   freeze <reg|expr> <value> pin a register or memory word to a value
+//   unfreeze <reg|expr>       release a frozen register or memory word
+//   print <expr>              show the value of an expression
+//   dump <expr> <bytes>       hexdump memory, naming the regions it is in
+//   expect <expr> <value>     fail unless the expression equals value
+//   reload <source> [keep-data]
+//                             reassemble the program and swap it in, moving
+//                             breakpoints to the symbols they were in
+pub struct Debugger<W: Write> {
+    machine: Machine<W>,
+    breakpoints: BTreeMap<Address, Option<Expr>>,
+    displays: Vec<(String, Expr)>,
+}
+
+impl<W: Write> Debugger<W> {
+    pub fn new(machine: Machine<W>) -> Self {
+        Self {
+            machine,
+            breakpoints: BTreeMap::new(),
+            displays: Vec::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine<W> {
+        &self.machine
+    }
+
+    pub fn into_machine(self) -> Machine<W> {
+        self.machine
+    }
+
+    // Executes the script line by line, writing each command's output to
+    // `out`, and stops at the first failing command.
+    pub fn run_script(&mut self, script: &str, out: &mut impl Write) -> Result<()> {
+        for (index, line) in script.lines().enumerate() {
+            let script_error = |error| Error::Script {
+                line: index + 1,
+                error: Box::new(error),
+            };
+            let output = self.execute(line).map_err(script_error)?;
+            if !output.is_empty() {
+                writeln!(out, "{output}")
+                    .map_err(|err| script_error(Error::CommandInvalid(err.to_string())))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn execute(&mut self, line: &str) -> Result<String> {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+        match (command, args) {
+            ("", _) => Ok(String::new()),
+            ("break" | "b", args) if !args.is_empty() => {
+                let (location, condition) = match args.split_once(" if ") {
+                    Some((location, condition)) => (location, Some(condition.parse()?)),
+                    None => (args, None),
+                };
+                let addr = self.eval(location)?;
+                self.breakpoints.insert(addr, condition);
+                Ok(format!("breakpoint set at {addr:#010x}"))
+            }
+            ("delete" | "d", args) if !args.is_empty() => {
+                let addr = self.eval(args)?;
+                self.breakpoints.remove(&addr);
+                Ok(String::new())
+            }
+            ("watch" | "w", args) if !args.is_empty() => {
+                if self.machine.symbols.get(args).is_some() {
+                    self.machine.watch_symbol(args)?;
+                } else {
+                    let addr = self.eval(args)?;
+                    self.machine.watch(addr, 4);
+                }
+                Ok(format!("watching {args}"))
+            }
+            ("display", args) if !args.is_empty() => {
+                self.displays.push((args.to_string(), args.parse()?));
+                self.print(args)
+            }
+            ("continue" | "c", "") => self.resume(u64::MAX),
+            ("step" | "s", "") => self.resume(1),
+            ("step" | "s", count) => {
+                let count = self.eval(count)?;
+                self.resume(count as u64)
+            }
+            ("until" | "u", args) if !args.is_empty() => {
+                let addr = self.eval(args)?;
+                let outcome = self.machine.run_to(addr)?;
+                self.describe(outcome)
+            }
+            ("finish", "") => {
+                let outcome = self.machine.finish()?;
+                self.describe(outcome)
+            }
+            ("patch", args) => {
+                let Some((addr, word)) = operands(args) else {
+                    return Err(Error::CommandInvalid(line.to_string()));
+                };
+                let (addr, word) = (self.eval(addr)?, self.eval(word)?);
+                self.machine.patch(addr, &word.to_be_bytes());
+                Ok(format!("patched {addr:#010x}"))
+            }
+            ("unpatch", args) if !args.is_empty() => {
+                let addr = self.eval(args)?;
+                if self.machine.unpatch(addr) {
+                    Ok(format!("restored {addr:#010x}"))
+                } else {
+                    Err(Error::CommandInvalid(format!("no patch at {addr:#010x}")))
+                }
+            }
+            ("skip", "") => {
+                let word = self.machine.skip_next();
+                Ok(format!("skipped {word:#010x}"))
+            }
+            ("freeze", args) => {
+                let Some((target, value)) = operands(args) else {
+                    return Err(Error::CommandInvalid(line.to_string()));
+                };
+                let (target, value) = (target.trim(), self.eval(value)?);
+                if let Ok(reg) = target.parse::<RegisterID>() {
+                    self.machine.freeze_register(reg, value);
+                } else {
+                    let addr = self.eval(target)?;
+                    self.machine.freeze_memory(addr, &value.to_be_bytes());
+                }
+                Ok(format!("froze {target} = {value:#010x}"))
+            }
+            ("unfreeze", args) if !args.is_empty() => {
+                if let Ok(reg) = args.parse::<RegisterID>() {
+                    self.machine.unfreeze_register(reg);
+                } else {
+                    let addr = self.eval(args)?;
+                    self.machine.unfreeze_memory(addr, 4);
+                }
+                Ok(String::new())
+            }
+            ("print" | "p", args) if !args.is_empty() => self.print(args),
+            ("dump", args) => {
+                let Some((addr, len)) = operands(args) else {
+                    return Err(Error::CommandInvalid(line.to_string()));
+                };
+                let (addr, len) = (self.eval(addr.trim())?, self.eval(len)?);
+                Ok(self
+                    .machine
+                    .hexdump(addr, len as usize)
+                    .trim_end()
+                    .to_string())
+            }
+            ("expect", args) => {
+                let Some((target, want)) = operands(args) else {
+                    return Err(Error::CommandInvalid(line.to_string()));
+                };
+                let (target, got, want) = (target.trim(), self.eval(target)?, self.eval(want)?);
+                if got == want {
+                    Ok(String::new())
+                } else {
+                    Err(Error::ExpectationFailed(format!(
+                        "{target} = {got:#x}, expected {want:#x}"
+                    )))
+                }
+            }
+            ("reload", args) if !args.is_empty() => {
+                let (path, keep_data) = match args.rsplit_once(char::is_whitespace) {
+                    Some((path, "keep-data")) => (path.trim(), true),
+                    _ => (args, false),
+                };
+                let (image, symbols) = assemble_file_with_symbols(path)?;
+                Ok(self.reload(path, &image, symbols, keep_data))
+            }
+            (
+                "break" | "b" | "delete" | "d" | "watch" | "w" | "display" | "continue" | "c"
+                | "until" | "u" | "finish" | "unpatch" | "skip" | "unfreeze" | "print" | "p"
+                | "reload",
+                _,
+            ) => Err(Error::CommandInvalid(line.to_string())),
+            _ => Err(Error::CommandUnknown(command.to_string())),
+        }
+    }
+
+    // Swaps in the rebuilt program from `path`, keeping each breakpoint's
+    // condition as it moves, and reports where the program now stands and
+    // the breakpoints whose symbol it no longer has.
+    fn reload(
+        &mut self,
+        path: &str,
+        image: &[u8],
+        symbols: SymbolTable,
+        keep_data: bool,
+    ) -> String {
+        let old = self.machine.symbols.clone();
+        let mut output = String::new();
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        for (addr, condition) in breakpoints {
+            match old.relocate(addr, &symbols) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr, condition);
+                }
+                None => output.push_str(&format!(
+                    "\nbreakpoint at {addr:#010x} dropped: {} is gone",
+                    old.label(addr).unwrap_or_default()
+                )),
+            }
+        }
+        self.machine.reload_program(image, symbols, keep_data);
+        format!("reloaded {path} at {:#010x}{output}", self.machine.pc)
+    }
+
+    fn eval(&self, text: &str) -> Result<Word> {
+        text.parse::<Expr>()?.eval(&self.machine)
+    }
+
+    fn print(&self, text: &str) -> Result<String> {
+        let value = self.eval(text)?;
+        Ok(format!("{text} = {value:#010x} ({value})"))
+    }
+
+    // Executes up to `count` instructions, stopping early before a
+    // breakpoint (other than one at the starting pc) whose condition holds,
+    // or when the machine halts. Returns a description of why execution
+    // stopped, followed by any displayed expressions.
+    fn resume(&mut self, count: u64) -> Result<String> {
+        let mut stop = None;
+        for executed in 0..count {
+            if executed > 0 && self.breakpoint_hit()? {
+                stop = Some(format!("breakpoint at {:#010x}", self.machine.pc));
+                break;
+            }
+            if let (_, StepOutcome::Halted(reason)) = self.machine.step()? {
+                stop = Some(reason.to_string());
+                break;
+            }
+        }
+        let stop = stop.unwrap_or_else(|| format!("stopped at {:#010x}", self.machine.pc));
+        self.with_displays(stop)
+    }
+
+    fn describe(&self, outcome: StepOutcome) -> Result<String> {
+        let stop = match outcome {
+            StepOutcome::Continue => format!("stopped at {:#010x}", self.machine.pc),
+            StepOutcome::Halted(reason) => reason.to_string(),
+        };
+        self.with_displays(stop)
+    }
+
+    fn with_displays(&self, mut output: String) -> Result<String> {
+        for (text, expr) in &self.displays {
+            let value = expr.eval(&self.machine)?;
+            output.push_str(&format!("\n{text} = {value:#010x} ({value})"));
+        }
+        Ok(output)
+    }
+
+    fn breakpoint_hit(&self) -> Result<bool> {
+        match self.breakpoints.get(&self.machine.pc) {
+            None => Ok(false),
+            Some(None) => Ok(true),
+            Some(Some(condition)) => Ok(condition.eval(&self.machine)? != 0),
+        }
+    }
+}
+
+// Splits the arguments of a command taking two expressions, such as
+// `patch table + 4 a1 + 1`, at the last space leaving an expression either
+// side, or failing that at the last space.
+fn operands(args: &str) -> Option<(&str, &str)> {
+    let parses = |text: &str| text.parse::<Expr>().is_ok();
+    args.char_indices()
+        .rev()
+        .filter(|(_, c)| c.is_whitespace())
+        .map(|(i, _)| (args[..i].trim(), args[i..].trim()))
+        .find(|&(first, second)| parses(first) && parses(second))
+        .or_else(|| args.rsplit_once(char::is_whitespace))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, Registers};
+    use claims::{assert_err_eq, assert_ok, assert_ok_eq};
+
+    // Three Adds that each bump a0 by 1, followed by an EBreak.
+    fn debugger() -> Debugger<Vec<u8>> {
+        let mut mem = Memory::default();
+        for i in 0..3 {
+            mem.write(i * 4, &[0b0000_0000, 0b0000_0010, 0b0000_0010, 0b0010_0010]);
+        }
+        mem.write(12, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        Debugger::new(Machine {
+            mem,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn scripts_drive_the_machine_and_report_output() {
+        let mut debugger = debugger();
+        let script = "\
+            # stop before the last add\n\
+            break 0x8\n\
+            continue\n\
+            print a0\n\
+            expect a0 2\n\
+            expect pc 8\n\
+            step\n\
+            continue\n\
+            expect a0 3\n";
+        let mut out = Vec::new();
+
+        assert_ok!(debugger.run_script(script, &mut out));
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "breakpoint set at 0x00000008\n\
+             breakpoint at 0x00000008\n\
+             a0 = 0x00000002 (2)\n\
+             stopped at 0x0000000c\n\
+             halted at breakpoint\n"
+        );
+    }
+
+    #[test]
+    fn commands_take_symbols_and_registers_wherever_they_take_a_value() {
+        let mut debugger = debugger();
+        debugger.machine.set_symbols(SymbolTable::from([
+            ("main", 0, 0),
+            ("last", 8, 4),
+            ("slot", 0x100, 8),
+        ]));
+        let script = "\
+            break last\n\
+            patch slot + 4 a0 + 5\n\
+            expect mem[slot+4] a0 + 5\n\
+            continue\n\
+            expect a0 last - 6\n\
+            dump slot + 4 a0 + 2\n\
+            until last+4\n\
+            expect pc main + 12\n";
+        let mut out = Vec::new();
+
+        assert_ok!(debugger.run_script(script, &mut out));
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "breakpoint set at 0x00000008\n\
+             patched 0x00000104\n\
+             breakpoint at 0x00000008\n\
+             0x00000104: 00 00 00 05\n\
+             stopped at 0x0000000c\n"
+        );
+    }
+
+    #[test]
+    fn a_failed_expectation_stops_the_script_with_its_line_number() {
+        let mut debugger = debugger();
+        let script = "step 2\nexpect a0 3\nstep\n";
+
+        assert_err_eq!(
+            debugger.run_script(script, &mut Vec::new()),
+            Error::Script {
+                line: 2,
+                error: Box::new(Error::ExpectationFailed(
+                    "a0 = 0x2, expected 0x3".to_string()
+                )),
+            }
+        );
+        assert_eq!(debugger.machine().pc, 8);
+    }
+
+    #[test]
+    fn conditional_breakpoints_only_stop_when_the_condition_holds() {
+        let mut debugger = debugger();
+        let script = "\
+            break 0x4 if a0 == 5\n\
+            break 0x8 if a0 == 2\n\
+            display a0 * 10\n\
+            continue\n";
+        let mut out = Vec::new();
+
+        assert_ok!(debugger.run_script(script, &mut out));
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "breakpoint set at 0x00000004\n\
+             breakpoint set at 0x00000008\n\
+             a0 * 10 = 0x00000000 (0)\n\
+             breakpoint at 0x00000008\n\
+             a0 * 10 = 0x00000014 (20)\n"
+        );
+    }
+
+    #[test]
+    fn expressions_can_be_printed_and_expected() {
+        let mut debugger = debugger();
+        assert_ok!(debugger.execute("step 2"));
+        assert_ok_eq!(
+            debugger.execute("print a0 + pc"),
+            "a0 + pc = 0x0000000a (10)".to_string()
+        );
+        assert_ok_eq!(debugger.execute("expect a0 * 2 4"), String::new());
+    }
+
+    #[test]
+    fn until_runs_to_the_given_address() {
+        let mut debugger = debugger();
+        assert_ok_eq!(
+            debugger.execute("until 4 + 4"),
+            "stopped at 0x00000008".to_string()
+        );
+        assert_err_eq!(debugger.execute("finish"), Error::NoCallFrame);
+    }
+
+    #[test]
+    fn code_can_be_patched_and_skipped() {
+        let mut debugger = debugger();
+        let script = "\
+            patch 0x4 0x18\n\
+            skip\n\
+            continue\n\
+            expect a0 0\n\
+            unpatch 4\n\
+            expect mem[4] 0x00020222\n";
+        let mut out = Vec::new();
+
+        assert_ok!(debugger.run_script(script, &mut out));
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "patched 0x00000004\n\
+             skipped 0x00020222\n\
+             halted at breakpoint\n\
+             restored 0x00000004\n"
+        );
+    }
+
+    #[test]
+    fn frozen_registers_ignore_guest_writes() {
+        let mut debugger = debugger();
+        let script = "\
+            freeze a0 7\n\
+            step 2\n\
+            expect a0 7\n\
+            unfreeze a0\n\
+            step\n\
+            expect a0 8\n";
+        let mut out = Vec::new();
+
+        assert_ok!(debugger.run_script(script, &mut out));
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "froze a0 = 0x00000007\n\
+             stopped at 0x00000008\n\
+             stopped at 0x0000000c\n"
+        );
+    }
+
+    #[test]
+    fn memory_can_be_printed() {
+        let mut debugger = debugger();
+        assert_ok_eq!(
+            debugger.execute("print mem[0xc]"),
+            "mem[0xc] = 0x00000018 (24)".to_string()
+        );
+    }
+
+    #[test]
+    fn memory_can_be_dumped() {
+        let mut debugger = debugger();
+        debugger.machine.name_region("code", 0, 0x20);
+        assert_ok_eq!(
+            debugger.execute("dump 0x8 + 4 4"),
+            "0x0000000c (code+0xc): 00 00 00 18".to_string()
+        );
+        assert_err_eq!(
+            debugger.execute("dump 0x8"),
+            Error::CommandInvalid("dump 0x8".to_string())
+        );
+    }
+
+    #[test]
+    fn reloading_moves_breakpoints_with_their_symbols() {
+        let path = std::env::temp_dir().join(format!("rmachine-reload-{}.s", std::process::id()));
+        std::fs::write(
+            &path,
+            "main:   li a0, 1
+             double: add a0, a0, a0
+                     ebreak
+             spare:  ebreak",
+        )
+        .unwrap();
+        let (image, symbols) = assemble_file_with_symbols(&path).unwrap();
+        let mut debugger: Debugger<Vec<u8>> = Debugger::new(
+            Machine::builder()
+                .program(&image)
+                .symbols(symbols)
+                .build()
+                .unwrap(),
+        );
+        assert_ok!(debugger.execute("break 0x4"));
+        assert_ok!(debugger.execute("break 0xc"));
+        assert_ok_eq!(debugger.execute("continue"), "breakpoint at 0x00000004");
+
+        std::fs::write(
+            &path,
+            "main:   li a0, 1
+                     li a1, 5
+             double: add a0, a0, a1
+                     ebreak",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        assert_ok_eq!(
+            debugger.execute(&format!("reload {path}")),
+            format!(
+                "reloaded {path} at 0x00000000\nbreakpoint at 0x0000000c dropped: spare is gone"
+            )
+        );
+        assert_ok_eq!(debugger.execute("continue"), "breakpoint at 0x00000008");
+        assert_ok_eq!(debugger.execute("continue"), "halted at breakpoint");
+        assert_ok_eq!(debugger.execute("print a0"), "a0 = 0x00000006 (6)");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_commands_return_errors() {
+        struct TestCase {
+            line: &'static str,
+            want: Error,
+        }
+        let cases = [
+            TestCase {
+                line: "frobnicate",
+                want: Error::CommandUnknown("frobnicate".to_string()),
+            },
+            TestCase {
+                line: "break",
+                want: Error::CommandInvalid("break".to_string()),
+            },
+            TestCase {
+                line: "break 0xz",
+                want: Error::ExprInvalid("invalid number \"0xz\"".to_string()),
+            },
+            TestCase {
+                line: "print q7",
+                want: Error::SymbolUnknown("q7".to_string()),
+            },
+        ];
+        let mut debugger = debugger();
+        for case in cases {
+            assert_err_eq!(debugger.execute(case.line), case.want);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use crate::{irq::DeviceBus, observer::Event, Address, Machine, Memory, Result, StepOutcome};
+use std::{fmt, io::Write};
+
+// The view of the machine a device has while emulated time passes.
+pub trait Bus {
+    fn read(&self, addr: Address, len: usize) -> Vec<u8>;
+    fn write(&mut self, addr: Address, data: &[u8]);
+    // Raises interrupt `line` for the guest, as of cycle `at`. Buses with
+    // no guest to interrupt ignore it.
+    fn raise_irq(&mut self, line: u8, at: u64) {}
+}
+
+impl Bus for Memory {
+    fn read(&self, addr: Address, len: usize) -> Vec<u8> {
+        Memory::read(self, addr, len)
+    }
+
+    fn write(&mut self, addr: Address, data: &[u8]) {
+        Memory::write(self, addr, data);
+    }
+}
+
+// A peripheral modelled outside the instruction set. Devices are told the
+// machine's time, in cycles, whenever they are brought up to date.
+pub trait Device {
+    fn advance(&mut self, now: u64, bus: &mut dyn Bus);
+
+    // The cycle at which the device next does something of its own accord,
+    // such as raising an interrupt, or None if it only responds to the
+    // guest. A guest waiting for an interrupt sleeps until then.
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+}
+
+// When devices are brought up to date: after every instruction, or only
+// when the embedder asks, so that it can keep an external model in step
+// with the machine between bursts of execution.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum DeviceSync {
+    #[default]
+    EveryInstruction,
+    Manual,
+}
+
+// The order devices advance in when they are brought up to date together.
+// Attached order is the one to rely on; shuffling perturbs it, with a new
+// order each time drawn from `seed`, so that code depending on devices
+// happening to advance in a particular order can be found, and the
+// failing order reproduced by running again with the same seed.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum DeviceOrder {
+    #[default]
+    Attached,
+    Shuffled {
+        seed: u64,
+    },
+}
+
+#[derive(Default)]
+pub struct Devices {
+    attached: Vec<Box<dyn Device>>,
+    sync: DeviceSync,
+    order: DeviceOrder,
+    // The state of the shuffle's generator.
+    rng: u64,
+}
+
+impl fmt::Debug for Devices {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Devices")
+            .field("count", &self.attached.len())
+            .field("sync", &self.sync)
+            .field("order", &self.order)
+            .finish()
+    }
+}
+
+impl Devices {
+    // splitmix64, which is enough to pick orders and needs no dependency.
+    fn random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // The indices of the devices in the order they advance in next.
+    fn schedule(&mut self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.attached.len()).collect();
+        if let DeviceOrder::Shuffled { .. } = self.order {
+            for i in (1..order.len()).rev() {
+                let j = (self.random() % (i as u64 + 1)) as usize;
+                order.swap(i, j);
+            }
+        }
+        order
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn attach_device(&mut self, device: impl Device + 'static) {
+        self.devices.attached.push(Box::new(device));
+    }
+
+    pub fn set_device_sync(&mut self, sync: DeviceSync) {
+        self.devices.sync = sync;
+    }
+
+    // Sets the order devices advance in, starting a shuffle afresh from its
+    // seed.
+    pub fn set_device_order(&mut self, order: DeviceOrder) {
+        self.devices.order = order;
+        if let DeviceOrder::Shuffled { seed } = order {
+            self.devices.rng = seed;
+        }
+    }
+
+    // Emulated time in cycles: one per retired instruction plus any time
+    // added with advance_time.
+    pub fn time(&self) -> u64 {
+        self.cycles
+    }
+
+    // Lets `cycles` of emulated time pass without executing instructions,
+    // as while the guest waits on the outside world, and brings the devices
+    // up to date.
+    pub fn advance_time(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        self.sync_devices();
+    }
+
+    // Brings every device up to the current time, one after another in the
+    // device order. Each sees what the ones before it did.
+    pub fn sync_devices(&mut self) {
+        let mut bus = DeviceBus {
+            mem: &mut self.mem,
+            irqs: &mut self.irqs,
+        };
+        for index in self.devices.schedule() {
+            self.devices.attached[index].advance(self.cycles, &mut bus);
+            self.observers.notify(&Event::DeviceAdvanced {
+                device: index,
+                cycle: self.cycles,
+            });
+        }
+    }
+
+    // Executes up to `cycles` worth of instructions, stopping early if the
+    // machine halts.
+    pub fn run_for(&mut self, cycles: u64) -> Result<StepOutcome> {
+        let deadline = self.cycles.saturating_add(cycles);
+        while self.cycles < deadline {
+            if let (_, StepOutcome::Halted(reason)) = self.step()? {
+                return Ok(StepOutcome::Halted(reason));
+            }
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    // The cycle of the soonest event any device has scheduled.
+    pub(crate) fn next_device_event(&self) -> Option<u64> {
+        self.devices
+            .attached
+            .iter()
+            .filter_map(|device| device.next_event())
+            .min()
+    }
+
+    pub(crate) fn tick_devices(&mut self) {
+        if self.devices.sync == DeviceSync::EveryInstruction && !self.devices.attached.is_empty() {
+            self.sync_devices();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{observer::Observer, HaltReason};
+    use claims::assert_ok_eq;
+    use std::{cell::RefCell, rc::Rc};
+
+    // Logs its name each time it advances.
+    struct Named(usize, Rc<RefCell<Vec<usize>>>);
+
+    impl Device for Named {
+        fn advance(&mut self, _: u64, _: &mut dyn Bus) {
+            self.1.borrow_mut().push(self.0);
+        }
+    }
+
+    // The devices' names in the order they advanced, ten times over.
+    fn advance_order(order: DeviceOrder) -> Vec<usize> {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut machine = machine();
+        for name in 0..3 {
+            machine.attach_device(Named(name, log.clone()));
+        }
+        machine.set_device_order(order);
+        for _ in 0..10 {
+            machine.advance_time(1);
+        }
+        log.take()
+    }
+
+    #[derive(Default)]
+    struct Events(Vec<Event>);
+
+    impl Observer for Events {
+        fn observe(&mut self, event: &Event) {
+            if !matches!(event, Event::Retired { .. } | Event::Fetched { .. }) {
+                self.0.push(event.clone());
+            }
+        }
+    }
+
+    // Publishes the time it was last brought up to date at 0x100.
+    #[derive(Default)]
+    struct Clock(Rc<RefCell<Vec<u64>>>);
+
+    impl Device for Clock {
+        fn advance(&mut self, now: u64, bus: &mut dyn Bus) {
+            self.0.borrow_mut().push(now);
+            bus.write(0x100, &now.to_be_bytes());
+        }
+    }
+
+    // Three Adds followed by an EBreak.
+    fn machine() -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        for i in 0..3 {
+            mem.write(i * 4, &[0b0000_0000, 0b0000_0010, 0b0000_0010, 0b0010_0010]);
+        }
+        mem.write(12, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        Machine {
+            mem,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn devices_advance_after_every_instruction_by_default() {
+        let ticks = Rc::new(RefCell::new(Vec::new()));
+        let mut machine = machine();
+        machine.attach_device(Clock(ticks.clone()));
+
+        assert_ok_eq!(machine.run_for(2), StepOutcome::Continue);
+
+        assert_eq!(*ticks.borrow(), vec![1, 2]);
+        assert_eq!(machine.mem.read(0x100, 8), 2u64.to_be_bytes());
+    }
+
+    #[test]
+    fn devices_advance_in_attached_order_after_each_instruction() {
+        let events = Rc::new(RefCell::new(Events::default()));
+        let mut machine = machine();
+        machine.attach_device(Clock::default());
+        machine.attach_device(Clock::default());
+        machine.add_observer(events.clone());
+
+        assert_ok_eq!(machine.run_for(2), StepOutcome::Continue);
+
+        assert_eq!(
+            events.borrow().0,
+            [(0, 1), (1, 1), (0, 2), (1, 2)]
+                .map(|(device, cycle)| Event::DeviceAdvanced { device, cycle })
+        );
+    }
+
+    #[test]
+    fn shuffled_device_orders_repeat_with_the_seed() {
+        let attached = advance_order(DeviceOrder::Attached);
+        assert_eq!(attached, [0, 1, 2].repeat(10));
+
+        let shuffled = advance_order(DeviceOrder::Shuffled { seed: 7 });
+        assert_eq!(shuffled, advance_order(DeviceOrder::Shuffled { seed: 7 }));
+        assert_ne!(shuffled, attached);
+        assert_ne!(shuffled, advance_order(DeviceOrder::Shuffled { seed: 8 }));
+        // Every device still advances once each time.
+        for round in shuffled.chunks(3) {
+            let mut round = round.to_vec();
+            round.sort_unstable();
+            assert_eq!(round, [0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn manually_synced_devices_only_advance_when_asked() {
+        let ticks = Rc::new(RefCell::new(Vec::new()));
+        let mut machine = machine();
+        machine.attach_device(Clock(ticks.clone()));
+        machine.set_device_sync(DeviceSync::Manual);
+
+        assert_ok_eq!(machine.run_for(2), StepOutcome::Continue);
+        assert!(ticks.borrow().is_empty());
+
+        machine.advance_time(100);
+        assert_eq!(*ticks.borrow(), vec![102]);
+        assert_eq!(machine.time(), 102);
+
+        assert_ok_eq!(
+            machine.run_for(10),
+            StepOutcome::Halted(HaltReason::Breakpoint)
+        );
+        assert_eq!(machine.time(), 104);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{symbols::SymbolTable, Address, Instruction, Opcode, RegisterID, Word};
+
+pub fn mnemonic(opcode: &Opcode) -> &'static str {
+    match opcode {
+        Opcode::LoadImmediate => "li",
+        Opcode::Add => "add",
+        Opcode::Sub => "sub",
+        Opcode::Mul => "mul",
+        Opcode::Div => "div",
+        Opcode::Rem => "rem",
+        Opcode::Auipc => "auipc",
+        Opcode::Lui => "lui",
+        Opcode::And => "and",
+        Opcode::Or => "or",
+        Opcode::Xor => "xor",
+        Opcode::Sll => "sll",
+        Opcode::Srl => "srl",
+        Opcode::Sra => "sra",
+        Opcode::Slt => "slt",
+        Opcode::Sltu => "sltu",
+        Opcode::Jal => "jal",
+        Opcode::Jalr => "jalr",
+        Opcode::Beq => "beq",
+        Opcode::Bne => "bne",
+        Opcode::Blt => "blt",
+        Opcode::Bge => "bge",
+        Opcode::Lb => "lb",
+        Opcode::Lh => "lh",
+        Opcode::Lw => "lw",
+        Opcode::Sb => "sb",
+        Opcode::Sh => "sh",
+        Opcode::Sw => "sw",
+        Opcode::ECall => "ecall",
+        Opcode::EBreak => "ebreak",
+        Opcode::Mret => "mret",
+    }
+}
+
+// Formats instructions as assembly text. Addresses appearing in branch and
+// jump targets or memory operands are shown as `label+offset` when a symbol
+// table is available.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Disassembler<'a> {
+    symbols: Option<&'a SymbolTable>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_symbols(symbols: &'a SymbolTable) -> Self {
+        Self {
+            symbols: Some(symbols),
+        }
+    }
+
+    pub fn address(&self, addr: Address) -> String {
+        self.symbols
+            .and_then(|symbols| symbols.label(addr))
+            .unwrap_or_else(|| format!("{addr:#x}"))
+    }
+
+    // `offset(rs1)`, or just the address when the base is x0.
+    fn memory_operand(&self, instruction: &Instruction) -> String {
+        match instruction.rs1 {
+            RegisterID::X0 => self.address(instruction.offset() as Address),
+            base => format!("{}({base})", instruction.offset()),
+        }
+    }
+
+    // `pc` is the address `instruction` was fetched from, which pc-relative
+    // operands are resolved against.
+    pub fn format(&self, instruction: &Instruction, pc: Address) -> String {
+        let name = mnemonic(&instruction.opcode);
+        match instruction.opcode {
+            Opcode::LoadImmediate => format!("{name} {}, {}", instruction.rd, instruction.imm),
+            Opcode::Auipc | Opcode::Lui => {
+                format!("{name} {}, {}", instruction.rd, instruction.offset())
+            }
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Rem
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Xor
+            | Opcode::Sll
+            | Opcode::Srl
+            | Opcode::Sra
+            | Opcode::Slt
+            | Opcode::Sltu => {
+                let registers = format!(
+                    "{name} {}, {}, {}",
+                    instruction.rd, instruction.rs1, instruction.rs2
+                );
+                match instruction.offset() {
+                    0 => registers,
+                    imm => format!("{registers}, {imm}"),
+                }
+            }
+            Opcode::Jal => format!(
+                "{name} {}, {}",
+                instruction.rd,
+                self.address(pc.wrapping_add_signed(instruction.offset()))
+            ),
+            Opcode::Jalr if instruction.offset() == 0 => {
+                format!("{name} {}, {}", instruction.rd, instruction.rs1)
+            }
+            Opcode::Jalr => format!(
+                "{name} {}, {}, {}",
+                instruction.rd,
+                instruction.rs1,
+                instruction.offset()
+            ),
+            Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge => format!(
+                "{name} {}, {}, {}",
+                instruction.rs1,
+                instruction.rs2,
+                self.address(pc.wrapping_add_signed(instruction.offset()))
+            ),
+            Opcode::Lb | Opcode::Lh | Opcode::Lw => format!(
+                "{name} {}, {}",
+                instruction.rd,
+                self.memory_operand(instruction)
+            ),
+            Opcode::Sb | Opcode::Sh | Opcode::Sw => format!(
+                "{name} {}, {}",
+                instruction.rs2,
+                self.memory_operand(instruction)
+            ),
+            Opcode::ECall | Opcode::EBreak | Opcode::Mret => name.to_string(),
+        }
+    }
+
+    // Formats a raw word fetched from `pc`, showing words that do not
+    // decode as data.
+    pub fn format_word(&self, word: Word, pc: Address) -> String {
+        match Instruction::try_from(word) {
+            Ok(instruction) => self.format(&instruction, pc),
+            Err(_) => format!(".word {word:#010x}"),
+        }
+    }
+
+    // A listing of the code in `bytes`, loaded at `base`: one line per word
+    // giving its address, the word itself and the instruction. Bytes left
+    // over after the last whole word are listed as data.
+    pub fn listing(&self, bytes: &[u8], base: Address) -> String {
+        let mut listing = String::new();
+        let words = bytes.chunks_exact(4);
+        let rest = words.remainder();
+        let mut addr = base;
+        for chunk in words {
+            let word = Word::from_be_bytes(chunk.try_into().unwrap());
+            let text = self.format_word(word, addr);
+            listing.push_str(&format!("{addr:#010x}: {word:08x}  {text}\n"));
+            addr = addr.wrapping_add(4);
+        }
+        if !rest.is_empty() {
+            let data: Vec<String> = rest.iter().map(|byte| format!("{byte:#04x}")).collect();
+            let hex: String = rest.iter().map(|byte| format!("{byte:02x}")).collect();
+            listing.push_str(&format!(
+                "{addr:#010x}: {hex:8}  .byte {}\n",
+                data.join(", ")
+            ));
+        }
+        listing
+    }
+}
+
+pub fn disassemble(instruction: &Instruction) -> String {
+    Disassembler::new().format(instruction, 0)
+}
+
+pub fn disassemble_word(word: Word) -> String {
+    Disassembler::new().format_word(word, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions_are_formatted_as_assembly() {
+        struct TestCase {
+            word: Word,
+            want: &'static str,
+        }
+        let cases = [
+            TestCase {
+                word: 0b0000_0000_0000_0100_0000_0000_0010_0001,
+                want: "li a0, 2",
+            },
+            TestCase {
+                word: 0b0000_0000_0000_0000_0110_0100_0010_0010,
+                want: "add a0, a1, a2",
+            },
+            TestCase {
+                word: 0b0000_0000_0000_0010_0011_1011_1100_0010,
+                want: "add ra, a12, a0, 1",
+            },
+            TestCase {
+                word: 0xfffe_0428,
+                want: "sra a0, a1, x0, -1",
+            },
+            TestCase {
+                // auipc a2, -2
+                word: 0xfffc_0066,
+                want: "auipc a2, -2",
+            },
+            TestCase {
+                word: 0x0018_01cc,
+                want: "jal ra, 0xc",
+            },
+            TestCase {
+                word: 0x0000_1c0d,
+                want: "jalr x0, ra",
+            },
+            TestCase {
+                word: 0xfff8_046d,
+                want: "jalr a2, a1, -4",
+            },
+            TestCase {
+                word: 0x0010_1e35,
+                want: "lw a0, 8(sp)",
+            },
+            TestCase {
+                word: 0xfffe_641b,
+                want: "sb a2, -1(a1)",
+            },
+            TestCase {
+                word: 0b0000_0000_0000_0000_0000_0000_0001_0111,
+                want: "ecall",
+            },
+            TestCase {
+                word: 0b0000_0000_0000_0000_0000_0000_0001_1000,
+                want: "ebreak",
+            },
+        ];
+        for case in cases {
+            let instruction = Instruction::try_from(case.word).unwrap();
+            assert_eq!(disassemble(&instruction), case.want);
+        }
+    }
+
+    #[test]
+    fn listings_show_each_word_and_leftover_bytes_as_data() {
+        let bytes = [
+            0x00, 0x04, 0x00, 0x21, // li a0, 2
+            0x00, 0x00, 0x00, 0x00, // not an instruction
+            0x00, 0x00, 0x00, 0x18, // ebreak
+            0x68, 0x69,
+        ];
+
+        assert_eq!(
+            Disassembler::new().listing(&bytes, 0x100),
+            "0x00000100: 00040021  li a0, 2\n\
+             0x00000104: 00000000  .word 0x00000000\n\
+             0x00000108: 00000018  ebreak\n\
+             0x0000010c: 6869      .byte 0x68, 0x69\n"
+        );
+    }
+
+    #[test]
+    fn addresses_are_annotated_with_symbols_when_available() {
+        let symbols = SymbolTable::from([("loop", 0x40, 0), ("data", 0x100, 8)]);
+        let disasm = Disassembler::with_symbols(&symbols);
+
+        assert_eq!(disasm.address(0x40), "loop");
+        assert_eq!(disasm.address(0x4c), "loop+0xc");
+        assert_eq!(disasm.address(0x104), "data+0x4");
+        assert_eq!(disasm.address(0x108), "0x108");
+        assert_eq!(Disassembler::new().address(0x40), "0x40");
+
+        // bne a1, a2, -8
+        let branch = Instruction::try_from(0xfff0_6410).unwrap();
+        assert_eq!(disasm.format(&branch, 0x48), "bne a1, a2, loop");
+        // lw a0, 0x104(x0)
+        let load = Instruction::try_from(0x0208_0035).unwrap();
+        assert_eq!(disasm.format(&load, 0), "lw a0, data+0x4");
+    }
+}
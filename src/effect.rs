@@ -0,0 +1,252 @@
+use crate::{memmap::MemoryMap, Address, Machine, RegisterID, Word};
+use std::{fmt, io::Write};
+
+// What is left for the surrounding environment to do after an instruction's
+// architectural effects have been applied.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum Control {
+    #[default]
+    Continue,
+    // Service the syscall numbered in a7.
+    Syscall,
+    Breakpoint,
+    // Return from the interrupt being handled.
+    InterruptReturn,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RegisterWrite {
+    pub reg: RegisterID,
+    pub old: Word,
+    pub new: Word,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MemoryWrite {
+    pub addr: Address,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+// The architectural changes made by one instruction, in the order they were
+// made, so that they can be displayed or undone without diffing state.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Effect {
+    pub pc: Address,
+    pub next_pc: Address,
+    pub regs: Vec<RegisterWrite>,
+    pub mem: Vec<MemoryWrite>,
+    pub control: Control,
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.describe(&MemoryMap::default()))
+    }
+}
+
+impl Effect {
+    // The changes, with the memory written placed in the regions of `map`.
+    pub fn describe(&self, map: &MemoryMap) -> String {
+        let mut changes: Vec<String> = self
+            .regs
+            .iter()
+            .map(|write| format!("{} = {:#x} (was {:#x})", write.reg, write.new, write.old))
+            .collect();
+        changes.extend(self.mem.iter().map(|write| {
+            format!(
+                "mem[{:#x}]{} = {:02x?}",
+                write.addr,
+                map.annotate(write.addr),
+                write.new
+            )
+        }));
+        if self.next_pc != self.pc.wrapping_add(4) {
+            changes.push(format!("pc = {:#x}", self.next_pc));
+        }
+        changes.join(", ")
+    }
+}
+
+// Effects are only recorded on request. `current` collects the effect of
+// the instruction being executed, including changes the machine makes
+// while servicing it, and becomes `last` once the instruction retires.
+// Unrecorded effects go back to `spare`, for the next instruction to reuse.
+#[derive(Debug, Default)]
+pub struct EffectLog {
+    enabled: bool,
+    current: Option<Effect>,
+    last: Option<Effect>,
+    spare: Effect,
+}
+
+impl EffectLog {
+    pub(crate) fn last(&self) -> Option<&Effect> {
+        self.last.as_ref()
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn record_effects(&mut self, enabled: bool) {
+        self.effects = EffectLog {
+            enabled,
+            ..Default::default()
+        };
+    }
+
+    pub(crate) fn recording_effects(&self) -> bool {
+        self.effects.enabled
+    }
+
+    // The effect of the most recently retired instruction.
+    pub fn last_effect(&self) -> Option<&Effect> {
+        self.effects.last.as_ref()
+    }
+
+    // An effect for the next instruction to fill in.
+    pub(crate) fn blank_effect(&mut self) -> Effect {
+        std::mem::take(&mut self.effects.spare)
+    }
+
+    pub(crate) fn begin_effect(&mut self, effect: Effect) {
+        if self.effects.enabled {
+            self.effects.current = Some(effect);
+        } else {
+            self.effects.spare = effect;
+        }
+    }
+
+    pub(crate) fn finish_effect(&mut self) {
+        if let Some(effect) = self.effects.current.take() {
+            self.effects.last = Some(effect);
+        }
+    }
+
+    pub(crate) fn take_last_effect(&mut self) -> Option<Effect> {
+        self.effects.last.take()
+    }
+
+    // Register and memory writes made on the guest's behalf outside of the
+    // instruction semantics go through these, so they are part of the
+    // instruction's effect.
+    pub(crate) fn set_register(&mut self, reg: RegisterID, value: Word) {
+        let old = self.regs.get(&reg);
+        self.regs.set(reg, value);
+        self.initialize_register(reg);
+        if let (Some(effect), false) = (&mut self.effects.current, reg == RegisterID::X0) {
+            effect.regs.push(RegisterWrite {
+                reg,
+                old,
+                new: value,
+            });
+        }
+    }
+
+    pub(crate) fn set_pc(&mut self, pc: Address) {
+        self.pc = pc;
+        if let Some(effect) = &mut self.effects.current {
+            effect.next_pc = pc;
+        }
+    }
+
+    pub(crate) fn write_memory(&mut self, addr: Address, data: &[u8]) {
+        if let (Some(effect), false) = (&mut self.effects.current, data.is_empty()) {
+            effect.mem.push(MemoryWrite {
+                addr,
+                old: self.mem.read(addr, data.len()),
+                new: data.to_vec(),
+            });
+        }
+        self.mem.write(addr, data);
+    }
+
+    // Undoes an effect, newest change first.
+    pub(crate) fn revert(&mut self, effect: &Effect) {
+        for write in effect.mem.iter().rev() {
+            self.mem.write(write.addr, &write.old);
+        }
+        for write in effect.regs.iter().rev() {
+            self.regs.set(write.reg, write.old);
+        }
+        self.pc = effect.pc;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, Registers};
+    use claims::{assert_none, assert_ok};
+
+    // add a0, a0, x0, 1; format_unsigned into 0x100; ebreak
+    fn machine() -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        mem.write(0, &[0b0000_0000, 0b0000_0010, 0b0000_0010, 0b0010_0010]);
+        mem.write(4, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111]);
+        mem.write(8, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        Machine {
+            mem,
+            regs: Registers::from([
+                (RegisterID::A0, 41),
+                (RegisterID::A1, 0x100),
+                (RegisterID::A2, 4),
+                (RegisterID::A7, 257),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn effects_include_changes_made_by_syscalls() {
+        let mut machine = machine();
+        machine.record_effects(true);
+
+        assert_ok!(machine.step());
+        assert_eq!(
+            machine.last_effect().map(ToString::to_string),
+            Some("a0 = 0x2a (was 0x29)".to_string())
+        );
+
+        assert_ok!(machine.step());
+        assert_eq!(
+            machine.last_effect(),
+            Some(&Effect {
+                pc: 4,
+                next_pc: 8,
+                regs: vec![RegisterWrite {
+                    reg: RegisterID::A0,
+                    old: 42,
+                    new: 2,
+                }],
+                mem: vec![MemoryWrite {
+                    addr: 0x100,
+                    old: vec![0, 0],
+                    new: b"42".to_vec(),
+                }],
+                control: Control::Syscall,
+            })
+        );
+    }
+
+    #[test]
+    fn effects_are_not_recorded_by_default() {
+        let mut machine = machine();
+        assert_ok!(machine.step());
+        assert_none!(machine.last_effect());
+    }
+
+    #[test]
+    fn step_back_undoes_the_last_effect_without_checkpoints() {
+        let mut machine = machine();
+        machine.record_effects(true);
+        assert_ok!(machine.step());
+        assert_ok!(machine.step());
+
+        assert_ok!(machine.step_back());
+
+        assert_eq!(machine.pc, 4);
+        assert_eq!(machine.retired, 1);
+        assert_eq!(machine.regs.get(&RegisterID::A0), 42);
+        assert_eq!(machine.mem.read(0x100, 2), vec![0, 0]);
+    }
+}
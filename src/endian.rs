@@ -0,0 +1,112 @@
+use crate::{Machine, Word};
+use std::io::Write;
+
+// The order of the bytes of instructions and data in memory. The machine
+// is big-endian unless told otherwise; little-endian mode lets it run
+// binaries from standard RISC-V toolchains, which are little-endian.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Endian {
+    #[default]
+    Big,
+    Little,
+}
+
+impl Endian {
+    // Puts big-endian bytes into this order, or this order back into
+    // big-endian, the two being the same swap.
+    pub fn arrange(self, bytes: &mut [u8]) {
+        if self == Endian::Little {
+            bytes.reverse();
+        }
+    }
+
+    // The word stored in memory as `bytes`.
+    pub fn word(self, bytes: [u8; 4]) -> Word {
+        match self {
+            Endian::Big => Word::from_be_bytes(bytes),
+            Endian::Little => Word::from_le_bytes(bytes),
+        }
+    }
+
+    // How `word` is stored in memory.
+    pub fn bytes(self, word: Word) -> [u8; 4] {
+        match self {
+            Endian::Big => word.to_be_bytes(),
+            Endian::Little => word.to_le_bytes(),
+        }
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, loader::Executable, HaltReason, RegisterID};
+    use claims::assert_ok_eq;
+
+    // The image with the bytes of each word reversed, as a little-endian
+    // toolchain would have laid it out.
+    fn swapped(image: &[u8]) -> Vec<u8> {
+        image
+            .chunks(4)
+            .flat_map(|word| word.iter().rev().copied().collect::<Vec<_>>())
+            .collect()
+    }
+
+    #[test]
+    fn little_endian_machines_fetch_load_and_store_little_endian() {
+        let image = assemble(
+            "lw a2, 0x100
+             lh a3, 0x100
+             lb a4, 0x100
+             sw a2, 0x104
+             sh a2, 0x108
+             lw a5, 0(a1)
+             ebreak
+             .space 0xe4
+             .word 0x11223344",
+        )
+        .unwrap();
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&swapped(&image))
+            .endian(Endian::Little)
+            .args(vec!["prog".to_string()])
+            .build()
+            .unwrap();
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.endian(), Endian::Little);
+        assert_eq!(machine.register(RegisterID::A2), 0x1122_3344);
+        assert_eq!(machine.register(RegisterID::A3), 0x3344);
+        assert_eq!(machine.register(RegisterID::A4), 0x44);
+        assert_eq!(machine.mem.read(0x104, 4), [0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(machine.mem.read(0x108, 2), [0x44, 0x33]);
+        // The loader lays out argv in the machine's order too.
+        let argv0 = machine.register(RegisterID::A5);
+        assert_eq!(machine.mem.read(argv0, 5), b"prog\0");
+    }
+
+    #[test]
+    fn little_endian_elf_files_run_little_endian() {
+        let image = assemble("li a0, 7\nebreak").unwrap();
+        let elf = Executable {
+            endian: Endian::Little,
+            ..Executable::flat(&swapped(&image), 0x400)
+        }
+        .to_elf();
+        assert_eq!(elf[5], 1);
+        assert_eq!(elf[24..28], [0x00, 0x04, 0x00, 0x00]);
+        let executable = Executable::parse_elf(&elf).unwrap();
+
+        let mut machine: Machine<Vec<u8>> =
+            Machine::builder().executable(executable).build().unwrap();
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register(RegisterID::A0), 7);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::{
+    observer::{Event, Observer},
+    Opcode,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+// Groups of instructions that cost roughly the same to execute.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum InstructionClass {
+    Alu,
+    Branch,
+    Jump,
+    Load,
+    Store,
+    System,
+}
+
+impl InstructionClass {
+    pub fn of(opcode: &Opcode) -> Self {
+        match opcode {
+            Opcode::LoadImmediate
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Rem
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Xor
+            | Opcode::Sll
+            | Opcode::Srl
+            | Opcode::Sra
+            | Opcode::Slt
+            | Opcode::Sltu
+            | Opcode::Auipc
+            | Opcode::Lui => Self::Alu,
+            Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge => Self::Branch,
+            Opcode::Jal | Opcode::Jalr => Self::Jump,
+            Opcode::Lb | Opcode::Lh | Opcode::Lw => Self::Load,
+            Opcode::Sb | Opcode::Sh | Opcode::Sw => Self::Store,
+            Opcode::ECall | Opcode::EBreak | Opcode::Mret => Self::System,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Alu => "alu",
+            Self::Branch => "branch",
+            Self::Jump => "jump",
+            Self::Load => "load",
+            Self::Store => "store",
+            Self::System => "system",
+        }
+    }
+}
+
+// The energy each class of instruction costs, in picojoules. Every
+// instruction takes one cycle, so this is what distinguishes them. The
+// defaults are in proportion to a small in-order core, where touching
+// memory costs several times an ALU operation and a trap into the
+// environment more again; courses can substitute figures for the part
+// they are modelling.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct EnergyModel {
+    pub alu: u64,
+    pub branch: u64,
+    pub jump: u64,
+    pub load: u64,
+    pub store: u64,
+    pub system: u64,
+}
+
+impl Default for EnergyModel {
+    fn default() -> Self {
+        Self {
+            alu: 10,
+            branch: 12,
+            jump: 14,
+            load: 40,
+            store: 45,
+            system: 100,
+        }
+    }
+}
+
+impl EnergyModel {
+    pub fn cost(&self, class: InstructionClass) -> u64 {
+        match class {
+            InstructionClass::Alu => self.alu,
+            InstructionClass::Branch => self.branch,
+            InstructionClass::Jump => self.jump,
+            InstructionClass::Load => self.load,
+            InstructionClass::Store => self.store,
+            InstructionClass::System => self.system,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct FunctionEnergy {
+    pub name: String,
+    pub calls: u64,
+    // Picojoules spent in the function including its callees.
+    pub inclusive: u64,
+    // Picojoules spent in the function's own code.
+    pub exclusive: u64,
+}
+
+struct Activation {
+    name: String,
+    entered: u64,
+    in_callees: u64,
+}
+
+// Estimates the energy a run uses from the instructions it retires,
+// splitting it by instruction class and, like Profile does for time, by
+// function. Its Display is a stable format for scripts, in picojoules:
+//
+//   rmachine-energy 1
+//   total 186
+//   class alu 30
+//   class jump 56
+//   class system 100
+//   function 0x00000010 48 48
+//
+// with one `function <name> <inclusive> <exclusive>` line per function,
+// the most expensive first.
+#[derive(Default)]
+pub struct Energy {
+    model: EnergyModel,
+    total: u64,
+    classes: BTreeMap<InstructionClass, u64>,
+    functions: HashMap<String, FunctionEnergy>,
+    active: Vec<Activation>,
+}
+
+impl Energy {
+    pub fn new(model: EnergyModel) -> Self {
+        Self {
+            model,
+            ..Default::default()
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn class(&self, class: InstructionClass) -> u64 {
+        self.classes.get(&class).copied().unwrap_or_default()
+    }
+
+    // Functions still running when this is called are not included.
+    pub fn functions(&self) -> Vec<FunctionEnergy> {
+        let mut functions: Vec<FunctionEnergy> = self.functions.values().cloned().collect();
+        functions.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then(a.name.cmp(&b.name)));
+        functions
+    }
+
+    fn enter(&mut self, name: String) {
+        self.functions
+            .entry(name.clone())
+            .or_insert_with(|| FunctionEnergy {
+                name: name.clone(),
+                ..Default::default()
+            })
+            .calls += 1;
+        self.active.push(Activation {
+            name,
+            entered: self.total,
+            in_callees: 0,
+        });
+    }
+
+    fn leave(&mut self) {
+        let Some(activation) = self.active.pop() else {
+            return;
+        };
+        let spent = self.total - activation.entered;
+        let recursive = self.active.iter().any(|a| a.name == activation.name);
+        if let Some(function) = self.functions.get_mut(&activation.name) {
+            function.exclusive += spent - activation.in_callees;
+            if !recursive {
+                function.inclusive += spent;
+            }
+        }
+        if let Some(caller) = self.active.last_mut() {
+            caller.in_callees += spent;
+        }
+    }
+}
+
+impl Observer for Energy {
+    fn observe(&mut self, event: &Event) {
+        match event {
+            Event::Retired { instruction, .. } => {
+                let class = InstructionClass::of(&instruction.opcode);
+                let cost = self.model.cost(class);
+                self.total += cost;
+                *self.classes.entry(class).or_default() += cost;
+            }
+            Event::Call { callee, symbol, .. } => {
+                let name = symbol.clone().unwrap_or_else(|| format!("{callee:#010x}"));
+                self.enter(name);
+            }
+            Event::Return { .. } => self.leave(),
+            Event::DeviceAdvanced { .. } | Event::Fetched { .. } | Event::InterruptTaken { .. } => {
+            }
+        }
+    }
+}
+
+impl fmt::Display for Energy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rmachine-energy 1")?;
+        writeln!(f, "total {}", self.total)?;
+        for (class, energy) in &self.classes {
+            writeln!(f, "class {} {energy}", class.name())?;
+        }
+        for function in self.functions() {
+            writeln!(
+                f,
+                "function {} {} {}",
+                function.name, function.inclusive, function.exclusive
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, Instruction, Machine};
+    use claims::assert_ok;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn energy_is_split_by_class_and_function() {
+        // main calls double twice.
+        let program = assemble(
+            "
+            main:   li a0, 3
+                    call double
+                    call double
+                    ebreak
+            double: add a0, a0, a0
+                    ret
+            ",
+        )
+        .unwrap();
+        let energy = Rc::new(RefCell::new(Energy::default()));
+        let mut machine: Machine<Vec<u8>> = Machine::builder().program(&program).build().unwrap();
+        machine.add_observer(energy.clone());
+
+        assert_ok!(machine.run());
+
+        let energy = energy.borrow();
+        let model = EnergyModel::default();
+        assert_eq!(energy.class(InstructionClass::Alu), 3 * model.alu);
+        assert_eq!(energy.class(InstructionClass::Jump), 4 * model.jump);
+        assert_eq!(energy.class(InstructionClass::Load), 0);
+        assert_eq!(
+            energy.total(),
+            3 * model.alu + 4 * model.jump + model.system
+        );
+        // A call is charged to the callee and a return to the caller, since
+        // each switches functions as it executes.
+        let double = 2 * (model.jump + model.alu);
+        assert_eq!(
+            energy.functions(),
+            vec![FunctionEnergy {
+                name: "0x00000010".to_string(),
+                calls: 2,
+                inclusive: double,
+                exclusive: double,
+            }]
+        );
+        assert_eq!(
+            energy.to_string(),
+            format!(
+                "rmachine-energy 1\ntotal {}\nclass alu 30\nclass jump 56\nclass system 100\nfunction 0x00000010 {double} {double}\n",
+                energy.total()
+            )
+        );
+    }
+
+    #[test]
+    fn the_model_can_be_replaced() {
+        let store = Instruction::try_from(0x0000_001c).unwrap();
+        let mut energy = Energy::new(EnergyModel {
+            store: 7,
+            ..Default::default()
+        });
+
+        energy.observe(&Event::Retired {
+            pc: 0,
+            instruction: store,
+            operands: [0, 0],
+        });
+
+        assert_eq!(energy.total(), 7);
+        assert_eq!(energy.class(InstructionClass::Store), 7);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{Address, HaltReason, Machine, RegisterID, Result, StepOutcome};
+use std::{fmt, io::Write};
+
+// The return address exit handlers are called with. Returning to it ends
+// the handler.
+const EXIT_RETURN: Address = 0xffff_fffc;
+
+type HaltHook = Box<dyn FnMut(&HaltReason)>;
+
+// Host callbacks run with the final halt reason once the machine stops.
+#[derive(Default)]
+pub struct HaltHooks(Vec<HaltHook>);
+
+impl fmt::Debug for HaltHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HaltHooks({})", self.0.len())
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Registers a callback to run when a run ends, after any guest exit
+    // handlers. The callback sees why the machine halted and can decide
+    // what to do about each kind of halt.
+    pub fn on_halt(&mut self, hook: impl FnMut(&HaltReason) + 'static) {
+        self.halt_hooks.0.push(Box::new(hook));
+    }
+
+    pub(crate) fn register_exit_handler(&mut self, handler: Address) {
+        self.exit_handlers.push(handler);
+    }
+
+    // Finishes a run that halted for `reason`. A program that finished
+    // normally, at a breakpoint or by calling exit, has its exit handlers
+    // called, most recently registered
+    // first, the way atexit(3) does; one that failed does not. A handler
+    // ends by returning or at a breakpoint. If a handler halts the machine
+    // for any other reason, the remaining handlers are skipped and that
+    // becomes the halt reason.
+    pub(crate) fn finalize(&mut self, reason: HaltReason) -> Result<HaltReason> {
+        let mut reason = reason;
+        if matches!(reason, HaltReason::Breakpoint | HaltReason::Exit { .. }) {
+            while let Some(handler) = self.exit_handlers.pop() {
+                if let Some(halt) = self.call_exit_handler(handler)? {
+                    reason = halt;
+                    break;
+                }
+            }
+        }
+        self.check_image()?;
+        for hook in &mut self.halt_hooks.0 {
+            hook(&reason);
+        }
+        Ok(reason)
+    }
+
+    fn call_exit_handler(&mut self, handler: Address) -> Result<Option<HaltReason>> {
+        self.pc = handler;
+        self.regs.set(RegisterID::RA, EXIT_RETURN);
+        loop {
+            match self.step()?.1 {
+                StepOutcome::Continue if self.pc == EXIT_RETURN => return Ok(None),
+                StepOutcome::Continue => {}
+                StepOutcome::Halted(HaltReason::Breakpoint) => return Ok(None),
+                StepOutcome::Halted(reason) => return Ok(Some(reason)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, Word};
+    use claims::assert_ok_eq;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn li(rd: RegisterID, imm: Word) -> Word {
+        (imm << 17) | ((rd as Word) << 5) | 0b0_0001
+    }
+
+    fn add(rd: RegisterID, rs1: RegisterID, rs2: RegisterID, imm: Word) -> Word {
+        (imm << 17) | ((rs2 as Word) << 13) | ((rs1 as Word) << 9) | ((rd as Word) << 5) | 0b0_0010
+    }
+
+    const ECALL: Word = 0b1_0111;
+    const EBREAK: Word = 0b1_1000;
+
+    fn machine(program: &[Word]) -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        for (i, word) in program.iter().enumerate() {
+            mem.write(4 * i as Address, &word.to_be_bytes());
+        }
+        Machine {
+            mem,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exit_handlers_run_in_reverse_order_when_the_program_finishes() {
+        let mut machine = machine(&[
+            li(RegisterID::A7, 261),
+            li(RegisterID::A0, 24),
+            ECALL,
+            li(RegisterID::A0, 32),
+            ECALL,
+            EBREAK,
+            // handler at 24 doubles a5
+            add(RegisterID::A5, RegisterID::A5, RegisterID::A5, 0),
+            EBREAK,
+            // handler at 32 increments a5
+            add(RegisterID::A5, RegisterID::A5, RegisterID::X0, 1),
+            EBREAK,
+        ]);
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.regs.get(&RegisterID::A5), 2);
+    }
+
+    #[test]
+    fn exit_halts_with_its_status_after_the_exit_handlers() {
+        let mut machine = machine(&[
+            li(RegisterID::A7, 261),
+            li(RegisterID::A0, 24),
+            ECALL,
+            li(RegisterID::A7, 93),
+            li(RegisterID::A0, 3),
+            ECALL,
+            // handler at 24 increments a5
+            add(RegisterID::A5, RegisterID::A5, RegisterID::X0, 1),
+            EBREAK,
+        ]);
+
+        assert_ok_eq!(machine.run(), HaltReason::Exit { code: 3 });
+
+        assert_eq!(machine.exit_code(), Some(3));
+        assert_eq!(machine.regs.get(&RegisterID::A5), 1);
+    }
+
+    #[test]
+    fn halt_hooks_see_the_final_halt_reason() {
+        let mut machine = machine(&[EBREAK]);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        machine.on_halt(move |reason| log.borrow_mut().push(reason.clone()));
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(*seen.borrow(), vec![HaltReason::Breakpoint]);
+    }
+}
@@ -0,0 +1,478 @@
+use crate::{Address, Error, Machine, RegisterID, Result, Word};
+use std::{io::Write, str::FromStr};
+
+// Expressions over machine state, shared by the debugger's print, display
+// and conditional breakpoints:
+//
+//   a0 + a1 * 4          registers, pc and integer literals (0x.. or decimal)
+//   mem[sp + 8]          the big-endian word at an address
+//   mem[buf] as u8       narrower (u8, u16) or signed (i8, i16, i32) accesses
+//   counter              the address of a symbol from the symbol table
+//   a0 == 3 && a1 != 0   comparisons and logic evaluate to 1 or 0
+//
+// Arithmetic wraps at 32 bits.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Expr {
+    Literal(Word),
+    Pc,
+    Register(RegisterID),
+    Symbol(String),
+    Memory(Box<Expr>),
+    Cast(Box<Expr>, Width),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Width {
+    U8,
+    U16,
+    U32,
+    I8,
+    I16,
+    I32,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    // Binding power; higher binds tighter.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::BitOr => 3,
+            BinaryOp::BitXor => 4,
+            BinaryOp::BitAnd => 5,
+            BinaryOp::Eq | BinaryOp::Ne => 6,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 7,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 10,
+        }
+    }
+
+    fn apply(self, lhs: Word, rhs: Word) -> Result<Word> {
+        let value = match self {
+            BinaryOp::Or => Word::from(lhs != 0 || rhs != 0),
+            BinaryOp::And => Word::from(lhs != 0 && rhs != 0),
+            BinaryOp::BitOr => lhs | rhs,
+            BinaryOp::BitXor => lhs ^ rhs,
+            BinaryOp::BitAnd => lhs & rhs,
+            BinaryOp::Eq => Word::from(lhs == rhs),
+            BinaryOp::Ne => Word::from(lhs != rhs),
+            BinaryOp::Lt => Word::from(lhs < rhs),
+            BinaryOp::Le => Word::from(lhs <= rhs),
+            BinaryOp::Gt => Word::from(lhs > rhs),
+            BinaryOp::Ge => Word::from(lhs >= rhs),
+            BinaryOp::Shl => lhs.wrapping_shl(rhs),
+            BinaryOp::Shr => lhs.wrapping_shr(rhs),
+            BinaryOp::Add => lhs.wrapping_add(rhs),
+            BinaryOp::Sub => lhs.wrapping_sub(rhs),
+            BinaryOp::Mul => lhs.wrapping_mul(rhs),
+            BinaryOp::Div => lhs.checked_div(rhs).ok_or(Error::DivideByZero)?,
+            BinaryOp::Rem => lhs.checked_rem(rhs).ok_or(Error::DivideByZero)?,
+        };
+        Ok(value)
+    }
+}
+
+impl Width {
+    fn bytes(self) -> usize {
+        match self {
+            Width::U8 | Width::I8 => 1,
+            Width::U16 | Width::I16 => 2,
+            Width::U32 | Width::I32 => 4,
+        }
+    }
+
+    fn convert(self, value: Word) -> Word {
+        match self {
+            Width::U8 => value & 0xff,
+            Width::U16 => value & 0xffff,
+            Width::U32 | Width::I32 => value,
+            Width::I8 => value as u8 as i8 as i32 as Word,
+            Width::I16 => value as u16 as i16 as i32 as Word,
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.expression(0)?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(Error::ExprInvalid(format!("unexpected {token:?}"))),
+        }
+    }
+}
+
+impl Expr {
+    pub fn eval<W: Write>(&self, machine: &Machine<W>) -> Result<Word> {
+        match self {
+            Expr::Literal(value) => Ok(*value),
+            Expr::Pc => Ok(machine.pc),
+            Expr::Register(reg) => Ok(machine.regs.get(reg)),
+            Expr::Symbol(name) => machine
+                .symbols
+                .get(name)
+                .map(|symbol| symbol.addr)
+                .ok_or_else(|| Error::SymbolUnknown(name.clone())),
+            Expr::Memory(addr) => Ok(read(machine, addr.eval(machine)?, Width::U32)),
+            Expr::Cast(inner, width) => match inner.as_ref() {
+                Expr::Memory(addr) => Ok(read(machine, addr.eval(machine)?, *width)),
+                _ => Ok(width.convert(inner.eval(machine)?)),
+            },
+            Expr::Unary(op, inner) => {
+                let value = inner.eval(machine)?;
+                Ok(match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Not => Word::from(value == 0),
+                    UnaryOp::BitNot => !value,
+                })
+            }
+            Expr::Binary(op, lhs, rhs) => op.apply(lhs.eval(machine)?, rhs.eval(machine)?),
+        }
+    }
+}
+
+fn read<W: Write>(machine: &Machine<W>, addr: Address, width: Width) -> Word {
+    let value = machine
+        .mem
+        .read(addr, width.bytes())
+        .into_iter()
+        .fold(0, |value, byte| (value << 8) | Word::from(byte));
+    width.convert(value)
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Token {
+    Number(Word),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            tokens.push(if c.is_ascii_digit() {
+                Token::Number(parse_number(word)?)
+            } else {
+                Token::Ident(word.to_string())
+            });
+            rest = &rest[end..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(Error::ExprInvalid(format!("unexpected character {c:?}")));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+pub(crate) fn parse_number(text: &str) -> Result<Word> {
+    let parsed = if let Some(hex) = text.strip_prefix("0x") {
+        Word::from_str_radix(&hex.replace('_', ""), 16)
+    } else if let Some(bin) = text.strip_prefix("0b") {
+        Word::from_str_radix(&bin.replace('_', ""), 2)
+    } else {
+        text.replace('_', "").parse()
+    };
+    parsed.map_err(|_| Error::ExprInvalid(format!("invalid number {text:?}")))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| Error::ExprInvalid("unexpected end of expression".to_string()))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, op: &'static str) -> Result<()> {
+        match self.next()? {
+            Token::Op(found) if found == op => Ok(()),
+            token => Err(Error::ExprInvalid(format!(
+                "expected {op:?}, found {token:?}"
+            ))),
+        }
+    }
+
+    fn expression(&mut self, min_precedence: u8) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek().and_then(binary_op) {
+            if op.precedence() <= min_precedence {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expression(op.precedence())?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let op = match self.peek() {
+            Some(Token::Op("-")) => Some(UnaryOp::Neg),
+            Some(Token::Op("!")) => Some(UnaryOp::Not),
+            Some(Token::Op("~")) => Some(UnaryOp::BitNot),
+            _ => None,
+        };
+        if let Some(op) = op {
+            self.pos += 1;
+            return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+        }
+        let mut expr = self.primary()?;
+        while self.peek() == Some(&Token::Ident("as".to_string())) {
+            self.pos += 1;
+            let width = match self.next()? {
+                Token::Ident(name) => match name.as_str() {
+                    "u8" => Width::U8,
+                    "u16" => Width::U16,
+                    "u32" => Width::U32,
+                    "i8" => Width::I8,
+                    "i16" => Width::I16,
+                    "i32" => Width::I32,
+                    _ => return Err(Error::ExprInvalid(format!("unknown type {name:?}"))),
+                },
+                token => {
+                    return Err(Error::ExprInvalid(format!(
+                        "expected type, found {token:?}"
+                    )))
+                }
+            };
+            expr = Expr::Cast(Box::new(expr), width);
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next()? {
+            Token::Number(value) => Ok(Expr::Literal(value)),
+            Token::Op("(") => {
+                let expr = self.expression(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Ident(name) if name == "mem" => {
+                self.expect("[")?;
+                let addr = self.expression(0)?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(addr)))
+            }
+            Token::Ident(name) if name == "pc" => Ok(Expr::Pc),
+            Token::Ident(name) => Ok(name.parse().map_or(Expr::Symbol(name), Expr::Register)),
+            token @ Token::Op(_) => Err(Error::ExprInvalid(format!("unexpected {token:?}"))),
+        }
+    }
+}
+
+fn binary_op(token: &Token) -> Option<BinaryOp> {
+    let Token::Op(op) = token else {
+        return None;
+    };
+    let op = match *op {
+        "||" => BinaryOp::Or,
+        "&&" => BinaryOp::And,
+        "|" => BinaryOp::BitOr,
+        "^" => BinaryOp::BitXor,
+        "&" => BinaryOp::BitAnd,
+        "==" => BinaryOp::Eq,
+        "!=" => BinaryOp::Ne,
+        "<" => BinaryOp::Lt,
+        "<=" => BinaryOp::Le,
+        ">" => BinaryOp::Gt,
+        ">=" => BinaryOp::Ge,
+        "<<" => BinaryOp::Shl,
+        ">>" => BinaryOp::Shr,
+        "+" => BinaryOp::Add,
+        "-" => BinaryOp::Sub,
+        "*" => BinaryOp::Mul,
+        "/" => BinaryOp::Div,
+        "%" => BinaryOp::Rem,
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{symbols::SymbolTable, Memory, Registers};
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    fn machine() -> Machine<Vec<u8>> {
+        Machine {
+            pc: 0x40,
+            regs: Registers::from([
+                (RegisterID::A0, 2),
+                (RegisterID::A1, 3),
+                (RegisterID::SP, 0x100),
+            ]),
+            mem: Memory::from([(0x108, 0x12), (0x109, 0x34), (0x10a, 0x56), (0x10b, 0xf8)]),
+            symbols: SymbolTable::from([("counter", 0x108, 4)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn expressions_evaluate_against_machine_state() {
+        struct TestCase {
+            text: &'static str,
+            want: Word,
+        }
+        let cases = [
+            TestCase {
+                text: "a0 + a1 * 4",
+                want: 14,
+            },
+            TestCase {
+                text: "(a0 + a1) * 4",
+                want: 20,
+            },
+            TestCase {
+                text: "pc - 0x10",
+                want: 0x30,
+            },
+            TestCase {
+                text: "mem[sp + 8]",
+                want: 0x1234_56f8,
+            },
+            TestCase {
+                text: "mem[0x108] as u32",
+                want: 0x1234_56f8,
+            },
+            TestCase {
+                text: "mem[counter + 3] as u8",
+                want: 0xf8,
+            },
+            TestCase {
+                text: "mem[counter + 3] as i8",
+                want: -8i32 as Word,
+            },
+            TestCase {
+                text: "mem[counter] as u16 == 0x1234",
+                want: 1,
+            },
+            TestCase {
+                text: "a0 == 2 && a1 != 3 || !x0",
+                want: 1,
+            },
+            TestCase {
+                text: "1 << a0 | 0b1",
+                want: 5,
+            },
+            TestCase {
+                text: "-a0",
+                want: -2i32 as Word,
+            },
+            TestCase {
+                text: "a0 - a1 > 0",
+                want: 1,
+            },
+        ];
+        let machine = machine();
+        for case in cases {
+            let expr: Expr = case.text.parse().unwrap();
+            assert_ok_eq!(expr.eval(&machine), case.want, "{}", case.text);
+        }
+    }
+
+    #[test]
+    fn invalid_expressions_return_errors() {
+        struct TestCase {
+            text: &'static str,
+            want: Error,
+        }
+        let cases = [
+            TestCase {
+                text: "a0 +",
+                want: Error::ExprInvalid("unexpected end of expression".to_string()),
+            },
+            TestCase {
+                text: "mem[a0",
+                want: Error::ExprInvalid("unexpected end of expression".to_string()),
+            },
+            TestCase {
+                text: "a0 $ 1",
+                want: Error::ExprInvalid("unexpected character '$'".to_string()),
+            },
+            TestCase {
+                text: "a0 as u64",
+                want: Error::ExprInvalid("unknown type \"u64\"".to_string()),
+            },
+        ];
+        for case in cases {
+            assert_err_eq!(case.text.parse::<Expr>(), case.want);
+        }
+    }
+
+    #[test]
+    fn evaluation_errors_are_reported() {
+        let machine = machine();
+        let expr: Expr = "a0 / x0".parse().unwrap();
+        assert_err_eq!(expr.eval(&machine), Error::DivideByZero);
+
+        let expr: Expr = "missing + 4".parse().unwrap();
+        assert_err_eq!(
+            expr.eval(&machine),
+            Error::SymbolUnknown("missing".to_string())
+        );
+    }
+}
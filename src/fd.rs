@@ -0,0 +1,616 @@
+use crate::{
+    checkpoint::Read,
+    hostfs::HostDir,
+    output::{errno, EAGAIN, EINVAL, EIO},
+    pipe::pipe,
+    snapshot::SavedFd,
+    trap::MAX_TRANSFER,
+    Error, Input, Machine, RegisterID, Result, Word,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{ErrorKind, Write},
+    time::Duration,
+};
+
+const EBADF: i32 = 9;
+
+// What an fd refers to. Several fds refer to the same file once one is
+// duplicated onto another, and the file stays open until the last of them
+// is closed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Description {
+    // The machine's own stdin and stdout.
+    Stdin,
+    Stdout,
+    Reader(u32),
+    Writer(u32),
+}
+
+// The guest's fd table. fds 0 and 1 start out as the machine's own stdin
+// and stdout; any other fd, stderr included, refers to nothing until the
+// embedder attaches a reader or writer to it, or the guest opens a file or
+// a pipe on it.
+pub struct Files {
+    fds: BTreeMap<Word, Description>,
+    readers: BTreeMap<u32, Input>,
+    writers: BTreeMap<u32, Box<dyn Write>>,
+    next: u32,
+    // Whether files stay open once no fd refers to them, for a rewind to
+    // point fds at again, and what the guest opened each of them as.
+    retain: bool,
+    origins: BTreeMap<u32, String>,
+    // The files the embedder attached rather than the guest opened.
+    attached: BTreeSet<u32>,
+    pub(crate) host_dir: Option<HostDir>,
+}
+
+// Which file each fd refers to, as a checkpoint records it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct FdTable {
+    fds: BTreeMap<Word, Description>,
+    next: u32,
+}
+
+impl Default for Files {
+    fn default() -> Self {
+        Self {
+            fds: BTreeMap::from([(0, Description::Stdin), (1, Description::Stdout)]),
+            readers: BTreeMap::new(),
+            writers: BTreeMap::new(),
+            next: 0,
+            retain: false,
+            origins: BTreeMap::new(),
+            attached: BTreeSet::new(),
+            host_dir: None,
+        }
+    }
+}
+
+impl fmt::Debug for Files {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Files")
+            .field("fds", &self.fds)
+            .field("host_dir", &self.host_dir)
+            .finish()
+    }
+}
+
+impl Files {
+    pub(crate) fn description(&self, fd: Word) -> Option<Description> {
+        self.fds.get(&fd).copied()
+    }
+
+    // Whether a read from the reader would return at once.
+    pub(crate) fn reader_ready(&mut self, id: u32) -> bool {
+        self.readers
+            .get_mut(&id)
+            .is_none_or(|reader| reader.0.wait_readable(Duration::ZERO).unwrap_or(true))
+    }
+
+    // How many fds refer to something.
+    pub(crate) fn open(&self) -> usize {
+        self.fds.len()
+    }
+
+    // The lowest fd from 3 up that refers to nothing.
+    pub(crate) fn free_fd(&self) -> Word {
+        (3..=Word::MAX)
+            .find(|fd| !self.fds.contains_key(fd))
+            .expect("fds run out after the host's memory does")
+    }
+
+    pub(crate) fn open_reader(&mut self, fd: Word, reader: Input) {
+        self.next += 1;
+        self.attached.remove(&self.next);
+        self.readers.insert(self.next, reader);
+        self.set(fd, Description::Reader(self.next));
+    }
+
+    pub(crate) fn open_writer(&mut self, fd: Word, writer: Box<dyn Write>) {
+        self.next += 1;
+        self.attached.remove(&self.next);
+        self.writers.insert(self.next, writer);
+        self.set(fd, Description::Writer(self.next));
+    }
+
+    // Points `fd` at `description`, closing what it referred to before.
+    fn set(&mut self, fd: Word, description: Description) {
+        self.close(fd);
+        self.fds.insert(fd, description);
+    }
+
+    // Closes `fd`, and the file it refers to if no other fd does, returning
+    // whether it referred to anything.
+    fn close(&mut self, fd: Word) -> bool {
+        let Some(description) = self.fds.remove(&fd) else {
+            return false;
+        };
+        if !self.retain && !self.fds.values().any(|other| *other == description) {
+            match description {
+                Description::Reader(id) => {
+                    self.readers.remove(&id);
+                }
+                Description::Writer(id) => {
+                    self.writers.remove(&id);
+                }
+                Description::Stdin | Description::Stdout => {}
+            }
+        }
+        true
+    }
+
+    // What each fd refers to, as a snapshot saves it.
+    pub(crate) fn saved_fds(&self) -> BTreeMap<Word, SavedFd> {
+        self.fds
+            .iter()
+            .map(|(fd, description)| {
+                let saved = match description {
+                    Description::Stdin => SavedFd::Stdin,
+                    Description::Stdout => SavedFd::Stdout,
+                    Description::Reader(id) | Description::Writer(id)
+                        if self.attached.contains(id) =>
+                    {
+                        SavedFd::Attached
+                    }
+                    Description::Reader(_) | Description::Writer(_) => SavedFd::Opened,
+                };
+                (*fd, saved)
+            })
+            .collect()
+    }
+
+    // Points the fds at a new process's stdin and stdout and at what its
+    // embedder attached again, as they were when `saved` was taken,
+    // closing every other fd. Fails, changing nothing, if the guest had
+    // opened a file or the embedder has not attached one it had before.
+    pub(crate) fn restore_saved_fds(&mut self, saved: &BTreeMap<Word, SavedFd>) -> Result<()> {
+        let mut fds = BTreeMap::new();
+        for (&fd, saved) in saved {
+            let description =
+                match saved {
+                    SavedFd::Stdin => Description::Stdin,
+                    SavedFd::Stdout => Description::Stdout,
+                    SavedFd::Attached => match self.fds.get(&fd) {
+                        Some(
+                            &description @ (Description::Reader(id) | Description::Writer(id)),
+                        ) if self.attached.contains(&id) => description,
+                        _ => {
+                            return Err(Error::SnapshotInvalid(format!(
+                                "fd {fd} was attached by the host, which has not attached it again"
+                            )))
+                        }
+                    },
+                    SavedFd::Opened => {
+                        return Err(Error::SnapshotInvalid(format!(
+                    "fd {fd} refers to a file the guest opened, which a resumed run cannot reopen"
+                )))
+                    }
+                };
+            fds.insert(fd, description);
+        }
+        let unsaved: Vec<Word> = self
+            .fds
+            .keys()
+            .filter(|fd| !fds.contains_key(fd))
+            .copied()
+            .collect();
+        for fd in unsaved {
+            self.close(fd);
+        }
+        self.fds = fds;
+        Ok(())
+    }
+
+    pub(crate) fn table(&self) -> FdTable {
+        FdTable {
+            fds: self.fds.clone(),
+            next: self.next,
+        }
+    }
+
+    // Points every fd back at what it referred to when `table` was taken.
+    // The files opened since stay open, for reopen to hand back when the
+    // guest opens them again.
+    pub(crate) fn restore_table(&mut self, table: FdTable) {
+        self.fds = table.fds;
+        self.next = table.next;
+    }
+
+    pub(crate) fn retain_closed(&mut self, retain: bool) {
+        self.retain = retain;
+        if !retain {
+            let open: Vec<Description> = self.fds.values().copied().collect();
+            self.readers
+                .retain(|id, _| open.contains(&Description::Reader(*id)));
+            self.writers
+                .retain(|id, _| open.contains(&Description::Writer(*id)));
+            self.origins.clear();
+        }
+    }
+
+    // Notes what the guest opened the latest file as, for reopen.
+    pub(crate) fn opened_as(&mut self, origin: &str) {
+        if self.retain {
+            self.origins.insert(self.next, origin.to_string());
+        }
+    }
+
+    // Points `fd` at the file the guest opened next before a rewind, if it
+    // is opening the same again, so that it is neither truncated nor loses
+    // what was written to it.
+    pub(crate) fn reopen(&mut self, fd: Word, origin: &str) -> bool {
+        let id = self.next + 1;
+        if self.origins.get(&id).map(String::as_str) != Some(origin) {
+            return false;
+        }
+        let description = if self.readers.contains_key(&id) {
+            Description::Reader(id)
+        } else if self.writers.contains_key(&id) {
+            Description::Writer(id)
+        } else {
+            return false;
+        };
+        self.next = id;
+        self.set(fd, description);
+        true
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Lets the guest read from `fd`. Attaching to fd 0 replaces stdin.
+    pub fn attach_reader(&mut self, fd: Word, reader: impl std::io::Read + 'static) {
+        match fd {
+            0 => {
+                self.set_stdin(reader);
+                self.files.set(0, Description::Stdin);
+            }
+            fd => {
+                self.files.open_reader(fd, Input::new(reader));
+                self.files.attached.insert(self.files.next);
+            }
+        }
+    }
+
+    // Lets the guest write to `fd`, such as the host's stderr at fd 2.
+    // Attaching to fd 1 sends the guest's output there rather than to the
+    // machine's stdout; attach a sink with add_stdout_sink to see stdout
+    // elsewhere as well.
+    pub fn attach_writer(&mut self, fd: Word, writer: impl Write + 'static) {
+        self.files.open_writer(fd, Box::new(writer));
+        self.files.attached.insert(self.files.next);
+    }
+
+    // Closes an fd, returning whether it referred to anything.
+    pub fn detach(&mut self, fd: Word) -> bool {
+        self.files.close(fd)
+    }
+
+    // close(fd) detaches `fd`, returning 0, or -EBADF if nothing was
+    // attached to it.
+    pub(crate) fn close_syscall(&mut self) {
+        let fd = self.regs.get(&RegisterID::A0);
+        let result = if self.detach(fd) { 0 } else { errno(EBADF) };
+        self.set_register(RegisterID::A0, result);
+    }
+
+    // The length of a read or write of `len` bytes at `addr`, which is
+    // cut short at MAX_TRANSFER.
+    fn transfer_len(&self, addr: Word, len: Word) -> Result<usize> {
+        self.guest_buffer(addr, u64::from(len).min(MAX_TRANSFER as u64))
+    }
+
+    // read(fd, buf, len) reads up to `len` bytes into `buf`, returning the
+    // number read, -EAGAIN if the fd is an empty pipe something could still
+    // write to, or -EBADF if it refers to nothing. Only reads from stdin
+    // count towards the input a manifest records. A read made again after
+    // a rewind gets what it got the first time.
+    pub(crate) fn read_syscall(&mut self) -> Result<()> {
+        let fd = self.regs.get(&RegisterID::A0);
+        let buf_addr = self.regs.get(&RegisterID::A1);
+        let len = self.transfer_len(buf_addr, self.regs.get(&RegisterID::A2))?;
+        let description = self.files.description(fd);
+        if !matches!(
+            description,
+            Some(Description::Stdin | Description::Reader(_))
+        ) {
+            self.set_register(RegisterID::A0, errno(EBADF));
+            return Ok(());
+        }
+        let read = self
+            .recorded_read()
+            .unwrap_or_else(|| self.read_input(description, len));
+        match read {
+            Ok(data) => {
+                let count = data.len().min(len);
+                self.store(buf_addr, &data[..count]);
+                self.taint_input(buf_addr, count);
+                self.set_register(RegisterID::A0, count as Word);
+            }
+            Err(code) => self.set_register(RegisterID::A0, errno(code)),
+        }
+        Ok(())
+    }
+
+    // Reads up to `len` bytes from the stdin or reader `description`
+    // refers to, recording what it read.
+    fn read_input(&mut self, description: Option<Description>, len: usize) -> Read {
+        let read = self.read_live(description, len);
+        if let (Ok(data), Some(Description::Stdin)) = (&read, description) {
+            self.input_digest.update(data);
+            self.input_read += data.len() as u64;
+        }
+        self.record_read(&read);
+        read
+    }
+
+    fn read_live(&mut self, description: Option<Description>, len: usize) -> Read {
+        let input = match description {
+            Some(Description::Stdin) => self.stdin.as_mut(),
+            Some(Description::Reader(id)) => self.files.readers.get_mut(&id),
+            _ => None,
+        };
+        let Some(input) = input else {
+            return Ok(Vec::new());
+        };
+        let mut data = vec![0; len];
+        match input.0.read(&mut data) {
+            Ok(count) => {
+                data.truncate(count);
+                Ok(data)
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(EAGAIN),
+            Err(_) => Err(EIO),
+        }
+    }
+
+    // write(fd, buf, len) writes up to `len` bytes from `buf`, returning
+    // the number written, or a negated errno: -EAGAIN for a full pipe.
+    pub(crate) fn write_syscall(&mut self) -> Result<()> {
+        let fd = self.regs.get(&RegisterID::A0);
+        let buf_addr = self.regs.get(&RegisterID::A1);
+        let len = self.transfer_len(buf_addr, self.regs.get(&RegisterID::A2))?;
+        let description = self.files.description(fd);
+        let len = match description {
+            Some(Description::Stdout | Description::Writer(_)) => self.output_allowance(len),
+            _ => len,
+        };
+        let written = match description {
+            Some(Description::Stdout) => {
+                let data = self.load(buf_addr, len);
+                self.write_stdout(&data)?
+            }
+            // Output the guest wrote the first time is not written again.
+            Some(Description::Writer(_)) if self.replaying => len as Word,
+            Some(Description::Writer(id)) => {
+                let data = self.load(buf_addr, len);
+                match self
+                    .files
+                    .writers
+                    .get_mut(&id)
+                    .map(|writer| writer.write(&data))
+                {
+                    Some(Ok(count)) => {
+                        self.count_output(count);
+                        count as Word
+                    }
+                    Some(Err(err)) if err.kind() == ErrorKind::WouldBlock => errno(EAGAIN),
+                    _ => errno(EIO),
+                }
+            }
+            _ => errno(EBADF),
+        };
+        self.set_register(RegisterID::A0, written);
+        Ok(())
+    }
+
+    // pipe2(fds, flags) opens a pipe, storing the fd to read it from and
+    // the fd to write to it as two words at `fds`, and returns 0; no flags
+    // are supported, so any give -EINVAL. Reading an empty pipe gives
+    // -EAGAIN until every fd writing to it is closed, and end of file
+    // after.
+    pub(crate) fn pipe_syscall(&mut self) -> Result<()> {
+        let fds_addr = self.regs.get(&RegisterID::A0);
+        if self.regs.get(&RegisterID::A1) != 0 {
+            self.set_register(RegisterID::A0, errno(EINVAL));
+            return Ok(());
+        }
+        self.guest_buffer(fds_addr, 8)?;
+        let (reader, writer) = pipe();
+        let read_fd = self.files.free_fd();
+        if !self.files.reopen(read_fd, "pipe") {
+            self.files.open_reader(read_fd, Input(Box::new(reader)));
+            self.files.opened_as("pipe");
+        }
+        let write_fd = self.files.free_fd();
+        if !self.files.reopen(write_fd, "pipe") {
+            self.files.open_writer(write_fd, Box::new(writer));
+            self.files.opened_as("pipe");
+        }
+        let mut fds = Vec::with_capacity(8);
+        for fd in [read_fd, write_fd] {
+            let mut bytes = fd.to_be_bytes();
+            self.endian.arrange(&mut bytes);
+            fds.extend(bytes);
+        }
+        self.store(fds_addr, &fds);
+        self.set_register(RegisterID::A0, 0);
+        Ok(())
+    }
+
+    // dup3(old, new, flags) makes `new` refer to what `old` does, closing
+    // what `new` referred to first, and returns `new`: duplicating a pipe
+    // onto fd 1 sends the guest's own output into it. Gives -EBADF if `old`
+    // refers to nothing and -EINVAL for flags or for `new` being `old`.
+    pub(crate) fn dup_syscall(&mut self) {
+        let old = self.regs.get(&RegisterID::A0);
+        let new = self.regs.get(&RegisterID::A1);
+        let result = match self.files.description(old) {
+            None => errno(EBADF),
+            Some(_) if old == new || self.regs.get(&RegisterID::A2) != 0 => errno(EINVAL),
+            Some(description) => {
+                self.files.set(new, description);
+                new
+            }
+        };
+        self.set_register(RegisterID::A0, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, output::SharedBuffer, HaltReason};
+    use claims::assert_ok_eq;
+
+    // Copies what it reads from fd 3 to fds 2 and 4, leaving the counts
+    // in a3, a4 and a5.
+    const COPY: &str = "
+        li a0, 3
+        li a1, 0x100
+        li a2, 16
+        li a7, 63
+        ecall
+        mv a3, a0
+        mv a2, a0
+        li a0, 2
+        li a7, 64
+        ecall
+        mv a4, a0
+        li a0, 4
+        ecall
+        mv a5, a0
+        ebreak
+    ";
+
+    #[test]
+    fn guests_use_the_files_the_host_attaches() {
+        let stderr = SharedBuffer::new();
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&assemble(COPY).unwrap())
+            .build()
+            .unwrap();
+        machine.attach_reader(3, &b"to stderr"[..]);
+        machine.attach_writer(2, stderr.clone());
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(stderr.contents(), b"to stderr");
+        assert_eq!(machine.register(RegisterID::A3), 9);
+        assert_eq!(machine.register(RegisterID::A4), 9);
+        assert_eq!(machine.register(RegisterID::A5), errno(EBADF));
+        // Only stdin is part of the recorded input.
+        assert_eq!(machine.input_read, 0);
+    }
+
+    #[test]
+    fn unattached_fds_are_bad() {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&assemble(COPY).unwrap())
+            .build()
+            .unwrap();
+        machine.attach_reader(3, &b""[..]);
+        assert!(machine.detach(3));
+        assert!(!machine.detach(3));
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.register(RegisterID::A3), errno(EBADF));
+    }
+
+    #[test]
+    fn guests_redirect_their_output_into_a_pipe() {
+        // Opens a pipe, duplicates its write end onto stdout, and reads
+        // back what it writes there once both write fds are closed.
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(
+                &assemble(
+                    "li a0, 0x2000
+                     li a1, 0
+                     li a7, 59
+                     ecall
+                     lw a3, 0x2000
+                     lw a4, 0x2004
+                     mv a0, a4
+                     li a1, 1
+                     li a7, 24
+                     ecall
+                     mv a0, a3
+                     li a1, 0x2100
+                     li a2, 8
+                     li a7, 63
+                     ecall
+                     mv a5, a0
+                     li a0, 1
+                     li a1, 0x2200
+                     li a2, 2
+                     li a7, 64
+                     ecall
+                     mv a0, a4
+                     li a7, 57
+                     ecall
+                     li a0, 1
+                     ecall
+                     mv a0, a3
+                     li a1, 0x2100
+                     li a2, 8
+                     li a7, 63
+                     ecall
+                     mv a6, a0
+                     mv a0, a3
+                     ecall
+                     ebreak",
+                )
+                .unwrap(),
+            )
+            .stdout(Vec::new())
+            .build()
+            .unwrap();
+        machine.mem.write(0x2200, b"hi");
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.register(RegisterID::A3), 3);
+        assert_eq!(machine.register(RegisterID::A4), 4);
+        // Empty while something could still write, then the bytes written,
+        // then end of file.
+        assert_eq!(machine.register(RegisterID::A5), errno(EAGAIN));
+        assert_eq!(machine.register(RegisterID::A6), 2);
+        assert_eq!(machine.register(RegisterID::A0), 0);
+        assert_eq!(machine.mem.read(0x2100, 2), b"hi");
+        assert_eq!(machine.stdout().unwrap(), b"");
+    }
+
+    #[test]
+    fn duplicating_bad_fds_fails() {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(
+                &assemble(
+                    "li a0, 5
+                     li a1, 6
+                     li a7, 24
+                     ecall
+                     mv a3, a0
+                     li a0, 1
+                     li a1, 1
+                     ecall
+                     mv a4, a0
+                     li a0, 0x2000
+                     li a1, 1
+                     li a7, 59
+                     ecall
+                     ebreak",
+                )
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.register(RegisterID::A3), errno(EBADF));
+        assert_eq!(machine.register(RegisterID::A4), errno(EINVAL));
+        assert_eq!(machine.register(RegisterID::A0), errno(EINVAL));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{observer::Event, trap::Trap, Address, Machine, Result, Word};
+use std::io::Write;
+
+// How many bytes the fetch unit reads from memory at a time. Every
+// instruction is four bytes for now, so with half-word parcels each is
+// fetched in two halves; the first parcel of an instruction decides its
+// length, which is what compressed instructions will need.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum FetchWidth {
+    Half,
+    #[default]
+    Word,
+}
+
+impl FetchWidth {
+    pub fn bytes(self) -> usize {
+        match self {
+            FetchWidth::Half => 2,
+            FetchWidth::Word => 4,
+        }
+    }
+}
+
+// What the fetch unit has done, for models of the front end such as an
+// instruction cache to build on.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct FetchStats {
+    pub instructions: u64,
+    pub parcels: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FetchUnit {
+    width: FetchWidth,
+    stats: FetchStats,
+}
+
+// The length of the instruction starting with `first`, its first parcel.
+fn instruction_len(_first: &[u8]) -> usize {
+    4
+}
+
+impl<W: Write> Machine<W> {
+    pub fn set_fetch_width(&mut self, width: FetchWidth) {
+        self.fetch.width = width;
+    }
+
+    pub fn fetch_width(&self) -> FetchWidth {
+        self.fetch.width
+    }
+
+    pub fn fetch_stats(&self) -> FetchStats {
+        self.fetch.stats
+    }
+
+    // Fetches the instruction at pc a parcel at a time, returning its word
+    // and length, or trapping with FetchFault if it runs past the end of
+    // memory or the memory limit. Observers see an Event::Fetched for each
+    // instruction fetched.
+    pub(crate) fn fetch(&mut self) -> Result<(Word, usize)> {
+        let parcel = self.fetch.width.bytes();
+        let mut bytes = [0; 4];
+        let (mut fetched, mut len) = (0, parcel);
+        while fetched < len {
+            let addr = self
+                .pc
+                .checked_add(fetched as Address)
+                .filter(|addr| self.within_memory(*addr, parcel as u64))
+                .ok_or_else(|| {
+                    self.trap(Trap::FetchFault {
+                        addr: self.pc,
+                        len: fetched + parcel,
+                    })
+                })?;
+            // A whole word at once is the common case, and read_u32 does it
+            // with one page lookup.
+            if parcel == 4 {
+                bytes = self.mem.read_u32(addr).to_be_bytes();
+            } else {
+                for (offset, byte) in bytes[fetched..fetched + parcel].iter_mut().enumerate() {
+                    *byte = self.mem.get(addr.wrapping_add(offset as Address));
+                }
+            }
+            fetched += parcel;
+            self.fetch.stats.parcels += 1;
+            if fetched == parcel {
+                len = instruction_len(&bytes[..parcel]);
+            }
+        }
+        self.fetch.stats.instructions += 1;
+        self.observers.notify(&Event::Fetched { pc: self.pc, len });
+        Ok((self.endian.word(bytes), len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, Error, HaltReason, RegisterID};
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    fn machine(width: FetchWidth) -> Machine<Vec<u8>> {
+        let mut machine = Machine::builder()
+            .program(&assemble("li a0, 1\nadd a0, a0, a0\nebreak").unwrap())
+            .build()
+            .unwrap();
+        machine.set_fetch_width(width);
+        machine
+    }
+
+    #[test]
+    fn half_word_parcels_fetch_the_same_instructions() {
+        for (width, parcels) in [(FetchWidth::Word, 3), (FetchWidth::Half, 6)] {
+            let mut machine = machine(width);
+
+            assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+            assert_eq!(machine.register(RegisterID::A0), 2);
+            assert_eq!(
+                machine.fetch_stats(),
+                FetchStats {
+                    instructions: 3,
+                    parcels,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn fetch_faults_give_the_parcels_they_needed() {
+        for (width, pc, len) in [
+            (FetchWidth::Word, 0xffff_fffe, 4),
+            (FetchWidth::Half, 0xffff_fffe, 4),
+            (FetchWidth::Half, 0xffff_ffff, 2),
+        ] {
+            let mut machine = machine(width);
+            machine.pc = pc;
+
+            assert_err_eq!(
+                machine.step(),
+                Error::Trap {
+                    pc,
+                    trap: Trap::FetchFault { addr: pc, len },
+                }
+            );
+        }
+    }
+}
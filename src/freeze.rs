@@ -0,0 +1,127 @@
+use crate::{Address, Machine, RegisterID, Word};
+use std::{collections::BTreeMap, io::Write};
+
+// Registers and memory bytes pinned to forced values. The values are
+// written when the freeze is set and re-applied after every instruction,
+// so guest writes to a frozen location never stick.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Freezes {
+    regs: BTreeMap<u8, (RegisterID, Word)>,
+    mem: BTreeMap<Address, u8>,
+}
+
+impl<W: Write> Machine<W> {
+    pub fn freeze_register(&mut self, reg: RegisterID, value: Word) {
+        self.freezes.regs.insert(reg as u8, (reg, value));
+        self.regs.set(reg, value);
+    }
+
+    pub fn unfreeze_register(&mut self, reg: RegisterID) {
+        self.freezes.regs.remove(&(reg as u8));
+    }
+
+    pub fn freeze_memory(&mut self, addr: Address, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.freezes.mem.insert(addr + offset as u32, *byte);
+        }
+        self.mem.write(addr, bytes);
+    }
+
+    pub fn unfreeze_memory(&mut self, addr: Address, len: usize) {
+        for offset in 0..len {
+            self.freezes.mem.remove(&(addr + offset as u32));
+        }
+    }
+
+    pub fn unfreeze_all(&mut self) {
+        self.freezes = Freezes::default();
+    }
+
+    pub(crate) fn apply_freezes(&mut self) {
+        let freezes = std::mem::take(&mut self.freezes);
+        for (reg, value) in freezes.regs.values() {
+            if self.regs.get(reg) != *value {
+                self.set_register(*reg, *value);
+            }
+        }
+        for (addr, value) in &freezes.mem {
+            if self.mem.get(*addr) != *value {
+                self.write_memory(*addr, &[*value]);
+            }
+        }
+        self.freezes = freezes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, Registers};
+    use claims::assert_ok;
+
+    // add a0, a0, x0, 1; then format a0 into the buffer at 0x100; ebreak.
+    fn machine() -> Machine<Vec<u8>> {
+        Machine {
+            regs: Registers::from([
+                (RegisterID::A1, 0x100),
+                (RegisterID::A2, 4),
+                (RegisterID::A7, 257),
+            ]),
+            mem: Memory::from([
+                // add a0, a0, x0, 1
+                (0, 0b0000_0000),
+                (1, 0b0000_0010),
+                (2, 0b0000_0010),
+                (3, 0b0010_0010),
+                // ECall
+                (4, 0b0000_0000),
+                (5, 0b0000_0000),
+                (6, 0b0000_0000),
+                (7, 0b0001_0111),
+                // EBreak
+                (8, 0b0000_0000),
+                (9, 0b0000_0000),
+                (10, 0b0000_0000),
+                (11, 0b0001_1000),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn frozen_registers_keep_their_forced_value() {
+        let mut machine = machine();
+        machine.freeze_register(RegisterID::A0, 41);
+
+        assert_ok!(machine.step());
+        assert_eq!(machine.regs.get(&RegisterID::A0), 41);
+
+        // The frozen value is what the guest observes as input.
+        assert_ok!(machine.run());
+        assert_eq!(machine.mem.read(0x100, 2), b"41");
+        assert_eq!(machine.regs.get(&RegisterID::A0), 41);
+    }
+
+    #[test]
+    fn frozen_memory_discards_guest_writes() {
+        let mut machine = machine();
+        machine.freeze_memory(0x101, b"?");
+
+        assert_ok!(machine.run());
+
+        assert_eq!(machine.mem.read(0x100, 2), b"1?");
+    }
+
+    #[test]
+    fn unfrozen_locations_can_be_written_again() {
+        let mut machine = machine();
+        machine.freeze_register(RegisterID::A0, 41);
+        machine.freeze_memory(0x100, b"??");
+        machine.unfreeze_register(RegisterID::A0);
+        machine.unfreeze_memory(0x100, 1);
+
+        assert_ok!(machine.run());
+
+        assert_eq!(machine.mem.read(0x100, 2), b"4?");
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{alignment::Alignment, disasm::mnemonic, HaltReason, Machine, Opcode, Word};
+use std::{collections::BTreeMap, io::Write};
+
+// What the machine has executed since it was built, kept whether or not a
+// run is being measured.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ExecutionStats {
+    pub retired: u64,
+    // Indexed by opcode number, so counting an instruction is cheap.
+    opcodes: [u64; 32],
+    pub syscalls: BTreeMap<Word, u64>,
+    pub accesses: Alignment,
+}
+
+impl ExecutionStats {
+    pub(crate) fn count_instruction(&mut self, opcode: &Opcode) {
+        self.retired += 1;
+        self.opcodes[opcode.code() as usize] += 1;
+    }
+
+    pub(crate) fn count_syscall(&mut self, number: Word) {
+        *self.syscalls.entry(number).or_default() += 1;
+    }
+
+    // How many times instructions with `opcode` have executed.
+    pub fn executed(&self, opcode: Opcode) -> u64 {
+        self.opcodes[opcode.code() as usize]
+    }
+
+    // The counts of the opcodes that have executed, by mnemonic.
+    pub fn instructions(&self) -> BTreeMap<&'static str, u64> {
+        Opcode::ALL
+            .iter()
+            .filter(|opcode| self.executed(**opcode) > 0)
+            .map(|opcode| (mnemonic(opcode), self.executed(*opcode)))
+            .collect()
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Limits the machine to `fuel` more instructions, after which stepping
+    // stops with ResourceExhausted until more is given; None removes the
+    // limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    // The instructions left before the machine runs out of fuel.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn execution_stats(&self) -> &ExecutionStats {
+        &self.execution
+    }
+
+    pub(crate) fn out_of_fuel(&self) -> Option<HaltReason> {
+        (self.fuel == Some(0)).then_some(HaltReason::ResourceExhausted { pc: self.pc })
+    }
+
+    pub(crate) fn burn_fuel(&mut self) {
+        if self.replaying {
+            return;
+        }
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::machine, RegisterID, StepOutcome};
+    use claims::assert_ok_eq;
+
+    #[test]
+    fn runs_stop_when_the_fuel_runs_out() {
+        let mut machine = machine("spin: add a0, a0, x0, 1\nj spin");
+        machine.set_fuel(Some(5));
+
+        assert_ok_eq!(machine.run(), HaltReason::ResourceExhausted { pc: 4 });
+        assert_eq!(machine.register(RegisterID::A0), 3);
+        assert_eq!(machine.fuel(), Some(0));
+        // Nothing more runs until the machine is given more fuel.
+        assert_ok_eq!(
+            machine.run_for(10),
+            StepOutcome::Halted(HaltReason::ResourceExhausted { pc: 4 })
+        );
+        assert_eq!(machine.execution_stats().retired, 5);
+
+        machine.set_fuel(Some(2));
+        assert_ok_eq!(machine.run(), HaltReason::ResourceExhausted { pc: 4 });
+        assert_eq!(machine.register(RegisterID::A0), 4);
+    }
+
+    #[test]
+    fn running_out_of_fuel_comes_before_fetching() {
+        let mut machine = machine("ebreak");
+        machine.set_fuel(Some(0));
+        // Nothing at 0x8000 decodes.
+        machine.pc = 0x8000;
+
+        assert_ok_eq!(
+            machine.step().map(|(_, outcome)| outcome),
+            StepOutcome::Halted(HaltReason::ResourceExhausted { pc: 0x8000 })
+        );
+    }
+
+    #[test]
+    fn execution_stats_count_opcodes_and_syscalls() {
+        let mut machine = machine("li a7, 214\necall\necall\nadd a0, a0, a0\nebreak");
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        let stats = machine.execution_stats();
+        assert_eq!(stats.retired, 5);
+        assert_eq!(stats.executed(Opcode::ECall), 2);
+        assert_eq!(
+            stats.instructions(),
+            BTreeMap::from([("add", 1), ("ebreak", 1), ("ecall", 2), ("li", 1)])
+        );
+        assert_eq!(stats.syscalls, BTreeMap::from([(214, 2)]));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{
+    disasm::mnemonic,
+    observer::{Event, Observer},
+    Address, Instruction, RegisterID,
+};
+use std::{collections::BTreeMap, fmt};
+
+// A pair of instructions that ran back to back, and how often.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FusionCandidate {
+    pub first: &'static str,
+    pub second: &'static str,
+    pub count: u64,
+    // How many times the second instruction read the first's result, which
+    // is what makes a pair worth fusing.
+    pub dependent: u64,
+    // The address of the first instruction of the most frequent occurrence.
+    pub hottest: Address,
+}
+
+impl fmt::Display for FusionCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}+{}: {} times, {} dependent, hottest at {:#010x}",
+            self.first, self.second, self.count, self.dependent, self.hottest
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Pair {
+    count: u64,
+    dependent: u64,
+    sites: BTreeMap<Address, u64>,
+}
+
+// Counts adjacent instruction pairs as they retire, to find macro-op fusion
+// opportunities: candidates for interpreter superinstructions, and hints
+// for people tuning hot guest code. A pair is only counted when the second
+// instruction directly follows the first in memory, so taken branches and
+// jumps break it up.
+#[derive(Debug, Default, Clone)]
+pub struct FusionStats {
+    pairs: BTreeMap<(&'static str, &'static str), Pair>,
+    previous: Option<(Address, Instruction)>,
+}
+
+impl FusionStats {
+    // Pairs seen at least `min_count` times, the most dependent first.
+    pub fn candidates(&self, min_count: u64) -> Vec<FusionCandidate> {
+        let mut candidates: Vec<FusionCandidate> = self
+            .pairs
+            .iter()
+            .filter(|(_, pair)| pair.count >= min_count)
+            .map(|(&(first, second), pair)| FusionCandidate {
+                first,
+                second,
+                count: pair.count,
+                dependent: pair.dependent,
+                hottest: pair
+                    .sites
+                    .iter()
+                    .max_by_key(|(_, count)| **count)
+                    .map(|(addr, _)| *addr)
+                    .unwrap_or_default(),
+            })
+            .collect();
+        candidates.sort_by(|a, b| {
+            (b.dependent, b.count)
+                .cmp(&(a.dependent, a.count))
+                .then((a.first, a.second).cmp(&(b.first, b.second)))
+        });
+        candidates
+    }
+}
+
+fn reads_result(first: &Instruction, second: &Instruction) -> bool {
+    first.rd != RegisterID::X0 && (second.rs1 == first.rd || second.rs2 == first.rd)
+}
+
+impl Observer for FusionStats {
+    fn observe(&mut self, event: &Event) {
+        let Event::Retired {
+            pc, instruction, ..
+        } = event
+        else {
+            return;
+        };
+        if let Some((previous_pc, previous)) = self.previous {
+            if previous_pc.wrapping_add(4) == *pc {
+                let key = (mnemonic(&previous.opcode), mnemonic(&instruction.opcode));
+                let pair = self.pairs.entry(key).or_default();
+                pair.count += 1;
+                pair.dependent += u64::from(reads_result(&previous, instruction));
+                *pair.sites.entry(previous_pc).or_default() += 1;
+            }
+        }
+        self.previous = Some((*pc, *instruction));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Word;
+
+    fn retired(pc: Address, word: Word) -> Event {
+        Event::Retired {
+            pc,
+            instruction: Instruction::try_from(word).unwrap(),
+            operands: [0, 0],
+        }
+    }
+
+    #[test]
+    fn adjacent_pairs_are_ranked_by_dependence() {
+        let li_a1 = (3 << 17) | (2 << 5) | 1; // li a1, 3
+        let add_a0 = (2 << 13) | (1 << 9) | (1 << 5) | 2; // add a0, a0, a1
+        let add_a2 = (1 << 17) | (3 << 9) | (3 << 5) | 2; // add a2, a2, x0, 1
+        let mut stats = FusionStats::default();
+        for event in [
+            retired(0x10, li_a1),
+            retired(0x14, add_a0),
+            retired(0x18, add_a2),
+            // a jump back to 0x10 does not make 0x18..0x10 a pair
+            retired(0x10, li_a1),
+            retired(0x14, add_a0),
+        ] {
+            stats.observe(&event);
+        }
+
+        let candidates = stats.candidates(1);
+        assert_eq!(
+            candidates,
+            vec![
+                FusionCandidate {
+                    first: "li",
+                    second: "add",
+                    count: 2,
+                    dependent: 2,
+                    hottest: 0x10,
+                },
+                FusionCandidate {
+                    first: "add",
+                    second: "add",
+                    count: 1,
+                    dependent: 0,
+                    hottest: 0x14,
+                },
+            ]
+        );
+        assert_eq!(
+            candidates[0].to_string(),
+            "li+add: 2 times, 2 dependent, hottest at 0x00000010"
+        );
+        assert_eq!(stats.candidates(2).len(), 1);
+    }
+}
@@ -0,0 +1,368 @@
+use crate::{effect::Effect, trap::Trap, Address, Error, Machine, RegisterID, Result, Word};
+use std::{fmt, io::Write};
+
+// Byte written over heap memory that the guest has not been given, or has
+// given back, when poisoning is enabled.
+pub const POISON: u8 = 0xa5;
+
+// How many out-of-bounds accesses a heap report lists. A guest overrunning
+// the heap in a loop makes one per iteration, so the rest are only counted.
+pub const MAX_REPORTED_ACCESSES: usize = 64;
+
+// The guest's program break, moved with the brk syscall. The heap spans
+// from the layout's heap base up to the break.
+#[derive(Debug, Default)]
+pub struct Heap {
+    pub(crate) brk: Option<Address>,
+    diagnostics: Option<HeapDiagnostics>,
+}
+
+#[derive(Debug, Default)]
+struct HeapDiagnostics {
+    poison: bool,
+    high_water: Address,
+    out_of_bounds: Vec<HeapAccess>,
+    out_of_bounds_count: u64,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// A guest access to memory between the program break and the stack.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct HeapAccess {
+    pub pc: Address,
+    pub addr: Address,
+    pub len: usize,
+    pub kind: AccessKind,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HeapReport {
+    // The largest the heap has been, in bytes.
+    pub high_water: u32,
+    // Heap bytes still allocated when the report was taken.
+    pub leaked: u32,
+    // The first MAX_REPORTED_ACCESSES out-of-bounds accesses, and how many
+    // there were in all.
+    pub out_of_bounds: Vec<HeapAccess>,
+    pub out_of_bounds_count: u64,
+}
+
+impl fmt::Display for HeapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "heap high-water mark: {} bytes", self.high_water)?;
+        write!(f, "heap still allocated: {} bytes", self.leaked)?;
+        for access in &self.out_of_bounds {
+            let kind = match access.kind {
+                AccessKind::Read => "read",
+                AccessKind::Write => "write",
+            };
+            write!(
+                f,
+                "\nout-of-bounds heap {kind} of {} bytes at {:#010x} (pc {:#010x})",
+                access.len, access.addr, access.pc
+            )?;
+        }
+        let unlisted = self.out_of_bounds_count - self.out_of_bounds.len() as u64;
+        if unlisted > 0 {
+            write!(f, "\nand {unlisted} more out-of-bounds heap accesses")?;
+        }
+        Ok(())
+    }
+}
+
+impl Heap {
+    // Puts the break back at the heap base, keeping any diagnostics.
+    pub(crate) fn reset(&mut self) {
+        self.brk = None;
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn brk(&self) -> Address {
+        self.heap.brk.unwrap_or(self.layout.heap_base)
+    }
+
+    // Starts tracking the heap's high-water mark and accesses beyond the
+    // break. With `poison` set, memory the heap grows into and memory it
+    // shrinks away from is filled with POISON, so that guests reading
+    // uninitialised or freed heap memory are easier to spot.
+    pub fn enable_heap_diagnostics(&mut self, poison: bool) {
+        self.heap.diagnostics = Some(HeapDiagnostics {
+            poison,
+            high_water: self.brk() - self.layout.heap_base,
+            out_of_bounds: Vec::new(),
+            out_of_bounds_count: 0,
+        });
+    }
+
+    pub fn heap_report(&self) -> Option<HeapReport> {
+        let diagnostics = self.heap.diagnostics.as_ref()?;
+        Some(HeapReport {
+            high_water: diagnostics.high_water,
+            leaked: self.brk() - self.layout.heap_base,
+            out_of_bounds: diagnostics.out_of_bounds.clone(),
+            out_of_bounds_count: diagnostics.out_of_bounds_count,
+        })
+    }
+
+    // Moves the program break to `requested` if it lies between the heap
+    // base and the stack top, and returns the break in effect afterwards,
+    // just like Linux does.
+    pub(crate) fn set_brk(&mut self, requested: Address) -> Address {
+        let current = self.brk();
+        if requested < self.layout.heap_base || requested >= self.layout.stack_top {
+            return current;
+        }
+        if let Some(diagnostics) = &mut self.heap.diagnostics {
+            let size = requested - self.layout.heap_base;
+            diagnostics.high_water = diagnostics.high_water.max(size);
+            if diagnostics.poison {
+                let (start, end) = (current.min(requested), current.max(requested));
+                self.write_memory(start, &vec![POISON; (end - start) as usize]);
+            }
+        }
+        self.heap.brk = Some(requested);
+        requested
+    }
+
+    // Traps a brk that would move the break past the stack pointer, so that
+    // the heap would take over the stack. sp below the heap base is taken
+    // to be a stack of the guest's own elsewhere.
+    pub(crate) fn check_brk_collision(&self, requested: Address) -> Result<()> {
+        let sp = self.regs.get(&RegisterID::SP);
+        if sp >= self.layout.heap_base && requested > sp {
+            return Err(self.syscall_trap(Trap::HeapStackCollision { brk: requested, sp }));
+        }
+        Ok(())
+    }
+
+    // Traps an instruction that moved the stack pointer into the heap,
+    // undoing the move so that the machine is left as it was before the
+    // instruction.
+    pub(crate) fn check_stack_collision(&mut self, effect: &Effect) -> Result<()> {
+        let brk = self.brk();
+        let Some(write) = effect.regs.iter().find(|write| {
+            write.reg == RegisterID::SP && (self.layout.heap_base..brk).contains(&write.new)
+        }) else {
+            return Ok(());
+        };
+        self.regs.set(RegisterID::SP, write.old);
+        self.pc = effect.pc;
+        Err(Error::Trap {
+            pc: effect.pc,
+            trap: Trap::HeapStackCollision { brk, sp: write.new },
+        })
+    }
+
+    // Records guest accesses that fall in the unallocated gap between the
+    // program break and the stack pointer.
+    pub(crate) fn check_heap_access(&mut self, addr: Address, len: usize, kind: AccessKind) {
+        if self.heap.diagnostics.is_none() || len == 0 {
+            return;
+        }
+        let end = addr.saturating_add(len as u32);
+        let (brk, sp) = (self.brk(), self.regs.get(&RegisterID::SP));
+        if addr < sp && end > brk {
+            let pc = self.pc.wrapping_sub(4);
+            if let Some(diagnostics) = &mut self.heap.diagnostics {
+                diagnostics.out_of_bounds_count += 1;
+                if diagnostics.out_of_bounds.len() < MAX_REPORTED_ACCESSES {
+                    diagnostics.out_of_bounds.push(HeapAccess {
+                        pc,
+                        addr,
+                        len,
+                        kind,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, Layout, Memory, Registers};
+    use claims::{assert_err_eq, assert_ok, assert_some_eq};
+
+    const ECALL: [u8; 4] = [0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111];
+    const EBREAK: [u8; 4] = [0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000];
+
+    // Makes the syscall numbered a7 with argument a0, then halts.
+    fn machine(a7: Word, a0: Word) -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        mem.write(0, &ECALL);
+        mem.write(4, &EBREAK);
+        let layout = Layout::default();
+        Machine {
+            mem,
+            regs: Registers::from([
+                (RegisterID::A0, a0),
+                (RegisterID::A1, 0x100),
+                (RegisterID::A2, 8),
+                (RegisterID::A7, a7),
+                (RegisterID::SP, layout.stack_top),
+            ]),
+            layout,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn brk_moves_the_break_within_the_heap() {
+        struct TestCase {
+            requested: Address,
+            want: Address,
+        }
+        let cases = [
+            TestCase {
+                requested: 0,
+                want: 0x0001_0000,
+            },
+            TestCase {
+                requested: 0x0001_0040,
+                want: 0x0001_0040,
+            },
+            TestCase {
+                requested: 0x0010_0000,
+                want: 0x0001_0000,
+            },
+        ];
+        for case in cases {
+            let mut machine = machine(214, case.requested);
+            assert_ok!(machine.run());
+            assert_eq!(machine.regs.get(&RegisterID::A0), case.want);
+            assert_eq!(machine.brk(), case.want);
+        }
+    }
+
+    #[test]
+    fn the_heap_and_stack_may_not_grow_into_each_other() {
+        let mut machine = machine(214, 0x0002_0000);
+        machine.regs.set(RegisterID::SP, 0x0001_8000);
+        assert_err_eq!(
+            machine.run(),
+            Error::Trap {
+                pc: 0,
+                trap: Trap::HeapStackCollision {
+                    brk: 0x0002_0000,
+                    sp: 0x0001_8000,
+                },
+            }
+        );
+        assert_eq!(machine.brk(), 0x0001_0000);
+
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(
+                &assemble(
+                    "li a0, 0x41
+                     sll a0, a0, x0, 10
+                     li a7, 214
+                     ecall
+                     mv sp, a0
+                     sub sp, sp, x0, 4
+                     ebreak",
+                )
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+        assert_err_eq!(
+            machine.run(),
+            Error::Trap {
+                pc: 20,
+                trap: Trap::HeapStackCollision {
+                    brk: 0x0001_0400,
+                    sp: 0x0001_03fc,
+                },
+            }
+        );
+        // The instruction is undone.
+        assert_eq!(machine.pc, 20);
+        assert_eq!(machine.regs.get(&RegisterID::SP), 0x0001_0400);
+    }
+
+    #[test]
+    fn heap_reports_track_the_high_water_mark_and_leaks() {
+        let mut machine = machine(214, 0x0001_0040);
+        machine.enable_heap_diagnostics(false);
+
+        assert_ok!(machine.run());
+        machine.set_brk(0x0001_0010);
+
+        assert_some_eq!(
+            machine.heap_report(),
+            HeapReport {
+                high_water: 0x40,
+                leaked: 0x10,
+                out_of_bounds: Vec::new(),
+                out_of_bounds_count: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn poisoning_fills_memory_the_heap_grows_into_and_frees() {
+        let mut machine = machine(214, 0x0001_0004);
+        machine.enable_heap_diagnostics(true);
+        machine.mem.write(0x0001_0000, &[1, 2, 3, 4, 5, 6]);
+
+        assert_ok!(machine.run());
+        assert_eq!(
+            machine.mem.read(0x0001_0000, 6),
+            vec![POISON, POISON, POISON, POISON, 5, 6]
+        );
+
+        machine.set_brk(0x0001_0002);
+        machine.mem.write(0x0001_0000, &[7, 7]);
+        assert_eq!(machine.mem.read(0x0001_0000, 4), vec![7, 7, POISON, POISON]);
+    }
+
+    #[test]
+    fn accesses_beyond_the_break_are_reported() {
+        // Formats a0 into eight bytes at 0x0001_0000, past the empty heap.
+        let mut machine = machine(257, 42);
+        machine.regs.set(RegisterID::A1, 0x0001_0000);
+        machine.enable_heap_diagnostics(false);
+
+        assert_ok!(machine.run());
+
+        let report = machine.heap_report().unwrap();
+        assert_eq!(
+            report.out_of_bounds,
+            vec![HeapAccess {
+                pc: 0,
+                addr: 0x0001_0000,
+                len: 2,
+                kind: AccessKind::Write,
+            }]
+        );
+        assert_eq!(
+            report.to_string(),
+            "heap high-water mark: 0 bytes\n\
+             heap still allocated: 0 bytes\n\
+             out-of-bounds heap write of 2 bytes at 0x00010000 (pc 0x00000000)"
+        );
+    }
+
+    #[test]
+    fn reports_list_only_the_first_out_of_bounds_accesses() {
+        let mut machine = machine(257, 42);
+        machine.enable_heap_diagnostics(false);
+        for addr in 0..100 {
+            machine.check_heap_access(0x0001_0000 + addr, 1, AccessKind::Read);
+        }
+
+        let report = machine.heap_report().unwrap();
+        assert_eq!(report.out_of_bounds.len(), MAX_REPORTED_ACCESSES);
+        assert_eq!(report.out_of_bounds_count, 100);
+        assert!(report
+            .to_string()
+            .ends_with("\nand 36 more out-of-bounds heap accesses"));
+    }
+}
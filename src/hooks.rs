@@ -0,0 +1,174 @@
+use crate::{Address, HaltReason, Instruction, Machine, Memory, Registers};
+use std::{collections::BTreeSet, fmt, io::Write};
+
+// What a step hook can see of the machine. It does not borrow the whole
+// machine, so that hooks need not know its stdout type.
+#[derive(Debug, Clone, Copy)]
+pub struct MachineView<'a> {
+    pub pc: Address,
+    pub registers: &'a Registers,
+    pub memory: &'a Memory,
+    pub retired: u64,
+}
+
+type StepHook = Box<dyn FnMut(&MachineView, &Instruction)>;
+
+// Host callbacks run around every instruction, for debuggers and tests
+// that want to look at the machine part way through a run.
+#[derive(Default)]
+pub struct StepHooks {
+    before: Vec<StepHook>,
+    after: Vec<StepHook>,
+}
+
+impl fmt::Debug for StepHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StepHooks({}, {})", self.before.len(), self.after.len())
+    }
+}
+
+// Addresses the host wants execution to stop at.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Breakpoints(BTreeSet<Address>);
+
+impl<W: Write> Machine<W> {
+    // Stops run, and anything else that steps the machine, whenever the pc
+    // reaches `addr`, before the instruction there executes. Running again
+    // carries on from it.
+    pub fn set_breakpoint(&mut self, addr: Address) {
+        self.breakpoints.0.insert(addr);
+    }
+
+    // Returns whether there was a breakpoint at `addr`.
+    pub fn clear_breakpoint(&mut self, addr: Address) -> bool {
+        self.breakpoints.0.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = Address> + '_ {
+        self.breakpoints.0.iter().copied()
+    }
+
+    // Registers a callback to run before each instruction executes, with
+    // the machine as the instruction finds it.
+    pub fn before_step(&mut self, hook: impl FnMut(&MachineView, &Instruction) + 'static) {
+        self.step_hooks.before.push(Box::new(hook));
+    }
+
+    // Registers a callback to run once each instruction has retired.
+    pub fn after_step(&mut self, hook: impl FnMut(&MachineView, &Instruction) + 'static) {
+        self.step_hooks.after.push(Box::new(hook));
+    }
+
+    pub(crate) fn run_step_hooks(&mut self, instruction: &Instruction, before: bool) {
+        let hooks = if before {
+            &mut self.step_hooks.before
+        } else {
+            &mut self.step_hooks.after
+        };
+        let view = MachineView {
+            pc: self.pc,
+            registers: &self.regs,
+            memory: &self.mem,
+            retired: self.retired,
+        };
+        for hook in hooks {
+            hook(&view, instruction);
+        }
+    }
+
+    pub(crate) fn breakpoint_hit(&self) -> Option<HaltReason> {
+        self.breakpoints
+            .0
+            .contains(&self.pc)
+            .then_some(HaltReason::HostBreakpoint { pc: self.pc })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::machine, Opcode, RegisterID, StepOutcome};
+    use claims::assert_ok_eq;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn step_executes_one_instruction_and_returns_it() {
+        let mut machine = machine("li a0, 7\nebreak");
+
+        let (instruction, outcome) = machine.step().unwrap();
+
+        assert_eq!(instruction.opcode, Opcode::LoadImmediate);
+        assert_eq!(outcome, StepOutcome::Continue);
+        assert_eq!(machine.pc(), 4);
+        assert_eq!(machine.register(RegisterID::A0), 7);
+        let (instruction, outcome) = machine.step().unwrap();
+        assert_eq!(instruction.opcode, Opcode::EBreak);
+        assert_eq!(outcome, StepOutcome::Halted(HaltReason::Breakpoint));
+    }
+
+    #[test]
+    fn runs_stop_at_breakpoints_and_carry_on_from_them() {
+        let mut machine = machine("li a0, 1\nli a0, 2\nli a0, 3\nebreak");
+        machine.set_breakpoint(8);
+
+        assert_ok_eq!(machine.run(), HaltReason::HostBreakpoint { pc: 8 });
+        assert_eq!(machine.register(RegisterID::A0), 2);
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register(RegisterID::A0), 3);
+
+        assert!(machine.clear_breakpoint(8));
+        assert!(!machine.clear_breakpoint(8));
+        assert_eq!(machine.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn an_interrupt_outlasts_a_halt_that_comes_first() {
+        let mut machine =
+            machine("li a0, 7\nli a1, 0x100\nli a2, 4\nli a7, 257\necall\nli a0, 0\nebreak");
+        let interrupt = machine.interrupt_handle();
+        machine.before_step(move |_, instruction| {
+            if instruction.opcode == Opcode::ECall {
+                interrupt.raise();
+            }
+        });
+        machine.watch(0x100, 1);
+
+        assert!(matches!(
+            machine.run(),
+            Ok(HaltReason::Watchpoint { pc: 16, .. })
+        ));
+        assert_ok_eq!(machine.run(), HaltReason::Interrupted { pc: 24 });
+    }
+
+    #[test]
+    fn hooks_see_the_machine_before_and_after_each_instruction() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut machine = machine("li a0, 5\nadd a0, a0, a0\nebreak");
+        let before = log.clone();
+        machine.before_step(move |machine, instruction| {
+            let a0 = machine.registers.get(&RegisterID::A0);
+            before
+                .borrow_mut()
+                .push((machine.pc, instruction.opcode, a0));
+        });
+        let after = log.clone();
+        machine.after_step(move |machine, instruction| {
+            let a0 = machine.registers.get(&RegisterID::A0);
+            after
+                .borrow_mut()
+                .push((machine.pc, instruction.opcode, a0));
+        });
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(
+            log.borrow()[..4],
+            [
+                (0, Opcode::LoadImmediate, 0),
+                (4, Opcode::LoadImmediate, 5),
+                (4, Opcode::Add, 5),
+                (8, Opcode::Add, 10),
+            ]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{
+    output::{errno, EIO},
+    Input, Machine, RegisterID, Result, Word,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+};
+
+// Flags for the open syscall. With neither, the file is opened for reading.
+// Writing creates the file if need be and empties it.
+pub const OPEN_WRITE: Word = 1 << 0;
+// Writing at the end of the file, creating it if need be.
+pub const OPEN_APPEND: Word = 1 << 1;
+
+const ENOENT: i32 = 2;
+const EACCES: i32 = 13;
+const EROFS: i32 = 30;
+
+// A host directory the guest may open files in, by paths relative to it,
+// so that what it reads can come from and what it writes can outlive the
+// run. Paths leaving the directory are refused, as are those outside the
+// allowlist if it is not empty; an entry allows the file or directory it
+// names and everything under it. Links inside the directory are followed
+// as the host sees them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HostDir {
+    pub root: PathBuf,
+    pub allow: Vec<PathBuf>,
+    pub read_only: bool,
+}
+
+impl HostDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            allow: Vec::new(),
+            read_only: false,
+        }
+    }
+
+    // Where the guest's `path` is on the host, or the errno refusing it.
+    fn resolve(&self, path: &str) -> std::result::Result<PathBuf, i32> {
+        let mut relative = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {}
+                _ => return Err(EACCES),
+            }
+        }
+        if !self.allow.is_empty()
+            && !self
+                .allow
+                .iter()
+                .any(|allowed| relative.starts_with(allowed))
+        {
+            return Err(EACCES);
+        }
+        Ok(self.root.join(relative))
+    }
+
+    fn open(&self, path: &str, flags: Word) -> std::result::Result<File, i32> {
+        let path = self.resolve(path)?;
+        let writing = flags & (OPEN_WRITE | OPEN_APPEND) != 0;
+        if writing && self.read_only {
+            return Err(EROFS);
+        }
+        let mut options = OpenOptions::new();
+        if flags & OPEN_APPEND != 0 {
+            options.append(true).create(true);
+        } else if writing {
+            options.write(true).create(true).truncate(true);
+        } else {
+            options.read(true);
+        }
+        options.open(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            io::ErrorKind::PermissionDenied => EACCES,
+            _ => EIO,
+        })
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Lets the guest open files in `dir` with the open syscall, in place of
+    // any directory mounted before.
+    pub fn mount(&mut self, dir: HostDir) {
+        self.files.host_dir = Some(dir);
+    }
+
+    // open(path, len, flags) opens the file at the `len` byte path, relative
+    // to the mounted directory, returning the lowest free fd from 3 up, or
+    // -ENOENT, -EACCES for a path the guest may not use (and for any path
+    // with nothing mounted), or -EROFS for writing a read-only directory.
+    pub(crate) fn open_syscall(&mut self) -> Result<()> {
+        let path = self.guest_string(RegisterID::A0, RegisterID::A1)?;
+        let flags = self.regs.get(&RegisterID::A2);
+        let fd = self.files.free_fd();
+        let origin = format!("{path} {flags:#x}");
+        if self.files.reopen(fd, &origin) {
+            self.set_register(RegisterID::A0, fd);
+            return Ok(());
+        }
+        let opened = self
+            .files
+            .host_dir
+            .as_ref()
+            .ok_or(EACCES)
+            .and_then(|dir| dir.open(&path, flags));
+        let result = match opened {
+            Ok(file) => {
+                if flags & (OPEN_WRITE | OPEN_APPEND) != 0 {
+                    self.files.open_writer(fd, Box::new(file));
+                } else {
+                    self.files.open_reader(fd, Input::new(file));
+                }
+                self.files.opened_as(&origin);
+                fd
+            }
+            Err(code) => errno(code),
+        };
+        self.set_register(RegisterID::A0, result);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, HaltReason};
+    use claims::assert_ok_eq;
+    use std::fs;
+
+    // Opens the a1 byte path at 0x200 with the flags in a2, reads or, if
+    // a3 is set, writes a5 bytes through the buffer at 0x300, and closes
+    // the file again, leaving the fd in a4 and the count in a5.
+    const OPEN: &str = "
+        li a0, 0x200
+        li a7, 274
+        ecall
+        mv a4, a0
+        li a1, 0x300
+        mv a2, a5
+        li a7, 63
+        beq a3, x0, 8
+        li a7, 64
+        ecall
+        mv a5, a0
+        mv a0, a4
+        li a7, 57
+        ecall
+        ebreak
+    ";
+
+    fn run(dir: &HostDir, path: &str, flags: Word, data: &[u8]) -> Machine<Vec<u8>> {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&assemble(OPEN).unwrap())
+            .register(RegisterID::A1, path.len() as Word)
+            .register(RegisterID::A2, flags)
+            .register(RegisterID::A3, !data.is_empty() as Word)
+            .register(
+                RegisterID::A5,
+                if data.is_empty() {
+                    16
+                } else {
+                    data.len() as Word
+                },
+            )
+            .build()
+            .unwrap();
+        machine.mount(dir.clone());
+        machine.mem.write(0x200, path.as_bytes());
+        machine.mem.write(0x300, data);
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        machine
+    }
+
+    fn dir(name: &str) -> HostDir {
+        let root = std::env::temp_dir().join(format!("rmachine-{name}-{}", std::process::id()));
+        fs::create_dir_all(root.join("data")).unwrap();
+        fs::write(root.join("data/in.txt"), b"course data").unwrap();
+        HostDir::new(root)
+    }
+
+    #[test]
+    fn guests_read_and_write_files_in_the_mounted_directory() {
+        let dir = dir("hostfs-rw");
+
+        let machine = run(&dir, "data/in.txt", 0, b"");
+        assert_eq!(machine.register(RegisterID::A4), 3);
+        assert_eq!(machine.register(RegisterID::A5), 11);
+        assert_eq!(machine.mem.read(0x300, 11), b"course data");
+
+        run(&dir, "out.txt", OPEN_WRITE, b"result");
+        run(&dir, "out.txt", OPEN_APPEND, b"s");
+        assert_eq!(fs::read(dir.root.join("out.txt")).unwrap(), b"results");
+        fs::remove_dir_all(&dir.root).unwrap();
+    }
+
+    #[test]
+    fn paths_outside_what_is_allowed_are_refused() {
+        let mut dir = dir("hostfs-deny");
+        dir.allow = vec![PathBuf::from("data")];
+        dir.read_only = true;
+
+        let cases = [
+            ("../x.txt", 0, EACCES),
+            ("/etc/pwd", 0, EACCES),
+            ("out.txt", 0, EACCES),
+            ("data/new", OPEN_WRITE, EROFS),
+            ("data/no", 0, ENOENT),
+        ];
+        for (path, flags, code) in cases {
+            let machine = run(&dir, path, flags, b"");
+            assert_eq!(machine.register(RegisterID::A4), errno(code), "{path}");
+        }
+        assert_eq!(run(&dir, "data/in.txt", 0, b"").register(RegisterID::A4), 3);
+        fs::remove_dir_all(&dir.root).unwrap();
+    }
+}
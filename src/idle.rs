@@ -0,0 +1,93 @@
+use crate::{HaltReason, Machine, StepOutcome};
+use std::io::Write;
+
+impl<W: Write> Machine<W> {
+    // Halts the machine with HaltReason::Idle when the guest waits for an
+    // interrupt that can never come: none is pending and no device or alarm
+    // has anything scheduled. An interrupt-driven guest that would otherwise
+    // sleep forever once its work is done, or because it deadlocked, then
+    // ends its run.
+    pub fn set_exit_on_idle(&mut self, enabled: bool) {
+        self.exit_on_idle = enabled;
+    }
+
+    // wfi() waits for an interrupt or signal, returning at once if one is
+    // pending. Otherwise emulated time jumps to the soonest event a device
+    // or an alarm has scheduled, which is then delivered as the syscall
+    // returns. With nothing scheduled it returns at once, or halts the
+    // machine with exit on idle set.
+    pub(crate) fn wait_for_interrupt_syscall(&mut self) -> StepOutcome {
+        if self.any_irq_pending() || self.any_signal_pending() {
+            return StepOutcome::Continue;
+        }
+        let wake = [self.next_device_event(), self.next_alarm()]
+            .into_iter()
+            .flatten()
+            .min();
+        match wake {
+            Some(at) => {
+                self.advance_time(at.saturating_sub(self.cycles));
+                StepOutcome::Continue
+            }
+            None if self.exit_on_idle => StepOutcome::Halted(HaltReason::Idle {
+                pc: self.pc.wrapping_sub(4),
+            }),
+            None => StepOutcome::Continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, irq::Timer, RegisterID};
+    use claims::assert_ok_eq;
+
+    // Handles interrupts at 24, counting them in a1, and sleeps in wfi
+    // between them.
+    const SLEEPER: &str = "
+                li a0, 24
+                li a7, 267
+                ecall
+        sleep:  li a7, 278
+                ecall
+                j sleep
+        handler:
+                add a1, a1, x0, 1
+                mret
+    ";
+
+    fn sleeper() -> Machine<Vec<u8>> {
+        let mut machine = Machine::builder()
+            .program(&assemble(SLEEPER).unwrap())
+            .build()
+            .unwrap();
+        machine.set_exit_on_idle(true);
+        machine
+    }
+
+    #[test]
+    fn a_guest_waiting_with_nothing_scheduled_is_idle() {
+        let mut machine = sleeper();
+        machine.raise_irq(3);
+
+        assert_ok_eq!(machine.run(), HaltReason::Idle { pc: 16 });
+        assert_eq!(machine.register(RegisterID::A1), 1);
+        assert_eq!(
+            HaltReason::Idle { pc: 16 }.to_string(),
+            "deadlock at pc 0x00000010: waiting for an interrupt with none pending or scheduled"
+        );
+    }
+
+    #[test]
+    fn waiting_skips_to_the_next_device_event() {
+        let mut machine = sleeper();
+        machine.attach_device(Timer::new(0, 1000));
+
+        assert_ok_eq!(machine.run_for(5500), StepOutcome::Continue);
+        // Each interrupt takes a handful of instructions rather than the
+        // thousand cycles between them.
+        assert_eq!(machine.register(RegisterID::A1), 5);
+        assert!(machine.retired < 50);
+    }
+}
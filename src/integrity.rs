@@ -0,0 +1,221 @@
+use crate::{
+    loader::Segment, manifest::Digest, shadow::ShadowMemory, trap::Trap, Address, Error, Machine,
+    Result,
+};
+use std::io::Write;
+
+// The program image as it was loaded, for telling whether the guest has
+// written over it since.
+#[derive(Debug, Default)]
+pub(crate) struct Image {
+    // The bytes each segment's file data put in memory, by address.
+    segments: Vec<(Address, Vec<u8>)>,
+    // With the check on, the bytes instructions have been fetched from.
+    fetched: Option<ShadowMemory<bool>>,
+    self_modifying: bool,
+}
+
+impl Image {
+    pub(crate) fn loaded(segments: &[Segment]) -> Self {
+        Self {
+            segments: segments
+                .iter()
+                .map(|segment| (segment.addr, segment.data.clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // Where each segment's file data is, and its length.
+    pub(crate) fn extents(&self) -> Vec<(Address, usize)> {
+        self.segments
+            .iter()
+            .map(|(addr, data)| (*addr, data.len()))
+            .collect()
+    }
+
+    // A digest of each segment's address and what `read` finds there.
+    fn hash(&self, read: impl Fn(Address, &[u8]) -> Vec<u8>) -> u64 {
+        let mut digest = Digest::default();
+        for (addr, data) in &self.segments {
+            digest.update(&addr.to_be_bytes());
+            digest.update(&read(*addr, data));
+        }
+        digest.finish()
+    }
+}
+
+// Consecutive addresses, gathered into runs of where each starts and its
+// length.
+fn runs(addrs: impl Iterator<Item = Address>) -> Vec<(Address, usize)> {
+    let mut runs: Vec<(Address, usize)> = Vec::new();
+    for addr in addrs {
+        match runs.last_mut() {
+            Some((start, len)) if start.wrapping_add(*len as Address) == addr => *len += 1,
+            _ => runs.push((addr, 1)),
+        }
+    }
+    runs
+}
+
+impl<W: Write> Machine<W> {
+    // A digest of the program image as loaded: the address and file bytes
+    // of each segment, in order.
+    pub fn image_hash(&self) -> u64 {
+        self.image.hash(|_, data| data.to_vec())
+    }
+
+    // The same digest of what memory now holds where the image was loaded,
+    // which differs from image_hash once the guest writes over it.
+    pub fn current_image_hash(&self) -> u64 {
+        self.image
+            .hash(|addr, data| self.mem.read(addr, data.len()))
+    }
+
+    // The parts of the image memory no longer holds as loaded.
+    pub fn modified_image(&self) -> Vec<(Address, usize)> {
+        runs(self.modified_image_bytes())
+    }
+
+    // Checks when a run ends that no instruction it executed came from a
+    // part of the image the guest had written over, halting it with
+    // Trap::ImageModified otherwise. Code rewriting itself by accident
+    // then shows up where it happened rather than as strange behaviour
+    // later. Data in the image may change freely; only bytes fetched as
+    // instructions count.
+    pub fn set_image_check(&mut self, enabled: bool) {
+        self.image.fetched = enabled.then(ShadowMemory::new);
+    }
+
+    // Declares that the guest rewrites its own code on purpose, which the
+    // image check then allows.
+    pub fn set_self_modifying(&mut self, self_modifying: bool) {
+        self.image.self_modifying = self_modifying;
+    }
+
+    fn modified_image_bytes(&self) -> impl Iterator<Item = Address> + '_ {
+        self.image.segments.iter().flat_map(|(addr, data)| {
+            let now = self.mem.read(*addr, data.len());
+            (0..data.len())
+                .filter(move |offset| now[*offset] != data[*offset])
+                .map(move |offset| addr.wrapping_add(offset as Address))
+        })
+    }
+
+    // Notes the `len` byte instruction at pc being executed.
+    pub(crate) fn note_fetch(&mut self, len: usize) {
+        if let Some(fetched) = &mut self.image.fetched {
+            fetched.fill(self.pc, len, true);
+        }
+    }
+
+    pub(crate) fn check_image(&self) -> Result<()> {
+        let Some(fetched) = self.image.fetched.as_ref() else {
+            return Ok(());
+        };
+        if self.image.self_modifying {
+            return Ok(());
+        }
+        let modified = runs(
+            self.modified_image_bytes()
+                .filter(|addr| fetched.get(*addr)),
+        );
+        if modified.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Trap {
+            pc: self.pc,
+            trap: Trap::ImageModified { modified },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::machine, HaltReason};
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    // Overwrites the `mv a5, a2` at 20 with the `li a5, 9` at 28 before
+    // running it, and changes the data word at 32.
+    const PATCHING: &str = "lw a2, 28
+                            sw a2, 20
+                            li a3, 3
+                            sw a3, 32
+                            j 4
+                            mv a5, a2
+                            ebreak
+                            li a5, 9
+                            .word 0";
+
+    #[test]
+    fn image_hashes_follow_writes_to_the_image() {
+        let mut machine = machine(PATCHING);
+        let loaded = machine.image_hash();
+        assert_eq!(machine.current_image_hash(), loaded);
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register(crate::RegisterID::A5), 9);
+        assert_eq!(machine.image_hash(), loaded);
+        assert_ne!(machine.current_image_hash(), loaded);
+        assert_eq!(machine.modified_image(), [(21, 3), (35, 1)]);
+        assert_eq!(
+            Trap::ImageModified {
+                modified: machine.modified_image()
+            }
+            .to_string(),
+            "executed code modified since it was loaded: 0x00000015..0x00000018, 0x00000023..0x00000024"
+        );
+    }
+
+    #[test]
+    fn code_runs_as_last_written() {
+        // Patches the instruction straight after the store, then the
+        // immediate of the add in the loop each time round, so that it adds
+        // 1, 2 and 3.
+        let mut machine = machine(
+            "lw a2, 16
+             sw a2, 8
+             mv a5, x0
+             j 8
+             li a5, 9
+             li a3, 3
+             li a6, 1
+             sll a6, a6, x0, 17
+             loop: add a4, a4, x0, 1
+             lw a2, 32
+             add a2, a2, a6
+             sw a2, 32
+             sub a3, a3, x0, 1
+             bne a3, x0, loop
+             ebreak",
+        );
+        machine.set_image_check(true);
+        machine.set_self_modifying(true);
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register(crate::RegisterID::A5), 9);
+        assert_eq!(machine.register(crate::RegisterID::A4), 6);
+    }
+
+    #[test]
+    fn executing_modified_code_fails_the_check() {
+        let mut machine = machine(PATCHING);
+        machine.set_image_check(true);
+
+        assert_err_eq!(
+            machine.run(),
+            Error::Trap {
+                pc: 28,
+                trap: Trap::ImageModified {
+                    modified: vec![(21, 3)],
+                },
+            }
+        );
+
+        let mut machine = self::machine(PATCHING);
+        machine.set_image_check(true);
+        machine.set_self_modifying(true);
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+    }
+}
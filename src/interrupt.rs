@@ -0,0 +1,135 @@
+use crate::{HaltReason, Machine, Word};
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// A handle for stopping a running machine from elsewhere, such as a console
+// reader that sees Ctrl-C or another thread. The machine halts after the
+// instruction it is executing when the interrupt is raised.
+#[derive(Debug, Default, Clone)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn raise(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn interrupt_handle(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    // Has interrupts post `signal` to the guest rather than halt it, so that
+    // a guest with a handler for it can clean up when the user presses
+    // Ctrl-C. An interrupt still halts the machine if the guest has no
+    // handler for the signal, or has not yet handled the one posted by the
+    // interrupt before, so pressing Ctrl-C twice stops a guest that is
+    // stuck. None goes back to halting.
+    pub fn forward_interrupts(&mut self, signal: Option<Word>) {
+        self.interrupt_signal = signal;
+    }
+
+    // Clears a pending interrupt, returning the halt it asked for.
+    pub(crate) fn take_interrupt(&mut self) -> Option<HaltReason> {
+        if !self.interrupt.take() {
+            return None;
+        }
+        let forwarded = self.interrupt_signal.is_some_and(|signal| {
+            !self.signal_pending(signal) && !self.in_signal_handler() && self.post_signal(signal)
+        });
+        (!forwarded).then_some(HaltReason::Interrupted { pc: self.pc })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, signal::SIGINT, Memory, RegisterID, StepOutcome};
+    use claims::assert_ok_eq;
+
+    #[test]
+    fn a_raised_interrupt_halts_after_the_current_instruction() {
+        // add a0, a0, x0, 1; ebreak
+        let mut mem = Memory::default();
+        mem.write(0, &[0b0000_0000, 0b0000_0010, 0b0000_0010, 0b0010_0010]);
+        mem.write(4, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem,
+            ..Default::default()
+        };
+
+        machine.interrupt_handle().raise();
+
+        assert_ok_eq!(
+            machine.step().map(|(_, outcome)| outcome),
+            StepOutcome::Halted(HaltReason::Interrupted { pc: 4 })
+        );
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+    }
+
+    // Installs the handler at 28 for SIGINT and spins until it has set the
+    // word at 0x2000. With a5 set, the handler never returns.
+    const CLEANUP: &str = "
+                li a0, 2
+                li a1, 28
+                li a7, 275
+                ecall
+        spin:   lw a3, 0x2000
+                beq a3, x0, spin
+                ebreak
+        handler:
+                li a3, 1
+                sw a3, 0x2000
+        stuck:  bne a5, x0, stuck
+                li a7, 276
+                ecall
+    ";
+
+    fn forwarding(stuck: bool) -> Machine<Vec<u8>> {
+        let mut machine = Machine::builder()
+            .program(&assemble(CLEANUP).unwrap())
+            .register(RegisterID::A5, stuck as Word)
+            .build()
+            .unwrap();
+        machine.forward_interrupts(Some(SIGINT));
+        for _ in 0..6 {
+            machine.step().unwrap();
+        }
+        machine
+    }
+
+    #[test]
+    fn forwarded_interrupts_reach_the_guest_handler() {
+        let mut machine = forwarding(false);
+        machine.interrupt_handle().raise();
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.mem.read(0x2000, 4), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn a_second_interrupt_halts_a_guest_still_handling_the_first() {
+        let mut machine = forwarding(true);
+        machine.interrupt_handle().raise();
+        for _ in 0..4 {
+            machine.step().unwrap();
+        }
+        assert!(machine.in_signal_handler());
+
+        machine.interrupt_handle().raise();
+        assert!(matches!(machine.run(), Ok(HaltReason::Interrupted { .. })));
+    }
+}
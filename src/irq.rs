@@ -0,0 +1,458 @@
+use crate::{
+    device::{Bus, Device},
+    observer::Event,
+    Address, HaltReason, Machine, RegisterID, StepOutcome, Word,
+};
+use std::{collections::BTreeMap, io::Write};
+
+// Delivery latencies of one interrupt line, in cycles from the interrupt
+// being raised to the first instruction of its handler.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Latency {
+    pub delivered: u64,
+    pub min: u64,
+    pub max: u64,
+    total: u64,
+}
+
+impl Latency {
+    pub(crate) fn record(&mut self, cycles: u64) {
+        if self.delivered == 0 {
+            self.min = cycles;
+        }
+        self.delivered += 1;
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.total += cycles;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.delivered == 0 {
+            return 0.0;
+        }
+        self.total as f64 / self.delivered as f64
+    }
+
+    // How far apart the quickest and slowest deliveries were.
+    pub fn jitter(&self) -> u64 {
+        self.max - self.min
+    }
+}
+
+// The priority lines have until one is set, above the level code runs at
+// outside any handler. A line with priority 0 is never delivered.
+pub const DEFAULT_PRIORITY: u8 = 1;
+
+// Set in the status register, no interrupt is delivered whatever its
+// priority.
+pub const STATUS_MASKED: Word = 1 << 8;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Frame {
+    line: u8,
+    // Where the interrupted code carries on once the handler returns.
+    epc: Address,
+    // The status register as the interrupted code had it.
+    status: Word,
+}
+
+// Interrupts raised for the guest to handle, as opposed to Interrupt, which
+// stops the machine. A raised line stays pending until it is delivered:
+// while the guest has a handler installed, the most urgent pending line is
+// delivered between instructions by jumping to the handler, and mret
+// returns to the interrupted code.
+//
+// Whether a line can be delivered depends on the status register. Its low
+// byte is the level the hart runs at, 0 outside any handler, and only lines
+// of a higher priority are delivered; STATUS_MASKED holds off every line.
+// Delivery stacks the status register and raises the level to the line's
+// priority, so a handler is preempted only by more urgent lines, and mret
+// restores it.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Irqs {
+    handler: Option<Address>,
+    // The cycle each pending line was first raised at.
+    pending: BTreeMap<u8, u64>,
+    // Lines without an entry have DEFAULT_PRIORITY.
+    priorities: BTreeMap<u8, u8>,
+    status: Word,
+    active: Vec<Frame>,
+    latency: BTreeMap<u8, Latency>,
+}
+
+impl Irqs {
+    fn raise(&mut self, line: u8, at: u64) {
+        let asserted = self.pending.entry(line).or_insert(at);
+        *asserted = (*asserted).min(at);
+    }
+
+    fn priority(&self, line: u8) -> u8 {
+        self.priorities
+            .get(&line)
+            .copied()
+            .unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    fn set_priority(&mut self, line: u8, priority: u8) -> u8 {
+        self.priorities
+            .insert(line, priority)
+            .unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    // The pending line to deliver now, if any: the one of the highest
+    // priority, and of those the lowest numbered.
+    fn next(&self) -> Option<u8> {
+        if self.handler.is_none() || self.status & STATUS_MASKED != 0 {
+            return None;
+        }
+        let level = self.status as u8;
+        self.pending
+            .keys()
+            .copied()
+            .filter(|&line| self.priority(line) > level)
+            .min_by_key(|&line| (std::cmp::Reverse(self.priority(line)), line))
+    }
+
+    pub(crate) fn reset(&mut self) {
+        let latency = std::mem::take(&mut self.latency);
+        *self = Self {
+            latency,
+            ..Default::default()
+        };
+    }
+}
+
+// What devices see of the machine while they are brought up to date.
+pub(crate) struct DeviceBus<'a> {
+    pub mem: &'a mut dyn Bus,
+    pub irqs: &'a mut Irqs,
+}
+
+impl Bus for DeviceBus<'_> {
+    fn read(&self, addr: Address, len: usize) -> Vec<u8> {
+        self.mem.read(addr, len)
+    }
+
+    fn write(&mut self, addr: Address, data: &[u8]) {
+        self.mem.write(addr, data);
+    }
+
+    fn raise_irq(&mut self, line: u8, at: u64) {
+        self.irqs.raise(line, at);
+    }
+}
+
+// Raises an interrupt line every `period` cycles, starting one period in.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Timer {
+    line: u8,
+    period: u64,
+    next: u64,
+}
+
+impl Timer {
+    pub fn new(line: u8, period: u64) -> Self {
+        let period = period.max(1);
+        Self {
+            line,
+            period,
+            next: period,
+        }
+    }
+}
+
+impl Device for Timer {
+    fn advance(&mut self, now: u64, bus: &mut dyn Bus) {
+        while self.next <= now {
+            bus.raise_irq(self.line, self.next);
+            self.next += self.period;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        Some(self.next)
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Raises interrupt `line` for the guest, as a device would.
+    pub fn raise_irq(&mut self, line: u8) {
+        self.irqs.raise(line, self.cycles);
+    }
+
+    pub fn irq_pending(&self, line: u8) -> bool {
+        self.irqs.pending.contains_key(&line)
+    }
+
+    pub(crate) fn any_irq_pending(&self) -> bool {
+        !self.irqs.pending.is_empty()
+    }
+
+    // Sets the priority of `line`, returning its previous one.
+    pub fn set_irq_priority(&mut self, line: u8, priority: u8) -> u8 {
+        self.irqs.set_priority(line, priority)
+    }
+
+    pub fn irq_status(&self) -> Word {
+        self.irqs.status
+    }
+
+    // How many handlers are running, each preempted by the next.
+    pub fn irq_depth(&self) -> usize {
+        self.irqs.active.len()
+    }
+
+    // Delivery latencies so far, by line.
+    pub fn irq_latency(&self) -> &BTreeMap<u8, Latency> {
+        &self.irqs.latency
+    }
+
+    // Enters the handler for the most urgent pending line, if the guest
+    // can take it now.
+    pub(crate) fn deliver_irq(&mut self) {
+        let (Some(handler), Some(line)) = (self.irqs.handler, self.irqs.next()) else {
+            return;
+        };
+        let asserted = self.irqs.pending.remove(&line).unwrap_or(self.cycles);
+        let latency = self.cycles.saturating_sub(asserted);
+        self.irqs.latency.entry(line).or_default().record(latency);
+        self.count_irq(line, latency);
+        self.observers.notify(&Event::InterruptTaken {
+            line,
+            cycle: self.cycles,
+        });
+        self.irqs.active.push(Frame {
+            line,
+            epc: self.pc,
+            status: self.irqs.status,
+        });
+        self.irqs.status = Word::from(self.irqs.priority(line));
+        self.set_pc(handler);
+    }
+
+    // mret: returns from the interrupt being handled.
+    pub(crate) fn return_from_irq(&mut self) -> StepOutcome {
+        match self.irqs.active.pop() {
+            Some(frame) => {
+                self.irqs.status = frame.status;
+                self.set_pc(frame.epc);
+                StepOutcome::Continue
+            }
+            None => StepOutcome::Halted(HaltReason::StrayInterruptReturn {
+                pc: self.pc.wrapping_sub(4),
+            }),
+        }
+    }
+
+    // irq_handler(handler) installs the handler interrupts are delivered
+    // to, or with 0 stops delivering them, and returns the previous one.
+    pub(crate) fn irq_handler_syscall(&mut self) {
+        let handler = self.regs.get(&RegisterID::A0);
+        let previous = std::mem::replace(&mut self.irqs.handler, (handler != 0).then_some(handler));
+        self.set_register(RegisterID::A0, previous.unwrap_or_default());
+    }
+
+    // irq_claim() returns the line of the interrupt being handled, or -1
+    // outside a handler.
+    pub(crate) fn irq_claim_syscall(&mut self) {
+        let line = self
+            .irqs
+            .active
+            .last()
+            .map_or(Word::MAX, |frame| frame.line.into());
+        self.set_register(RegisterID::A0, line);
+    }
+
+    // irq_priority(line, priority) sets the priority of `line`, returning
+    // its previous one.
+    pub(crate) fn irq_priority_syscall(&mut self) {
+        let line = self.regs.get(&RegisterID::A0) as u8;
+        let priority = self.regs.get(&RegisterID::A1) as u8;
+        let previous = self.irqs.set_priority(line, priority);
+        self.set_register(RegisterID::A0, previous.into());
+    }
+
+    // irq_status(status) replaces the status register, returning the
+    // previous value, so that code can raise its level or mask interrupts
+    // for a critical section and put it back afterwards.
+    pub(crate) fn irq_status_syscall(&mut self) {
+        let status = self.regs.get(&RegisterID::A0) & (STATUS_MASKED | 0xff);
+        let previous = std::mem::replace(&mut self.irqs.status, status);
+        self.set_register(RegisterID::A0, previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::machine;
+    use claims::assert_ok_eq;
+
+    // Counts interrupts in a1 while spinning until the count reaches a2,
+    // with the handler, at 24, recording the line it served in a3.
+    const COUNTER: &str = "
+                li a0, 24
+                li a7, 267
+                ecall
+                li a2, 3
+        spin:   blt a1, a2, spin
+                ebreak
+        handler:
+                li a7, 268
+                ecall
+                mv a3, a0
+                add a1, a1, x0, 1
+                mret
+    ";
+
+    #[test]
+    fn timer_interrupts_run_the_handler_and_return() {
+        let mut machine = machine(COUNTER);
+        machine.attach_device(Timer::new(5, 20));
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.register(RegisterID::A1), 3);
+        assert_eq!(machine.register(RegisterID::A3), 5);
+        // The timer fires between instructions, so each interrupt is taken
+        // as soon as it is raised.
+        let latency = machine.irq_latency()[&5];
+        assert_eq!((latency.delivered, latency.min, latency.max), (3, 0, 0));
+    }
+
+    #[test]
+    fn the_instruction_an_interrupt_follows_records_entering_the_handler() {
+        let mut machine = machine(COUNTER);
+        machine.record_effects(true);
+        assert_ok_eq!(machine.run_for(3), StepOutcome::Continue);
+        machine.raise_irq(2);
+
+        assert_ok_eq!(machine.run_for(1), StepOutcome::Continue);
+
+        let effect = machine.last_effect().unwrap();
+        assert_eq!((effect.pc, effect.next_pc), (12, 24));
+    }
+
+    #[test]
+    fn latency_counts_the_time_interrupts_wait() {
+        let mut machine = machine(COUNTER);
+        // One interrupt is raised before the handler is installed and waits
+        // for it, and another arrives during the first one's handler.
+        machine.raise_irq(1);
+        assert_ok_eq!(machine.run_for(3), StepOutcome::Continue);
+        assert!(!machine.irq_pending(1));
+        machine.raise_irq(2);
+        machine.raise_irq(2);
+        assert_ok_eq!(machine.run_for(5), StepOutcome::Continue);
+        machine.raise_irq(3);
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        let latency = machine.irq_latency();
+        assert_eq!(latency[&1].max, 3);
+        // Raised as the handler for line 1 started, and delivered after
+        // its five instructions.
+        assert_eq!(latency[&2].max, 5);
+        assert_eq!(latency[&2].delivered, 1);
+        assert_eq!(latency[&3].delivered, 1);
+    }
+
+    #[test]
+    fn jitter_is_the_spread_of_latencies() {
+        let mut latency = Latency::default();
+        for cycles in [4, 2, 9] {
+            latency.record(cycles);
+        }
+
+        assert_eq!((latency.min, latency.max, latency.jitter()), (2, 9, 7));
+        assert!((latency.mean() - 5.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn higher_priority_interrupts_preempt_lower_ones() {
+        let mut machine = machine(
+            "
+                    li a0, 32
+                    li a7, 267
+                    ecall
+                    li a0, 2
+                    li a1, 5
+                    li a7, 269
+                    ecall
+            spin:   j spin
+            handler:
+                    li a7, 268
+                    ecall
+                    add a3, a3, x0, 1
+                    mret
+            ",
+        );
+        assert_ok_eq!(machine.run_for(8), StepOutcome::Continue);
+        machine.raise_irq(1);
+        assert_ok_eq!(machine.run_for(3), StepOutcome::Continue);
+        assert_eq!(machine.register(RegisterID::A0), 1);
+        assert_eq!(machine.irq_status(), 1);
+
+        // Line 2 preempts the handler for line 1 as soon as it is raised.
+        machine.raise_irq(2);
+        assert_ok_eq!(machine.run_for(3), StepOutcome::Continue);
+        assert_eq!(machine.register(RegisterID::A0), 2);
+        assert_eq!((machine.irq_depth(), machine.irq_status()), (2, 5));
+
+        // Line 3 has the priority of line 1, so waits for its handler.
+        machine.raise_irq(3);
+        assert_ok_eq!(machine.run_for(2), StepOutcome::Continue);
+        assert_eq!((machine.irq_depth(), machine.irq_status()), (1, 1));
+        assert!(machine.irq_pending(3));
+        assert_ok_eq!(machine.run_for(1), StepOutcome::Continue);
+        assert!(!machine.irq_pending(3));
+        assert_eq!((machine.irq_depth(), machine.irq_status()), (1, 1));
+        assert_eq!(machine.register(RegisterID::A3), 2);
+    }
+
+    #[test]
+    fn the_status_register_holds_off_interrupts() {
+        let mut machine = machine(
+            "
+                    li a0, 40
+                    li a7, 267
+                    ecall
+                    li a0, 0x100
+                    li a7, 270
+                    ecall
+                    ebreak
+                    li a7, 270
+                    ecall
+                    ebreak
+            handler:
+                    add a1, a1, x0, 1
+                    mret
+            ",
+        );
+        machine.raise_irq(1);
+        assert_eq!(machine.set_irq_priority(2, 0), DEFAULT_PRIORITY);
+        machine.raise_irq(2);
+
+        // Line 1 is taken once the handler is installed, but raised again
+        // after the guest masks interrupts, it waits.
+        assert_ok_eq!(machine.run_for(8), StepOutcome::Continue);
+        assert_eq!(machine.register(RegisterID::A1), 1);
+        machine.raise_irq(1);
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.irq_status(), STATUS_MASKED);
+        assert!(machine.irq_pending(1));
+
+        // Putting the status back lets it through, but line 2 has priority
+        // 0 and is never delivered.
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register(RegisterID::A1), 2);
+        assert!(machine.irq_pending(2));
+    }
+
+    #[test]
+    fn mret_outside_a_handler_halts() {
+        let mut machine = machine("li a0, 1\nmret");
+
+        assert_ok_eq!(machine.run(), HaltReason::StrayInterruptReturn { pc: 4 });
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{Instruction, Opcode, RegisterID, Word};
+
+// Problems with the instruction encoding tables, found by decoding every
+// value of the opcode field and encoding every opcode.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Audit {
+    // Opcode field values claimed by more than one opcode.
+    pub overlapping: Vec<(Word, Vec<Opcode>)>,
+    // Opcode field values that do not decode, and so are free to allocate.
+    pub unused: Vec<Word>,
+    // Field values that decode to an opcode which encodes differently, or
+    // which is missing from Opcode::ALL.
+    pub mismatched: Vec<(Word, Opcode)>,
+    // Instructions that decode differently after being encoded.
+    pub lossy: Vec<Instruction>,
+}
+
+impl Audit {
+    // Unused encoding space is expected, so it does not count as a problem.
+    pub fn is_clean(&self) -> bool {
+        self.overlapping.is_empty() && self.mismatched.is_empty() && self.lossy.is_empty()
+    }
+}
+
+// Immediates at the edges of each interpretation of the 15-bit field: as an
+// unsigned value, and as a sign-extended offset.
+const BOUNDARY_IMMEDIATES: [u16; 6] = [0, 1, 0x3fff, 0x4000, 0x7ffe, 0x7fff];
+
+pub fn audit() -> Audit {
+    let mut audit = Audit::default();
+    for code in 0..32 {
+        let claimed: Vec<Opcode> = Opcode::ALL
+            .into_iter()
+            .filter(|opcode| opcode.code() == code)
+            .collect();
+        if claimed.len() > 1 {
+            audit.overlapping.push((code, claimed));
+        }
+        match Opcode::try_from(code) {
+            Ok(opcode) if opcode.code() != code || !Opcode::ALL.contains(&opcode) => {
+                audit.mismatched.push((code, opcode));
+            }
+            Ok(_) => {}
+            Err(_) => audit.unused.push(code),
+        }
+    }
+    for opcode in Opcode::ALL {
+        for imm in BOUNDARY_IMMEDIATES {
+            let instruction = Instruction {
+                opcode,
+                rd: RegisterID::SP,
+                rs1: RegisterID::A12,
+                rs2: RegisterID::RA,
+                imm,
+            };
+            let decoded = Instruction::try_from(instruction.encode());
+            if decoded.map_or(true, |decoded| {
+                decoded != instruction || decoded.offset() != instruction.offset()
+            }) {
+                audit.lossy.push(instruction);
+            }
+        }
+    }
+    audit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_encoding_tables_are_consistent() {
+        let audit = audit();
+        assert!(audit.is_clean(), "{audit:#?}");
+        assert_eq!(audit.unused.len(), 32 - Opcode::ALL.len());
+    }
+
+    #[test]
+    fn immediates_wider_than_the_field_are_reported() {
+        let instruction = Instruction {
+            opcode: Opcode::LoadImmediate,
+            rd: RegisterID::A0,
+            rs1: RegisterID::X0,
+            rs2: RegisterID::X0,
+            imm: 0x8000,
+        };
+        assert_ne!(Instruction::try_from(instruction.encode()), Ok(instruction));
+    }
+}
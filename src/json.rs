@@ -0,0 +1,178 @@
+use std::{collections::BTreeMap, fmt::Write as _};
+
+// Just enough JSON for the reports the tools write and read back: batch
+// and stats reports and comparisons of them.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    // Kept as written, so counts beyond what an f64 holds exactly survive.
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.get(key),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(number) => number.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+// `text` as a JSON string, quotes and all.
+pub(crate) fn string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Parses a document holding one value, returning None if it is not JSON.
+pub(crate) fn parse(text: &str) -> Option<Value> {
+    let (value, rest) = parse_value(text.trim_start())?;
+    rest.trim().is_empty().then_some(value)
+}
+
+fn parse_value(text: &str) -> Option<(Value, &str)> {
+    for (literal, value) in [
+        ("null", Value::Null),
+        ("true", Value::Bool(true)),
+        ("false", Value::Bool(false)),
+    ] {
+        if let Some(rest) = text.strip_prefix(literal) {
+            return Some((value, rest));
+        }
+    }
+    match text.chars().next()? {
+        '"' => parse_string(text).map(|(value, rest)| (Value::String(value), rest)),
+        '[' => {
+            let mut items = Vec::new();
+            let rest = parse_members(&text[1..], ']', |text| {
+                let (item, rest) = parse_value(text)?;
+                items.push(item);
+                Some(rest)
+            })?;
+            Some((Value::Array(items), rest))
+        }
+        '{' => {
+            let mut members = BTreeMap::new();
+            let rest = parse_members(&text[1..], '}', |text| {
+                let (key, rest) = parse_string(text)?;
+                let rest = rest.trim_start().strip_prefix(':')?;
+                let (value, rest) = parse_value(rest.trim_start())?;
+                members.insert(key, value);
+                Some(rest)
+            })?;
+            Some((Value::Object(members), rest))
+        }
+        '-' | '0'..='9' => {
+            let end = text
+                .find(|c: char| !matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+                .unwrap_or(text.len());
+            let (number, rest) = text.split_at(end);
+            number
+                .parse::<f64>()
+                .ok()
+                .map(|_| (Value::Number(number.to_string()), rest))
+        }
+        _ => None,
+    }
+}
+
+// Parses the comma-separated members of an array or object up to `close`,
+// each with `member`, returning what follows.
+fn parse_members<'a>(
+    text: &'a str,
+    close: char,
+    mut member: impl FnMut(&'a str) -> Option<&'a str>,
+) -> Option<&'a str> {
+    let mut rest = text.trim_start();
+    if let Some(rest) = rest.strip_prefix(close) {
+        return Some(rest);
+    }
+    loop {
+        rest = member(rest)?.trim_start();
+        if let Some(after) = rest.strip_prefix(close) {
+            return Some(after);
+        }
+        rest = rest.strip_prefix(',')?.trim_start();
+    }
+}
+
+// Parses a string starting at its opening quote, returning it and what
+// follows the closing quote.
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &text[i + 2..])),
+            '\\' => value.push(match chars.next()?.1 {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    let hex: String = (0..4)
+                        .map(|_| chars.next().map(|(_, c)| c))
+                        .collect::<Option<_>>()?;
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                c @ ('"' | '\\' | '/') => c,
+                _ => return None,
+            }),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_parse_back_to_what_was_written() {
+        let text = format!(
+            "{{\"name\": {}, \"counts\": [1, -2.5e3, 18446744073709551615], \"ok\": true, \"none\": null, \"empty\": {{}}}}",
+            string("a \"q\"\n\u{1}")
+        );
+        let value = parse(&text).unwrap();
+
+        assert_eq!(
+            value.get("name"),
+            Some(&Value::String("a \"q\"\n\u{1}".to_string()))
+        );
+        let Some(Value::Array(counts)) = value.get("counts") else {
+            panic!("counts is not an array");
+        };
+        assert_eq!(counts[2].as_u64(), Some(u64::MAX));
+        assert_eq!(counts[1], Value::Number("-2.5e3".to_string()));
+        assert_eq!(value.get("ok"), Some(&Value::Bool(true)));
+        assert_eq!(value.get("none"), Some(&Value::Null));
+        assert_eq!(value.get("empty"), Some(&Value::Object(BTreeMap::new())));
+        assert_eq!(parse("[1, 2,]"), None);
+        assert_eq!(parse("{\"a\": 1} x"), None);
+    }
+}
@@ -1,62 +1,744 @@
 #![allow(unused, clippy::cast_lossless, clippy::cast_possible_truncation)]
-use std::{collections::HashMap, io::Write, num::TryFromIntError};
+// CI runs clippy::pedantic. Of its lints, these go against how the crate is
+// written: comments are plain `//` ones rather than rustdoc with Errors and
+// Panics sections, and results are not marked must_use one by one.
+#![allow(
+    clippy::must_use_candidate,
+    clippy::return_self_not_must_use,
+    clippy::missing_errors_doc,
+    clippy::missing_panics_doc
+)]
+// An emulator reinterprets words as signed and back, and counts in u64
+// where precision beyond 2^52 does not matter for reporting, on purpose.
+#![allow(
+    clippy::cast_sign_loss,
+    clippy::cast_possible_wrap,
+    clippy::cast_precision_loss
+)]
+// Registers are named rs1 and rs2 and passed as &RegisterID throughout the
+// public API; instruction and syscall dispatch are long matches; and
+// configuration structs have several flags.
+#![allow(
+    clippy::similar_names,
+    clippy::trivially_copy_pass_by_ref,
+    clippy::too_many_lines,
+    clippy::struct_excessive_bools
+)]
+// Decoders and state machines are easier to check against their spec with
+// one arm per case, Debug impls for machine parts summarize them rather
+// than dump every field, and text is built up with format!, io::Write
+// being the Write in scope nearly everywhere.
+#![allow(
+    clippy::match_same_arms,
+    clippy::missing_fields_in_debug,
+    clippy::format_push_string,
+    clippy::format_collect
+)]
+pub mod abi;
+pub mod alignment;
+pub mod asm;
+pub mod bank;
+pub mod batch;
+pub mod boot;
+pub mod branches;
+pub mod callstack;
+pub mod capture;
+mod checkpoint;
+pub mod cli;
+pub mod clock;
+pub mod concolic;
+pub mod console;
+pub mod cosim;
+pub mod debugger;
+pub mod device;
+pub mod disasm;
+pub mod effect;
+pub mod endian;
+pub mod energy;
+mod exit;
+mod expr;
+mod fd;
+pub mod fetch;
+mod freeze;
+pub mod fuel;
+pub mod fusion;
+pub mod heap;
+pub mod hooks;
+pub mod hostfs;
+mod idle;
+mod integrity;
+pub mod interrupt;
+pub mod irq;
+pub mod isa;
+mod json;
+pub mod limits;
+pub mod link;
+pub mod loader;
+pub mod logging;
+pub mod manifest;
+mod mapping;
+pub mod memmap;
+pub mod minimize;
+pub mod observer;
+pub mod output;
+mod overflow;
+pub mod patch;
+pub mod perf;
+pub mod pipe;
+pub mod poll;
+pub mod pool;
+pub mod profile;
+pub mod regbank;
+mod reload;
+pub mod scripting;
+pub mod semantics;
+mod shadow;
+pub mod signal;
+pub mod snapshot;
+pub mod state;
+pub mod stats;
+pub mod strict;
+pub mod summary;
+pub mod symbols;
+pub mod sysinfo;
+pub mod taint;
+pub mod trace;
+pub mod tracefile;
+pub mod trap;
+pub mod uninit;
+pub mod vectors;
+pub mod verify;
+mod watch;
+
+use abi::AbiCheck;
+use bank::{BankConfig, Banks};
+use boot::{boot_rom, Boot, BOOT_ROM_LEN};
+use callstack::Frame;
+use capture::Capture;
+use checkpoint::CheckpointRing;
+use clock::Clock;
+use console::TerminalInfo;
+use cosim::Cosim;
+use device::Devices;
+use effect::{Control, EffectLog};
+use endian::Endian;
+use exit::HaltHooks;
+use fd::Files;
+use fetch::FetchUnit;
+use freeze::Freezes;
+use fuel::ExecutionStats;
+use heap::{AccessKind, Heap};
+use hooks::{Breakpoints, StepHooks};
+use integrity::Image;
+use interrupt::Interrupt;
+use irq::Irqs;
+use limits::Usage;
+use loader::{Executable, Libraries};
+use logging::GuestLog;
+use manifest::Digest;
+use mapping::Mapping;
+use memmap::MemoryMap;
+use observer::{Event, Observers};
+use output::Tee;
+use patch::Patch;
+use poll::{Ready, Source};
+use pool::BasePages;
+use profile::Profile;
+use regbank::{RegisterBankConfig, RegisterBanks};
+use scripting::{HostFunctions, HOST_FUNCTIONS};
+use semantics::ArchState;
+use signal::Signals;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    io::{Read, Write},
+    num::TryFromIntError,
+    sync::Arc,
+    time::Instant,
+};
+use summary::RunCounters;
+use symbols::SymbolTable;
+use taint::{Taint, TaintTracker};
+use trace::Tracer;
+use tracefile::TraceWriter;
+use trap::Trap;
+use uninit::UninitTracker;
+use watch::Watchpoint;
 
 #[derive(Debug, PartialEq)]
-enum Error {
+pub enum Error {
     OpcodeUnknown(u32),
     RegisterUnknown(u32),
     SyscallUnknown(u32),
     ImmediateValue(TryFromIntError),
+    LayoutInvalid(&'static str),
+    CheckpointUnavailable(u64),
+    SymbolUnknown(String),
+    RegisterNameUnknown(String),
+    CommandUnknown(String),
+    CommandInvalid(String),
+    ExpectationFailed(String),
+    Script { line: usize, error: Box<Error> },
+    ExprInvalid(String),
+    DivideByZero,
+    NoCallFrame,
+    CosimFailed(String),
+    LibraryUnknown(String),
+    LibraryInvalid(String),
+    MapFailed(String),
+    ManifestInvalid(String),
+    VectorsInvalid(String),
+    FailureNotReproduced,
+    SnapshotInvalid(String),
+    SnapshotFailed(String),
+    StateInvalid(String),
+    BatchInvalid(String),
+    AsmInvalid(String),
+    BranchTraceInvalid(String),
+    TraceInvalid(String),
+    ExecutableInvalid(String),
+    LinkerScriptInvalid(String),
+    LinkFailed(String),
+    SymbolsInvalid(String),
+    StatsInvalid(String),
+    // Host functions are bound to syscall numbers from HOST_FUNCTIONS up.
+    HostFunctionInvalid(Word),
+    // A field the instruction ignores is not zero.
+    EncodingNonCanonical { word: Word, field: &'static str },
+    // The guest did something the machine cannot carry on from.
+    Trap { pc: Address, trap: Trap },
+}
+
+// A message for a person, as the CLI prints it. Errors that carry their
+// own message, naming the line or file they are about, show it as it is.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OpcodeUnknown(opcode) => write!(f, "unknown opcode {opcode:#x}"),
+            Error::RegisterUnknown(reg) => write!(f, "unknown register {reg}"),
+            Error::SyscallUnknown(number) => write!(f, "unknown syscall {number}"),
+            Error::ImmediateValue(err) => write!(f, "immediate out of range: {err}"),
+            Error::LayoutInvalid(reason) => f.write_str(reason),
+            Error::CheckpointUnavailable(retired) => {
+                write!(f, "no checkpoint reaches back to instruction {retired}")
+            }
+            Error::SymbolUnknown(name) => write!(f, "unknown symbol {name:?}"),
+            Error::RegisterNameUnknown(name) => write!(f, "unknown register {name:?}"),
+            Error::CommandUnknown(name) => write!(f, "unknown command {name:?}"),
+            Error::ExpectationFailed(message) => write!(f, "expectation failed: {message}"),
+            Error::Script { line, error } => write!(f, "line {line}: {error}"),
+            Error::ExprInvalid(message) => write!(f, "invalid expression: {message}"),
+            Error::DivideByZero => f.write_str("division by zero"),
+            Error::NoCallFrame => f.write_str("no function call to finish"),
+            Error::CosimFailed(message) => write!(f, "co-simulation diverged: {message}"),
+            Error::LibraryUnknown(name) => write!(f, "unknown library {name:?}"),
+            Error::LibraryInvalid(message) => write!(f, "invalid library: {message}"),
+            Error::ManifestInvalid(message) => write!(f, "invalid manifest: {message}"),
+            Error::VectorsInvalid(message) => write!(f, "invalid test vectors: {message}"),
+            Error::FailureNotReproduced => f.write_str("the program does not fail that way"),
+            Error::SnapshotInvalid(message) => write!(f, "invalid snapshot: {message}"),
+            Error::SnapshotFailed(message) => write!(f, "saving a snapshot failed: {message}"),
+            Error::StateInvalid(message) => write!(f, "invalid state: {message}"),
+            Error::BatchInvalid(message) => write!(f, "invalid batch file: {message}"),
+            Error::BranchTraceInvalid(message) => write!(f, "invalid branch trace: {message}"),
+            Error::TraceInvalid(message) => write!(f, "invalid trace: {message}"),
+            Error::ExecutableInvalid(message) => write!(f, "invalid executable: {message}"),
+            Error::SymbolsInvalid(message) => write!(f, "invalid symbol file: {message}"),
+            Error::StatsInvalid(message) => write!(f, "invalid stats report: {message}"),
+            Error::CommandInvalid(message)
+            | Error::MapFailed(message)
+            | Error::AsmInvalid(message)
+            | Error::LinkerScriptInvalid(message)
+            | Error::LinkFailed(message) => f.write_str(message),
+            Error::HostFunctionInvalid(number) => write!(
+                f,
+                "host functions are bound to syscalls from {HOST_FUNCTIONS:#x} up, not {number:#x}"
+            ),
+            Error::EncodingNonCanonical { word, field } => {
+                write!(
+                    f,
+                    "non-canonical instruction {word:#010x}: {field} is not zero"
+                )
+            }
+            Error::Trap { pc, trap } => write!(f, "guest trapped at pc {pc:#010x}: {trap}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub type Word = u32;
+
+pub type Address = u32;
+
+#[derive(Debug, PartialEq)]
+pub enum StepOutcome {
+    Continue,
+    Halted(HaltReason),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HaltReason {
+    Breakpoint,
+    Abort {
+        pc: Address,
+        message: String,
+    },
+    Watchpoint {
+        pc: Address,
+        label: String,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+    ReturnMismatch {
+        pc: Address,
+        expected: Address,
+        target: Address,
+    },
+    CosimDivergence {
+        pc: Address,
+        retired: u64,
+    },
+    Interrupted {
+        pc: Address,
+    },
+    // The guest called exit with `code`.
+    Exit {
+        code: i32,
+    },
+    // Execution reached a breakpoint the host set.
+    HostBreakpoint {
+        pc: Address,
+    },
+    // mret ran with no interrupt being handled.
+    StrayInterruptReturn {
+        pc: Address,
+    },
+    // sigreturn ran with no signal being handled.
+    StraySignalReturn {
+        pc: Address,
+    },
+    // The guest waited for an interrupt at `pc` with none pending or
+    // scheduled, with exit on idle set.
+    Idle {
+        pc: Address,
+    },
+    // The machine used up its fuel before the instruction at `pc`.
+    ResourceExhausted {
+        pc: Address,
+    },
+    // The guest went over one of its Limits, before the instruction at
+    // `pc` for steps and wall-clock time and by it for the rest.
+    StepLimit {
+        pc: Address,
+    },
+    MemoryLimit {
+        pc: Address,
+    },
+    // The guest wrote the `limit` bytes of output its Limits allow, at
+    // the write to `pc` that was cut short.
+    OutputLimit {
+        pc: Address,
+        limit: u64,
+    },
+    OpenFileLimit {
+        pc: Address,
+    },
+    WallClockLimit {
+        pc: Address,
+    },
 }
 
-type Result<T> = std::result::Result<T, Error>;
+impl fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HaltReason::Breakpoint => write!(f, "halted at breakpoint"),
+            HaltReason::Abort { pc, message } => {
+                write!(f, "guest aborted at pc {pc:#010x}: {message}")
+            }
+            HaltReason::Watchpoint {
+                pc,
+                label,
+                old,
+                new,
+            } => write!(
+                f,
+                "watchpoint on {label} hit at pc {pc:#010x}: {old:02x?} -> {new:02x?}"
+            ),
+            HaltReason::ReturnMismatch {
+                pc,
+                expected,
+                target,
+            } => write!(
+                f,
+                "return at pc {pc:#010x} to {target:#010x} does not match the return address {expected:#010x}"
+            ),
+            HaltReason::CosimDivergence { pc, retired } => write!(
+                f,
+                "co-simulation diverged at pc {pc:#010x} after {retired} instructions"
+            ),
+            HaltReason::Interrupted { pc } => write!(f, "interrupted at pc {pc:#010x}"),
+            HaltReason::Exit { code } => write!(f, "exited with status {code}"),
+            HaltReason::HostBreakpoint { pc } => write!(f, "stopped at breakpoint at pc {pc:#010x}"),
+            HaltReason::StrayInterruptReturn { pc } => {
+                write!(f, "mret at pc {pc:#010x} outside an interrupt handler")
+            }
+            HaltReason::StraySignalReturn { pc } => {
+                write!(f, "sigreturn at pc {pc:#010x} outside a signal handler")
+            }
+            HaltReason::Idle { pc } => write!(
+                f,
+                "deadlock at pc {pc:#010x}: waiting for an interrupt with none pending or scheduled"
+            ),
+            HaltReason::ResourceExhausted { pc } => write!(f, "ran out of fuel at pc {pc:#010x}"),
+            HaltReason::StepLimit { pc } => write!(f, "step limit reached at pc {pc:#010x}"),
+            HaltReason::MemoryLimit { pc } => {
+                write!(f, "memory limit exceeded at pc {pc:#010x}")
+            }
+            HaltReason::OutputLimit { pc, limit } => {
+                write!(f, "output limit of {limit} bytes reached at pc {pc:#010x}")
+            }
+            HaltReason::OpenFileLimit { pc } => {
+                write!(f, "open file limit exceeded at pc {pc:#010x}")
+            }
+            HaltReason::WallClockLimit { pc } => {
+                write!(f, "wall-clock limit reached at pc {pc:#010x}")
+            }
+        }
+    }
+}
+
+const DEFAULT_PAGE_SIZE: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Layout {
+    pub page_size: u32,
+    pub load_address: Address,
+    pub stack_top: Address,
+    pub heap_base: Address,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            page_size: DEFAULT_PAGE_SIZE,
+            load_address: 0,
+            stack_top: 0x0010_0000,
+            heap_base: 0x0001_0000,
+        }
+    }
+}
+
+impl Layout {
+    // A 16 KiB address space with small pages, in the style of a microcontroller.
+    pub fn microcontroller() -> Self {
+        Self {
+            page_size: 256,
+            load_address: 0,
+            stack_top: 0x4000,
+            heap_base: 0x2000,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.page_size.is_power_of_two() {
+            return Err(Error::LayoutInvalid("page size must be a power of two"));
+        }
+        if self.heap_base < self.load_address {
+            return Err(Error::LayoutInvalid(
+                "heap base must not precede the load address",
+            ));
+        }
+        if self.stack_top < self.heap_base {
+            return Err(Error::LayoutInvalid(
+                "stack top must not precede the heap base",
+            ));
+        }
+        Ok(())
+    }
+}
 
-type Word = u32;
+// The guest's address space, stored a page at a time: a page is allocated
+// the first time anything in it is written, and reads of addresses in no
+// page see mapped data, if any, then the base image's pages, or zero.
+#[derive(Debug, Clone, Eq)]
+pub struct Memory {
+    pages: HashMap<Address, Box<[u8]>>,
+    // Pages shared with every machine started from the same base image,
+    // copied into `pages` when first written.
+    base: Option<Arc<BasePages>>,
+    page_size: u32,
+    dirty: BTreeSet<Address>,
+    banks: Option<Box<Banks>>,
+    mappings: Vec<Mapping>,
+}
 
-type Address = u32;
+// Dirty-page bookkeeping is not part of the memory contents, and a page
+// that was allocated but holds only what reads would see anyway is
+// indistinguishable from one never written.
+impl PartialEq for Memory {
+    fn eq(&self, other: &Self) -> bool {
+        let contains =
+            |a: &Self, b: &Self| a.pages().iter().all(|page| a.page(*page) == b.page(*page));
+        self.page_size == other.page_size
+            && self.banks == other.banks
+            && self.mappings == other.mappings
+            && contains(self, other)
+            && contains(other, self)
+    }
+}
 
-#[derive(Debug, Default, Eq, PartialEq)]
-struct Memory {
-    inner: HashMap<Address, u8>,
+impl Default for Memory {
+    fn default() -> Self {
+        Self::with_page_size(DEFAULT_PAGE_SIZE)
+    }
 }
 
 impl Memory {
-    fn get(&self, addr: Address) -> u8 {
-        *self.inner.get(&addr).unwrap_or(&u8::default())
+    // Memory allocated `page_size` bytes at a time, which must be a power
+    // of two, as Layout::validate checks; anything else panics here rather
+    // than on the first access.
+    pub fn with_page_size(page_size: u32) -> Self {
+        assert!(
+            page_size.is_power_of_two(),
+            "page size must be a power of two, not {page_size}"
+        );
+        Self {
+            pages: HashMap::new(),
+            base: None,
+            page_size,
+            dirty: BTreeSet::new(),
+            banks: None,
+            mappings: Vec::new(),
+        }
     }
 
-    fn read(&self, addr: Address, len: usize) -> Vec<u8> {
-        let mut data = Vec::new();
-        for offset in 0..len {
-            data.push(self.get(addr + offset as u32));
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    fn page_of(&self, addr: Address) -> Address {
+        addr & !(self.page_size - 1)
+    }
+
+    fn offset_in_page(&self, addr: Address) -> usize {
+        (addr & (self.page_size - 1)) as usize
+    }
+
+    // Base addresses of the pages written since the last call to
+    // clear_dirty, in address order, so that a host saving memory
+    // incrementally need only copy those.
+    pub fn dirty_pages(&self) -> Vec<Address> {
+        self.dirty.iter().copied().collect()
+    }
+
+    // Marks every page clean, typically once the host has saved the dirty
+    // ones.
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+
+    // Base addresses of every page holding data.
+    fn pages(&self) -> BTreeSet<Address> {
+        let banked = self.banked_addresses();
+        let based = self.base.iter().flat_map(|base| base.keys());
+        self.pages
+            .keys()
+            .chain(based)
+            .copied()
+            .chain(banked.iter().map(|addr| self.page_of(*addr)))
+            .collect()
+    }
+
+    fn page(&self, page: Address) -> Vec<u8> {
+        self.read(page, self.page_size as usize)
+    }
+
+    // The byte at `addr` if it is in an allocated page.
+    pub(crate) fn stored(&self, addr: Address) -> Option<u8> {
+        let page = self.pages.get(&self.page_of(addr))?;
+        Some(page[self.offset_in_page(addr)])
+    }
+
+    // What `addr` reads as when no page holds it.
+    fn unstored(&self, addr: Address) -> u8 {
+        self.mapped(addr)
+            .or_else(|| self.based(addr))
+            .unwrap_or_default()
+    }
+
+    // The page holding `addr` for reading straight from: the allocated one,
+    // or the base image's when nothing has written it and no mapping may
+    // lie over it.
+    fn readable_page(&self, addr: Address) -> Option<&[u8]> {
+        let page = self.page_of(addr);
+        match self.pages.get(&page) {
+            Some(data) => Some(data),
+            None if self.mappings.is_empty() => self.base.as_ref()?.get(&page).map(|data| &**data),
+            None => None,
+        }
+    }
+
+    // The page holding `addr`, allocated with the mapped data underneath
+    // it, so that writing part of a mapped page copies the rest.
+    fn page_mut(&mut self, addr: Address) -> &mut [u8] {
+        let base = self.page_of(addr);
+        if !self.pages.contains_key(&base) {
+            let page = (0..self.page_size)
+                .map(|offset| self.unstored(base + offset))
+                .collect();
+            self.pages.insert(base, page);
+        }
+        self.pages
+            .get_mut(&base)
+            .expect("the page was just allocated")
+    }
+
+    // Replaces a whole page without marking it dirty; `None` clears it,
+    // leaving only mapped data. Banked addresses are restored into the
+    // selected bank.
+    fn restore_page(&mut self, page: Address, data: Option<&[u8]>) {
+        self.pages.remove(&page);
+        let Some(data) = data else {
+            // A base page is hidden under one holding only mapped data.
+            if self
+                .base
+                .as_ref()
+                .is_some_and(|base| base.contains_key(&page))
+            {
+                let data = (0..self.page_size)
+                    .map(|offset| self.mapped(page + offset).unwrap_or_default())
+                    .collect();
+                self.pages.insert(page, data);
+            }
+            return;
+        };
+        for (offset, byte) in data.iter().enumerate() {
+            self.bank_set(page + offset as Address, *byte);
+        }
+        let unstored = (0..self.page_size).all(|offset| {
+            let addr = page + offset;
+            self.bank_slot(addr).is_some() || data[offset as usize] == self.unstored(addr)
+        });
+        if !unstored {
+            self.pages.insert(page, data.into());
+        }
+    }
+
+    pub fn get(&self, addr: Address) -> u8 {
+        if let Some(value) = self.bank_get(addr) {
+            return value;
+        }
+        self.stored(addr).unwrap_or_else(|| self.unstored(addr))
+    }
+
+    // The big-endian word at `addr`, read with a single page lookup when
+    // it lies within one page.
+    pub fn read_u32(&self, addr: Address) -> u32 {
+        let offset = self.offset_in_page(addr);
+        if self.banks.is_none() && offset + 4 <= self.page_size as usize {
+            if let Some(page) = self.readable_page(addr) {
+                let bytes = &page[offset..offset + 4];
+                return u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+        }
+        let byte = |offset| self.get(addr.wrapping_add(offset));
+        u32::from_be_bytes([byte(0), byte(1), byte(2), byte(3)])
+    }
+
+    pub fn read(&self, addr: Address, len: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(len);
+        let mut addr = addr;
+        while data.len() < len {
+            let offset = self.offset_in_page(addr);
+            let chunk = (self.page_size as usize - offset).min(len - data.len());
+            match self.readable_page(addr) {
+                Some(page) if self.banks.is_none() => {
+                    data.extend_from_slice(&page[offset..offset + chunk]);
+                }
+                _ => data.extend((0..chunk).map(|i| self.get(addr.wrapping_add(i as u32)))),
+            }
+            addr = addr.wrapping_add(chunk as u32);
         }
         data
     }
+
+    pub fn set(&mut self, addr: Address, value: u8) {
+        self.dirty.insert(self.page_of(addr));
+        if !self.bank_set(addr, value) {
+            let offset = self.offset_in_page(addr);
+            self.page_mut(addr)[offset] = value;
+        }
+    }
+
+    pub fn write(&mut self, addr: Address, data: &[u8]) {
+        let mut addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let offset = self.offset_in_page(addr);
+            let chunk = (self.page_size as usize - offset).min(data.len());
+            let (head, tail) = data.split_at(chunk);
+            if self.banks.is_some() {
+                for (i, byte) in head.iter().enumerate() {
+                    self.set(addr.wrapping_add(i as u32), *byte);
+                }
+            } else {
+                self.dirty.insert(self.page_of(addr));
+                self.page_mut(addr)[offset..offset + chunk].copy_from_slice(head);
+            }
+            addr = addr.wrapping_add(chunk as u32);
+            data = tail;
+        }
+    }
 }
 
 impl<const N: usize> From<[(Address, u8); N]> for Memory {
     fn from(values: [(Address, u8); N]) -> Self {
-        Self {
-            inner: HashMap::from(values),
+        let mut mem = Self::default();
+        for (addr, value) in values {
+            mem.set(addr, value);
         }
+        mem.clear_dirty();
+        mem
     }
 }
 
-#[derive(Debug, Default, Eq, PartialEq)]
-struct Registers {
+#[derive(Debug, Default, Clone, Eq)]
+pub struct Registers {
     inner: HashMap<RegisterID, Word>,
+    banks: Option<Box<RegisterBanks>>,
+}
+
+// A register never written is the same as one holding zero.
+impl PartialEq for Registers {
+    fn eq(&self, other: &Self) -> bool {
+        (0..16).all(|id| {
+            let reg = state::register(id);
+            self.get(&reg) == other.get(&reg)
+        }) && self.banks == other.banks
+    }
 }
 
 impl Registers {
-    fn get(&self, reg: &RegisterID) -> Word {
+    pub fn get(&self, reg: &RegisterID) -> Word {
+        if let Some(value) = self.banked(*reg) {
+            return value;
+        }
         *self.inner.get(reg).unwrap_or(&Word::default())
     }
 
-    fn set(&mut self, reg: RegisterID, value: Word) {
+    pub fn set(&mut self, reg: RegisterID, value: Word) {
         let value = match reg {
             RegisterID::X0 => 0,
             _ => value,
         };
+        if let Some(banked) = self.banked_mut(reg) {
+            *banked = value;
+            return;
+        }
         self.inner.insert(reg, value);
     }
 }
@@ -65,16 +747,103 @@ impl<const N: usize> From<[(RegisterID, Word); N]> for Registers {
     fn from(values: [(RegisterID, Word); N]) -> Self {
         Self {
             inner: HashMap::from(values),
+            banks: None,
         }
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
-struct Machine<W: Write> {
+// A source of guest input, such as the host's stdin.
+struct Input(Box<dyn Source>);
+
+impl Input {
+    fn new(reader: impl Read + 'static) -> Self {
+        Self(Box::new(Ready(reader)))
+    }
+}
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Input")
+    }
+}
+
+#[derive(Debug)]
+pub struct Machine<W: Write> {
     pc: Word,
     mem: Memory,
     regs: Registers,
     stdout: Option<W>,
+    sinks: Tee,
+    stdin: Option<Input>,
+    layout: Layout,
+    retired: u64,
+    cycles: u64,
+    checkpoints: Option<CheckpointRing>,
+    replaying: bool,
+    symbols: SymbolTable,
+    memory_map: MemoryMap,
+    watchpoints: Vec<Watchpoint>,
+    tracer: Option<Tracer>,
+    call_stack: Vec<Frame>,
+    return_protection: bool,
+    abi: Option<AbiCheck>,
+    patches: Vec<Patch>,
+    freezes: Freezes,
+    observers: Observers,
+    heap: Heap,
+    taint: Option<TaintTracker>,
+    uninit: Option<UninitTracker>,
+    image: Image,
+    effects: EffectLog,
+    devices: Devices,
+    cosim: Option<Cosim>,
+    capture: Option<Capture>,
+    exit_handlers: Vec<Address>,
+    halt_hooks: HaltHooks,
+    libraries: Libraries,
+    hart_id: Word,
+    interrupt: Interrupt,
+    fetch: FetchUnit,
+    memory_limit: Option<Address>,
+    host_functions: HostFunctions,
+    guest_log: GuestLog,
+    interrupt_signal: Option<Word>,
+    exit_on_idle: bool,
+    irqs: Irqs,
+    signals: Signals,
+    profile: Profile,
+    input_digest: Digest,
+    input_read: u64,
+    run_counters: Option<RunCounters>,
+    fuel: Option<u64>,
+    usage: Option<Usage>,
+    execution: ExecutionStats,
+    misalignment_penalty: u64,
+    strict_decoding: bool,
+    overflow_trap: bool,
+    endian: Endian,
+    terminal: TerminalInfo,
+    clock: Clock,
+    clock_start: Instant,
+    boot: Boot,
+    breakpoints: Breakpoints,
+    step_hooks: StepHooks,
+    files: Files,
+    exit_code: Option<i32>,
+    trace_writer: Option<TraceWriter<Box<dyn Write>>>,
+}
+
+// Machines are equal when their architectural state is. Execution
+// bookkeeping such as the retired count and checkpoints is not part of
+// it, and nor is the stdout the host gave the machine.
+impl<W: Write> PartialEq for Machine<W> {
+    fn eq(&self, other: &Self) -> bool {
+        self.pc == other.pc
+            && self.mem == other.mem
+            && self.regs == other.regs
+            && self.layout == other.layout
+            && self.endian == other.endian
+    }
 }
 
 impl<W: Write> Default for Machine<W> {
@@ -82,65 +851,689 @@ impl<W: Write> Default for Machine<W> {
         Self {
             pc: 0,
             stdout: None,
+            sinks: Tee::new(),
+            stdin: None,
+            layout: Layout::default(),
             mem: Memory::default(),
             regs: Registers::default(),
+            retired: 0,
+            cycles: 0,
+            checkpoints: None,
+            replaying: false,
+            symbols: SymbolTable::default(),
+            memory_map: MemoryMap::default(),
+            watchpoints: Vec::new(),
+            tracer: None,
+            call_stack: Vec::new(),
+            return_protection: false,
+            abi: None,
+            patches: Vec::new(),
+            freezes: Freezes::default(),
+            observers: Observers::default(),
+            heap: Heap::default(),
+            taint: None,
+            uninit: None,
+            image: Image::default(),
+            effects: EffectLog::default(),
+            devices: Devices::default(),
+            cosim: None,
+            capture: None,
+            exit_handlers: Vec::new(),
+            halt_hooks: HaltHooks::default(),
+            libraries: Libraries::default(),
+            hart_id: 0,
+            interrupt: Interrupt::default(),
+            fetch: FetchUnit::default(),
+            memory_limit: None,
+            host_functions: HostFunctions::default(),
+            guest_log: GuestLog::default(),
+            interrupt_signal: None,
+            exit_on_idle: false,
+            irqs: Irqs::default(),
+            signals: Signals::default(),
+            profile: Profile::default(),
+            input_digest: Digest::default(),
+            input_read: 0,
+            run_counters: None,
+            fuel: None,
+            usage: None,
+            execution: ExecutionStats::default(),
+            misalignment_penalty: 0,
+            strict_decoding: false,
+            overflow_trap: false,
+            endian: Endian::Big,
+            terminal: TerminalInfo::default(),
+            clock: Clock::default(),
+            clock_start: Instant::now(),
+            boot: Boot::default(),
+            breakpoints: Breakpoints::default(),
+            step_hooks: StepHooks::default(),
+            files: Files::default(),
+            exit_code: None,
+            trace_writer: None,
         }
     }
 }
 
 impl<W: Write> Machine<W> {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self::default()
     }
 
-    fn next(&mut self) -> Result<Instruction> {
-        let b1 = self.mem.get(self.pc);
-        let b2 = self.mem.get(self.pc + 1);
-        let b3 = self.mem.get(self.pc + 2);
-        let b4 = self.mem.get(self.pc + 3);
-        let word = u32::from_be_bytes([b1, b2, b3, b4]);
-        Instruction::try_from(word)
+    pub fn builder() -> MachineBuilder<W> {
+        MachineBuilder::new()
+    }
+
+    pub fn pc(&self) -> Address {
+        self.pc
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.regs
+    }
+
+    pub fn register(&self, reg: RegisterID) -> Word {
+        self.regs.get(&reg)
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.mem
+    }
+
+    pub fn stdout(&self) -> Option<&W> {
+        self.stdout.as_ref()
+    }
+
+    pub fn take_stdout(&mut self) -> Option<W> {
+        self.stdout.take()
+    }
+
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    // The status the guest passed to exit, if it has exited.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // Replaces where the read syscall gets guest input from.
+    pub fn set_stdin(&mut self, stdin: impl Read + 'static) {
+        self.stdin = Some(Input::new(stdin));
     }
 
-    fn run(&mut self) -> Result<()> {
+    fn next(&mut self) -> Result<(Instruction, usize)> {
+        let (word, len) = self.fetch()?;
+        Ok((self.decode(word)?, len))
+    }
+
+    pub fn run(&mut self) -> Result<HaltReason> {
         loop {
-            let instruction = self.next()?;
-            self.pc += 4;
+            if let (_, StepOutcome::Halted(reason)) = self.step()? {
+                return self.finalize(reason);
+            }
+        }
+    }
 
-            match instruction.opcode {
-                Opcode::LoadImmediate => {
-                    self.regs.set(instruction.rd, instruction.imm as Word);
-                }
-                Opcode::Add => {
-                    let rs1 = self.regs.get(&instruction.rs1);
-                    let rs2 = self.regs.get(&instruction.rs2);
-                    let imm = instruction.imm as Word;
-                    self.regs.set(instruction.rd, rs1 + rs2 + imm);
-                }
-                Opcode::ECall => match self.regs.get(&RegisterID::A7).try_into()? {
-                    Syscall::Write => {
-                        let fd = self.regs.get(&RegisterID::A0);
-                        assert_eq!(fd, 1, "expected file descriptor to specify stdout (1)");
-
-                        let buf_addr = self.regs.get(&RegisterID::A1);
-                        let len = self.regs.get(&RegisterID::A2);
-                        let data = self.mem.read(buf_addr, len as usize);
-
-                        if let Some(stdout) = &mut self.stdout {
-                            stdout.write_all(&data).expect("failed to write to stdout");
-                        };
-                    }
-                },
-                Opcode::EBreak => break,
+    // Executes exactly one instruction, returning it with the outcome.
+    // Unlike run, a step that halts does not finish the program: exit
+    // handlers and halt hooks are left for the run that ends it.
+    pub fn step(&mut self) -> Result<(Instruction, StepOutcome)> {
+        // Out of fuel, the instruction is left to run once there is more,
+        // without being fetched, so a bad pc does not trap first. The step
+        // returns it if there is one at pc, or a nop. A rewind replays
+        // instructions that were paid for the first time.
+        let halted = (!self.replaying)
+            .then(|| self.out_of_fuel().or_else(|| self.limit_reached()))
+            .flatten();
+        if let Some(reason) = halted {
+            let word = self.endian.word(self.mem.read_u32(self.pc).to_be_bytes());
+            let instruction = Instruction::try_from(word).unwrap_or(Instruction {
+                opcode: Opcode::Add,
+                rd: RegisterID::X0,
+                rs1: RegisterID::X0,
+                rs2: RegisterID::X0,
+                imm: 0,
+            });
+            return Ok((instruction, StepOutcome::Halted(reason)));
+        }
+        let (instruction, len) = self.next()?;
+        self.check_uninitialized(&instruction)?;
+        self.note_fetch(len);
+        self.run_step_hooks(&instruction, true);
+        let pc = self.pc;
+        let operands = [
+            self.regs.get(&instruction.rs1),
+            self.regs.get(&instruction.rs2),
+        ];
+        let watched = self.watched_values();
+        self.trace_instruction(&instruction);
+        self.propagate_taint(&instruction);
+        let outcome = self.execute(instruction)?;
+        self.retired += 1;
+        self.cycles += 1;
+        self.burn_fuel();
+        self.count_instruction(&instruction.opcode);
+        self.observers.notify(&Event::Retired {
+            pc,
+            instruction,
+            operands,
+        });
+        // Entering a handler is part of the instruction it follows, so that
+        // traces, co-simulation and step_back see it.
+        self.tick_devices();
+        if outcome == StepOutcome::Continue {
+            self.deliver_irq();
+            self.deliver_signal();
+        }
+        self.apply_freezes();
+        self.finish_effect();
+        self.trace_effect();
+        self.write_trace_record();
+        let diverged = self.stream_effect()?;
+        self.record_checkpoint_if_due();
+        // Every instruction counts towards the limits, but the checks after
+        // them only run until one halts the machine, so that an interrupt
+        // is not taken, for one, when a watchpoint already stops it.
+        let limit = self.limit_exceeded(pc);
+        let hit = diverged
+            .or(limit)
+            .or_else(|| self.triggered_watchpoint(pc, watched))
+            .or_else(|| self.take_interrupt())
+            .or_else(|| self.breakpoint_hit());
+        self.run_step_hooks(&instruction, false);
+        match (outcome, hit) {
+            (StepOutcome::Continue, Some(hit)) => Ok((instruction, StepOutcome::Halted(hit))),
+            (outcome, _) => Ok((instruction, outcome)),
+        }
+    }
+
+    // Executes the instruction at pc. The architectural part is the pure
+    // semantics::step_into; the machine services whatever it asks for.
+    fn execute(&mut self, instruction: Instruction) -> Result<StepOutcome> {
+        let access = semantics::memory_access(&instruction, &self.regs);
+        self.check_data_access(access.map(|(addr, len, _)| (addr, len)))?;
+        self.check_overflow(&instruction)?;
+        let mut state = ArchState {
+            pc: self.pc,
+            regs: std::mem::take(&mut self.regs),
+            mem: std::mem::take(&mut self.mem),
+            endian: self.endian,
+        };
+        let mut effect = self.blank_effect();
+        semantics::step_into(&mut state, &instruction, &mut effect);
+        (self.pc, self.regs, self.mem) = (state.pc, state.regs, state.mem);
+        self.check_stack_collision(&effect)?;
+        if let Some((addr, len, kind)) = access {
+            self.count_access(addr, len);
+            self.check_heap_access(addr, len, kind);
+        }
+        let control = effect.control;
+        let pc = effect.pc;
+        self.begin_effect(effect);
+        if let Some((addr, len, kind)) = access {
+            if self.check_reset_register(addr, len, kind) {
+                return Ok(StepOutcome::Continue);
+            }
+            self.check_register_bank_select(addr, len, kind);
+        }
+        match control {
+            Control::Continue => Ok(self.track_jump(pc, &instruction)),
+            Control::Syscall => self.syscall(),
+            Control::Breakpoint => Ok(StepOutcome::Halted(HaltReason::Breakpoint)),
+            Control::InterruptReturn => Ok(self.return_from_irq()),
+        }
+    }
+
+    fn syscall(&mut self) -> Result<StepOutcome> {
+        let number = self.regs.get(&RegisterID::A7);
+        self.count_syscall(number);
+        if number >= HOST_FUNCTIONS {
+            return self.call_host_function(number);
+        }
+        let syscall = Syscall::try_from(number)
+            .map_err(|_| self.syscall_trap(Trap::UnknownSyscall { number }))?;
+        match syscall {
+            Syscall::Read => self.read_syscall()?,
+            Syscall::Write => self.write_syscall()?,
+            Syscall::Exit => {
+                let code = self.regs.get(&RegisterID::A0) as i32;
+                self.exit_code = Some(code);
+                return Ok(StepOutcome::Halted(HaltReason::Exit { code }));
+            }
+            Syscall::FormatSigned => {
+                let value = self.regs.get(&RegisterID::A0) as i32;
+                self.format_into_buffer(&value.to_string())?;
+            }
+            Syscall::FormatUnsigned => {
+                let value = self.regs.get(&RegisterID::A0);
+                self.format_into_buffer(&value.to_string())?;
+            }
+            Syscall::FormatHex => {
+                let value = self.regs.get(&RegisterID::A0);
+                self.format_into_buffer(&format!("{value:x}"))?;
+            }
+            Syscall::Brk => {
+                let requested = self.regs.get(&RegisterID::A0);
+                self.check_brk_collision(requested)?;
+                let brk = self.set_brk(requested);
+                self.set_register(RegisterID::A0, brk);
+            }
+            Syscall::MarkSecret => {
+                let addr = self.regs.get(&RegisterID::A0);
+                let len = self.regs.get(&RegisterID::A1);
+                let len = self.guest_buffer(addr, len.into())?;
+                self.taint_memory(addr, len, Taint::SECRET);
+            }
+            Syscall::AtExit => {
+                let handler = self.regs.get(&RegisterID::A0);
+                self.register_exit_handler(handler);
+            }
+            Syscall::DlOpen => self.dlopen()?,
+            Syscall::DlSym => self.dlsym()?,
+            Syscall::SysInfo => self.sysinfo_syscall()?,
+            Syscall::Reset => {
+                let flags = self.regs.get(&RegisterID::A0);
+                self.reboot(flags);
+            }
+            Syscall::ClockGetTime => self.clock_gettime()?,
+            Syscall::IrqHandler => self.irq_handler_syscall(),
+            Syscall::IrqClaim => self.irq_claim_syscall(),
+            Syscall::IrqPriority => self.irq_priority_syscall(),
+            Syscall::IrqStatus => self.irq_status_syscall(),
+            Syscall::RegionBegin => self.region_begin_syscall()?,
+            Syscall::RegionEnd => self.region_end_syscall()?,
+            Syscall::TermInfo => self.terminfo_syscall()?,
+            Syscall::Open => self.open_syscall()?,
+            Syscall::Close => self.close_syscall(),
+            Syscall::Pipe => self.pipe_syscall()?,
+            Syscall::Dup => self.dup_syscall(),
+            Syscall::SigAction => self.sigaction_syscall(),
+            Syscall::SigReturn => return Ok(self.sigreturn_syscall()),
+            Syscall::Alarm => self.alarm_syscall(),
+            Syscall::WaitForInterrupt => return Ok(self.wait_for_interrupt_syscall()),
+            Syscall::Log => self.log_syscall()?,
+            Syscall::Poll => {
+                let ready = self.poll()?;
+                self.set_register(RegisterID::A0, ready);
+            }
+            Syscall::Abort => {
+                let msg_addr = self.regs.get(&RegisterID::A0);
+                let len = self.regs.get(&RegisterID::A1);
+                let len = self.guest_buffer(msg_addr, len.into())?;
+                let data = self.load(msg_addr, len);
+                return Ok(StepOutcome::Halted(HaltReason::Abort {
+                    pc: self.pc.wrapping_sub(4),
+                    message: String::from_utf8_lossy(&data).into_owned(),
+                }));
             }
         }
+        Ok(StepOutcome::Continue)
+    }
+
+    // Copies as much of `text` as fits into the buffer at a1 (capacity a2)
+    // and returns the full length in a0, like snprintf without the NUL.
+    fn format_into_buffer(&mut self, text: &str) -> Result<()> {
+        let buf_addr = self.regs.get(&RegisterID::A1);
+        let buf_len = self.regs.get(&RegisterID::A2);
+        let bytes = text.as_bytes();
+        let len = self.guest_buffer(buf_addr, u64::from(buf_len).min(bytes.len() as u64))?;
+        self.store(buf_addr, &bytes[..len]);
+        self.set_register(RegisterID::A0, bytes.len() as Word);
         Ok(())
     }
+
+    // Memory accesses made on behalf of the guest go through load and store
+    // so that they can be checked.
+    fn load(&mut self, addr: Address, len: usize) -> Vec<u8> {
+        self.check_heap_access(addr, len, AccessKind::Read);
+        self.mem.read(addr, len)
+    }
+
+    fn store(&mut self, addr: Address, data: &[u8]) {
+        self.check_heap_access(addr, data.len(), AccessKind::Write);
+        self.initialize_memory(addr, data.len());
+        self.write_memory(addr, data);
+    }
+}
+
+pub struct MachineBuilder<W: Write> {
+    layout: Layout,
+    stdout: Option<W>,
+    stdin: Option<Input>,
+    symbols: SymbolTable,
+    memory_map: MemoryMap,
+    program: Vec<u8>,
+    executable: Option<Executable>,
+    args: Vec<String>,
+    banks: Option<BankConfig>,
+    bank_sections: Vec<(u8, Address, Vec<u8>)>,
+    register_banks: Option<RegisterBankConfig>,
+    endian: Option<Endian>,
+    hart_id: Word,
+    reset_vector: Option<Address>,
+    boot_rom: bool,
+    reset_register: Option<Address>,
+    regs: Vec<(RegisterID, Word)>,
+    fuel: Option<u64>,
+}
+
+impl<W: Write> Default for MachineBuilder<W> {
+    fn default() -> Self {
+        Self {
+            layout: Layout::default(),
+            stdout: None,
+            stdin: None,
+            symbols: SymbolTable::default(),
+            memory_map: MemoryMap::default(),
+            program: Vec::new(),
+            executable: None,
+            args: Vec::new(),
+            banks: None,
+            bank_sections: Vec::new(),
+            register_banks: None,
+            endian: None,
+            hart_id: 0,
+            reset_vector: None,
+            boot_rom: false,
+            reset_register: None,
+            regs: Vec::new(),
+            fuel: None,
+        }
+    }
+}
+
+impl<W: Write> MachineBuilder<W> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.layout.page_size = page_size;
+        self
+    }
+
+    pub fn load_address(mut self, addr: Address) -> Self {
+        self.layout.load_address = addr;
+        self
+    }
+
+    pub fn stack_top(mut self, addr: Address) -> Self {
+        self.layout.stack_top = addr;
+        self
+    }
+
+    pub fn heap_base(mut self, addr: Address) -> Self {
+        self.layout.heap_base = addr;
+        self
+    }
+
+    pub fn stdout(mut self, stdout: W) -> Self {
+        self.stdout = Some(stdout);
+        self
+    }
+
+    pub fn stdin(mut self, stdin: impl Read + 'static) -> Self {
+        self.stdin = Some(Input::new(stdin));
+        self
+    }
+
+    fn symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    // Names `len` bytes from `base`, as Machine::name_region does.
+    pub fn region(mut self, name: &str, base: Address, len: u32) -> Self {
+        self.memory_map.insert(name, base, len);
+        self
+    }
+
+    // The program image, loaded at the load address.
+    pub fn program(mut self, program: &[u8]) -> Self {
+        self.program = program.to_vec();
+        self
+    }
+
+    // A program made of segments, loaded at their own addresses and
+    // entered at its entry point, in place of a flat image at the load
+    // address.
+    pub fn executable(mut self, executable: Executable) -> Self {
+        self.executable = Some(executable);
+        self
+    }
+
+    // Command-line arguments for the guest, starting with the program name.
+    pub fn args<S: Into<String>>(mut self, args: impl IntoIterator<Item = S>) -> Self {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    // A register's value at reset, overriding the stack pointer and
+    // arguments the machine would otherwise set up.
+    pub fn register(mut self, reg: RegisterID, value: Word) -> Self {
+        self.regs.push((reg, value));
+        self
+    }
+
+    pub fn hart_id(mut self, hart_id: Word) -> Self {
+        self.hart_id = hart_id;
+        self
+    }
+
+    // How many instructions the machine may execute; see set_fuel.
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    // Where the hart starts after reset; the load address by default.
+    pub fn reset_vector(mut self, addr: Address) -> Self {
+        self.reset_vector = Some(addr);
+        self
+    }
+
+    // Maps a boot ROM at the reset vector that jumps to the program.
+    pub fn boot_rom(mut self, enabled: bool) -> Self {
+        self.boot_rom = enabled;
+        self
+    }
+
+    // A word that resets the machine when the guest stores to it, taking
+    // the same flags as the reset syscall.
+    pub fn reset_register(mut self, addr: Address) -> Self {
+        self.reset_register = Some(addr);
+        self
+    }
+
+    fn banks(mut self, config: BankConfig) -> Self {
+        self.banks = Some(config);
+        self
+    }
+
+    // The byte order instructions and data are in, overriding the
+    // executable's own.
+    pub fn endian(mut self, endian: Endian) -> Self {
+        self.endian = Some(endian);
+        self
+    }
+
+    // Banks the register file, with the banks selected by the word at
+    // `config.select`.
+    pub fn register_banks(mut self, config: RegisterBankConfig) -> Self {
+        self.register_banks = Some(config);
+        self
+    }
+
+    // Data to load into a specific bank of the banked window.
+    fn bank_section(mut self, bank: u8, addr: Address, data: &[u8]) -> Self {
+        self.bank_sections.push((bank, addr, data.to_vec()));
+        self
+    }
+
+    pub fn build(self) -> Result<Machine<W>> {
+        self.layout.validate()?;
+        let mut mem = Memory::with_page_size(self.layout.page_size);
+        if let Some(config) = self.banks {
+            mem.set_banks(Banks::new(config)?);
+        }
+        for (bank, addr, data) in &self.bank_sections {
+            mem.write_bank(*bank, *addr, data)?;
+        }
+        let executable = self
+            .executable
+            .unwrap_or_else(|| Executable::flat(&self.program, self.layout.load_address));
+        // Memory reads as zero until written, so only the bytes of each
+        // segment that are in the file need writing.
+        for segment in &executable.segments {
+            mem.write(segment.addr, &segment.data);
+        }
+        let entry = executable.entry;
+        let endian = self.endian.unwrap_or(executable.endian);
+        let vector = self.reset_vector.unwrap_or(entry);
+        if self.boot_rom {
+            let rom = u64::from(vector)..u64::from(vector) + BOOT_ROM_LEN as u64;
+            let overlaps = |start: u64, end: u64| start < rom.end && rom.start < end;
+            if overlaps(u64::from(entry), u64::from(entry) + 1)
+                || executable.segments.iter().any(|segment| {
+                    let start = u64::from(segment.addr);
+                    overlaps(start, start + u64::from(segment.size))
+                })
+            {
+                return Err(Error::LayoutInvalid("boot ROM overlaps the program"));
+            }
+            let mut rom = boot_rom(entry);
+            for word in rom.chunks_mut(4) {
+                endian.arrange(word);
+            }
+            mem.map(vector, rom.into())?;
+        }
+        let mut regs = Registers::default();
+        if let Some(config) = self.register_banks {
+            regs.set_banks(RegisterBanks::new(&config)?);
+        }
+        regs.set(RegisterID::SP, self.layout.stack_top);
+        if !self.args.is_empty() {
+            push_args(
+                &mut mem,
+                &mut regs,
+                self.layout.stack_top,
+                &self.args,
+                endian,
+            )?;
+        }
+        for (reg, value) in self.regs {
+            regs.set(reg, value);
+        }
+        mem.clear_dirty();
+        let boot = Boot {
+            vector,
+            regs: regs.clone(),
+            mem: mem.clone(),
+            register: self.reset_register,
+        };
+        Ok(Machine {
+            pc: vector,
+            mem,
+            boot,
+            regs,
+            stdout: self.stdout,
+            stdin: self.stdin,
+            hart_id: self.hart_id,
+            layout: self.layout,
+            symbols: self.symbols,
+            memory_map: self.memory_map,
+            fuel: self.fuel,
+            image: Image::loaded(&executable.segments),
+            endian,
+            ..Default::default()
+        })
+    }
+}
+
+// Lays out the arguments below `stack_top` the way a C runtime expects to
+// find them: sp points at argc, followed by the argv pointers, a null
+// pointer and the NUL-terminated strings. a0 and a1 are set to argc and
+// argv, so the entry point can be an ordinary main(argc, argv). Fails if
+// they do not fit between address 0 and the stack top.
+fn push_args(
+    mem: &mut Memory,
+    regs: &mut Registers,
+    stack_top: Address,
+    args: &[String],
+    endian: Endian,
+) -> Result<()> {
+    let too_big = || Error::LayoutInvalid("arguments do not fit below the stack top");
+    let below = |addr: Address, len: usize| {
+        Address::try_from(len)
+            .ok()
+            .and_then(|len| addr.checked_sub(len))
+            .ok_or_else(too_big)
+    };
+    let mut addr = stack_top;
+    let mut pointers = Vec::new();
+    for arg in args.iter().rev() {
+        addr = below(addr, arg.len() + 1)?;
+        mem.write(addr, arg.as_bytes());
+        mem.write(addr + arg.len() as Address, &[0]);
+        pointers.push(addr);
+    }
+    pointers.reverse();
+    pointers.push(0);
+
+    let argv = below(addr & !3, 4 * pointers.len())?;
+    for (i, pointer) in pointers.iter().enumerate() {
+        mem.write(argv + 4 * i as Address, &endian.bytes(*pointer));
+    }
+    let sp = below(argv, 4)?;
+    let argc = args.len() as Word;
+    mem.write(sp, &endian.bytes(argc));
+
+    regs.set(RegisterID::SP, sp);
+    regs.set(RegisterID::A0, argc);
+    regs.set(RegisterID::A1, argv);
+    Ok(())
 }
 
 #[derive(Debug, PartialEq)]
 enum Syscall {
+    Read,
     Write,
+    Exit,
+    ClockGetTime,
+    Brk,
+    FormatSigned,
+    FormatUnsigned,
+    FormatHex,
+    Abort,
+    MarkSecret,
+    AtExit,
+    DlOpen,
+    DlSym,
+    Poll,
+    SysInfo,
+    Reset,
+    IrqHandler,
+    IrqClaim,
+    IrqPriority,
+    IrqStatus,
+    RegionBegin,
+    RegionEnd,
+    TermInfo,
+    Open,
+    Close,
+    Pipe,
+    Dup,
+    SigAction,
+    SigReturn,
+    Alarm,
+    WaitForInterrupt,
+    Log,
 }
 
 impl TryFrom<Word> for Syscall {
@@ -148,18 +1541,150 @@ impl TryFrom<Word> for Syscall {
 
     fn try_from(word: Word) -> Result<Self> {
         match word {
+            63 => Ok(Syscall::Read),
+            57 => Ok(Syscall::Close),
+            59 => Ok(Syscall::Pipe),
+            24 => Ok(Syscall::Dup),
             64 => Ok(Syscall::Write),
+            93 => Ok(Syscall::Exit),
+            113 => Ok(Syscall::ClockGetTime),
+            214 => Ok(Syscall::Brk),
+            256 => Ok(Syscall::FormatSigned),
+            257 => Ok(Syscall::FormatUnsigned),
+            258 => Ok(Syscall::FormatHex),
+            259 => Ok(Syscall::Abort),
+            260 => Ok(Syscall::MarkSecret),
+            261 => Ok(Syscall::AtExit),
+            262 => Ok(Syscall::DlOpen),
+            263 => Ok(Syscall::DlSym),
+            264 => Ok(Syscall::Poll),
+            265 => Ok(Syscall::SysInfo),
+            266 => Ok(Syscall::Reset),
+            267 => Ok(Syscall::IrqHandler),
+            268 => Ok(Syscall::IrqClaim),
+            269 => Ok(Syscall::IrqPriority),
+            270 => Ok(Syscall::IrqStatus),
+            271 => Ok(Syscall::RegionBegin),
+            272 => Ok(Syscall::RegionEnd),
+            273 => Ok(Syscall::TermInfo),
+            274 => Ok(Syscall::Open),
+            275 => Ok(Syscall::SigAction),
+            276 => Ok(Syscall::SigReturn),
+            277 => Ok(Syscall::Alarm),
+            278 => Ok(Syscall::WaitForInterrupt),
+            279 => Ok(Syscall::Log),
             _ => Err(Error::SyscallUnknown(word)),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum Opcode {
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Opcode {
     LoadImmediate,
     Add,
+    Jal,
+    Jalr,
+    Beq,
+    Bne,
+    Blt,
+    Bge,
+    Lw,
+    Sw,
     ECall,
     EBreak,
+    Lb,
+    Lh,
+    Sb,
+    Sh,
+    Mret,
+    And,
+    Or,
+    Xor,
+    Sra,
+    Sub,
+    Sll,
+    Srl,
+    Slt,
+    Sltu,
+    Mul,
+    Div,
+    Rem,
+    Auipc,
+    Lui,
+}
+
+impl Opcode {
+    // Every opcode, so the ISA audit can check the tables against each other.
+    pub const ALL: [Opcode; 31] = [
+        Opcode::LoadImmediate,
+        Opcode::Add,
+        Opcode::Jal,
+        Opcode::Jalr,
+        Opcode::Beq,
+        Opcode::Bne,
+        Opcode::Blt,
+        Opcode::Bge,
+        Opcode::Lw,
+        Opcode::Sw,
+        Opcode::ECall,
+        Opcode::EBreak,
+        Opcode::Lb,
+        Opcode::Lh,
+        Opcode::Sb,
+        Opcode::Sh,
+        Opcode::Mret,
+        Opcode::And,
+        Opcode::Or,
+        Opcode::Xor,
+        Opcode::Sra,
+        Opcode::Sub,
+        Opcode::Sll,
+        Opcode::Srl,
+        Opcode::Slt,
+        Opcode::Sltu,
+        Opcode::Mul,
+        Opcode::Div,
+        Opcode::Rem,
+        Opcode::Auipc,
+        Opcode::Lui,
+    ];
+
+    // The value of the opcode field; the inverse of decoding.
+    pub fn code(&self) -> Word {
+        match self {
+            Opcode::LoadImmediate => 0b00001,
+            Opcode::Add => 0b00010,
+            Opcode::Jal => 0b01100,
+            Opcode::Jalr => 0b01101,
+            Opcode::Mret => 0b01110,
+            Opcode::Beq => 0b01111,
+            Opcode::Bne => 0b10000,
+            Opcode::Blt => 0b10001,
+            Opcode::Bge => 0b10010,
+            Opcode::Lw => 0b10101,
+            Opcode::Sw => 0b10110,
+            Opcode::ECall => 0b10111,
+            Opcode::EBreak => 0b11000,
+            Opcode::Lb => 0b11001,
+            Opcode::Lh => 0b11010,
+            Opcode::Sb => 0b11011,
+            Opcode::Sh => 0b11100,
+            Opcode::And => 0b00011,
+            Opcode::Or => 0b00101,
+            Opcode::Xor => 0b00111,
+            Opcode::Sra => 0b01000,
+            Opcode::Sub => 0b01001,
+            Opcode::Sll => 0b01010,
+            Opcode::Srl => 0b01011,
+            Opcode::Slt => 0b10011,
+            Opcode::Sltu => 0b10100,
+            Opcode::Mul => 0b11101,
+            Opcode::Div => 0b11110,
+            Opcode::Rem => 0b11111,
+            Opcode::Auipc => 0b00110,
+            Opcode::Lui => 0b00100,
+        }
+    }
 }
 
 impl TryFrom<Word> for Opcode {
@@ -169,15 +1694,42 @@ impl TryFrom<Word> for Opcode {
         match word {
             0b00001 => Ok(Opcode::LoadImmediate),
             0b00010 => Ok(Opcode::Add),
+            0b01100 => Ok(Opcode::Jal),
+            0b01101 => Ok(Opcode::Jalr),
+            0b01110 => Ok(Opcode::Mret),
+            0b01111 => Ok(Opcode::Beq),
+            0b10000 => Ok(Opcode::Bne),
+            0b10001 => Ok(Opcode::Blt),
+            0b10010 => Ok(Opcode::Bge),
+            0b10101 => Ok(Opcode::Lw),
+            0b10110 => Ok(Opcode::Sw),
             0b10111 => Ok(Opcode::ECall),
             0b11000 => Ok(Opcode::EBreak),
+            0b11001 => Ok(Opcode::Lb),
+            0b11010 => Ok(Opcode::Lh),
+            0b11011 => Ok(Opcode::Sb),
+            0b11100 => Ok(Opcode::Sh),
+            0b00011 => Ok(Opcode::And),
+            0b00101 => Ok(Opcode::Or),
+            0b00111 => Ok(Opcode::Xor),
+            0b01000 => Ok(Opcode::Sra),
+            0b01001 => Ok(Opcode::Sub),
+            0b01010 => Ok(Opcode::Sll),
+            0b01011 => Ok(Opcode::Srl),
+            0b10011 => Ok(Opcode::Slt),
+            0b10100 => Ok(Opcode::Sltu),
+            0b11101 => Ok(Opcode::Mul),
+            0b11110 => Ok(Opcode::Div),
+            0b11111 => Ok(Opcode::Rem),
+            0b00110 => Ok(Opcode::Auipc),
+            0b00100 => Ok(Opcode::Lui),
             _ => Err(Error::OpcodeUnknown(word)),
         }
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Hash, PartialOrd)]
-enum RegisterID {
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, PartialOrd)]
+pub enum RegisterID {
     X0,
     A0,
     A1,
@@ -196,6 +1748,56 @@ enum RegisterID {
     SP,
 }
 
+impl fmt::Display for RegisterID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RegisterID::X0 => "x0",
+            RegisterID::A0 => "a0",
+            RegisterID::A1 => "a1",
+            RegisterID::A2 => "a2",
+            RegisterID::A3 => "a3",
+            RegisterID::A4 => "a4",
+            RegisterID::A5 => "a5",
+            RegisterID::A6 => "a6",
+            RegisterID::A7 => "a7",
+            RegisterID::A8 => "a8",
+            RegisterID::A9 => "a9",
+            RegisterID::A10 => "a10",
+            RegisterID::A11 => "a11",
+            RegisterID::A12 => "a12",
+            RegisterID::RA => "ra",
+            RegisterID::SP => "sp",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for RegisterID {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "x0" | "zero" => Ok(RegisterID::X0),
+            "a0" => Ok(RegisterID::A0),
+            "a1" => Ok(RegisterID::A1),
+            "a2" => Ok(RegisterID::A2),
+            "a3" => Ok(RegisterID::A3),
+            "a4" => Ok(RegisterID::A4),
+            "a5" => Ok(RegisterID::A5),
+            "a6" => Ok(RegisterID::A6),
+            "a7" => Ok(RegisterID::A7),
+            "a8" => Ok(RegisterID::A8),
+            "a9" => Ok(RegisterID::A9),
+            "a10" => Ok(RegisterID::A10),
+            "a11" => Ok(RegisterID::A11),
+            "a12" => Ok(RegisterID::A12),
+            "ra" => Ok(RegisterID::RA),
+            "sp" => Ok(RegisterID::SP),
+            _ => Err(Error::RegisterNameUnknown(name.to_string())),
+        }
+    }
+}
+
 impl TryFrom<Word> for RegisterID {
     type Error = Error;
 
@@ -222,13 +1824,31 @@ impl TryFrom<Word> for RegisterID {
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct Instruction {
-    opcode: Opcode,
-    rd: RegisterID,
-    rs1: RegisterID,
-    rs2: RegisterID,
-    imm: u16,
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Instruction {
+    pub opcode: Opcode,
+    pub rd: RegisterID,
+    pub rs1: RegisterID,
+    pub rs2: RegisterID,
+    pub imm: u16,
+}
+
+impl Instruction {
+    // The immediate sign-extended from 15 bits, as branch and jump offsets,
+    // memory offsets and ALU operands use it.
+    pub fn offset(&self) -> i32 {
+        (i32::from(self.imm) << 17) >> 17
+    }
+
+    // Immediates wider than the 15-bit field are truncated; the ISA audit
+    // checks that the ones the ISA uses survive the round trip.
+    pub fn encode(&self) -> Word {
+        (Word::from(self.imm) & 0x7fff) << 17
+            | (self.rs2 as Word) << 13
+            | (self.rs1 as Word) << 9
+            | (self.rd as Word) << 5
+            | self.opcode.code()
+    }
 }
 
 impl TryFrom<Word> for Instruction {
@@ -250,21 +1870,156 @@ impl TryFrom<Word> for Instruction {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use claims::{assert_err, assert_err_eq, assert_ok, assert_ok_eq, assert_some_eq};
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err, assert_err_eq, assert_ok, assert_ok_eq, assert_some_eq};
+
+    // A machine with `program` assembled and loaded, and nothing attached,
+    // for the tests of every module.
+    pub(crate) fn machine(program: &str) -> Machine<Vec<u8>> {
+        Machine::builder()
+            .program(&asm::assemble(program).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn new_returns_initialized_machine() {
+        let want: Machine<&mut Vec<u8>> = Machine {
+            pc: 0u32,
+            stdout: None,
+            mem: Memory::default(),
+            regs: Registers::default(),
+            ..Default::default()
+        };
+        let got = Machine::new();
+        assert_eq!(want, got);
+    }
+
+    #[test]
+    fn builder_applies_the_configured_layout() {
+        let machine: Machine<&mut Vec<u8>> = Machine::builder()
+            .layout(Layout::microcontroller())
+            .load_address(0x100)
+            .build()
+            .unwrap();
+
+        assert_eq!(machine.pc, 0x100);
+        assert_eq!(machine.regs.get(&RegisterID::SP), 0x4000);
+        assert_eq!(machine.mem.page_size(), 256);
+        assert_eq!(
+            machine.layout,
+            Layout {
+                page_size: 256,
+                load_address: 0x100,
+                stack_top: 0x4000,
+                heap_base: 0x2000,
+            }
+        );
+    }
+
+    #[test]
+    fn builder_loads_the_program_and_sets_up_arguments() {
+        let machine: Machine<&mut Vec<u8>> = Machine::builder()
+            .layout(Layout::microcontroller())
+            .load_address(0x100)
+            .program(&[1, 2, 3, 4])
+            .args(["prog", "-v"])
+            .build()
+            .unwrap();
+
+        assert_eq!(machine.mem.read(0x100, 4), vec![1, 2, 3, 4]);
+        assert_eq!(machine.mem.read(0x3ff8, 8), b"prog\0-v\0");
+        let sp = machine.regs.get(&RegisterID::SP);
+        assert_eq!(sp, 0x3fe8);
+        assert_eq!(
+            machine.mem.read(sp, 16),
+            [
+                [0x00, 0x00, 0x00, 0x02], // argc
+                [0x00, 0x00, 0x3f, 0xf8], // argv[0]
+                [0x00, 0x00, 0x3f, 0xfd], // argv[1]
+                [0x00, 0x00, 0x00, 0x00], // argv[2]
+            ]
+            .concat()
+        );
+        assert_eq!(machine.regs.get(&RegisterID::A0), 2);
+        assert_eq!(machine.regs.get(&RegisterID::A1), sp + 4);
+        assert!(machine.mem.dirty_pages().is_empty());
+    }
+
+    #[test]
+    fn builder_presets_registers_and_state_is_inspectable_after_a_run() {
+        // add a0, a1, a2, then ebreak.
+        let program = [0, 0, 0x64, 0x22, 0, 0, 0, 0x18];
+        let mut machine = Machine::builder()
+            .load_address(0x40)
+            .program(&program)
+            .register(RegisterID::A1, 3)
+            .register(RegisterID::A2, 4)
+            .stdout(Vec::new())
+            .build()
+            .unwrap();
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.pc(), 0x48);
+        assert_eq!(machine.register(RegisterID::A0), 7);
+        assert_eq!(machine.registers().get(&RegisterID::SP), 0x0010_0000);
+        assert_eq!(machine.memory().read(0x40, 4), [0, 0, 0x64, 0x22]);
+        assert_eq!(machine.retired(), 2);
+        assert_eq!(machine.take_stdout(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn builder_places_sections_into_banks() {
+        let machine: Machine<&mut Vec<u8>> = Machine::builder()
+            .banks(BankConfig {
+                window: 0x8000..0x9000,
+                select: 0xff00,
+                count: 4,
+            })
+            .bank_section(2, 0x8000, b"overlay")
+            .build()
+            .unwrap();
+        let mut mem = machine.mem;
+
+        assert_eq!(mem.read(0x8000, 7), [0; 7]);
+        mem.set(0xff00, 2);
+        assert_eq!(mem.read(0x8000, 7), b"overlay");
+        assert_eq!(mem.pages(), BTreeSet::from([0x8000, 0xf000]));
+    }
 
     #[test]
-    fn new_returns_initialized_machine() {
-        let want: Machine<&mut Vec<u8>> = Machine {
-            pc: 0u32,
-            stdout: None,
-            mem: Memory::default(),
-            regs: Registers::default(),
-        };
-        let got = Machine::new();
-        assert_eq!(want, got);
+    fn builder_rejects_an_invalid_layout() {
+        struct TestCase {
+            builder: MachineBuilder<&'static mut Vec<u8>>,
+            want: Error,
+        }
+        let cases = [
+            TestCase {
+                builder: Machine::builder().page_size(1000),
+                want: Error::LayoutInvalid("page size must be a power of two"),
+            },
+            TestCase {
+                builder: Machine::builder().load_address(0x2_0000),
+                want: Error::LayoutInvalid("heap base must not precede the load address"),
+            },
+            TestCase {
+                builder: Machine::builder().stack_top(0x100),
+                want: Error::LayoutInvalid("stack top must not precede the heap base"),
+            },
+            TestCase {
+                builder: Machine::builder()
+                    .heap_base(0)
+                    .stack_top(0x10)
+                    .args(["prog", "an argument longer than the stack"]),
+                want: Error::LayoutInvalid("arguments do not fit below the stack top"),
+            },
+        ];
+        for case in cases {
+            assert_err_eq!(case.builder.build(), case.want);
+        }
     }
 
     #[test]
@@ -278,10 +2033,136 @@ mod tests {
             word: Word,
             want: Syscall,
         }
-        let cases = [TestCase {
-            word: 64,
-            want: Syscall::Write,
-        }];
+        let cases = [
+            TestCase {
+                word: 63,
+                want: Syscall::Read,
+            },
+            TestCase {
+                word: 57,
+                want: Syscall::Close,
+            },
+            TestCase {
+                word: 59,
+                want: Syscall::Pipe,
+            },
+            TestCase {
+                word: 24,
+                want: Syscall::Dup,
+            },
+            TestCase {
+                word: 64,
+                want: Syscall::Write,
+            },
+            TestCase {
+                word: 93,
+                want: Syscall::Exit,
+            },
+            TestCase {
+                word: 113,
+                want: Syscall::ClockGetTime,
+            },
+            TestCase {
+                word: 214,
+                want: Syscall::Brk,
+            },
+            TestCase {
+                word: 256,
+                want: Syscall::FormatSigned,
+            },
+            TestCase {
+                word: 257,
+                want: Syscall::FormatUnsigned,
+            },
+            TestCase {
+                word: 258,
+                want: Syscall::FormatHex,
+            },
+            TestCase {
+                word: 259,
+                want: Syscall::Abort,
+            },
+            TestCase {
+                word: 260,
+                want: Syscall::MarkSecret,
+            },
+            TestCase {
+                word: 261,
+                want: Syscall::AtExit,
+            },
+            TestCase {
+                word: 262,
+                want: Syscall::DlOpen,
+            },
+            TestCase {
+                word: 263,
+                want: Syscall::DlSym,
+            },
+            TestCase {
+                word: 264,
+                want: Syscall::Poll,
+            },
+            TestCase {
+                word: 265,
+                want: Syscall::SysInfo,
+            },
+            TestCase {
+                word: 266,
+                want: Syscall::Reset,
+            },
+            TestCase {
+                word: 267,
+                want: Syscall::IrqHandler,
+            },
+            TestCase {
+                word: 268,
+                want: Syscall::IrqClaim,
+            },
+            TestCase {
+                word: 269,
+                want: Syscall::IrqPriority,
+            },
+            TestCase {
+                word: 270,
+                want: Syscall::IrqStatus,
+            },
+            TestCase {
+                word: 271,
+                want: Syscall::RegionBegin,
+            },
+            TestCase {
+                word: 272,
+                want: Syscall::RegionEnd,
+            },
+            TestCase {
+                word: 273,
+                want: Syscall::TermInfo,
+            },
+            TestCase {
+                word: 274,
+                want: Syscall::Open,
+            },
+            TestCase {
+                word: 275,
+                want: Syscall::SigAction,
+            },
+            TestCase {
+                word: 276,
+                want: Syscall::SigReturn,
+            },
+            TestCase {
+                word: 277,
+                want: Syscall::Alarm,
+            },
+            TestCase {
+                word: 278,
+                want: Syscall::WaitForInterrupt,
+            },
+            TestCase {
+                word: 279,
+                want: Syscall::Log,
+            },
+        ];
         for case in cases {
             assert_ok_eq!(Syscall::try_from(case.word), case.want);
         }
@@ -307,6 +2188,22 @@ mod tests {
                 word: 0b00010,
                 want: Opcode::Add,
             },
+            TestCase {
+                word: 0b01100,
+                want: Opcode::Jal,
+            },
+            TestCase {
+                word: 0b01101,
+                want: Opcode::Jalr,
+            },
+            TestCase {
+                word: 0b10001,
+                want: Opcode::Blt,
+            },
+            TestCase {
+                word: 0b10101,
+                want: Opcode::Lw,
+            },
             TestCase {
                 word: 0b10111,
                 want: Opcode::ECall,
@@ -315,6 +2212,34 @@ mod tests {
                 word: 0b11000,
                 want: Opcode::EBreak,
             },
+            TestCase {
+                word: 0b11100,
+                want: Opcode::Sh,
+            },
+            TestCase {
+                word: 0b01110,
+                want: Opcode::Mret,
+            },
+            TestCase {
+                word: 0b01001,
+                want: Opcode::Sub,
+            },
+            TestCase {
+                word: 0b10100,
+                want: Opcode::Sltu,
+            },
+            TestCase {
+                word: 0b11111,
+                want: Opcode::Rem,
+            },
+            TestCase {
+                word: 0b00110,
+                want: Opcode::Auipc,
+            },
+            TestCase {
+                word: 0b00100,
+                want: Opcode::Lui,
+            },
         ];
         for case in cases {
             assert_ok_eq!(Opcode::try_from(case.word), case.want);
@@ -329,6 +2254,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn registers_can_be_parsed_from_their_names() {
+        assert_ok_eq!("a12".parse::<RegisterID>(), RegisterID::A12);
+        assert_ok_eq!("zero".parse::<RegisterID>(), RegisterID::X0);
+        assert_err_eq!(
+            "a13".parse::<RegisterID>(),
+            Error::RegisterNameUnknown("a13".to_string())
+        );
+    }
+
     #[test]
     fn registers_can_be_decoded_from_binary_representation() {
         struct TestCase {
@@ -483,6 +2418,7 @@ mod tests {
                 (2, 0b0000_0000),
                 (3, 0b0010_0001),
             ]),
+            ..Default::default()
         };
         assert_eq!(want, machine);
     }
@@ -516,10 +2452,82 @@ mod tests {
                 (2, 0b0110_0100),
                 (3, 0b0010_0010),
             ]),
+            ..Default::default()
         };
         assert_eq!(want, machine);
     }
 
+    // Loads the words into memory from address 0.
+    fn program(words: &[Word]) -> Memory {
+        let mut mem = Memory::default();
+        for (i, word) in words.iter().enumerate() {
+            mem.write(4 * i as Address, &word.to_be_bytes());
+        }
+        mem
+    }
+
+    #[test]
+    fn run_executes_a_loop_with_a_backwards_branch() {
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem: program(&[
+                0x0006_0021, // li a0, 3
+                0x0002_0442, // add a1, a1, x0, 1
+                0xfff8_2411, // blt a1, a0, -4
+                0x0000_0018, // ebreak
+            ]),
+            ..Default::default()
+        };
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.regs.get(&RegisterID::A1), 3);
+        assert_eq!(machine.pc, 16);
+        assert_eq!(machine.retired, 8);
+    }
+
+    #[test]
+    fn run_executes_calls_and_returns_with_jal_and_jalr() {
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem: program(&[
+                0x0018_01cc, // jal ra, +12
+                0x0000_0242, // add a1, a0, x0
+                0x0000_0018, // ebreak
+                0x000e_0021, // li a0, 7
+                0x0000_1c0d, // jalr x0, ra, 0
+            ]),
+            ..Default::default()
+        };
+
+        assert_ok!(machine.step());
+        assert_eq!((machine.pc, machine.regs.get(&RegisterID::RA)), (12, 4));
+        assert_eq!(machine.call_stack().len(), 1);
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.regs.get(&RegisterID::A1), 7);
+        assert_eq!(machine.pc, 12);
+        assert!(machine.call_stack().is_empty());
+    }
+
+    #[test]
+    fn jumps_take_sign_extended_offsets() {
+        let mut machine: Machine<Vec<u8>> = Machine {
+            pc: 0x20,
+            // jal x0, -8
+            mem: Memory::from([(0x20, 0xff), (0x21, 0xf0), (0x22, 0x00), (0x23, 0x0c)]),
+            ..Default::default()
+        };
+        assert_ok!(machine.step());
+        assert_eq!(machine.pc, 0x18);
+
+        // jalr a2, a1, -4
+        machine.mem.write(0x18, &0xfff8_046d_u32.to_be_bytes());
+        machine.regs.set(RegisterID::A1, 0x104);
+        assert_ok!(machine.step());
+        assert_eq!(machine.pc, 0x100);
+        assert_eq!(machine.regs.get(&RegisterID::A2), 0x1c);
+    }
+
     #[test]
     fn run_executes_an_ebreak_instruction() {
         let mut machine: Machine<&mut Vec<u8>> = Machine {
@@ -532,7 +2540,7 @@ mod tests {
             ..Default::default()
         };
 
-        assert_ok!(machine.run());
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
 
         let want = Machine {
             pc: 4,
@@ -544,10 +2552,42 @@ mod tests {
                 (2, 0b0000_0000),
                 (3, 0b0001_1000),
             ]),
+            ..Default::default()
         };
         assert_eq!(want, machine);
     }
 
+    #[test]
+    fn run_executes_an_ecall_instruction_that_reads_data_from_stdin() {
+        let mut machine: Machine<&mut Vec<u8>> = Machine {
+            pc: 0,
+            stdin: Some(Input::new(&b"hello"[..])),
+            regs: Registers::from([
+                (RegisterID::A0, 0),  // fd = 0 (stdin)
+                (RegisterID::A1, 8),  // *buf = 8
+                (RegisterID::A2, 4),  // len = 4
+                (RegisterID::A7, 63), // syscall "read"
+            ]),
+            mem: Memory::from([
+                // ECall
+                (0, 0b0000_0000),
+                (1, 0b0000_0000),
+                (2, 0b0000_0000),
+                (3, 0b0001_0111),
+                // EBreak
+                (4, 0b0000_0000),
+                (5, 0b0000_0000),
+                (6, 0b0000_0000),
+                (7, 0b0001_1000),
+            ]),
+            ..Default::default()
+        };
+        assert_ok!(machine.run());
+
+        assert_eq!(machine.regs.get(&RegisterID::A0), 4);
+        assert_eq!(machine.mem.read(8, 5), b"hell\0");
+    }
+
     #[test]
     fn run_executes_an_ecall_instruction_that_writes_data_to_stdout() {
         let mut output: Vec<u8> = Vec::new();
@@ -578,6 +2618,7 @@ mod tests {
                 (11, 'l'.try_into().unwrap()),
                 (12, 'o'.try_into().unwrap()),
             ]),
+            ..Default::default()
         };
         assert_ok!(machine.run());
 
@@ -641,10 +2682,185 @@ mod tests {
                 (14, 0b0000_0000),
                 (15, 0b0001_1000),
             ]),
+            ..Default::default()
         };
         assert_eq!(want, machine);
     }
 
+    #[test]
+    fn run_executes_ecall_instructions_that_format_numbers_into_memory() {
+        struct TestCase {
+            syscall: Word,
+            value: Word,
+            want: &'static str,
+        }
+        let cases = [
+            TestCase {
+                syscall: 256,
+                value: -1234i32 as Word,
+                want: "-1234",
+            },
+            TestCase {
+                syscall: 257,
+                value: u32::MAX,
+                want: "4294967295",
+            },
+            TestCase {
+                syscall: 258,
+                value: 0xbeef,
+                want: "beef",
+            },
+        ];
+        for case in cases {
+            let mut machine: Machine<&mut Vec<u8>> = Machine {
+                regs: Registers::from([
+                    (RegisterID::A0, case.value),
+                    (RegisterID::A1, 0x100), // *buf = 0x100
+                    (RegisterID::A2, 16),    // len = 16
+                    (RegisterID::A7, case.syscall),
+                ]),
+                mem: Memory::from([
+                    // ECall
+                    (0, 0b0000_0000),
+                    (1, 0b0000_0000),
+                    (2, 0b0000_0000),
+                    (3, 0b0001_0111),
+                    // EBreak
+                    (4, 0b0000_0000),
+                    (5, 0b0000_0000),
+                    (6, 0b0000_0000),
+                    (7, 0b0001_1000),
+                ]),
+                ..Default::default()
+            };
+            assert_ok!(machine.run());
+
+            assert_eq!(machine.regs.get(&RegisterID::A0), case.want.len() as Word);
+            assert_eq!(
+                machine.mem.read(0x100, case.want.len()),
+                case.want.as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn format_syscalls_truncate_output_to_the_buffer_length() {
+        let mut machine: Machine<&mut Vec<u8>> = Machine {
+            regs: Registers::from([
+                (RegisterID::A0, 123_456),
+                (RegisterID::A1, 0x100), // *buf = 0x100
+                (RegisterID::A2, 3),     // len = 3
+                (RegisterID::A7, 257),   // syscall "format unsigned"
+            ]),
+            mem: Memory::from([
+                // ECall
+                (0, 0b0000_0000),
+                (1, 0b0000_0000),
+                (2, 0b0000_0000),
+                (3, 0b0001_0111),
+                // EBreak
+                (4, 0b0000_0000),
+                (5, 0b0000_0000),
+                (6, 0b0000_0000),
+                (7, 0b0001_1000),
+            ]),
+            ..Default::default()
+        };
+        assert_ok!(machine.run());
+
+        assert_eq!(machine.regs.get(&RegisterID::A0), 6);
+        assert_eq!(machine.mem.read(0x100, 4), b"123\0");
+    }
+
+    #[test]
+    fn run_halts_with_the_guest_message_when_an_abort_syscall_is_made() {
+        let mut machine: Machine<&mut Vec<u8>> = Machine {
+            regs: Registers::from([
+                (RegisterID::A0, 8),   // *msg = 8
+                (RegisterID::A1, 4),   // len = 4
+                (RegisterID::A7, 259), // syscall "abort"
+            ]),
+            mem: Memory::from([
+                // Add
+                (0, 0b0000_0000),
+                (1, 0b0000_0000),
+                (2, 0b0000_0000),
+                (3, 0b0000_0010),
+                // ECall
+                (4, 0b0000_0000),
+                (5, 0b0000_0000),
+                (6, 0b0000_0000),
+                (7, 0b0001_0111),
+                // data
+                (8, b'o'),
+                (9, b'o'),
+                (10, b'p'),
+                (11, b's'),
+            ]),
+            ..Default::default()
+        };
+
+        let reason = machine.run().unwrap();
+
+        assert_eq!(
+            reason,
+            HaltReason::Abort {
+                pc: 4,
+                message: "oops".to_string(),
+            }
+        );
+        assert_eq!(
+            reason.to_string(),
+            "guest aborted at pc 0x00000004: oops".to_string()
+        );
+    }
+
+    #[test]
+    fn memory_tracks_pages_written_since_the_last_clear() {
+        let mut mem = Memory::with_page_size(256);
+        assert!(mem.dirty_pages().is_empty());
+
+        mem.set(0x10, 1);
+        mem.write(0x2fe, &[1, 2, 3, 4]);
+        assert_eq!(mem.dirty_pages(), vec![0x000, 0x200, 0x300]);
+
+        mem.clear_dirty();
+        assert!(mem.dirty_pages().is_empty());
+        assert_eq!(mem.get(0x301), 4);
+
+        mem.set(0x1ff, 1);
+        assert_eq!(mem.dirty_pages(), vec![0x100]);
+    }
+
+    #[test]
+    #[should_panic(expected = "page size must be a power of two, not 24")]
+    fn memory_pages_must_be_a_power_of_two_bytes() {
+        Memory::with_page_size(24);
+    }
+
+    #[test]
+    fn memory_accesses_cross_pages_and_wrap_around() {
+        let mut mem = Memory::with_page_size(16);
+
+        mem.write(0xe, &[1, 2, 3, 4]);
+        mem.write(0xffff_fffe, &[5, 6, 7, 8]);
+
+        assert_eq!(mem.read_u32(0xe), 0x0102_0304);
+        assert_eq!(mem.read_u32(0xffff_fffe), 0x0506_0708);
+        assert_eq!(mem.read(0xffff_fffc, 8), [0, 0, 5, 6, 7, 8, 0, 0]);
+        assert_eq!(mem.pages(), BTreeSet::from([0, 0x10, 0xffff_fff0]));
+        // A page holding only zeroes reads the same as no page at all.
+        let before = mem.clone();
+        mem.write(0x100, &[0]);
+        assert_eq!(mem, before);
+    }
+
+    #[test]
+    fn memory_loaded_from_an_image_starts_clean() {
+        let mem = Memory::from([(0, 1), (0x5000, 2)]);
+        assert!(mem.dirty_pages().is_empty());
+    }
+
     #[test]
     fn x0_register_is_always_zero() {
         let mut registers = Registers::default();
@@ -0,0 +1,292 @@
+use crate::{Address, HaltReason, Machine};
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+// How often, in instructions, the wall clock is read.
+const CLOCK_INTERVAL: u64 = 1024;
+
+// What one run of an untrusted guest may use, for hosts running many of
+// them side by side. A guest going over a limit halts with the reason
+// naming it. Steps and wall-clock time are checked before each
+// instruction, which is left to run, and the rest once it and the syscall
+// or device activity it led to are done, so a guest is stopped at the
+// instruction that took it over.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Limits {
+    // Instructions the guest may execute.
+    pub max_steps: Option<u64>,
+    // Bytes of memory the guest may have written to, counted a page at a
+    // time; pages shared with a base image count once written.
+    pub max_memory: Option<u64>,
+    // Bytes the guest may write to stdout, pipes and host files in all.
+    // The write that would take it over writes only what is left of the
+    // limit, so no more ever reaches the host, and halts the machine.
+    pub max_output_bytes: Option<u64>,
+    // Fds the guest may have open at once, stdin and stdout included.
+    pub max_open_files: Option<usize>,
+    // How long the guest may run for in host time.
+    pub wall_clock: Option<Duration>,
+}
+
+// What the guest has used of the limits in force, as a snapshot saves it
+// for the resumed run to carry on counting from.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct LimitUsage {
+    pub steps: u64,
+    pub output_bytes: u64,
+    pub elapsed: Duration,
+}
+
+// The limits in force and what the guest has used of them since they were
+// set.
+#[derive(Debug)]
+pub(crate) struct Usage {
+    limits: Limits,
+    steps: u64,
+    output: u64,
+    // Whether a write was cut short at the output limit.
+    output_cut: bool,
+    started: Instant,
+}
+
+impl<W: Write> Machine<W> {
+    // Enforces `limits` from now on, counting from nothing used; None
+    // removes them.
+    pub fn set_limits(&mut self, limits: Option<Limits>) {
+        self.usage = limits.map(|limits| Usage {
+            limits,
+            steps: 0,
+            output: 0,
+            output_cut: false,
+            started: Instant::now(),
+        });
+    }
+
+    pub fn limits(&self) -> Option<Limits> {
+        self.usage.as_ref().map(|usage| usage.limits)
+    }
+
+    // Nothing used when no limits are in force.
+    pub fn limit_usage(&self) -> LimitUsage {
+        self.usage
+            .as_ref()
+            .map_or_else(LimitUsage::default, |usage| LimitUsage {
+                steps: usage.steps,
+                output_bytes: usage.output,
+                elapsed: usage.started.elapsed(),
+            })
+    }
+
+    // Counts on from `used` under the limits in force, if there are any.
+    pub(crate) fn resume_limit_usage(&mut self, used: LimitUsage) {
+        if let Some(usage) = &mut self.usage {
+            usage.steps = used.steps;
+            usage.output = used.output_bytes;
+            usage.started = Instant::now()
+                .checked_sub(used.elapsed)
+                .unwrap_or(usage.started);
+        }
+    }
+
+    // The limit the instruction at pc would take the guest over by
+    // running at all.
+    pub(crate) fn limit_reached(&self) -> Option<HaltReason> {
+        let usage = self.usage.as_ref()?;
+        let pc = self.pc;
+        if usage.limits.max_steps.is_some_and(|max| usage.steps >= max) {
+            return Some(HaltReason::StepLimit { pc });
+        }
+        let timed = usage.steps % CLOCK_INTERVAL == 0;
+        if timed
+            && usage
+                .limits
+                .wall_clock
+                .is_some_and(|max| usage.started.elapsed() >= max)
+        {
+            return Some(HaltReason::WallClockLimit { pc });
+        }
+        None
+    }
+
+    // Counts the instruction at `pc` just executed, returning the limit it
+    // took the guest over, if any.
+    pub(crate) fn limit_exceeded(&mut self, pc: Address) -> Option<HaltReason> {
+        let memory = self.mem.pages.len() as u64 * u64::from(self.mem.page_size());
+        let open_files = self.files.open();
+        let replaying = self.replaying;
+        let usage = self.usage.as_mut()?;
+        if !replaying {
+            usage.steps += 1;
+        }
+        let limits = usage.limits;
+        if limits.max_memory.is_some_and(|max| memory > max) {
+            Some(HaltReason::MemoryLimit { pc })
+        } else if let (true, Some(limit)) = (usage.output_cut, limits.max_output_bytes) {
+            Some(HaltReason::OutputLimit { pc, limit })
+        } else if limits.max_open_files.is_some_and(|max| open_files > max) {
+            Some(HaltReason::OpenFileLimit { pc })
+        } else {
+            None
+        }
+    }
+
+    // How much of a `len` byte write the output limit lets through.
+    pub(crate) fn output_allowance(&mut self, len: usize) -> usize {
+        let Some(usage) = &mut self.usage else {
+            return len;
+        };
+        let Some(max) = usage.limits.max_output_bytes else {
+            return len;
+        };
+        let left = max.saturating_sub(usage.output);
+        if len as u64 > left {
+            usage.output_cut = true;
+            left as usize
+        } else {
+            len
+        }
+    }
+
+    pub(crate) fn count_output(&mut self, bytes: usize) {
+        if let Some(usage) = &mut self.usage {
+            usage.output += bytes as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, RegisterID};
+    use claims::assert_ok_eq;
+
+    fn run(program: &str, limits: Limits) -> HaltReason {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .stdout(Vec::new())
+            .program(&assemble(program).unwrap())
+            .build()
+            .unwrap();
+        machine.set_limits(Some(limits));
+        machine.run().unwrap()
+    }
+
+    #[test]
+    fn each_limit_halts_with_its_own_reason() {
+        let spin = "spin: j spin";
+        // Writes a page further on every time round.
+        let touch = "li a1, 0x4000
+             loop:  sw a1, 0(a1)
+                    add a1, a1, a1
+                    j loop";
+        // Writes the eight bytes at 0 to stdout over and over.
+        let print = "li a2, 8
+             loop:  li a0, 1
+                    li a7, 64
+                    ecall
+                    j loop";
+        // Opens pipes until stopped.
+        let pipes = "li a0, 0x2000
+             loop:  li a7, 59
+                    ecall
+                    j loop";
+        let cases = [
+            (
+                spin,
+                Limits {
+                    max_steps: Some(10),
+                    ..Limits::default()
+                },
+                HaltReason::StepLimit { pc: 0 },
+            ),
+            (
+                touch,
+                Limits {
+                    max_memory: Some(3 * 4096),
+                    ..Limits::default()
+                },
+                HaltReason::MemoryLimit { pc: 4 },
+            ),
+            (
+                print,
+                Limits {
+                    max_output_bytes: Some(20),
+                    ..Limits::default()
+                },
+                HaltReason::OutputLimit { pc: 12, limit: 20 },
+            ),
+            (
+                pipes,
+                Limits {
+                    max_open_files: Some(6),
+                    ..Limits::default()
+                },
+                HaltReason::OpenFileLimit { pc: 8 },
+            ),
+            (
+                spin,
+                Limits {
+                    wall_clock: Some(Duration::ZERO),
+                    ..Limits::default()
+                },
+                HaltReason::WallClockLimit { pc: 0 },
+            ),
+        ];
+        for (program, limits, reason) in cases {
+            assert_eq!(run(program, limits), reason);
+        }
+    }
+
+    #[test]
+    fn output_stops_at_the_limit() {
+        // Prints the eight bytes at 0x2000 until a write comes up short.
+        let program = "li a2, 8
+                       sw a5, 0x2000
+                       sw a5, 0x2004
+               loop:   li a0, 1
+                       li a1, 0x2000
+                       li a7, 64
+                       ecall
+                       beq a0, a2, loop
+                       ebreak";
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .stdout(Vec::new())
+            .program(&assemble(program).unwrap())
+            .register(RegisterID::A5, u32::from_be_bytes(*b"spam"))
+            .build()
+            .unwrap();
+        machine.set_limits(Some(Limits {
+            max_output_bytes: Some(20),
+            ..Limits::default()
+        }));
+
+        assert_ok_eq!(machine.run(), HaltReason::OutputLimit { pc: 24, limit: 20 });
+        assert_eq!(machine.stdout().unwrap(), b"spamspamspamspamspam");
+        assert_eq!(machine.register(RegisterID::A0), 4);
+        assert_eq!(
+            HaltReason::OutputLimit { pc: 24, limit: 20 }.to_string(),
+            "output limit of 20 bytes reached at pc 0x00000018"
+        );
+    }
+
+    #[test]
+    fn limits_count_from_when_they_are_set() {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&assemble("spin: j spin").unwrap())
+            .fuel(5)
+            .build()
+            .unwrap();
+        assert_ok_eq!(machine.run(), HaltReason::ResourceExhausted { pc: 0 });
+
+        let limits = Limits {
+            max_steps: Some(3),
+            ..Limits::default()
+        };
+        machine.set_limits(Some(limits));
+        machine.set_fuel(None);
+        assert_ok_eq!(machine.run(), HaltReason::StepLimit { pc: 0 });
+        assert_eq!(machine.retired, 8);
+        assert_eq!(machine.limits(), Some(limits));
+    }
+}
@@ -0,0 +1,372 @@
+use crate::{Address, Error, Result};
+use std::collections::HashMap;
+
+// A named range of the address space that sections can be placed in.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub origin: Address,
+    pub length: u32,
+}
+
+impl MemoryRegion {
+    fn end(&self) -> u64 {
+        u64::from(self.origin) + u64::from(self.length)
+    }
+}
+
+// Where the linker places the sections of a program, in a minimal form of
+// the GNU ld script language:
+//
+//   ENTRY(_start)
+//   MEMORY {
+//       rom : ORIGIN = 0x0, LENGTH = 16K
+//       ram : ORIGIN = 0x80000000, LENGTH = 64K
+//   }
+//   SECTIONS {
+//       .text > rom
+//       .rodata > rom
+//       .data > ram
+//   }
+//
+// Sections are placed in the order they are listed, each on a word
+// boundary after the last one placed in the same region. Lengths may be
+// given in K or M, and `#` starts a comment.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct LinkerScript {
+    // The label the program starts at, rather than the start of .text.
+    pub entry: Option<String>,
+    pub regions: Vec<MemoryRegion>,
+    // Each section and the region it goes in.
+    pub sections: Vec<(String, String)>,
+}
+
+fn failed(reason: &str) -> Error {
+    Error::LinkFailed(reason.to_string())
+}
+
+// The script's words and punctuation, each with its line number.
+fn tokens(text: &str) -> Vec<(usize, String)> {
+    let mut tokens = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split_once('#').map_or(line, |(code, _)| code);
+        let mut word = String::new();
+        for c in line.chars() {
+            if c.is_ascii_alphanumeric() || "_.$".contains(c) {
+                word.push(c);
+                continue;
+            }
+            if !word.is_empty() {
+                tokens.push((index + 1, std::mem::take(&mut word)));
+            }
+            if !c.is_whitespace() {
+                tokens.push((index + 1, c.to_string()));
+            }
+        }
+        if !word.is_empty() {
+            tokens.push((index + 1, word));
+        }
+    }
+    tokens
+}
+
+// A decimal or 0x hexadecimal number, optionally in K or M.
+fn number(text: &str) -> Option<u64> {
+    let (digits, scale) = match text.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1 << 10),
+        None => match text.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 1 << 20),
+            None => (text, 1),
+        },
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    value.checked_mul(scale)
+}
+
+struct Parser {
+    tokens: Vec<(usize, String)>,
+    next: usize,
+}
+
+impl Parser {
+    fn error(&self, reason: &str) -> Error {
+        let line = self
+            .tokens
+            .get(self.next)
+            .or(self.tokens.last())
+            .map_or(1, |(line, _)| *line);
+        Error::LinkerScriptInvalid(format!("line {line}: {reason}"))
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.next).map(|(_, token)| token.as_str())
+    }
+
+    fn take(&mut self) -> Result<String> {
+        let (_, token) = self
+            .tokens
+            .get(self.next)
+            .ok_or_else(|| self.error("unexpected end of script"))?;
+        self.next += 1;
+        Ok(token.clone())
+    }
+
+    fn expect(&mut self, want: &str) -> Result<()> {
+        match self.peek() {
+            Some(token) if token == want => {
+                self.next += 1;
+                Ok(())
+            }
+            Some(token) => Err(self.error(&format!("expected {want:?}, found {token:?}"))),
+            None => Err(self.error(&format!("expected {want:?}"))),
+        }
+    }
+
+    // `name = value`, for a value that fits in 32 bits.
+    fn assignment(&mut self, name: &str) -> Result<u32> {
+        self.expect(name)?;
+        self.expect("=")?;
+        let value = self.take()?;
+        self.next -= 1;
+        let value = number(&value)
+            .and_then(|value| u32::try_from(value).ok())
+            .ok_or_else(|| self.error(&format!("invalid {name} {value:?}")))?;
+        self.next += 1;
+        Ok(value)
+    }
+
+    // Parses a `{ ... }` block, calling `item` until the closing brace.
+    fn block(&mut self, mut item: impl FnMut(&mut Self) -> Result<()>) -> Result<()> {
+        self.expect("{")?;
+        while self.peek() != Some("}") {
+            item(self)?;
+        }
+        self.expect("}")
+    }
+}
+
+impl LinkerScript {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokens(text),
+            next: 0,
+        };
+        let mut script = Self::default();
+        while let Some(command) = parser.peek() {
+            match command {
+                "ENTRY" => {
+                    parser.next += 1;
+                    parser.expect("(")?;
+                    script.entry = Some(parser.take()?);
+                    parser.expect(")")?;
+                }
+                "MEMORY" => {
+                    parser.next += 1;
+                    parser.block(|parser| {
+                        let name = parser.take()?;
+                        parser.expect(":")?;
+                        let origin = parser.assignment("ORIGIN")?;
+                        parser.expect(",")?;
+                        let length = parser.assignment("LENGTH")?;
+                        if script.regions.iter().any(|region| region.name == name) {
+                            return Err(parser.error(&format!("duplicate region {name:?}")));
+                        }
+                        script.regions.push(MemoryRegion {
+                            name,
+                            origin,
+                            length,
+                        });
+                        Ok(())
+                    })?;
+                }
+                "SECTIONS" => {
+                    parser.next += 1;
+                    parser.block(|parser| {
+                        let section = parser.take()?;
+                        parser.expect(">")?;
+                        let region = parser.take()?;
+                        if script.sections.iter().any(|(name, _)| *name == section) {
+                            return Err(parser.error(&format!("duplicate section {section:?}")));
+                        }
+                        script.sections.push((section, region));
+                        Ok(())
+                    })?;
+                }
+                command => return Err(parser.error(&format!("unknown command {command:?}"))),
+            }
+        }
+        script.validate()?;
+        Ok(script)
+    }
+
+    // Checks that the regions fit in memory without overlapping, and that
+    // every section goes in one of them.
+    fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(Error::LinkerScriptInvalid(reason));
+        for (index, region) in self.regions.iter().enumerate() {
+            if region.end() > 1 << 32 {
+                return invalid(format!(
+                    "region {} runs past the end of memory",
+                    region.name
+                ));
+            }
+            for other in &self.regions[..index] {
+                if u64::from(region.origin) < other.end() && u64::from(other.origin) < region.end()
+                {
+                    return invalid(format!(
+                        "regions {} and {} overlap",
+                        other.name, region.name
+                    ));
+                }
+            }
+        }
+        for (section, region) in &self.sections {
+            if !self.regions.iter().any(|known| known.name == *region) {
+                return invalid(format!(
+                    "section {section} is placed in unknown region {region}"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Places sections of the given names and sizes, returning the address
+    // of each. Empty sections need not be placed.
+    pub fn place(&self, sections: &[(&str, u32)]) -> Result<Vec<Address>> {
+        let mut cursors: HashMap<&str, u64> = self
+            .regions
+            .iter()
+            .map(|region| (region.name.as_str(), u64::from(region.origin)))
+            .collect();
+        let mut bases = vec![None; sections.len()];
+        for (section, name) in &self.sections {
+            let Some(index) = sections.iter().position(|(placed, _)| placed == section) else {
+                continue;
+            };
+            let (Some(region), Some(cursor)) = (
+                self.regions.iter().find(|region| region.name == *name),
+                cursors.get_mut(name.as_str()),
+            ) else {
+                return Err(failed(&format!(
+                    "section {section} is placed in unknown region {name}"
+                )));
+            };
+            let base = cursor.next_multiple_of(4);
+            let end = base + u64::from(sections[index].1);
+            if end > region.end() {
+                return Err(failed(&format!("section {section} does not fit in {name}")));
+            }
+            bases[index] = Some(base as Address);
+            *cursor = end;
+        }
+        sections
+            .iter()
+            .zip(bases)
+            .map(|((section, size), base)| match base {
+                Some(base) => Ok(base),
+                None if *size == 0 => Ok(0),
+                None => Err(failed(&format!(
+                    "section {section} is not placed by the script"
+                ))),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err_eq, assert_ok, assert_ok_eq};
+
+    const SCRIPT: &str = "
+        ENTRY(_start)
+        MEMORY {
+            rom : ORIGIN = 0x0, LENGTH = 16K   # code and constants
+            ram : ORIGIN = 0x80000000, LENGTH = 0x10
+        }
+        SECTIONS {
+            .text > rom
+            .data > ram
+            .rodata > rom
+        }
+    ";
+
+    #[test]
+    fn sections_are_placed_in_order_within_their_regions() {
+        let script = assert_ok!(LinkerScript::parse(SCRIPT));
+
+        assert_eq!(script.entry.as_deref(), Some("_start"));
+        assert_eq!(
+            script.regions[0],
+            MemoryRegion {
+                name: "rom".to_string(),
+                origin: 0,
+                length: 0x4000,
+            }
+        );
+        assert_ok_eq!(
+            script.place(&[(".text", 10), (".rodata", 4), (".data", 16), (".bss", 0)]),
+            vec![0, 12, 0x8000_0000, 0]
+        );
+        assert_err_eq!(
+            script.place(&[(".data", 17)]),
+            Error::LinkFailed("section .data does not fit in ram".to_string())
+        );
+        assert_err_eq!(
+            script.place(&[(".bss", 4)]),
+            Error::LinkFailed("section .bss is not placed by the script".to_string())
+        );
+
+        let script = LinkerScript {
+            sections: vec![(".text".to_string(), "flash".to_string())],
+            ..LinkerScript::default()
+        };
+        assert_err_eq!(
+            script.place(&[(".text", 4)]),
+            Error::LinkFailed("section .text is placed in unknown region flash".to_string())
+        );
+    }
+
+    #[test]
+    fn regions_may_end_at_the_top_of_memory() {
+        let script = assert_ok!(LinkerScript::parse(
+            "MEMORY { top : ORIGIN = 0xfffffff0, LENGTH = 16\n low : ORIGIN = 0, LENGTH = 16 }\n\
+             SECTIONS { .data > top }"
+        ));
+
+        assert_ok_eq!(script.place(&[(".data", 16)]), vec![0xffff_fff0]);
+    }
+
+    #[test]
+    fn malformed_scripts_are_rejected() {
+        let cases = [
+            (
+                "MEMORY { rom : ORIGIN = 0 }",
+                "line 1: expected \",\", found \"}\"",
+            ),
+            ("STARTUP(crt0.o)", "line 1: unknown command \"STARTUP\""),
+            (
+                "MEMORY {\n rom : ORIGIN = 0x1_0000_0000, LENGTH = 4 }",
+                "line 2: invalid ORIGIN \"0x1_0000_0000\"",
+            ),
+            (
+                "MEMORY { a : ORIGIN = 0, LENGTH = 8\n b : ORIGIN = 4, LENGTH = 8 }",
+                "regions a and b overlap",
+            ),
+            (
+                "SECTIONS { .text > flash }",
+                "section .text is placed in unknown region flash",
+            ),
+            ("SECTIONS { .text >", "line 1: unexpected end of script"),
+        ];
+        for (script, reason) in cases {
+            assert_err_eq!(
+                LinkerScript::parse(script),
+                Error::LinkerScriptInvalid(reason.to_string())
+            );
+        }
+    }
+}
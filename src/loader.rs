@@ -0,0 +1,682 @@
+use crate::{endian::Endian, Address, Error, Machine, RegisterID, Result, Word};
+use std::{collections::HashMap, io::Write};
+
+const MAGIC: &[u8; 4] = b"RSO1";
+
+// A fix-up applied to a word of a library's image once its load address
+// is known. The word already in the image is the addend.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Relocation {
+    // Adds the library's load address.
+    Relative { offset: u32 },
+    // Adds the address of the import at `index`.
+    Import { offset: u32, index: u32 },
+}
+
+// A position-independent library image. The format, big-endian throughout:
+//
+//   magic        "RSO1"
+//   image        u32 length, then the bytes
+//   exports      u32 count, each: u8 name length, name, u32 offset
+//   imports      u32 count, each: u8 name length, name
+//   relocations  u32 count, each: u8 kind (0 relative, 1 import),
+//                u32 offset, and for imports a u32 import index
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct SharedObject {
+    pub image: Vec<u8>,
+    pub exports: Vec<(String, u32)>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::LibraryInvalid(reason.to_string())
+}
+
+// Reads big-endian fields from a binary format, reporting malformed input
+// with the format's own error.
+pub(crate) struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub invalid: fn(&str) -> Error,
+}
+
+// Reads fields from an ELF file, in the byte order the file says it is in.
+struct ElfReader<'a> {
+    reader: Reader<'a>,
+    endian: Endian,
+}
+
+impl ElfReader<'_> {
+    fn new(bytes: &[u8], endian: Endian) -> ElfReader<'_> {
+        ElfReader {
+            reader: Reader {
+                bytes,
+                invalid: elf_invalid,
+            },
+            endian,
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let value = self.reader.u16()?;
+        Ok(match self.endian {
+            Endian::Big => value,
+            Endian::Little => value.swap_bytes(),
+        })
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.reader.take(4)?;
+        Ok(self.endian.word([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err((self.invalid)("truncated"));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from(self.u32()?) << 32 | u64::from(self.u32()?))
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let len = self.u8()?;
+        let bytes = self.take(len.into())?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| (self.invalid)("name is not UTF-8"))
+    }
+}
+
+impl SharedObject {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes, invalid };
+        if reader.take(4)? != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let len = reader.u32()?;
+        let image = reader.take(len as usize)?.to_vec();
+        let mut exports = Vec::new();
+        for _ in 0..reader.u32()? {
+            exports.push((reader.name()?, reader.u32()?));
+        }
+        let mut imports = Vec::new();
+        for _ in 0..reader.u32()? {
+            imports.push(reader.name()?);
+        }
+        let mut relocations = Vec::new();
+        for _ in 0..reader.u32()? {
+            let relocation = match reader.u8()? {
+                0 => Relocation::Relative {
+                    offset: reader.u32()?,
+                },
+                1 => Relocation::Import {
+                    offset: reader.u32()?,
+                    index: reader.u32()?,
+                },
+                _ => return Err(invalid("unknown relocation kind")),
+            };
+            relocations.push(relocation);
+        }
+        if !reader.bytes.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(Self {
+            image,
+            exports,
+            imports,
+            relocations,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn name(out: &mut Vec<u8>, name: &str) {
+            out.push(name.len() as u8);
+            out.extend(name.as_bytes());
+        }
+        let mut out = MAGIC.to_vec();
+        out.extend((self.image.len() as u32).to_be_bytes());
+        out.extend(&self.image);
+        out.extend((self.exports.len() as u32).to_be_bytes());
+        for (export, offset) in &self.exports {
+            name(&mut out, export);
+            out.extend(offset.to_be_bytes());
+        }
+        out.extend((self.imports.len() as u32).to_be_bytes());
+        for import in &self.imports {
+            name(&mut out, import);
+        }
+        out.extend((self.relocations.len() as u32).to_be_bytes());
+        for relocation in &self.relocations {
+            match relocation {
+                Relocation::Relative { offset } => {
+                    out.push(0);
+                    out.extend(offset.to_be_bytes());
+                }
+                Relocation::Import { offset, index } => {
+                    out.push(1);
+                    out.extend(offset.to_be_bytes());
+                    out.extend(index.to_be_bytes());
+                }
+            }
+        }
+        out
+    }
+}
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PHDR_LEN: usize = 32;
+
+// A part of a program placed in memory at `addr`. Memory past the end of
+// `data`, up to `size` bytes from `addr`, is zero, as for a .bss section.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Segment {
+    pub addr: Address,
+    pub data: Vec<u8>,
+    pub size: u32,
+}
+
+// A program ready to load: the segments it occupies, where it starts, and
+// the byte order its instructions and data are in.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Executable {
+    pub entry: Address,
+    pub segments: Vec<Segment>,
+    pub endian: Endian,
+}
+
+fn elf_invalid(reason: &str) -> Error {
+    Error::ExecutableInvalid(reason.to_string())
+}
+
+impl Executable {
+    // A flat image, loaded as a single segment at `addr` and entered at
+    // its first byte.
+    pub fn flat(image: &[u8], addr: Address) -> Self {
+        Self {
+            entry: addr,
+            segments: vec![Segment {
+                addr,
+                data: image.to_vec(),
+                size: image.len() as u32,
+            }],
+            endian: Endian::Big,
+        }
+    }
+
+    // An ELF file if `bytes` starts with the ELF magic, and otherwise a
+    // flat image loaded at `addr`.
+    pub fn parse(bytes: &[u8], addr: Address) -> Result<Self> {
+        if bytes.starts_with(ELF_MAGIC) {
+            Self::parse_elf(bytes)
+        } else {
+            Ok(Self::flat(bytes, addr))
+        }
+    }
+
+    // A 32-bit ELF executable, of which only the entry point and the
+    // PT_LOAD program headers matter: each is loaded at its virtual
+    // address. A little-endian file makes a little-endian executable.
+    // Sections, and the machine the file says it is for, are ignored.
+    pub fn parse_elf(bytes: &[u8]) -> Result<Self> {
+        let ident = bytes.get(..16).ok_or_else(|| elf_invalid("truncated"))?;
+        if ident[..4] != *ELF_MAGIC {
+            return Err(elf_invalid("bad magic"));
+        }
+        if ident[4] != ELFCLASS32 {
+            return Err(elf_invalid("not a 32-bit file"));
+        }
+        let endian = match ident[5] {
+            ELFDATA2MSB => Endian::Big,
+            ELFDATA2LSB => Endian::Little,
+            _ => return Err(elf_invalid("unknown byte order")),
+        };
+        let mut reader = ElfReader::new(&bytes[16..], endian);
+        if reader.u16()? != ET_EXEC {
+            return Err(elf_invalid("not an executable"));
+        }
+        let _machine = reader.u16()?;
+        let _version = reader.u32()?;
+        let entry = reader.u32()?;
+        let phoff = reader.u32()? as usize;
+        let _shoff = reader.u32()?;
+        let _flags = reader.u32()?;
+        let _ehsize = reader.u16()?;
+        let phentsize = reader.u16()? as usize;
+        let phnum = reader.u16()? as usize;
+        if phnum > 0 && phentsize < PHDR_LEN {
+            return Err(elf_invalid("program headers are too small"));
+        }
+
+        let mut segments: Vec<Segment> = Vec::new();
+        for index in 0..phnum {
+            let start = phoff + index * phentsize;
+            let mut header = ElfReader::new(bytes.get(start..).unwrap_or_default(), endian);
+            let kind = header.u32()?;
+            let offset = header.u32()? as usize;
+            let addr = header.u32()?;
+            let _paddr = header.u32()?;
+            let filesz = header.u32()?;
+            let size = header.u32()?;
+            if kind != PT_LOAD {
+                continue;
+            }
+            if filesz > size {
+                return Err(elf_invalid("segment is larger in the file than in memory"));
+            }
+            if u64::from(addr) + u64::from(size) > 1 << 32 {
+                return Err(elf_invalid("segment runs past the end of memory"));
+            }
+            let data = bytes
+                .get(offset..offset + filesz as usize)
+                .ok_or_else(|| elf_invalid("segment runs past the end of the file"))?;
+            let overlaps = |other: &Segment| {
+                u64::from(addr) < u64::from(other.addr) + u64::from(other.size)
+                    && u64::from(other.addr) < u64::from(addr) + u64::from(size)
+            };
+            if segments.iter().any(overlaps) {
+                return Err(elf_invalid("segments overlap"));
+            }
+            segments.push(Segment {
+                addr,
+                data: data.to_vec(),
+                size,
+            });
+        }
+        Ok(Self {
+            entry,
+            segments,
+            endian,
+        })
+    }
+
+    // The executable as an ELF file parse_elf reads back: the header, a
+    // program header for each segment, readable, writable and executable,
+    // and then the segments' data in order, in the executable's byte order.
+    pub fn to_elf(&self) -> Vec<u8> {
+        let endian = self.endian;
+        let half = |value: u16| match endian {
+            Endian::Big => value.to_be_bytes(),
+            Endian::Little => value.to_le_bytes(),
+        };
+        let phnum = self.segments.len() as u16;
+        let data = match endian {
+            Endian::Big => ELFDATA2MSB,
+            Endian::Little => ELFDATA2LSB,
+        };
+        let mut out = [&ELF_MAGIC[..], &[ELFCLASS32, data, 1], &[0; 9]].concat();
+        out.extend(half(ET_EXEC));
+        out.extend(half(0));
+        out.extend(endian.bytes(1));
+        out.extend(endian.bytes(self.entry));
+        out.extend(endian.bytes(52));
+        out.extend(endian.bytes(0));
+        out.extend(endian.bytes(0));
+        out.extend(half(52));
+        out.extend(half(PHDR_LEN as u16));
+        out.extend(half(phnum));
+        out.extend([0; 6]);
+        let mut offset = 52 + PHDR_LEN * usize::from(phnum);
+        for segment in &self.segments {
+            let filesz = segment.data.len() as u32;
+            let header = [
+                PT_LOAD,
+                offset as u32,
+                segment.addr,
+                segment.addr,
+                filesz,
+                segment.size,
+                7,
+                4,
+            ];
+            out.extend(header.iter().flat_map(|field| endian.bytes(*field)));
+            offset += segment.data.len();
+        }
+        for segment in &self.segments {
+            out.extend(&segment.data);
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Library {
+    name: String,
+    exports: HashMap<String, Address>,
+}
+
+// Libraries the host has made available to the guest, and the ones the
+// guest has loaded. Loaded libraries are mapped one after another from the
+// library base, each starting on a page boundary. Handles given to the
+// guest are one more than the index of the library, so zero can mean
+// failure.
+#[derive(Debug, Default, Clone)]
+pub struct Libraries {
+    available: HashMap<String, Vec<u8>>,
+    loaded: Vec<Library>,
+    base: Option<Address>,
+    next: Option<Address>,
+}
+
+impl Libraries {
+    // Forgets the loaded libraries, so the next one loads at the base again.
+    pub(crate) fn unload_all(&mut self) {
+        self.loaded.clear();
+        self.next = self.base;
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn add_library(&mut self, name: &str, bytes: Vec<u8>) {
+        self.libraries.available.insert(name.to_string(), bytes);
+    }
+
+    pub fn set_library_base(&mut self, base: Address) {
+        self.libraries.base = Some(base);
+        self.libraries.next = Some(base);
+    }
+
+    // Loads the named library, if it is not loaded already, and returns its
+    // handle. Imports are resolved against the exports of the libraries
+    // loaded before it, and its exports are added to the symbol table.
+    pub fn load_library(&mut self, name: &str) -> Result<Word> {
+        if let Some(index) = self
+            .libraries
+            .loaded
+            .iter()
+            .position(|lib| lib.name == name)
+        {
+            return Ok(index as Word + 1);
+        }
+        let bytes = self
+            .libraries
+            .available
+            .get(name)
+            .ok_or_else(|| Error::LibraryUnknown(name.to_string()))?;
+        let object = SharedObject::parse(bytes)?;
+        let base = self
+            .libraries
+            .next
+            .ok_or(Error::LayoutInvalid("no library base is set"))?;
+
+        let imports = object
+            .imports
+            .iter()
+            .map(|import| {
+                self.libraries
+                    .loaded
+                    .iter()
+                    .find_map(|lib| lib.exports.get(import).copied())
+                    .ok_or_else(|| Error::SymbolUnknown(import.clone()))
+            })
+            .collect::<Result<Vec<Address>>>()?;
+        let mut image = object.image;
+        for relocation in &object.relocations {
+            let (offset, value) = match *relocation {
+                Relocation::Relative { offset } => (offset, base),
+                Relocation::Import { offset, index } => {
+                    let addr = imports
+                        .get(index as usize)
+                        .ok_or_else(|| invalid("relocation names a missing import"))?;
+                    (offset, *addr)
+                }
+            };
+            let word = image
+                .get_mut(offset as usize..offset as usize + 4)
+                .ok_or_else(|| invalid("relocation is outside the image"))?;
+            let addend = Word::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            word.copy_from_slice(&addend.wrapping_add(value).to_be_bytes());
+        }
+
+        self.write_memory(base, &image);
+        let mut exports = HashMap::new();
+        for (export, offset) in object.exports {
+            let addr = base + offset;
+            self.symbols.insert(&export, addr, 0);
+            exports.insert(export, addr);
+        }
+        let page = self.layout.page_size;
+        self.libraries.next = Some((base + image.len() as Address).next_multiple_of(page));
+        self.libraries.loaded.push(Library {
+            name: name.to_string(),
+            exports,
+        });
+        Ok(self.libraries.loaded.len() as Word)
+    }
+
+    pub fn library_symbol(&self, handle: Word, name: &str) -> Option<Address> {
+        let index = handle.checked_sub(1)?;
+        let library = self.libraries.loaded.get(index as usize)?;
+        library.exports.get(name).copied()
+    }
+
+    // The guest side of the loader: dlopen(name, len) and
+    // dlsym(handle, name, len), both returning zero on failure.
+    pub(crate) fn dlopen(&mut self) -> Result<()> {
+        let name = self.guest_string(RegisterID::A0, RegisterID::A1)?;
+        let handle = self.load_library(&name).unwrap_or(0);
+        self.set_register(RegisterID::A0, handle);
+        Ok(())
+    }
+
+    pub(crate) fn dlsym(&mut self) -> Result<()> {
+        let handle = self.regs.get(&RegisterID::A0);
+        let name = self.guest_string(RegisterID::A1, RegisterID::A2)?;
+        let addr = self.library_symbol(handle, &name).unwrap_or(0);
+        self.set_register(RegisterID::A0, addr);
+        Ok(())
+    }
+
+    pub(crate) fn guest_string(&mut self, addr: RegisterID, len: RegisterID) -> Result<String> {
+        let addr = self.regs.get(&addr);
+        let len = self.guest_buffer(addr, self.regs.get(&len).into())?;
+        Ok(String::from_utf8_lossy(&self.load(addr, len)).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    fn libc() -> SharedObject {
+        SharedObject {
+            image: vec![0; 8],
+            exports: vec![("puts".to_string(), 4)],
+            ..Default::default()
+        }
+    }
+
+    // A table of two words: a pointer to its own second word, and a
+    // pointer to puts.
+    fn app() -> SharedObject {
+        SharedObject {
+            image: [4u32.to_be_bytes(), 0u32.to_be_bytes()].concat(),
+            exports: vec![("table".to_string(), 0)],
+            imports: vec!["puts".to_string()],
+            relocations: vec![
+                Relocation::Relative { offset: 0 },
+                Relocation::Import {
+                    offset: 4,
+                    index: 0,
+                },
+            ],
+        }
+    }
+
+    // An ELF executable entered at `entry`, with a PT_LOAD header for each
+    // (address, bytes, size in memory) and a PT_NOTE header.
+    fn elf(entry: Address, segments: &[(Address, &[u8], u32)]) -> Vec<u8> {
+        let phnum = segments.len() as u16 + 1;
+        let mut out = [&ELF_MAGIC[..], &[ELFCLASS32, ELFDATA2MSB, 1], &[0; 9]].concat();
+        out.extend(ET_EXEC.to_be_bytes());
+        out.extend(0u16.to_be_bytes());
+        out.extend(1u32.to_be_bytes());
+        out.extend(entry.to_be_bytes());
+        out.extend(52u32.to_be_bytes());
+        out.extend([0; 10]);
+        out.extend((PHDR_LEN as u16).to_be_bytes());
+        out.extend(phnum.to_be_bytes());
+        out.extend([0; 6]);
+        let mut offset = 52 + PHDR_LEN * usize::from(phnum);
+        let mut headers = vec![4u32, 0, 0, 0, 0, 0, 0, 0];
+        for (addr, data, size) in segments {
+            let filesz = data.len() as u32;
+            headers.extend([PT_LOAD, offset as u32, *addr, *addr, filesz, *size, 5, 4]);
+            offset += data.len();
+        }
+        out.extend(headers.iter().flat_map(|field| field.to_be_bytes()));
+        for (_, data, _) in segments {
+            out.extend(*data);
+        }
+        out
+    }
+
+    #[test]
+    fn elf_segments_load_at_their_addresses() {
+        let code = crate::asm::assemble(
+            "lw a0, 0x2000
+lw a1, 0x2004
+ebreak",
+        )
+        .unwrap();
+        let bytes = elf(0x400, &[(0x400, &code, 12), (0x2000, &[0, 0, 0, 7], 8)]);
+        let executable = Executable::parse(&bytes, 0).unwrap();
+        assert_eq!(executable.entry, 0x400);
+        assert_eq!(executable.segments.len(), 2);
+
+        let mut machine: Machine<Vec<u8>> =
+            Machine::builder().executable(executable).build().unwrap();
+        assert_eq!(machine.pc(), 0x400);
+        assert_eq!(
+            machine.register(RegisterID::SP),
+            crate::Layout::default().stack_top
+        );
+
+        assert_ok_eq!(machine.run(), crate::HaltReason::Breakpoint);
+        assert_eq!(machine.register(RegisterID::A0), 7);
+        assert_eq!(machine.register(RegisterID::A1), 0);
+    }
+
+    #[test]
+    fn executables_round_trip_through_elf() {
+        let big = Executable {
+            entry: 0x8000_0004,
+            segments: vec![
+                Segment {
+                    addr: 0x8000_0000,
+                    data: vec![1, 2, 3, 4, 5],
+                    size: 5,
+                },
+                Segment {
+                    addr: 0x100,
+                    data: vec![6],
+                    size: 0x40,
+                },
+            ],
+            endian: Endian::Big,
+        };
+        let little = Executable {
+            endian: Endian::Little,
+            ..big.clone()
+        };
+
+        for executable in [big, little] {
+            assert_ok_eq!(Executable::parse(&executable.to_elf(), 0), executable);
+        }
+    }
+
+    #[test]
+    fn anything_but_elf_is_a_flat_image() {
+        assert_ok_eq!(
+            Executable::parse(b"\x7fEL", 0x100),
+            Executable::flat(b"\x7fEL", 0x100)
+        );
+    }
+
+    #[test]
+    fn malformed_elf_files_are_rejected() {
+        let bytes = elf(0, &[(0x400, &[1, 2, 3, 4], 4)]);
+        let unknown_order = [&bytes[..5], &[3], &bytes[6..]].concat();
+        let overlapping = elf(0, &[(0x400, &[0; 8], 8), (0x404, &[0; 4], 4)]);
+        let oversized = elf(0, &[(0xffff_fffc, &[0; 4], 8)]);
+        let cases = [
+            (&bytes[..40], "truncated"),
+            (
+                &bytes[..bytes.len() - 1],
+                "segment runs past the end of the file",
+            ),
+            (&unknown_order, "unknown byte order"),
+            (&overlapping, "segments overlap"),
+            (&oversized, "segment runs past the end of memory"),
+        ];
+        for (bytes, reason) in cases {
+            assert_err_eq!(
+                Executable::parse_elf(bytes),
+                Error::ExecutableInvalid(reason.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn shared_objects_round_trip_through_bytes() {
+        let object = app();
+        assert_ok_eq!(SharedObject::parse(&object.to_bytes()), object);
+        assert_err_eq!(
+            SharedObject::parse(&object.to_bytes()[..10]),
+            Error::LibraryInvalid("truncated".to_string())
+        );
+    }
+
+    #[test]
+    fn libraries_are_mapped_relocated_and_resolved() {
+        let mut machine: Machine<Vec<u8>> = Machine::new();
+        machine.add_library("libc", libc().to_bytes());
+        machine.add_library("app", app().to_bytes());
+        assert_err_eq!(
+            machine.load_library("app"),
+            Error::LayoutInvalid("no library base is set")
+        );
+        machine.set_library_base(0x8000);
+
+        assert_err_eq!(
+            machine.load_library("app"),
+            Error::SymbolUnknown("puts".to_string())
+        );
+        assert_ok_eq!(machine.load_library("libc"), 1);
+        assert_ok_eq!(machine.load_library("app"), 2);
+        assert_ok_eq!(machine.load_library("libc"), 1);
+
+        assert_eq!(machine.library_symbol(1, "puts"), Some(0x8004));
+        assert_eq!(machine.library_symbol(2, "table"), Some(0x9000));
+        assert_eq!(machine.library_symbol(2, "puts"), None);
+        assert_eq!(
+            machine.mem.read(0x9000, 8),
+            [0x9004u32.to_be_bytes(), 0x8004u32.to_be_bytes()].concat()
+        );
+        assert_eq!(machine.symbols.label(0x9004), Some("table+0x4".to_string()));
+    }
+}
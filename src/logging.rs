@@ -0,0 +1,149 @@
+use crate::{
+    output::{errno, EINVAL},
+    Address, Machine, RegisterID, Result, Word,
+};
+use std::{fmt, io::Write};
+
+// How severe a guest log record is, numbered 1 (Error) to 5 (Trace) as the
+// log and tracing crates number theirs, so that hosts can map one onto the
+// other directly.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum LogLevel {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl TryFrom<Word> for LogLevel {
+    type Error = Word;
+
+    fn try_from(level: Word) -> std::result::Result<Self, Word> {
+        match level {
+            1 => Ok(LogLevel::Error),
+            2 => Ok(LogLevel::Warn),
+            3 => Ok(LogLevel::Info),
+            4 => Ok(LogLevel::Debug),
+            5 => Ok(LogLevel::Trace),
+            _ => Err(level),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        };
+        f.write_str(name)
+    }
+}
+
+// A message the guest logged, with the pc of the ecall that logged it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    pub pc: Address,
+    pub message: String,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} pc {:#010x}: {}", self.level, self.pc, self.message)
+    }
+}
+
+type Logger = Box<dyn FnMut(&LogRecord)>;
+
+#[derive(Default)]
+pub struct GuestLog(Option<Logger>);
+
+impl fmt::Debug for GuestLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GuestLog({})", self.0.is_some())
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Passes each record the guest logs to `logger`, replacing any logger set
+    // before. Without one the records are discarded.
+    pub fn set_logger(&mut self, logger: impl FnMut(&LogRecord) + 'static) {
+        self.guest_log.0 = Some(Box::new(logger));
+    }
+
+    // log(level, msg, len) logs the `len` byte `msg` at `level`, 1 (error)
+    // to 5 (trace), returning 0, or -EINVAL for any other level.
+    pub(crate) fn log_syscall(&mut self) -> Result<()> {
+        let level = LogLevel::try_from(self.regs.get(&RegisterID::A0));
+        let message = self.guest_string(RegisterID::A1, RegisterID::A2)?;
+        let result = match level {
+            Ok(level) => {
+                if let Some(logger) = &mut self.guest_log.0 {
+                    logger(&LogRecord {
+                        level,
+                        pc: self.pc.wrapping_sub(4),
+                        message,
+                    });
+                }
+                0
+            }
+            Err(_) => errno(EINVAL),
+        };
+        self.set_register(RegisterID::A0, result);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, HaltReason};
+    use claims::assert_ok_eq;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn guest_log_records_carry_the_level_and_pc() {
+        // Logs the four bytes at 0x2000 at level a0, keeping the result in
+        // a3.
+        let program = assemble(
+            "sw a5, 0x2000
+             li a1, 0x2000
+             li a2, 4
+             li a7, 279
+             ecall
+             mv a3, a0
+             ebreak",
+        )
+        .unwrap();
+        let records = Rc::new(RefCell::new(Vec::new()));
+        for (level, result) in [(2, 0), (6, errno(EINVAL))] {
+            let mut machine = Machine::<Vec<u8>>::builder()
+                .program(&program)
+                .register(RegisterID::A0, level)
+                .register(RegisterID::A5, Word::from_be_bytes(*b"full"))
+                .build()
+                .unwrap();
+            let log = records.clone();
+            machine.set_logger(move |record| log.borrow_mut().push(record.clone()));
+
+            assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+            assert_eq!(machine.register(RegisterID::A3), result);
+        }
+
+        let records = records.borrow();
+        assert_eq!(
+            *records,
+            [LogRecord {
+                level: LogLevel::Warn,
+                pc: 16,
+                message: "full".to_string(),
+            }]
+        );
+        assert_eq!(records[0].to_string(), "warn pc 0x00000010: full");
+    }
+}
@@ -0,0 +1,248 @@
+use crate::{clock::Clock, state, Error, Layout, Machine, RegisterID, Result, Word};
+use std::{fmt, io::Write};
+
+// 64-bit FNV-1a, which is enough to tell runs apart and is simple enough to
+// reimplement when checking a manifest by hand.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Digest(u64);
+
+impl Default for Digest {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Digest {
+    pub fn of(data: &[u8]) -> u64 {
+        let mut digest = Self::default();
+        digest.update(data);
+        digest.finish()
+    }
+
+    // Continues a digest from the value `finish` returned.
+    pub fn resume(hash: u64) -> Self {
+        Self(hash)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // A digest of the architectural state: pc, the registers in order and
+    // every page holding data.
+    pub fn state_hash(&self) -> u64 {
+        let mut digest = Digest::default();
+        digest.update(&self.pc.to_be_bytes());
+        for id in 0..16 {
+            digest.update(&self.regs.get(&state::register(id)).to_be_bytes());
+        }
+        for page in self.mem.pages() {
+            digest.update(&page.to_be_bytes());
+            digest.update(&self.mem.page(page));
+        }
+        digest.finish()
+    }
+
+    // A digest of every byte the guest has read from stdin.
+    pub fn input_hash(&self) -> u64 {
+        self.input_digest.finish()
+    }
+}
+
+// Everything needed to reproduce a run and check that it was reproduced:
+// the program and its configuration going in, and digests of what came out.
+// The machine has no sources of randomness, so there are no seeds to record.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Manifest {
+    pub program: String,
+    pub program_hash: u64,
+    pub args: Vec<String>,
+    pub layout: Layout,
+    pub hart_id: Word,
+    pub clock: Clock,
+    pub input_hash: u64,
+    pub steps: u64,
+    pub state_hash: u64,
+    pub halt: String,
+}
+
+const HEADER: &str = "rmachine-manifest 1";
+
+impl Manifest {
+    pub fn record<W: Write>(
+        machine: &Machine<W>,
+        program: &str,
+        image: &[u8],
+        args: &[String],
+        halt: &str,
+    ) -> Self {
+        Self {
+            program: program.to_string(),
+            program_hash: Digest::of(image),
+            args: args.to_vec(),
+            layout: machine.layout,
+            hart_id: machine.hart_id,
+            clock: machine.clock(),
+            input_hash: machine.input_hash(),
+            steps: machine.retired,
+            state_hash: machine.state_hash(),
+            halt: halt.to_string(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = |reason: String| Error::ManifestInvalid(reason);
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(invalid("missing header".to_string()));
+        }
+        let mut fields = std::collections::HashMap::new();
+        let mut args = Vec::new();
+        for line in lines.filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            if key == "arg" {
+                args.push(value.to_string());
+            } else {
+                fields.insert(key, value);
+            }
+        }
+        let text = |key: &str| {
+            fields
+                .get(key)
+                .map(ToString::to_string)
+                .ok_or_else(|| invalid(format!("missing {key}")))
+        };
+        let number = |key: &str| {
+            let value = text(key)?;
+            let parsed = match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => value.parse(),
+            };
+            parsed.map_err(|_| invalid(format!("{key} is not a number")))
+        };
+        let word = |key: &str| {
+            Word::try_from(number(key)?).map_err(|_| invalid(format!("{key} is out of range")))
+        };
+        Ok(Self {
+            program: text("program")?,
+            program_hash: number("program-hash")?,
+            args,
+            layout: Layout {
+                page_size: word("page-size")?,
+                load_address: word("load-address")?,
+                heap_base: word("heap-base")?,
+                stack_top: word("stack-top")?,
+            },
+            hart_id: word("hart-id")?,
+            clock: parse_clock(&text("clock")?).ok_or_else(|| invalid("bad clock".to_string()))?,
+            input_hash: number("input-hash")?,
+            steps: number("steps")?,
+            state_hash: number("state-hash")?,
+            halt: text("halt")?,
+        })
+    }
+
+    // The fields describing the outcome of a run that differ between two
+    // manifests, for reporting a failed reproduction.
+    pub fn differences(&self, other: &Manifest) -> Vec<&'static str> {
+        let mut differences = Vec::new();
+        let outcome = [
+            ("program-hash", self.program_hash == other.program_hash),
+            ("input-hash", self.input_hash == other.input_hash),
+            ("steps", self.steps == other.steps),
+            ("state-hash", self.state_hash == other.state_hash),
+            ("halt", self.halt == other.halt),
+        ];
+        for (field, same) in outcome {
+            if !same {
+                differences.push(field);
+            }
+        }
+        differences
+    }
+}
+
+// Parses "host" or "cycles <hz> <epoch>".
+fn parse_clock(text: &str) -> Option<Clock> {
+    match text.split(' ').collect::<Vec<_>>()[..] {
+        ["host"] => Some(Clock::Host),
+        ["cycles", hz, epoch] => Some(Clock::Cycles {
+            hz: hz.parse().ok()?,
+            epoch: epoch.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        writeln!(f, "program {}", self.program)?;
+        writeln!(f, "program-hash {:#018x}", self.program_hash)?;
+        for arg in &self.args {
+            writeln!(f, "arg {arg}")?;
+        }
+        writeln!(f, "page-size {}", self.layout.page_size)?;
+        writeln!(f, "load-address {:#010x}", self.layout.load_address)?;
+        writeln!(f, "heap-base {:#010x}", self.layout.heap_base)?;
+        writeln!(f, "stack-top {:#010x}", self.layout.stack_top)?;
+        writeln!(f, "hart-id {}", self.hart_id)?;
+        match self.clock {
+            Clock::Host => writeln!(f, "clock host")?,
+            Clock::Cycles { hz, epoch } => writeln!(f, "clock cycles {hz} {epoch}")?,
+        }
+        writeln!(f, "input-hash {:#018x}", self.input_hash)?;
+        writeln!(f, "steps {}", self.steps)?;
+        writeln!(f, "state-hash {:#018x}", self.state_hash)?;
+        writeln!(f, "halt {}", self.halt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Memory;
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    #[test]
+    fn digests_match_the_fnv1a_reference_values() {
+        assert_eq!(Digest::of(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(Digest::of(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn manifests_round_trip_through_text() {
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem: Memory::from([(3, 0b0001_1000)]),
+            ..Default::default()
+        };
+        machine.input_digest.update(b"input");
+        machine.set_clock(Clock::Cycles {
+            hz: 1_000_000,
+            epoch: 0,
+        });
+        let image = machine.mem.read(0, 4);
+        let args = vec!["prog.bin".to_string(), "two words".to_string()];
+        let manifest =
+            Manifest::record(&machine, "prog.bin", &image, &args, "halted at breakpoint");
+
+        assert_ok_eq!(Manifest::parse(&manifest.to_string()), manifest.clone());
+        assert_err_eq!(
+            Manifest::parse("rmachine-manifest 1\nprogram p\n"),
+            Error::ManifestInvalid("missing program-hash".to_string())
+        );
+
+        let mut changed = manifest.clone();
+        changed.steps += 1;
+        changed.state_hash ^= 1;
+        assert_eq!(manifest.differences(&changed), vec!["steps", "state-hash"]);
+    }
+}
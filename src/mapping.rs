@@ -0,0 +1,128 @@
+use crate::{Address, Error, Machine, Memory, Result};
+use std::{fs, io::Write, path::Path, rc::Rc};
+
+// Read-only data shown in the address space without being copied into
+// memory's pages. The first guest write to a mapped page copies the page,
+// and later reads see the copy.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Mapping {
+    base: Address,
+    data: Rc<[u8]>,
+}
+
+impl Mapping {
+    fn end(&self) -> u64 {
+        u64::from(self.base) + self.data.len() as u64
+    }
+
+    fn contains(&self, addr: Address) -> bool {
+        addr >= self.base && u64::from(addr) < self.end()
+    }
+}
+
+impl Memory {
+    pub(crate) fn map(&mut self, base: Address, data: Rc<[u8]>) -> Result<()> {
+        let mapping = Mapping { base, data };
+        if mapping.end() > 1 << 32 {
+            return Err(Error::LayoutInvalid(
+                "mapping extends past the address space",
+            ));
+        }
+        let overlaps = self
+            .mappings
+            .iter()
+            .any(|other| u64::from(other.base) < mapping.end() && u64::from(base) < other.end());
+        if overlaps {
+            return Err(Error::LayoutInvalid("mapping overlaps another mapping"));
+        }
+        // Pages already holding data show the mapping from now on too.
+        let end = mapping.end();
+        for (base, page) in &mut self.pages {
+            for (offset, byte) in page.iter_mut().enumerate() {
+                let addr = u64::from(*base) + offset as u64;
+                if u64::from(mapping.base) <= addr && addr < end {
+                    *byte = mapping.data[(addr - u64::from(mapping.base)) as usize];
+                }
+            }
+        }
+        self.mappings.push(mapping);
+        Ok(())
+    }
+
+    // The mapped byte underneath `addr`, ignoring any guest writes.
+    pub(crate) fn mapped(&self, addr: Address) -> Option<u8> {
+        let mapping = self.mappings.iter().find(|m| m.contains(addr))?;
+        Some(mapping.data[(addr - mapping.base) as usize])
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Maps the contents of a host file read-only at `base`. The file is
+    // read once and shared; guest writes are copy-on-write.
+    pub fn map_file(&mut self, base: Address, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let data =
+            fs::read(path).map_err(|err| Error::MapFailed(format!("{}: {err}", path.display())))?;
+        self.mem.map(base, data.into())
+    }
+
+    pub fn map_bytes(&mut self, base: Address, data: Rc<[u8]>) -> Result<()> {
+        self.mem.map(base, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err_eq, assert_ok};
+
+    #[test]
+    fn writes_to_mapped_data_are_copy_on_write() {
+        let data: Rc<[u8]> = Rc::from(&b"dataset"[..]);
+        let mut mem = Memory::default();
+        assert_ok!(mem.map(0x100, data.clone()));
+
+        assert_eq!(mem.read(0x100, 8), b"dataset\0");
+        mem.write(0x100, b"DA");
+        mem.set(0x102, 0);
+        assert_eq!(mem.read(0x100, 7), b"DA\0aset");
+        assert_eq!(&data[..], b"dataset");
+
+        assert_err_eq!(
+            mem.map(0x106, data),
+            Error::LayoutInvalid("mapping overlaps another mapping")
+        );
+    }
+
+    #[test]
+    fn mappings_show_through_pages_already_written() {
+        let mut mem = Memory::default();
+        mem.write(0xfe, b"xyz");
+
+        assert_ok!(mem.map(0x100, Rc::from(&b"ab"[..])));
+
+        assert_eq!(mem.read(0xfe, 4), b"xyab");
+    }
+
+    #[test]
+    fn rewinding_restores_the_mapped_view() {
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem: Memory::from([(3, 0b0001_0111), (7, 0b0001_1000)]),
+            ..Default::default()
+        };
+        assert_ok!(machine.map_bytes(0x1000, Rc::from(&b"abcd"[..])));
+        machine.enable_checkpoints(1, 4);
+        machine.mem.write(0x1000, &[0, 0]);
+        machine.record_checkpoint_if_due();
+
+        machine.mem.set(0x1001, b'B');
+        assert_ok!(machine.rewind_to(0));
+
+        // The zeros written over the mapping are part of the checkpoint.
+        assert_eq!(machine.mem.read(0x1000, 4), b"\0\0cd");
+        assert_err_eq!(
+            machine.map_file(0, "/nonexistent/file"),
+            Error::MapFailed("/nonexistent/file: No such file or directory (os error 2)".into())
+        );
+    }
+}
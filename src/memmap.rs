@@ -0,0 +1,148 @@
+use crate::{Address, Machine};
+use std::io::Write;
+
+// A named stretch of the address space, such as the code, the stack or a
+// device's registers ("mmio:uart"), for diagnostics to place addresses in.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub base: Address,
+    pub len: u32,
+}
+
+impl MemoryRegion {
+    fn contains(&self, addr: Address) -> bool {
+        addr.wrapping_sub(self.base) < self.len
+    }
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct MemoryMap(Vec<MemoryRegion>);
+
+impl MemoryMap {
+    // Names `len` bytes from `base`, replacing any region of the same name.
+    // MemoryMap may nest, a device window within a wider mmio region say.
+    pub fn insert(&mut self, name: &str, base: Address, len: u32) {
+        self.0.retain(|region| region.name != name);
+        self.0.push(MemoryRegion {
+            name: name.to_string(),
+            base,
+            len,
+        });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.0.iter()
+    }
+
+    // The smallest region containing `addr` and the offset of `addr`
+    // within it.
+    pub fn resolve(&self, addr: Address) -> Option<(&MemoryRegion, u32)> {
+        let region = self
+            .0
+            .iter()
+            .filter(|region| region.contains(addr))
+            .min_by_key(|region| region.len)?;
+        Some((region, addr - region.base))
+    }
+
+    // Names `addr` as "<region>+<offset>".
+    pub fn label(&self, addr: Address) -> Option<String> {
+        self.resolve(addr)
+            .map(|(region, offset)| format!("{}+{offset:#x}", region.name))
+    }
+
+    // The label of `addr` in parentheses after a space, for following an
+    // address in a message, or nothing outside every region.
+    pub fn annotate(&self, addr: Address) -> String {
+        self.label(addr)
+            .map_or_else(String::new, |label| format!(" ({label})"))
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Names a region of memory for fault messages, traces and hexdumps.
+    pub fn name_region(&mut self, name: &str, base: Address, len: u32) {
+        self.memory_map.insert(name, base, len);
+    }
+
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    // The `len` bytes from `addr`, sixteen to a line, each line starting
+    // with its address and the region it is in.
+    pub fn hexdump(&self, addr: Address, len: usize) -> String {
+        let bytes = self.mem.read(addr, len);
+        let mut dump = String::new();
+        for (index, line) in bytes.chunks(16).enumerate() {
+            let at = addr.wrapping_add(index as Address * 16);
+            let hex: Vec<String> = line.iter().map(|byte| format!("{byte:02x}")).collect();
+            dump.push_str(&format!(
+                "{at:#010x}{}: {}\n",
+                self.memory_map.annotate(at),
+                hex.join(" ")
+            ));
+        }
+        dump
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, Error, RegisterID, Trap};
+    use claims::{assert_err, assert_none, assert_some_eq};
+
+    #[test]
+    fn addresses_are_named_by_the_smallest_region_holding_them() {
+        let mut regions = MemoryMap::default();
+        regions.insert("mmio", 0xf000_0000, 0x1000);
+        regions.insert("mmio:uart", 0xf000_0100, 0x10);
+        regions.insert("stack", 0x8000, 0x1000);
+
+        assert_some_eq!(regions.label(0xf000_0104), "mmio:uart+0x4".to_string());
+        assert_some_eq!(regions.label(0xf000_0200), "mmio+0x200".to_string());
+        assert_eq!(regions.annotate(0x8ff0), " (stack+0xff0)");
+        assert_none!(regions.label(0x9000));
+        assert_eq!(regions.annotate(0x9000), "");
+
+        regions.insert("stack", 0x9000, 0x1000);
+        assert_none!(regions.label(0x8ff0));
+        assert_eq!(regions.iter().count(), 3);
+    }
+
+    #[test]
+    fn diagnostics_place_addresses_in_regions() {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&assemble("sw a5, 0x2004\nlw a0, 0(a1)").unwrap())
+            .region("code", 0, 8)
+            .region("heap", 0x2000, 0x100)
+            .register(RegisterID::A5, 0x0102_0304)
+            .register(RegisterID::A1, 0xfffe)
+            .build()
+            .unwrap();
+        machine.set_memory_limit(Some(0x1_0000));
+        machine.name_region("mmio:uart", 0xfff0, 0x10);
+
+        let err = assert_err!(machine.run());
+        let Error::Trap { pc, trap } = err else {
+            panic!("expected a trap, not {err:?}");
+        };
+        assert_eq!(machine.memory_map().annotate(pc), " (code+0x4)");
+        assert_eq!(
+            trap.describe(machine.memory_map()),
+            "4 byte access at 0x0000fffe (mmio:uart+0xe) runs past the end of memory"
+        );
+        assert_eq!(
+            Trap::MemoryFault { addr: 0, len: 4 }.to_string(),
+            "4 byte access at 0x00000000 runs past the end of memory"
+        );
+        assert_eq!(
+            machine.hexdump(0x1ff8, 20),
+            "0x00001ff8: 00 00 00 00 00 00 00 00 00 00 00 00 01 02 03 04
+0x00002008 (heap+0x8): 00 00 00 00
+"
+        );
+    }
+}
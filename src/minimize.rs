@@ -0,0 +1,250 @@
+use crate::{trap::Trap, Address, Error, HaltReason, Machine, Result, StepOutcome};
+use std::{
+    io::Cursor,
+    panic::{self, AssertUnwindSafe},
+};
+
+// What a program has to do to count as failing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    // The run stops with an error or a halt of the named kind, such as
+    // "IllegalInstruction" or "Abort", or with "Panic" if the interpreter
+    // itself panics.
+    Trap(String),
+    // The run stops, however it stops, having written something other
+    // than the expected bytes to stdout.
+    WrongOutput(Vec<u8>),
+}
+
+// The kind of trap a run ended with: the name of the trap, error or halt
+// reason, or None if the program stopped at a breakpoint.
+pub fn trap_kind(result: &Result<HaltReason>) -> Option<String> {
+    let debug = match result {
+        Ok(HaltReason::Breakpoint | HaltReason::Exit { code: 0 }) => return None,
+        Ok(reason) => format!("{reason:?}"),
+        Err(Error::Trap { trap, .. }) => format!("{trap:?}"),
+        Err(err) => format!("{err:?}"),
+    };
+    Some(
+        debug
+            .chars()
+            .take_while(char::is_ascii_alphanumeric)
+            .collect(),
+    )
+}
+
+// Shrinks a failing program with delta debugging over its instruction
+// words: chunks of instructions are removed for as long as what remains
+// still fails the same way, until removing any single instruction would
+// make it pass. Each candidate runs in a fresh machine with the same
+// input, for at most `max_steps` instructions; one that runs out of steps
+// counts as passing, and one that runs off the end of its image stops
+// there, as though at a breakpoint, rather than trapping on whatever
+// follows. Guest exit handlers are not run.
+pub struct Minimizer {
+    failure: Failure,
+    input: Vec<u8>,
+    args: Vec<String>,
+    max_steps: u64,
+    runs: usize,
+}
+
+impl Minimizer {
+    pub fn new(failure: Failure) -> Self {
+        Self {
+            failure,
+            input: Vec::new(),
+            args: Vec::new(),
+            max_steps: 100_000,
+            runs: 0,
+        }
+    }
+
+    // What the program reads from stdin on every run.
+    pub fn input(mut self, input: &[u8]) -> Self {
+        self.input = input.to_vec();
+        self
+    }
+
+    pub fn args<S: Into<String>>(mut self, args: impl IntoIterator<Item = S>) -> Self {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    // How many candidate programs have been run so far.
+    pub fn runs(&self) -> usize {
+        self.runs
+    }
+
+    pub fn fails(&mut self, program: &[u8]) -> bool {
+        self.runs += 1;
+        let Some((trap, output)) = self.execute(program) else {
+            return false;
+        };
+        match &self.failure {
+            Failure::Trap(kind) => trap.as_ref() == Some(kind),
+            Failure::WrongOutput(expected) => output != *expected,
+        }
+    }
+
+    // Runs the program, returning the kind of trap it stopped with and
+    // what it wrote, or None if it was still running after max_steps
+    // instructions.
+    fn execute(&self, program: &[u8]) -> Option<(Option<String>, Vec<u8>)> {
+        let run = || {
+            let mut machine = Machine::builder()
+                .stdout(Vec::new())
+                .stdin(Cursor::new(self.input.clone()))
+                .program(program)
+                .args(self.args.clone())
+                .build()?;
+            let start = machine.layout.load_address;
+            let image = start..start.saturating_add(program.len() as Address);
+            for _ in 0..self.max_steps {
+                if !image.contains(&machine.pc) {
+                    return Ok(Some((None, machine.stdout.unwrap_or_default())));
+                }
+                let result = match machine.step() {
+                    Ok((_, StepOutcome::Continue)) => continue,
+                    Ok((_, StepOutcome::Halted(reason))) => Ok(reason),
+                    Err(err) => Err(err),
+                };
+                return Ok(Some((
+                    trap_kind(&result),
+                    machine.stdout.unwrap_or_default(),
+                )));
+            }
+            Ok(None)
+        };
+        match panic::catch_unwind(AssertUnwindSafe(run)) {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(err)) => Some((trap_kind(&Err(err)), Vec::new())),
+            Err(_) => Some((Some("Panic".to_string()), Vec::new())),
+        }
+    }
+
+    // Returns the smallest failing program found by removing instructions
+    // from `program`, which must fail to begin with.
+    pub fn minimize(&mut self, program: &[u8]) -> Result<Vec<u8>> {
+        if !self.fails(program) {
+            return Err(Error::FailureNotReproduced);
+        }
+        let mut words: Vec<&[u8]> = program.chunks(4).collect();
+        let mut granularity = 2;
+        while words.len() >= 2 {
+            let size = words.len().div_ceil(granularity);
+            let starts: Vec<usize> = (0..words.len()).step_by(size).collect();
+            let subset = starts.iter().find_map(|&start| {
+                let subset = words[start..(start + size).min(words.len())].to_vec();
+                self.fails(&subset.concat()).then_some(subset)
+            });
+            if let Some(subset) = subset {
+                words = subset;
+                granularity = 2;
+                continue;
+            }
+            let complement = starts.iter().find_map(|&start| {
+                let mut complement = words[..start].to_vec();
+                complement.extend(words.iter().skip(start + size));
+                self.fails(&complement.concat()).then_some(complement)
+            });
+            if let Some(complement) = complement {
+                words = complement;
+                granularity = (granularity - 1).max(2);
+                continue;
+            }
+            if granularity >= words.len() {
+                break;
+            }
+            granularity = (granularity * 2).min(words.len());
+        }
+        Ok(words.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    const LI_A0_1: [u8; 4] = [0b0000_0000, 0b0000_0010, 0b0000_0000, 0b0010_0001];
+    const ADD_A1_A0: [u8; 4] = [0b0000_0000, 0b0000_0000, 0b0000_0010, 0b0100_0010];
+    const EBREAK: [u8; 4] = [0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000];
+    const INVALID: [u8; 4] = [0, 0, 0, 0];
+
+    fn program(words: &[[u8; 4]]) -> Vec<u8> {
+        words.concat()
+    }
+
+    #[test]
+    fn trap_kinds_are_named_after_the_error_or_halt_reason() {
+        assert_eq!(trap_kind(&Ok(HaltReason::Breakpoint)), None);
+        assert_eq!(
+            trap_kind(&Err(Error::OpcodeUnknown(31))),
+            Some("OpcodeUnknown".to_string())
+        );
+        assert_eq!(
+            trap_kind(&Err(Error::Trap {
+                pc: 4,
+                trap: Trap::IllegalInstruction { word: 31 },
+            })),
+            Some("IllegalInstruction".to_string())
+        );
+        assert_eq!(
+            trap_kind(&Ok(HaltReason::Interrupted { pc: 8 })),
+            Some("Interrupted".to_string())
+        );
+    }
+
+    #[test]
+    fn minimizing_a_trap_keeps_only_the_instruction_that_causes_it() {
+        let original = program(&[LI_A0_1, ADD_A1_A0, LI_A0_1, INVALID, ADD_A1_A0, EBREAK]);
+        let mut minimizer = Minimizer::new(Failure::Trap("IllegalInstruction".to_string()));
+
+        assert_ok_eq!(minimizer.minimize(&original), INVALID.to_vec());
+        assert!(minimizer.runs() > 1);
+    }
+
+    #[test]
+    fn minimizing_wrong_output_keeps_the_instructions_that_produce_it() {
+        // li a0, 1; li a1, 0x100; li a2, 1; li a7, 64; ecall; ebreak,
+        // printing the byte at 0x100, which is not the expected 'x'.
+        let original = program(&[
+            LI_A0_1,
+            [0b0000_0010, 0b0000_0000, 0b0000_0000, 0b0100_0001],
+            [0b0000_0000, 0b0000_0010, 0b0000_0000, 0b0110_0001],
+            [0b0000_0000, 0b1000_0000, 0b0000_0001, 0b0000_0001],
+            [0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111],
+            EBREAK,
+        ]);
+        let mut minimizer = Minimizer::new(Failure::WrongOutput(b"x".to_vec()));
+        let minimized = minimizer.minimize(&original).unwrap();
+
+        // Any single instruction that stops the program prints nothing,
+        // which is just as wrong.
+        assert_eq!(minimized.len(), 4);
+        assert!(minimizer.fails(&minimized));
+    }
+
+    #[test]
+    fn a_program_that_does_not_fail_cannot_be_minimized() {
+        let mut minimizer = Minimizer::new(Failure::Trap("IllegalInstruction".to_string()));
+        assert_err_eq!(
+            minimizer.minimize(&program(&[LI_A0_1, EBREAK])),
+            Error::FailureNotReproduced
+        );
+    }
+
+    #[test]
+    fn a_candidate_that_runs_out_of_steps_passes() {
+        // beq x0, x0, 0 loops forever.
+        let looping = program(&[[0, 0, 0, 0b0000_1111]]);
+        let mut minimizer = Minimizer::new(Failure::WrongOutput(b"x".to_vec())).max_steps(100);
+        assert!(!minimizer.fails(&looping));
+    }
+}
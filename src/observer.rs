@@ -0,0 +1,322 @@
+use crate::{Address, Instruction, Machine, Word};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::Write,
+    rc::Rc,
+};
+
+// Something that happened during execution. Cycle counts are the number of
+// instructions retired when the event occurred, and the events of a run
+// arrive in the order the machine carried them out, which is the same
+// every time the run is repeated.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Event {
+    // An instruction at `pc` finished executing. `operands` are the values
+    // of rs1 and rs2 as the instruction read them.
+    Retired {
+        pc: Address,
+        instruction: Instruction,
+        operands: [Word; 2],
+    },
+    // Control was transferred to a function.
+    Call {
+        callee: Address,
+        symbol: Option<String>,
+        caller: Address,
+        cycle: u64,
+    },
+    // A function returned to the address its caller left in ra.
+    Return {
+        callee: Address,
+        symbol: Option<String>,
+        return_addr: Address,
+        cycle: u64,
+    },
+    // The device attached `device`th was brought up to time `cycle`.
+    DeviceAdvanced {
+        device: usize,
+        cycle: u64,
+    },
+    // The `len` byte instruction at `pc` was fetched, before it was decoded
+    // and executed.
+    Fetched {
+        pc: Address,
+        len: usize,
+    },
+    // The guest entered its handler for interrupt `line`.
+    InterruptTaken {
+        line: u8,
+        cycle: u64,
+    },
+}
+
+pub trait Observer {
+    fn observe(&mut self, event: &Event);
+}
+
+// Lets the host keep a handle on an observer it has given to a machine, so
+// results can be read back while or after the machine runs.
+impl<T: Observer> Observer for Rc<RefCell<T>> {
+    fn observe(&mut self, event: &Event) {
+        self.borrow_mut().observe(event);
+    }
+}
+
+#[derive(Default)]
+pub struct Observers(Vec<Box<dyn Observer>>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl Observers {
+    pub(crate) fn notify(&mut self, event: &Event) {
+        for observer in &mut self.0 {
+            observer.observe(event);
+        }
+    }
+}
+
+// How often each instruction address was executed.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct PcHistogram {
+    counts: BTreeMap<Address, u64>,
+}
+
+impl PcHistogram {
+    pub fn get(&self, pc: Address) -> u64 {
+        self.counts.get(&pc).copied().unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Address, u64)> + '_ {
+        self.counts.iter().map(|(pc, count)| (*pc, *count))
+    }
+}
+
+impl Observer for PcHistogram {
+    fn observe(&mut self, event: &Event) {
+        if let Event::Retired { pc, .. } = event {
+            *self.counts.entry(*pc).or_default() += 1;
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    // Cycles spent in the function including its callees.
+    pub inclusive: u64,
+    // Cycles spent in the function's own code.
+    pub exclusive: u64,
+}
+
+struct Activation {
+    name: String,
+    entered: u64,
+    in_callees: u64,
+}
+
+// Aggregates call and return events into per-function time. Time spent in
+// a recursive function is only counted once towards its inclusive total.
+#[derive(Default)]
+pub struct Profile {
+    functions: HashMap<String, FunctionProfile>,
+    active: Vec<Activation>,
+}
+
+impl Profile {
+    // The profiled functions, those with the most inclusive time first.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions: Vec<FunctionProfile> = self.functions.values().cloned().collect();
+        functions.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then(a.name.cmp(&b.name)));
+        functions
+    }
+
+    fn enter(&mut self, name: String, cycle: u64) {
+        self.functions
+            .entry(name.clone())
+            .or_insert_with(|| FunctionProfile {
+                name: name.clone(),
+                ..Default::default()
+            })
+            .calls += 1;
+        self.active.push(Activation {
+            name,
+            entered: cycle,
+            in_callees: 0,
+        });
+    }
+
+    fn leave(&mut self, cycle: u64) {
+        let Some(activation) = self.active.pop() else {
+            return;
+        };
+        let elapsed = cycle.saturating_sub(activation.entered);
+        let recursive = self.active.iter().any(|a| a.name == activation.name);
+        if let Some(function) = self.functions.get_mut(&activation.name) {
+            function.exclusive += elapsed.saturating_sub(activation.in_callees);
+            if !recursive {
+                function.inclusive += elapsed;
+            }
+        }
+        if let Some(caller) = self.active.last_mut() {
+            caller.in_callees += elapsed;
+        }
+    }
+}
+
+impl Observer for Profile {
+    fn observe(&mut self, event: &Event) {
+        match event {
+            Event::Call {
+                callee,
+                symbol,
+                cycle,
+                ..
+            } => {
+                let name = symbol.clone().unwrap_or_else(|| format!("{callee:#010x}"));
+                self.enter(name, *cycle);
+            }
+            Event::Return { cycle, .. } => self.leave(*cycle),
+            Event::Retired { .. }
+            | Event::DeviceAdvanced { .. }
+            | Event::Fetched { .. }
+            | Event::InterruptTaken { .. } => {}
+        }
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn add_observer(&mut self, observer: impl Observer + 'static) {
+        self.observers.0.push(Box::new(observer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, Opcode, RegisterID};
+    use claims::assert_ok;
+
+    fn call(callee: Address, cycle: u64) -> Event {
+        Event::Call {
+            callee,
+            symbol: None,
+            caller: 0,
+            cycle,
+        }
+    }
+
+    fn ret(cycle: u64) -> Event {
+        Event::Return {
+            callee: 0,
+            symbol: None,
+            return_addr: 0,
+            cycle,
+        }
+    }
+
+    #[test]
+    fn the_histogram_counts_executed_instructions() {
+        let histogram = Rc::new(RefCell::new(PcHistogram::default()));
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem: Memory::from([(3, 0b0010_0010), (7, 0b0001_1000)]),
+            ..Default::default()
+        };
+        machine.add_observer(histogram.clone());
+
+        assert_ok!(machine.run());
+
+        let histogram = histogram.borrow();
+        assert_eq!(histogram.iter().collect::<Vec<_>>(), vec![(0, 1), (4, 1)]);
+        assert_eq!(histogram.get(8), 0);
+    }
+
+    #[test]
+    fn profiles_split_time_between_callers_and_callees() {
+        let mut profile = Profile::default();
+        for event in [
+            call(0x10, 0),
+            call(0x20, 2),
+            ret(7),
+            call(0x20, 8),
+            ret(9),
+            ret(10),
+        ] {
+            profile.observe(&event);
+        }
+
+        assert_eq!(
+            profile.functions(),
+            vec![
+                FunctionProfile {
+                    name: "0x00000010".to_string(),
+                    calls: 1,
+                    inclusive: 10,
+                    exclusive: 4,
+                },
+                FunctionProfile {
+                    name: "0x00000020".to_string(),
+                    calls: 2,
+                    inclusive: 6,
+                    exclusive: 6,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn recursive_calls_are_counted_once_inclusively() {
+        let mut profile = Profile::default();
+        for event in [call(0x10, 0), call(0x10, 2), ret(5), ret(6)] {
+            profile.observe(&event);
+        }
+
+        let functions = profile.functions();
+        assert_eq!(functions[0].calls, 2);
+        assert_eq!(functions[0].inclusive, 6);
+        assert_eq!(functions[0].exclusive, 6);
+    }
+
+    #[test]
+    fn fetches_and_retired_events_carry_the_instruction() {
+        #[derive(Default)]
+        struct Log(Vec<Event>);
+        impl Observer for Log {
+            fn observe(&mut self, event: &Event) {
+                self.0.push(event.clone());
+            }
+        }
+        let log = Rc::new(RefCell::new(Log::default()));
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem: Memory::from([(3, 0b0001_1000)]),
+            ..Default::default()
+        };
+        machine.add_observer(log.clone());
+
+        assert_ok!(machine.run());
+
+        assert_eq!(
+            log.borrow().0,
+            vec![
+                Event::Fetched { pc: 0, len: 4 },
+                Event::Retired {
+                    pc: 0,
+                    instruction: Instruction {
+                        opcode: Opcode::EBreak,
+                        rd: RegisterID::X0,
+                        rs1: RegisterID::X0,
+                        rs2: RegisterID::X0,
+                        imm: 0,
+                    },
+                    operands: [0, 0],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{trap::Trap, Machine, Result, Word};
+use std::{
+    cell::RefCell,
+    io::{self, ErrorKind, Write},
+    rc::Rc,
+};
+
+pub(crate) const EIO: i32 = 5;
+pub(crate) const EINVAL: i32 = 22;
+pub(crate) const EAGAIN: i32 = 11;
+
+pub(crate) fn errno(code: i32) -> Word {
+    code.wrapping_neg() as Word
+}
+
+// A writer that copies everything written to it into each of its sinks, in
+// the order they were added.
+#[derive(Default)]
+pub struct Tee {
+    sinks: Vec<Box<dyn Write>>,
+}
+
+impl Tee {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, sink: impl Write + 'static) -> Self {
+        self.push(sink);
+        self
+    }
+
+    pub fn push(&mut self, sink: impl Write + 'static) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+
+impl std::fmt::Debug for Tee {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Tee({})", self.sinks.len())
+    }
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for sink in &mut self.sinks {
+            sink.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// An in-memory sink that can be handed to a machine and read back by the
+// host. Clones share the same buffer, so one can be given both to stdout
+// and to a tracer to interleave guest output with the trace.
+#[derive(Debug, Default, Clone)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Adds a sink that receives guest stdout alongside the machine's own
+    // stdout writer.
+    pub fn add_stdout_sink(&mut self, sink: impl Write + 'static) {
+        self.sinks.push(sink);
+    }
+
+    // Writes guest output with write(2) semantics: one attempt at the
+    // machine's stdout, which may accept fewer bytes than offered. The
+    // other sinks get exactly what stdout took. Returns the value for a0:
+    // the number of bytes written, or a negated errno. Output replayed by a
+    // rewind has been written once already and goes nowhere.
+    pub(crate) fn write_stdout(&mut self, data: &[u8]) -> Result<Word> {
+        if self.replaying {
+            return Ok(data.len() as Word);
+        }
+        let written = match &mut self.stdout {
+            Some(stdout) => loop {
+                match stdout.write(data) {
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                    result => break result,
+                }
+            },
+            None => Ok(data.len()),
+        };
+        let count = match written {
+            Ok(count) => count,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(errno(EAGAIN)),
+            Err(_) => return Ok(errno(EIO)),
+        };
+        // The guest has been told the bytes were written, so a sink that
+        // cannot take them is the host's failure to report.
+        self.sinks.write_all(&data[..count]).map_err(|err| {
+            self.syscall_trap(Trap::IoError {
+                message: err.to_string(),
+            })
+        })?;
+        self.capture_write(&data[..count]);
+        self.count_output(count);
+        Ok(count as Word)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, RegisterID, Registers};
+    use claims::{assert_ok, assert_ok_eq};
+
+    #[test]
+    fn guest_output_reaches_every_sink() {
+        let trace = SharedBuffer::new();
+        let extra = SharedBuffer::new();
+        let mut machine = Machine {
+            stdout: Some(Tee::new().with(trace.clone())),
+            regs: Registers::from([
+                (RegisterID::A0, 1),
+                (RegisterID::A1, 8),
+                (RegisterID::A2, 2),
+                (RegisterID::A7, 64),
+            ]),
+            mem: Memory::from([(3, 0b0001_0111), (7, 0b0001_1000), (8, b'h'), (9, b'i')]),
+            ..Default::default()
+        };
+        machine.add_stdout_sink(extra.clone());
+        machine.set_tracer(crate::trace::Tracer::new(trace.clone()));
+
+        assert_ok!(machine.run());
+
+        assert_eq!(extra.contents(), b"hi");
+        let trace = String::from_utf8(trace.contents()).unwrap();
+        assert!(trace.contains("ecall\nhi"), "{trace}");
+    }
+
+    // Accepts at most `room` bytes per write, or none at all.
+    struct Slow {
+        room: usize,
+        taken: Vec<u8>,
+    }
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let count = buf.len().min(self.room);
+            self.taken.extend(&buf[..count]);
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_can_be_short_or_fail_with_eagain() {
+        let extra = SharedBuffer::new();
+        let mut machine = Machine {
+            stdout: Some(Slow {
+                room: 3,
+                taken: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        machine.add_stdout_sink(extra.clone());
+
+        assert_ok_eq!(machine.write_stdout(b"hello"), 3);
+        assert_eq!(extra.contents(), b"hel");
+
+        machine.stdout.as_mut().unwrap().room = 0;
+        assert_ok_eq!(machine.write_stdout(b"lo"), -11i32 as Word);
+        assert_eq!(machine.stdout.unwrap().taken, b"hel");
+    }
+}
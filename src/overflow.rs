@@ -0,0 +1,102 @@
+use crate::{trap::Trap, Instruction, Machine, Opcode, Result, Word};
+use std::io::Write;
+
+impl<W: Write> Machine<W> {
+    // Makes add, sub and mul trap with ArithmeticOverflow when the result,
+    // taking the operands as signed, does not fit in a word, as MIPS's
+    // add and sub do, rather than wrapping around. Guests do pointer
+    // arithmetic with add too, so unsigned pointers crossing 0x80000000
+    // trap as well; loads and stores adding their offset never do. Off by
+    // default.
+    pub fn set_overflow_trap(&mut self, trap: bool) {
+        self.overflow_trap = trap;
+    }
+
+    pub fn overflow_trap(&self) -> bool {
+        self.overflow_trap
+    }
+
+    // Traps if the instruction about to execute overflows with the check
+    // on. The second operand is rs2 plus the immediate, as it is for the
+    // ALU, and the sum making it up may wrap without trapping.
+    pub(crate) fn check_overflow(&self, instruction: &Instruction) -> Result<()> {
+        if !self.overflow_trap {
+            return Ok(());
+        }
+        let lhs = self.regs.get(&instruction.rs1);
+        let rhs = self
+            .regs
+            .get(&instruction.rs2)
+            .wrapping_add(instruction.offset() as Word);
+        let (signed_lhs, signed_rhs) = (lhs as i32, rhs as i32);
+        let overflowed = match instruction.opcode {
+            Opcode::Add => signed_lhs.checked_add(signed_rhs).is_none(),
+            Opcode::Sub => signed_lhs.checked_sub(signed_rhs).is_none(),
+            Opcode::Mul => signed_lhs.checked_mul(signed_rhs).is_none(),
+            _ => false,
+        };
+        if overflowed {
+            return Err(self.trap(Trap::ArithmeticOverflow {
+                opcode: instruction.opcode,
+                lhs,
+                rhs,
+            }));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, Error, HaltReason, RegisterID};
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    fn run(program: &str, a1: Word, trap: bool) -> Result<HaltReason> {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&assemble(program).unwrap())
+            .register(RegisterID::A1, a1)
+            .build()
+            .unwrap();
+        machine.set_overflow_trap(trap);
+        machine.run()
+    }
+
+    #[test]
+    fn signed_overflow_traps_with_the_check_on() {
+        let cases = [
+            ("add a0, a1, x0, 1", 0x7fff_ffff, Opcode::Add, 1),
+            ("sub a0, a1, x0, 1", 0x8000_0000, Opcode::Sub, 1),
+            ("mul a0, a1, x0, 2", 0x4000_0000, Opcode::Mul, 2),
+        ];
+        for (instruction, a1, opcode, rhs) in cases {
+            let program = format!("{instruction}\nebreak");
+            assert_err_eq!(
+                run(&program, a1, true),
+                Error::Trap {
+                    pc: 0,
+                    trap: Trap::ArithmeticOverflow {
+                        opcode,
+                        lhs: a1,
+                        rhs,
+                    },
+                }
+            );
+            assert_ok_eq!(run(&program, a1, false), HaltReason::Breakpoint);
+        }
+        // Borrowing through zero is fine when the operands are signed.
+        assert_ok_eq!(
+            run("sub a0, a1, x0, 1\nebreak", 0, true),
+            HaltReason::Breakpoint
+        );
+        assert_eq!(
+            Trap::ArithmeticOverflow {
+                opcode: Opcode::Add,
+                lhs: 0x7fff_ffff,
+                rhs: 1,
+            }
+            .to_string(),
+            "add of 2147483647 and 1 overflows a word"
+        );
+    }
+}
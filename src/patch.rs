@@ -0,0 +1,111 @@
+use crate::{Address, Machine, Word};
+use std::io::Write;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Patch {
+    pub addr: Address,
+    pub original: Vec<u8>,
+}
+
+impl<W: Write> Machine<W> {
+    // Overwrites guest memory, remembering the original bytes so the patch
+    // can be undone with unpatch or restore_patches.
+    pub fn patch(&mut self, addr: Address, bytes: &[u8]) {
+        let original = self.mem.read(addr, bytes.len());
+        self.mem.write(addr, bytes);
+        self.patches.push(Patch { addr, original });
+    }
+
+    pub fn patches(&self) -> &[Patch] {
+        &self.patches
+    }
+
+    // Undoes the most recent patch made at `addr`, returning whether there
+    // was one.
+    pub fn unpatch(&mut self, addr: Address) -> bool {
+        let Some(index) = self.patches.iter().rposition(|patch| patch.addr == addr) else {
+            return false;
+        };
+        let patch = self.patches.remove(index);
+        self.mem.write(patch.addr, &patch.original);
+        true
+    }
+
+    // Undoes every patch, newest first, so overlapping patches unwind to
+    // the original program.
+    pub fn restore_patches(&mut self) {
+        while let Some(patch) = self.patches.pop() {
+            self.mem.write(patch.addr, &patch.original);
+        }
+    }
+
+    // Moves past the next instruction without executing it, returning the
+    // raw word that was skipped. The word need not be a valid instruction.
+    pub fn skip_next(&mut self) -> Word {
+        let bytes = self.mem.read(self.pc, 4);
+        self.pc += 4;
+        Word::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HaltReason, Memory, RegisterID, StepOutcome};
+    use claims::{assert_ok, assert_ok_eq};
+
+    // Two Adds bumping a0 followed by an EBreak.
+    fn machine() -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        for i in 0..2 {
+            mem.write(i * 4, &[0b0000_0000, 0b0000_0010, 0b0000_0010, 0b0010_0010]);
+        }
+        mem.write(8, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        Machine {
+            mem,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn patched_code_is_executed() {
+        let mut machine = machine();
+        // ebreak in place of the second add
+        machine.patch(4, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.regs.get(&RegisterID::A0), 1);
+        assert_eq!(machine.pc, 8);
+    }
+
+    #[test]
+    fn patches_can_be_undone() {
+        let mut machine = machine();
+        let original = machine.mem.read(0, 12);
+        machine.patch(4, &[0xff, 0xff]);
+        machine.patch(5, &[0xee, 0xee, 0xee]);
+        assert!(!machine.unpatch(0));
+
+        assert!(machine.unpatch(5));
+        assert_eq!(
+            machine.mem.read(4, 4),
+            vec![0xff, 0xff, 0b0000_0010, 0b0010_0010]
+        );
+
+        machine.patch(8, &[0xdd; 4]);
+        machine.restore_patches();
+        assert_eq!(machine.mem.read(0, 12), original);
+        assert!(machine.patches().is_empty());
+    }
+
+    #[test]
+    fn skip_next_moves_past_an_instruction_without_executing_it() {
+        let mut machine = machine();
+        machine.patch(0, &[0xff; 4]);
+
+        assert_eq!(machine.skip_next(), 0xffff_ffff);
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.regs.get(&RegisterID::A0), 1);
+        assert_eq!(machine.retired, 2);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::{
+    json::{self, Value},
+    Error, Result,
+};
+use std::{collections::BTreeMap, fmt, fmt::Write as _};
+
+fn invalid(line: usize, reason: &str) -> Error {
+    Error::StatsInvalid(format!("line {line}: {reason}"))
+}
+
+// The figures of a stats report, as `rmachine run --stats` prints them or
+// `--stats-json` writes them, that can regress between runs of the same
+// program: instructions retired, cycles, memory in bytes and the cycles
+// spent in each region. Wall time is left out, varying as it does from one
+// run to the next.
+fn figures(text: &str) -> Result<BTreeMap<String, u64>> {
+    if text.trim_start().starts_with('{') {
+        return json_figures(text);
+    }
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line));
+    match lines.next() {
+        Some((_, "rmachine-stats 1")) => {}
+        _ => return Err(invalid(1, "expected an rmachine-stats 1 report")),
+    }
+    let mut figures = BTreeMap::new();
+    for (number, line) in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (name, value) = match fields[..] {
+            [] => continue,
+            [name @ ("retired" | "cycles" | "memory-bytes"), value] => (name.to_string(), value),
+            ["region", name, _, cycles, _] => (format!("region {name} cycles"), cycles),
+            _ => continue,
+        };
+        let value = value
+            .parse()
+            .map_err(|_| invalid(number, "expected a count"))?;
+        figures.insert(name, value);
+    }
+    Ok(figures)
+}
+
+fn json_figures(text: &str) -> Result<BTreeMap<String, u64>> {
+    let invalid = |reason: &str| Error::StatsInvalid(reason.to_string());
+    let report = json::parse(text).ok_or_else(|| invalid("not JSON"))?;
+    if report.get("rmachine-stats").and_then(Value::as_u64) != Some(1) {
+        return Err(invalid("expected an rmachine-stats 1 report"));
+    }
+    let count = |value: Option<&Value>, name: &str| {
+        value
+            .and_then(Value::as_u64)
+            .ok_or_else(|| invalid(&format!("{name} must be a count")))
+    };
+    let mut figures = BTreeMap::new();
+    for name in ["retired", "cycles", "memory-bytes"] {
+        figures.insert(name.to_string(), count(report.get(name), name)?);
+    }
+    if let Some(regions) = report.get("regions") {
+        let Value::Object(regions) = regions else {
+            return Err(invalid("regions must be an object"));
+        };
+        for (name, region) in regions {
+            let name = format!("region {name} cycles");
+            let cycles = count(region.get("cycles"), &name)?;
+            figures.insert(name, cycles);
+        }
+    }
+    Ok(figures)
+}
+
+// How one figure changed between two reports. A figure only one of them has
+// is None in the other.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Change {
+    pub name: String,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+    // Whether the figure grew by more than the threshold.
+    pub regressed: bool,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: Option<u64>| value.map_or("-".to_string(), |value| value.to_string());
+        write!(f, "{} {} -> {}", self.name, show(self.old), show(self.new))?;
+        if let (Some(old), Some(new)) = (self.old, self.new) {
+            if old > 0 {
+                write!(
+                    f,
+                    " {:+.1}%",
+                    (new as f64 - old as f64) * 100.0 / old as f64
+                )?;
+            }
+        }
+        if self.regressed {
+            write!(f, " regression")?;
+        }
+        Ok(())
+    }
+}
+
+// Compares the stats reports of two runs of a program, `old` the baseline
+// and `new` the run to check, flagging every figure that grew by more than
+// `threshold` percent of its old value. The machine is deterministic, so
+// with the same input any growth at all is a real change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub changes: Vec<Change>,
+}
+
+impl Comparison {
+    pub fn new(old: &str, new: &str, threshold: f64) -> Result<Self> {
+        let (old, new) = (figures(old)?, figures(new)?);
+        let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+        names.sort();
+        names.dedup();
+        let changes = names
+            .into_iter()
+            .map(|name| {
+                let (old, new) = (old.get(name).copied(), new.get(name).copied());
+                let regressed = match (old, new) {
+                    (Some(old), Some(new)) => new as f64 > old as f64 * (1.0 + threshold / 100.0),
+                    _ => false,
+                };
+                Change {
+                    name: name.clone(),
+                    old,
+                    new,
+                    regressed,
+                }
+            })
+            .collect();
+        Ok(Self { changes })
+    }
+
+    pub fn regressions(&self) -> impl Iterator<Item = &Change> {
+        self.changes.iter().filter(|change| change.regressed)
+    }
+
+    // The comparison as a JSON object: each change, with a figure only one
+    // report has null in the other, and how many are regressions.
+    pub fn to_json(&self) -> String {
+        let show = |value: Option<u64>| value.map_or("null".to_string(), |value| value.to_string());
+        let mut out = String::from("{\"changes\": [");
+        for (i, change) in self.changes.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            let _ = write!(
+                out,
+                "{separator}{{\"name\": {}, \"old\": {}, \"new\": {}, \"regressed\": {}}}",
+                json::string(&change.name),
+                show(change.old),
+                show(change.new),
+                change.regressed
+            );
+        }
+        let _ = writeln!(out, "], \"regressions\": {}}}", self.regressions().count());
+        out
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "{change}")?;
+        }
+        match self.regressions().count() {
+            0 => writeln!(f, "no regressions"),
+            1 => writeln!(f, "1 regression"),
+            count => writeln!(f, "{count} regressions"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err_eq, assert_ok};
+
+    const OLD: &str = "rmachine-stats 1
+retired 1000
+cycles 1200
+wall-time-us 52
+memory-pages 2
+memory-bytes 8192
+instruction add 6
+region parse 2 400 380
+region sort 1 300 290
+";
+
+    const NEW: &str = "rmachine-stats 1
+retired 1005
+cycles 1400
+wall-time-us 97
+memory-pages 3
+memory-bytes 12288
+region parse 2 380 360
+region emit 1 50 40
+";
+
+    #[test]
+    fn figures_that_grow_past_the_threshold_are_regressions() {
+        let comparison = assert_ok!(Comparison::new(OLD, NEW, 1.0));
+
+        assert_eq!(
+            comparison.to_string(),
+            "cycles 1200 -> 1400 +16.7% regression
+memory-bytes 8192 -> 12288 +50.0% regression
+region emit cycles - -> 50
+region parse cycles 400 -> 380 -5.0%
+region sort cycles 300 -> -
+retired 1000 -> 1005 +0.5%
+2 regressions
+"
+        );
+        let strict = assert_ok!(Comparison::new(OLD, NEW, 0.0));
+        assert_eq!(strict.regressions().count(), 3);
+        assert_eq!(
+            assert_ok!(Comparison::new(OLD, OLD, 0.0))
+                .regressions()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn json_reports_compare_as_the_text_ones_do() {
+        let old = r#"{"rmachine-stats": 1, "retired": 1000, "cycles": 1200,
+            "wall-time-us": 52, "memory-pages": 2, "memory-bytes": 8192,
+            "instructions": {"add": 6}, "regions": {"parse": {"entries": 2,
+            "cycles": 400, "retired": 380}, "sort": {"entries": 1, "cycles": 300,
+            "retired": 290}}}"#;
+        let new = r#"{"rmachine-stats": 1, "retired": 1005, "cycles": 1400,
+            "wall-time-us": 97, "memory-pages": 3, "memory-bytes": 12288,
+            "regions": {"parse": {"entries": 2, "cycles": 380, "retired": 360},
+            "emit": {"entries": 1, "cycles": 50, "retired": 40}}}"#;
+        let comparison = assert_ok!(Comparison::new(old, new, 1.0));
+
+        assert_eq!(comparison, assert_ok!(Comparison::new(OLD, NEW, 1.0)));
+        // Text and JSON reports can be compared with each other too.
+        assert_eq!(comparison, assert_ok!(Comparison::new(OLD, new, 1.0)));
+        assert_eq!(
+            comparison.to_json(),
+            concat!(
+                r#"{"changes": [{"name": "cycles", "old": 1200, "new": 1400, "regressed": true}, "#,
+                r#"{"name": "memory-bytes", "old": 8192, "new": 12288, "regressed": true}, "#,
+                r#"{"name": "region emit cycles", "old": null, "new": 50, "regressed": false}, "#,
+                r#"{"name": "region parse cycles", "old": 400, "new": 380, "regressed": false}, "#,
+                r#"{"name": "region sort cycles", "old": 300, "new": null, "regressed": false}, "#,
+                r#"{"name": "retired", "old": 1000, "new": 1005, "regressed": false}], "#,
+                r#""regressions": 2}"#,
+                "\n"
+            )
+        );
+        assert_err_eq!(
+            Comparison::new(old, r#"{"rmachine-stats": 1, "retired": 5}"#, 0.0),
+            Error::StatsInvalid("cycles must be a count".to_string())
+        );
+        assert_err_eq!(
+            Comparison::new(old, "{\"retired\": ", 0.0),
+            Error::StatsInvalid("not JSON".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_must_be_stats_reports() {
+        assert_err_eq!(
+            Comparison::new("retired 5\n", NEW, 0.0),
+            Error::StatsInvalid("line 1: expected an rmachine-stats 1 report".to_string())
+        );
+        assert_err_eq!(
+            Comparison::new(OLD, "rmachine-stats 1\ncycles many\n", 0.0),
+            Error::StatsInvalid("line 2: expected a count".to_string())
+        );
+    }
+}
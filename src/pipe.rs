@@ -0,0 +1,86 @@
+use crate::poll::Source;
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// How many bytes a pipe holds before writes to it would block.
+pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug, Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    writers: usize,
+}
+
+// The two ends of a pipe. Nothing ever blocks: reading an empty pipe and
+// writing a full one fail with ErrorKind::WouldBlock, which guests see as
+// -EAGAIN, until the pipe is drained or, for reads, every writer is dropped
+// and the reader gets end of file. The guest opens pipes with the pipe2
+// syscall; a host can also attach the ends to different machines to have
+// one guest feed another.
+#[derive(Debug)]
+pub struct PipeReader(Arc<Mutex<Buffer>>);
+
+#[derive(Debug)]
+pub struct PipeWriter(Arc<Mutex<Buffer>>);
+
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let buffer = Arc::new(Mutex::new(Buffer {
+        data: VecDeque::new(),
+        writers: 1,
+    }));
+    (PipeReader(buffer.clone()), PipeWriter(buffer))
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buffer = self.0.lock().unwrap();
+        if buffer.data.is_empty() && buffer.writers > 0 && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let count = buf.len().min(buffer.data.len());
+        for (byte, data) in buf.iter_mut().zip(buffer.data.drain(..count)) {
+            *byte = data;
+        }
+        Ok(count)
+    }
+}
+
+impl Source for PipeReader {
+    fn wait_readable(&mut self, _timeout: Duration) -> io::Result<bool> {
+        let buffer = self.0.lock().unwrap();
+        Ok(!buffer.data.is_empty() || buffer.writers == 0)
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        self.0.lock().unwrap().writers += 1;
+        Self(self.0.clone())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().writers -= 1;
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffer = self.0.lock().unwrap();
+        let count = buf.len().min(PIPE_CAPACITY - buffer.data.len());
+        if count == 0 && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        buffer.data.extend(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{fd::Description, Address, Machine, RegisterID, Word};
+use std::{
+    io::{self, Read, Write},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+const POLLIN: u16 = 0x01;
+const POLLOUT: u16 = 0x04;
+const POLLNVAL: u16 = 0x20;
+const EINTR: i32 = 4;
+
+// How often a blocked poll checks for an interrupt.
+const INTERRUPT_CHECK: Duration = Duration::from_millis(10);
+
+// Guest input that can say whether a read would block. Waiting up to
+// `timeout` for input lets a blocked poll sleep instead of spinning.
+pub trait Source: Read {
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool>;
+}
+
+// A reader that never blocks for long, such as a file or an in-memory
+// buffer, is always readable: a read returns data or end of file.
+pub struct Ready<R>(pub R);
+
+impl<R: Read> Read for Ready<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read> Source for Ready<R> {
+    fn wait_readable(&mut self, _timeout: Duration) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+// Makes any blocking reader pollable by reading it on a background
+// thread. End of file and errors are reported as readable, so the next
+// read sees them.
+pub struct Pipe {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    pub fn new(mut input: impl Read + Send + 'static) -> Self {
+        let (sender, chunks) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut buf = vec![0; 4096];
+            let result = input.read(&mut buf).map(|count| {
+                buf.truncate(count);
+                buf
+            });
+            let done = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+            if sender.send(result).is_err() || done {
+                break;
+            }
+        });
+        Self {
+            chunks,
+            pending: Vec::new(),
+            closed: false,
+        }
+    }
+
+    fn accept(&mut self, chunk: io::Result<Vec<u8>>) -> io::Result<()> {
+        let chunk = chunk?;
+        self.closed |= chunk.is_empty();
+        self.pending.extend(chunk);
+        Ok(())
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && !self.closed {
+            match self.chunks.recv() {
+                Ok(chunk) => self.accept(chunk)?,
+                Err(_) => self.closed = true,
+            }
+        }
+        let count = buf.len().min(self.pending.len());
+        buf[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        Ok(count)
+    }
+}
+
+impl Source for Pipe {
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool> {
+        if !self.pending.is_empty() || self.closed {
+            return Ok(true);
+        }
+        let chunk = if timeout.is_zero() {
+            match self.chunks.try_recv() {
+                Ok(chunk) => chunk,
+                Err(TryRecvError::Empty) => return Ok(false),
+                Err(TryRecvError::Disconnected) => Ok(Vec::new()),
+            }
+        } else {
+            match self.chunks.recv_timeout(timeout) {
+                Ok(chunk) => chunk,
+                Err(RecvTimeoutError::Timeout) => return Ok(false),
+                Err(RecvTimeoutError::Disconnected) => Ok(Vec::new()),
+            }
+        };
+        self.accept(chunk)?;
+        Ok(true)
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Sets guest input to a source that can be polled without blocking.
+    pub fn set_stdin_source(&mut self, source: impl Source + 'static) {
+        self.stdin = Some(crate::Input(Box::new(source)));
+    }
+
+    fn readable(&mut self, timeout: Duration) -> bool {
+        match &mut self.stdin {
+            // Errors are left for the read to report.
+            Some(stdin) => stdin.0.wait_readable(timeout).unwrap_or(true),
+            None => true,
+        }
+    }
+
+    // poll(fds, nfds, timeout): waits until one of the guest fds is ready.
+    // Each entry of `fds` is 8 bytes: u32 fd, u16 requested events, u16
+    // returned events. A negative timeout waits forever, otherwise it is
+    // in milliseconds. Returns the number of ready entries, zero on
+    // timeout, or -EINTR if the machine was interrupted while waiting.
+    pub(crate) fn poll(&mut self) -> crate::Result<Word> {
+        let fds = self.regs.get(&RegisterID::A0);
+        let count = self.regs.get(&RegisterID::A1);
+        self.guest_buffer(fds, 8 * u64::from(count))?;
+        let timeout = self.regs.get(&RegisterID::A2) as i32;
+        let deadline =
+            (timeout >= 0).then(|| Instant::now() + Duration::from_millis(timeout as u64));
+        let mut wait = Duration::ZERO;
+        loop {
+            let mut revents = Vec::new();
+            for entry in 0..count {
+                let addr = fds.wrapping_add(8 * entry);
+                let fields = self.load(addr, 8);
+                let fd = self
+                    .endian
+                    .word([fields[0], fields[1], fields[2], fields[3]]);
+                let events = self.half(&fields[4..6]);
+                let ready = match self.files.description(fd) {
+                    Some(Description::Stdin) if events & POLLIN != 0 && self.readable(wait) => {
+                        POLLIN
+                    }
+                    Some(Description::Stdin) => 0,
+                    Some(Description::Reader(id)) if self.files.reader_ready(id) => events & POLLIN,
+                    Some(Description::Reader(_)) => 0,
+                    Some(Description::Stdout | Description::Writer(_)) => events & POLLOUT,
+                    None => POLLNVAL,
+                };
+                revents.push((addr, ready));
+                wait = Duration::ZERO;
+            }
+            let ready = revents.iter().filter(|(_, ready)| *ready != 0).count();
+            let now = Instant::now();
+            let expired = deadline.is_some_and(|deadline| now >= deadline);
+            if ready > 0 || expired {
+                for (addr, ready) in revents {
+                    let mut ready = ready.to_be_bytes();
+                    self.endian.arrange(&mut ready);
+                    self.store(addr.wrapping_add(6), &ready);
+                }
+                return Ok(ready as Word);
+            }
+            if self.interrupt.is_raised() {
+                return Ok(EINTR.wrapping_neg() as Word);
+            }
+            wait = deadline.map_or(INTERRUPT_CHECK, |deadline| {
+                INTERRUPT_CHECK.min(deadline.saturating_duration_since(now))
+            });
+            if !self.polls_stdin(fds, count) {
+                thread::sleep(wait);
+            }
+        }
+    }
+
+    fn polls_stdin(&self, fds: Address, count: Word) -> bool {
+        (0..count).any(|entry| {
+            let fields = self.mem.read(fds.wrapping_add(8 * entry), 8);
+            let fd = self
+                .endian
+                .word([fields[0], fields[1], fields[2], fields[3]]);
+            self.files.description(fd) == Some(Description::Stdin)
+                && self.half(&fields[4..6]) & POLLIN != 0
+        })
+    }
+
+    // The halfword stored in memory as `bytes`.
+    fn half(&self, bytes: &[u8]) -> u16 {
+        let mut bytes = [bytes[0], bytes[1]];
+        self.endian.arrange(&mut bytes);
+        u16::from_be_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, StepOutcome};
+    use claims::assert_ok_eq;
+
+    fn pollfd(fd: Word, events: u16) -> Vec<u8> {
+        [&fd.to_be_bytes()[..], &events.to_be_bytes(), &[0, 0]].concat()
+    }
+
+    fn machine(timeout: i32) -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        mem.write(0, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111]);
+        mem.write(
+            0x100,
+            &[pollfd(0, POLLIN), pollfd(1, POLLIN), pollfd(9, POLLIN)].concat(),
+        );
+        let mut machine = Machine {
+            mem,
+            ..Default::default()
+        };
+        machine.regs.set(RegisterID::A0, 0x100);
+        machine.regs.set(RegisterID::A1, 3);
+        machine.regs.set(RegisterID::A2, timeout as Word);
+        machine.regs.set(RegisterID::A7, 264);
+        machine
+    }
+
+    fn revents(machine: &Machine<Vec<u8>>, entry: Word) -> u16 {
+        let bytes = machine.mem.read(0x100 + 8 * entry + 6, 2);
+        u16::from_be_bytes([bytes[0], bytes[1]])
+    }
+
+    #[test]
+    fn poll_reports_which_fds_are_ready() {
+        struct Channel(Receiver<u8>);
+        impl Read for Channel {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                Ok(self.0.recv().map_or(0, |byte| {
+                    buf[0] = byte;
+                    1
+                }))
+            }
+        }
+        let (sender, receiver) = mpsc::channel::<u8>();
+        let mut machine = machine(0);
+        machine.set_stdin_source(Pipe::new(Channel(receiver)));
+
+        assert_ok_eq!(
+            machine.step().map(|(_, outcome)| outcome),
+            StepOutcome::Continue
+        );
+        assert_eq!(machine.regs.get(&RegisterID::A0), 1);
+        assert_eq!(
+            (
+                revents(&machine, 0),
+                revents(&machine, 1),
+                revents(&machine, 2)
+            ),
+            (0, 0, POLLNVAL)
+        );
+
+        // Without the invalid fd, the poll blocks until input arrives.
+        sender.send(b'x').unwrap();
+        machine.pc = 0;
+        machine.regs.set(RegisterID::A0, 0x100);
+        machine.regs.set(RegisterID::A1, 2);
+        machine.regs.set(RegisterID::A2, -1i32 as Word);
+        assert_ok_eq!(
+            machine.step().map(|(_, outcome)| outcome),
+            StepOutcome::Continue
+        );
+        assert_eq!(machine.regs.get(&RegisterID::A0), 1);
+        assert_eq!(revents(&machine, 0), POLLIN);
+    }
+
+    #[test]
+    fn a_blocked_poll_returns_when_the_machine_is_interrupted() {
+        let (_sender, receiver) = mpsc::channel::<io::Result<Vec<u8>>>();
+        let mut machine = machine(-1);
+        machine.regs.set(RegisterID::A1, 2);
+        machine.set_stdin_source(Pipe {
+            chunks: receiver,
+            pending: Vec::new(),
+            closed: false,
+        });
+        let interrupt = machine.interrupt_handle();
+        let raiser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            interrupt.raise();
+        });
+
+        assert_ok_eq!(
+            machine.step().map(|(_, outcome)| outcome),
+            StepOutcome::Halted(crate::HaltReason::Interrupted { pc: 4 })
+        );
+        assert_eq!(machine.regs.get(&RegisterID::A0), -4i32 as Word);
+        raiser.join().unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    endian::Endian,
+    regbank::RegisterBanks,
+    state::{register, State},
+    Address, Layout, Machine, Memory, Result, Word,
+};
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+pub(crate) type BasePages = HashMap<Address, Box<[u8]>>;
+
+// A machine's state frozen for starting many machines from, such as one
+// per request a service handles. Its pages are shared, never copied, by the
+// machines started from it, each of which copies only the pages it writes,
+// so starting one costs next to nothing however large the image. Like a
+// State it holds what the guest can observe, and nothing the host attached.
+#[derive(Debug, Clone)]
+pub struct BaseImage {
+    pc: Address,
+    regs: [Word; 16],
+    register_banks: Option<RegisterBanks>,
+    layout: Layout,
+    endian: Endian,
+    pages: Arc<BasePages>,
+}
+
+impl BaseImage {
+    pub fn from_state(state: &State) -> Result<Self> {
+        state.validate()?;
+        Ok(Self {
+            pc: state.pc,
+            regs: state.regs,
+            register_banks: state.register_banks.clone(),
+            layout: state.layout,
+            endian: state.endian,
+            pages: Arc::new(
+                state
+                    .pages
+                    .iter()
+                    .map(|(page, data)| (*page, data.as_slice().into()))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+impl Memory {
+    // The base image's byte at `addr`, ignoring any guest writes.
+    pub(crate) fn based(&self, addr: Address) -> Option<u8> {
+        let page = self.base.as_ref()?.get(&self.page_of(addr))?;
+        Some(page[self.offset_in_page(addr)])
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Freezes the machine as it is now, typically once a program has been
+    // loaded and has run its initialization.
+    pub fn base_image(&self) -> BaseImage {
+        BaseImage {
+            pc: self.pc,
+            regs: std::array::from_fn(|id| self.regs.get(&register(id))),
+            register_banks: self.saved_register_banks(),
+            layout: self.layout,
+            endian: self.endian,
+            pages: Arc::new(
+                self.saved_pages()
+                    .into_iter()
+                    .map(|(page, data)| (page, data.into()))
+                    .collect(),
+            ),
+        }
+    }
+
+    // A machine in the state of `base`, writing to `stdout`, with nothing
+    // else attached.
+    pub fn from_base_image(base: &BaseImage, stdout: W) -> Self {
+        let mut mem = Memory::with_page_size(base.layout.page_size);
+        mem.base = Some(base.pages.clone());
+        let mut machine = Self {
+            pc: base.pc,
+            mem,
+            stdout: Some(stdout),
+            layout: base.layout,
+            endian: base.endian,
+            ..Default::default()
+        };
+        machine.restore_register_banks(base.register_banks.as_ref());
+        for (id, value) in base.regs.iter().enumerate() {
+            machine.regs.set(register(id), *value);
+        }
+        machine
+    }
+}
+
+// Runs independent jobs on a fixed number of worker threads. Machines are
+// not Send, so each job builds and runs its own machine on the worker that
+// picks it up.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MachinePool {
+    workers: usize,
+}
+
+impl MachinePool {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: workers.max(1),
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    // Runs `work` on every job, returning the results in the order of the
+    // jobs however the work was scheduled.
+    pub fn run<J, T>(&self, jobs: Vec<J>, work: impl Fn(J) -> T + Sync) -> Vec<T>
+    where
+        J: Send,
+        T: Send,
+    {
+        let count = jobs.len();
+        let jobs: Vec<Mutex<Option<J>>> =
+            jobs.into_iter().map(|job| Mutex::new(Some(job))).collect();
+        let results: Vec<Mutex<Option<T>>> = (0..count).map(|_| Mutex::new(None)).collect();
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..self.workers.min(count) {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(slot) = jobs.get(index) else {
+                        break;
+                    };
+                    let job = slot
+                        .lock()
+                        .expect("job lock")
+                        .take()
+                        .expect("jobs run once");
+                    let result = work(job);
+                    *results[index].lock().expect("result lock") = Some(result);
+                });
+            }
+        });
+        results
+            .into_iter()
+            .map(|result| {
+                result
+                    .into_inner()
+                    .expect("result lock")
+                    .expect("every job ran")
+            })
+            .collect()
+    }
+
+    // Runs `work` on every job with a machine of its own started from
+    // `base`, keeping its output in memory.
+    pub fn run_from<J, T>(
+        &self,
+        base: &BaseImage,
+        jobs: Vec<J>,
+        work: impl Fn(Machine<Vec<u8>>, J) -> T + Sync,
+    ) -> Vec<T>
+    where
+        J: Send,
+        T: Send,
+    {
+        self.run(jobs, |job| {
+            work(Machine::from_base_image(base, Vec::new()), job)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, HaltReason, RegisterID};
+    use claims::assert_ok_eq;
+
+    #[test]
+    fn results_come_back_in_job_order() {
+        let pool = MachinePool::new(3);
+        let results = pool.run((0..10u64).collect(), |n| {
+            thread::sleep(std::time::Duration::from_millis(10 - n));
+            n * n
+        });
+        assert_eq!(results, (0..10).map(|n| n * n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn machines_started_from_a_base_image_share_its_pages() {
+        // Initializes the table at 0x2000 and stops; each job then adds its
+        // input in a1 to the first entry and exits with the sum.
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(
+                &assemble(
+                    "li a0, 40
+                     sw a0, 0x2000
+                     ebreak
+                     lw a0, 0x2000
+                     add a0, a0, a1
+                     sw a0, 0x2000
+                     li a7, 93
+                     ecall",
+                )
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        let base = machine.base_image();
+
+        let results = MachinePool::new(2).run_from(&base, vec![1, 2, 3], |mut machine, n| {
+            machine.set_register(RegisterID::A1, n);
+            let reason = machine.run().unwrap();
+            (reason, machine.mem.read(0x2000, 4))
+        });
+
+        assert_eq!(
+            results,
+            [1, 2, 3].map(|n| (
+                HaltReason::Exit { code: 40 + n },
+                vec![0, 0, 0, 40 + n as u8]
+            ))
+        );
+        // The base image is as it was, and a machine started from it looks
+        // the same as the one it froze.
+        let started = Machine::from_base_image(&base, Vec::new());
+        assert_eq!(started.mem.read(0x2000, 4), [0, 0, 0, 40]);
+        assert_eq!(started.state(), machine.state());
+        assert_eq!(base.pages.len(), machine.mem.pages().len());
+    }
+}
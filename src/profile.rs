@@ -0,0 +1,158 @@
+use crate::{
+    output::{errno, EINVAL},
+    Machine, RegisterID, Result, Word,
+};
+use std::{collections::BTreeMap, io::Write};
+
+// What the guest spent inside one named region, over every time it was
+// entered and left again.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Region {
+    pub entries: u64,
+    pub cycles: u64,
+    pub retired: u64,
+}
+
+impl Region {
+    pub(crate) fn record(&mut self, cycles: u64, retired: u64) {
+        self.entries += 1;
+        self.cycles += cycles;
+        self.retired += retired;
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Open {
+    name: String,
+    cycles: u64,
+    retired: u64,
+}
+
+// Regions the guest marks around the code it wants measured. A region
+// counts the instructions between the syscall that begins it and the one
+// that ends it, not the syscalls themselves. Regions may nest, and a nested
+// region counts towards each region enclosing it as well as its own.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Profile {
+    open: Vec<Open>,
+    regions: BTreeMap<String, Region>,
+}
+
+impl Profile {
+    // Forgets the regions left open, keeping the figures so far.
+    pub(crate) fn close_all(&mut self) {
+        self.open.clear();
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // The figures for every region ended so far, by name.
+    pub fn regions(&self) -> &BTreeMap<String, Region> {
+        &self.profile.regions
+    }
+
+    // The name of a region, which is shown in the stats report and so may
+    // not be empty or contain whitespace.
+    fn region_name(&mut self) -> Result<Option<String>> {
+        let addr = self.regs.get(&RegisterID::A0);
+        let len = self.guest_buffer(addr, self.regs.get(&RegisterID::A1).into())?;
+        let name = String::from_utf8(self.load(addr, len)).ok();
+        Ok(name.filter(|name| !name.is_empty() && !name.contains(char::is_whitespace)))
+    }
+
+    // region_begin(name, len) starts measuring the region named by the
+    // `len` bytes at `name`, returning 0, or -EINVAL for a bad name.
+    pub(crate) fn region_begin_syscall(&mut self) -> Result<()> {
+        let Some(name) = self.region_name()? else {
+            self.set_register(RegisterID::A0, errno(EINVAL));
+            return Ok(());
+        };
+        // The ecall itself retires once this returns.
+        self.profile.open.push(Open {
+            name,
+            cycles: self.cycles + 1,
+            retired: self.retired + 1,
+        });
+        self.set_register(RegisterID::A0, 0);
+        Ok(())
+    }
+
+    // region_end(name, len) stops measuring the most recently begun region
+    // of that name, returning 0, or -EINVAL if none is open.
+    pub(crate) fn region_end_syscall(&mut self) -> Result<()> {
+        let name = self.region_name()?;
+        let index =
+            name.and_then(|name| self.profile.open.iter().rposition(|open| open.name == name));
+        let Some(index) = index else {
+            self.set_register(RegisterID::A0, errno(EINVAL));
+            return Ok(());
+        };
+        let open = self.profile.open.remove(index);
+        let cycles = self.cycles.saturating_sub(open.cycles);
+        let retired = self.retired.saturating_sub(open.retired);
+        self.profile
+            .regions
+            .entry(open.name.clone())
+            .or_default()
+            .record(cycles, retired);
+        self.count_region(open.name, cycles, retired);
+        self.set_register(RegisterID::A0, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::machine, HaltReason};
+    use claims::assert_ok_eq;
+
+    // Calls syscall `number` on the region named by the `len` bytes at
+    // `name`.
+    fn region(number: Word, name: &str, len: u32) -> String {
+        format!("li a0, {name}\nli a1, {len}\nli a7, {number}\necall\n")
+    }
+
+    #[test]
+    fn regions_count_the_instructions_between_their_markers() {
+        let program = [
+            region(271, "0x100", 5),
+            "li a2, 3\nagain:\n".to_string(),
+            region(271, "0x108", 4),
+            "add a3, a3, x0, 1\n".to_string(),
+            region(272, "0x108", 4),
+            "blt a3, a2, again\n".to_string(),
+            region(272, "0x100", 5),
+            region(272, "0x100", 5),
+            "mv a4, a0\nebreak\n".to_string(),
+        ]
+        .concat();
+        let mut machine = machine(&program);
+        machine.mem.write(0x100, b"outer");
+        machine.mem.write(0x108, b"loop");
+
+        let outcome = machine.run_with_stats().unwrap();
+
+        assert_eq!(outcome.reason, HaltReason::Breakpoint);
+        // The second end of "outer" has nothing to close.
+        assert_eq!(machine.register(RegisterID::A4), errno(EINVAL));
+        // The add, then the three instructions setting up the end marker.
+        let inner = machine.regions()["loop"];
+        assert_eq!((inner.entries, inner.retired, inner.cycles), (3, 12, 12));
+        // Each time round the loop is ten instructions, markers included.
+        let outer = machine.regions()["outer"];
+        assert_eq!((outer.entries, outer.retired), (1, 1 + 3 * 10 + 3));
+        let stats = outcome.stats().to_string();
+        assert!(stats.ends_with("region loop 3 12 12\nregion outer 1 34 34\n"));
+    }
+
+    #[test]
+    fn region_names_must_be_words() {
+        let mut machine = machine(&format!("{}ebreak", region(271, "0x100", 0)));
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.register(RegisterID::A0), errno(EINVAL));
+        assert!(machine.regions().is_empty());
+    }
+}
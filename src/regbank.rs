@@ -0,0 +1,252 @@
+use crate::{heap::AccessKind, Address, Error, Machine, RegisterID, Registers, Result, Word};
+use std::io::Write;
+
+// Banks of the register file, for trying out register windows and other
+// ways of giving code more registers than an instruction can name. Each
+// register in `banked` has a copy per bank, and the word at `select`, a
+// memory-mapped control register, chooses which copy an instruction naming
+// it uses. Instructions are decoded as ever; only the register file
+// resolves the names. Other registers are shared by every bank, and select
+// values wrap around the number of banks.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RegisterBankConfig {
+    pub select: Address,
+    pub count: u8,
+    pub banked: Vec<RegisterID>,
+}
+
+// Every bank of a banked register file and which is selected, as states
+// and snapshots save it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RegisterBanks {
+    pub(crate) select: Address,
+    // The banked registers, one bit each.
+    pub(crate) banked: u16,
+    pub(crate) selected: u8,
+    // Each bank's copies of the banked registers, by register number.
+    pub(crate) contents: Vec<[Word; 16]>,
+}
+
+impl RegisterBanks {
+    pub(crate) fn new(config: &RegisterBankConfig) -> Result<Self> {
+        if config.count == 0 {
+            return Err(Error::LayoutInvalid(
+                "a register bank needs at least one bank",
+            ));
+        }
+        if config.banked.contains(&RegisterID::X0) {
+            return Err(Error::LayoutInvalid("x0 cannot be banked"));
+        }
+        Ok(Self {
+            select: config.select,
+            banked: config
+                .banked
+                .iter()
+                .fold(0, |bits, reg| bits | 1 << *reg as u16),
+            selected: 0,
+            contents: vec![[0; 16]; config.count.into()],
+        })
+    }
+
+    // Whether banks read from a state or snapshot could have come from a
+    // machine: there are banks to select from, no more than a config can
+    // ask for, and x0 is not among the banked registers.
+    pub(crate) fn is_valid(&self) -> bool {
+        !self.contents.is_empty()
+            && u8::try_from(self.contents.len()).is_ok()
+            && usize::from(self.selected) < self.contents.len()
+            && self.banked & 1 == 0
+    }
+}
+
+impl Registers {
+    pub(crate) fn set_banks(&mut self, banks: RegisterBanks) {
+        self.banks = Some(Box::new(banks));
+    }
+
+    // The selected bank's copy of `reg`, if it is banked.
+    pub(crate) fn banked_mut(&mut self, reg: RegisterID) -> Option<&mut Word> {
+        let banks = self.banks.as_mut()?;
+        (banks.banked & 1 << reg as u16 != 0)
+            .then(|| &mut banks.contents[usize::from(banks.selected)][reg as usize])
+    }
+
+    pub(crate) fn banked(&self, reg: RegisterID) -> Option<Word> {
+        let banks = self.banks.as_ref()?;
+        (banks.banked & 1 << reg as u16 != 0)
+            .then(|| banks.contents[usize::from(banks.selected)][reg as usize])
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // The register bank instructions are using, if the register file is
+    // banked.
+    pub fn register_bank(&self) -> Option<u8> {
+        self.regs.banks.as_ref().map(|banks| banks.selected)
+    }
+
+    // A bank's copy of `reg`, whether or not the bank is selected. None if
+    // `reg` is not banked or there is no such bank.
+    pub fn banked_register(&self, bank: u8, reg: RegisterID) -> Option<Word> {
+        let banks = self.regs.banks.as_ref()?;
+        let contents = banks.contents.get(usize::from(bank))?;
+        (banks.banked & 1 << reg as u16 != 0).then_some(contents[reg as usize])
+    }
+
+    pub(crate) fn saved_register_banks(&self) -> Option<RegisterBanks> {
+        self.regs.banks.as_deref().cloned()
+    }
+
+    // Replaces the register file's banks, the banked registers along with
+    // them, before the registers themselves are restored.
+    pub(crate) fn restore_register_banks(&mut self, banks: Option<&RegisterBanks>) {
+        self.regs.banks = banks.cloned().map(Box::new);
+    }
+
+    // Selects the bank the select register names, as a write to it does.
+    pub(crate) fn sync_register_bank(&mut self) {
+        let Some(banks) = &self.regs.banks else {
+            return;
+        };
+        let value = self
+            .endian
+            .word(self.mem.read(banks.select, 4).try_into().unwrap());
+        let count = banks.contents.len() as Word;
+        if let Some(banks) = &mut self.regs.banks {
+            banks.selected = (value % count) as u8;
+        }
+    }
+
+    // Switches banks if a store just wrote to the select register.
+    pub(crate) fn check_register_bank_select(
+        &mut self,
+        addr: Address,
+        len: usize,
+        kind: AccessKind,
+    ) {
+        let Some(banks) = &self.regs.banks else {
+            return;
+        };
+        let select = u64::from(banks.select);
+        let end = u64::from(addr) + len as u64;
+        if kind == AccessKind::Write && end > select && select + 4 > u64::from(addr) {
+            self.sync_register_bank();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, pool::BaseImage, snapshot::Snapshot, HaltReason};
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    fn machine(program: &str) -> Result<Machine<Vec<u8>>> {
+        Machine::builder()
+            .program(&assemble(program).unwrap())
+            .register_banks(RegisterBankConfig {
+                select: 0x2000,
+                count: 2,
+                banked: vec![RegisterID::A8, RegisterID::A9],
+            })
+            .build()
+    }
+
+    #[test]
+    fn the_select_register_switches_banked_registers() {
+        let mut machine = machine(
+            "li a8, 1
+             li a1, 7
+             li a2, 1
+             sw a2, 0x2000
+             li a8, 2
+             add a9, a8, a1
+             sw x0, 0x2000
+             add a0, a8, x0
+             lw a3, 0x2000
+             ebreak",
+        )
+        .unwrap();
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register_bank(), Some(0));
+        // Bank 0's a8 is as it was before the switch, and a1 is shared.
+        assert_eq!(machine.register(RegisterID::A0), 1);
+        assert_eq!(machine.register(RegisterID::A8), 1);
+        assert_eq!(machine.register(RegisterID::A9), 0);
+        // The select register reads back as written.
+        assert_eq!(machine.register(RegisterID::A3), 0);
+        assert_eq!(machine.banked_register(1, RegisterID::A8), Some(2));
+        assert_eq!(machine.banked_register(1, RegisterID::A9), Some(9));
+        assert_eq!(machine.banked_register(1, RegisterID::A1), None);
+        assert_eq!(machine.banked_register(2, RegisterID::A8), None);
+    }
+
+    #[test]
+    fn select_values_wrap_around_the_bank_count() {
+        let mut machine = machine("li a2, 3\nsw a2, 0x2000\nli a8, 4\nebreak").unwrap();
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register_bank(), Some(1));
+        assert_eq!(machine.banked_register(1, RegisterID::A8), Some(4));
+    }
+
+    #[test]
+    fn states_and_snapshots_keep_every_bank() {
+        let mut machine = machine(
+            "li a2, 1
+             sw a2, 0x2000
+             li a8, 2
+             sw x0, 0x2000
+             li a8, 1
+             ebreak",
+        )
+        .unwrap();
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        let kept = |machine: &Machine<Vec<u8>>| {
+            assert_eq!(machine.register_bank(), Some(0));
+            assert_eq!(machine.register(RegisterID::A8), 1);
+            assert_eq!(machine.banked_register(1, RegisterID::A8), Some(2));
+        };
+
+        let state = machine.state();
+        kept(&Machine::from_state(&state).unwrap());
+        kept(&Machine::from_base_image(
+            &BaseImage::from_state(&state).unwrap(),
+            Vec::new(),
+        ));
+        kept(&Machine::from_base_image(&machine.base_image(), Vec::new()));
+        let snapshot = Snapshot::take(&machine, b"");
+        let parsed = Snapshot::parse(&snapshot.to_bytes(), 4096).unwrap();
+        assert_eq!(parsed, snapshot);
+        let mut resumed = self::machine("ebreak").unwrap();
+        resumed.restore_snapshot(&parsed).unwrap();
+        kept(&resumed);
+    }
+
+    #[test]
+    fn states_with_invalid_banks_are_refused() {
+        let mut state = machine("ebreak").unwrap().state();
+        if let Some(banks) = &mut state.register_banks {
+            banks.selected = 2;
+        }
+        assert_err_eq!(
+            Machine::<Vec<u8>>::from_state(&state),
+            Error::StateInvalid("register banks are invalid".to_string())
+        );
+    }
+
+    #[test]
+    fn x0_cannot_be_banked() {
+        let config = RegisterBankConfig {
+            select: 0x2000,
+            count: 2,
+            banked: vec![RegisterID::X0],
+        };
+        assert_err_eq!(
+            RegisterBanks::new(&config),
+            Error::LayoutInvalid("x0 cannot be banked")
+        );
+    }
+}
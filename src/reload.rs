@@ -0,0 +1,121 @@
+use crate::{integrity::Image, loader::Executable, symbols::SymbolTable, Machine};
+use std::io::Write;
+
+impl<W: Write> Machine<W> {
+    // Swaps in `image`, a rebuild of the running program as a flat image at
+    // the load address, with `symbols` for it, so a program can be fixed and
+    // run again without leaving the debugger. The rebuild becomes what a
+    // cold reset loads. Without `keep_data` the machine then starts it
+    // afresh, with a cold reset; with it, memory outside the program image,
+    // the registers and everything else the guest has set up stay as they
+    // are, and the pc and the host's breakpoints move with the symbols they
+    // are in. Breakpoints in a symbol the rebuild no longer has are
+    // dropped. Patches, being to the old code, are forgotten.
+    pub fn reload_program(&mut self, image: &[u8], symbols: SymbolTable, keep_data: bool) {
+        let executable = Executable::flat(image, self.layout.load_address);
+        for (addr, len) in self.image.extents() {
+            self.boot.mem.write(addr, &vec![0; len]);
+            if keep_data {
+                self.mem.write(addr, &vec![0; len]);
+            }
+        }
+        for segment in &executable.segments {
+            self.boot.mem.write(segment.addr, &segment.data);
+            if keep_data {
+                self.mem.write(segment.addr, &segment.data);
+                self.initialize_memory(segment.addr, segment.data.len());
+            }
+        }
+        self.boot.mem.clear_dirty();
+        self.image = Image::loaded(&executable.segments);
+        self.patches.clear();
+        let old = std::mem::replace(&mut self.symbols, symbols);
+        let breakpoints: Vec<_> = self.breakpoints().collect();
+        for addr in breakpoints {
+            self.clear_breakpoint(addr);
+            if let Some(addr) = old.relocate(addr, &self.symbols) {
+                self.set_breakpoint(addr);
+            }
+        }
+        if keep_data {
+            self.pc = old.relocate(self.pc, &self.symbols).unwrap_or(self.pc);
+            self.call_stack.clear();
+        } else {
+            self.reboot(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, HaltReason, RegisterID};
+    use claims::assert_ok_eq;
+
+    // Counts a1 up to 3 in `count` and stores it at 0x2000; the fixed
+    // version adds 2 at a time and has an extra instruction up front.
+    const BUGGY: &str = "main:  li a1, 0
+                count: add a1, a1, x0, 1
+                       sw a1, 0x2000
+                       blt a1, a3, count
+                       ebreak";
+    const FIXED: &str = "main:  li a1, 0
+                       li a2, 2
+                count: add a1, a1, a2
+                       sw a1, 0x2000
+                       blt a1, a3, count
+                       ebreak";
+
+    fn symbols(main: u32, count: u32) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("main", main, 0);
+        symbols.insert("count", count, 0);
+        symbols
+    }
+
+    fn machine() -> Machine<Vec<u8>> {
+        let mut machine = Machine::builder()
+            .program(&assemble(BUGGY).unwrap())
+            .symbols(symbols(0, 4))
+            .register(RegisterID::A3, 6)
+            .build()
+            .unwrap();
+        // Stop on the sw, once round the loop.
+        machine.set_breakpoint(8);
+        assert_ok_eq!(machine.run(), HaltReason::HostBreakpoint { pc: 8 });
+        machine
+    }
+
+    #[test]
+    fn reloading_restarts_the_rebuilt_program() {
+        let mut machine = machine();
+        machine.reload_program(&assemble(FIXED).unwrap(), symbols(0, 8), false);
+
+        assert_eq!(machine.pc, 0);
+        assert_eq!(machine.breakpoints().collect::<Vec<_>>(), [12]);
+        machine.clear_breakpoint(12);
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.mem.read(0x2000, 4), [0, 0, 0, 6]);
+        assert_eq!(machine.register(RegisterID::A3), 6);
+    }
+
+    #[test]
+    fn reloading_can_keep_the_guest_where_it_was() {
+        let mut machine = machine();
+        machine.mem.write(0x2000, &[0, 0, 0, 1]);
+        machine.reload_program(&assemble(FIXED).unwrap(), symbols(0, 8), true);
+
+        // The pc moved from the sw in count to the sw in its rebuild.
+        assert_eq!(machine.pc, 12);
+        assert_eq!(machine.mem.read(0x2000, 4), [0, 0, 0, 1]);
+        assert_eq!(machine.register(RegisterID::A1), 1);
+        machine.clear_breakpoint(12);
+        // a2 is only set by the new main, which the guest never ran.
+        machine.set_register(RegisterID::A2, 2);
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.mem.read(0x2000, 4), [0, 0, 0, 7]);
+
+        machine.reboot(0);
+        assert_eq!(machine.mem.read(0, 8), assemble(FIXED).unwrap()[..8]);
+    }
+}
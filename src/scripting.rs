@@ -0,0 +1,360 @@
+use crate::{
+    clock::Clock, endian::Endian, limits::Limits, Address, Error, Layout, Machine, Memory,
+    RegisterID, Result, StepOutcome, Trap, Word,
+};
+use std::{collections::BTreeMap, fmt, io::Write};
+
+// Syscall numbers from here up are the embedder's to bind to host
+// functions; the machine's own stay below.
+pub const HOST_FUNCTIONS: Word = 0x1000;
+
+// The memory, fuel and output a script gets with Machine::scripting.
+pub const SCRIPT_MEMORY: Address = 0x1_0000;
+pub const SCRIPT_FUEL: u64 = 1_000_000;
+pub const SCRIPT_OUTPUT: u64 = 0x1_0000;
+
+type HostFunction = Box<dyn FnMut(&mut HostCall) -> Word>;
+
+#[derive(Default)]
+pub struct HostFunctions(BTreeMap<Word, HostFunction>);
+
+impl fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.keys()).finish()
+    }
+}
+
+// A value the host can copy into guest memory and back, laid out the way
+// the guest sees it: integers in the machine's byte order, and arrays and
+// structs as their elements or fields one after another, without padding.
+// Structs implement it field by field:
+//
+//   impl Marshal for Point {
+//       const SIZE: usize = 8;
+//       fn to_guest(&self, endian: Endian, out: &mut Vec<u8>) {
+//           self.x.to_guest(endian, out);
+//           self.y.to_guest(endian, out);
+//       }
+//       fn from_guest(endian: Endian, bytes: &mut &[u8]) -> Self {
+//           let x = Word::from_guest(endian, bytes);
+//           Point { x, y: Word::from_guest(endian, bytes) }
+//       }
+//   }
+pub trait Marshal: Sized {
+    const SIZE: usize;
+    fn to_guest(&self, endian: Endian, out: &mut Vec<u8>);
+    // Reads the value from the front of `bytes`, leaving the rest.
+    fn from_guest(endian: Endian, bytes: &mut &[u8]) -> Self;
+}
+
+macro_rules! marshal_integer {
+    ($($ty:ty),*) => {$(
+        impl Marshal for $ty {
+            const SIZE: usize = std::mem::size_of::<$ty>();
+
+            fn to_guest(&self, endian: Endian, out: &mut Vec<u8>) {
+                let mut bytes = self.to_be_bytes();
+                endian.arrange(&mut bytes);
+                out.extend(bytes);
+            }
+
+            fn from_guest(endian: Endian, bytes: &mut &[u8]) -> Self {
+                let (value, rest) = bytes.split_at(Self::SIZE);
+                let mut value: [u8; std::mem::size_of::<$ty>()] = value.try_into().unwrap();
+                endian.arrange(&mut value);
+                *bytes = rest;
+                <$ty>::from_be_bytes(value)
+            }
+        }
+    )*};
+}
+
+marshal_integer!(u8, i8, u16, i16, u32, i32, u64, i64);
+
+// A byte, 0 or 1.
+impl Marshal for bool {
+    const SIZE: usize = 1;
+
+    fn to_guest(&self, endian: Endian, out: &mut Vec<u8>) {
+        u8::from(*self).to_guest(endian, out);
+    }
+
+    fn from_guest(endian: Endian, bytes: &mut &[u8]) -> Self {
+        u8::from_guest(endian, bytes) != 0
+    }
+}
+
+impl<T: Marshal, const N: usize> Marshal for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn to_guest(&self, endian: Endian, out: &mut Vec<u8>) {
+        for value in self {
+            value.to_guest(endian, out);
+        }
+    }
+
+    fn from_guest(endian: Endian, bytes: &mut &[u8]) -> Self {
+        std::array::from_fn(|_| T::from_guest(endian, bytes))
+    }
+}
+
+fn encode<T: Marshal>(value: &T, endian: Endian) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(T::SIZE);
+    value.to_guest(endian, &mut bytes);
+    bytes
+}
+
+fn decode<T: Marshal>(mem: &Memory, addr: Address, endian: Endian) -> T {
+    T::from_guest(endian, &mut mem.read(addr, T::SIZE).as_slice())
+}
+
+// What a host function sees of the guest calling it: the arguments it
+// passed in a0 to a2, and its memory.
+pub struct HostCall<'a> {
+    pub args: [Word; 3],
+    mem: &'a mut Memory,
+    endian: Endian,
+    // What the function wrote, for the machine to count as initialized.
+    written: Vec<(Address, usize)>,
+}
+
+impl HostCall<'_> {
+    pub fn read_value<T: Marshal>(&self, addr: Address) -> T {
+        decode(self.mem, addr, self.endian)
+    }
+
+    pub fn write_value<T: Marshal>(&mut self, addr: Address, value: &T) {
+        self.write_bytes(addr, &encode(value, self.endian));
+    }
+
+    pub fn read_bytes(&self, addr: Address, len: usize) -> Vec<u8> {
+        self.mem.read(addr, len)
+    }
+
+    pub fn write_bytes(&mut self, addr: Address, data: &[u8]) {
+        self.mem.write(addr, data);
+        self.written.push((addr, data.len()));
+    }
+}
+
+impl Machine<Vec<u8>> {
+    // A machine set up for running untrusted scripts embedded in a host
+    // program, such as rules for it to evaluate: the program is confined to
+    // SCRIPT_MEMORY bytes of memory and SCRIPT_FUEL instructions, decoded
+    // strictly, with no stdin, no host directory and a clock that runs off
+    // the cycle count, and up to SCRIPT_OUTPUT bytes of its output are kept
+    // in memory. The script talks
+    // to the host through the functions bound with bind_host_function.
+    pub fn scripting(program: &[u8]) -> Result<Self> {
+        let mut machine = Machine::builder()
+            .layout(Layout {
+                page_size: 256,
+                load_address: 0,
+                stack_top: SCRIPT_MEMORY,
+                heap_base: SCRIPT_MEMORY / 2,
+            })
+            .stdout(Vec::new())
+            .program(program)
+            .fuel(SCRIPT_FUEL)
+            .build()?;
+        machine.set_memory_limit(Some(SCRIPT_MEMORY));
+        machine.set_strict_decoding(true);
+        machine.set_limits(Some(Limits {
+            max_output_bytes: Some(SCRIPT_OUTPUT),
+            ..Limits::default()
+        }));
+        machine.set_clock(Clock::Cycles {
+            hz: 1_000_000,
+            epoch: 0,
+        });
+        Ok(machine)
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Binds syscall `number`, HOST_FUNCTIONS or above, to `function`, which
+    // is called with the guest's arguments and memory and returns the value
+    // for a0. Binding a number again replaces its function.
+    pub fn bind_host_function(
+        &mut self,
+        number: Word,
+        function: impl FnMut(&mut HostCall) -> Word + 'static,
+    ) -> Result<()> {
+        if number < HOST_FUNCTIONS {
+            return Err(Error::HostFunctionInvalid(number));
+        }
+        self.host_functions.0.insert(number, Box::new(function));
+        Ok(())
+    }
+
+    // Copies `value` into guest memory at `addr`.
+    pub fn write_value<T: Marshal>(&mut self, addr: Address, value: &T) {
+        let bytes = encode(value, self.endian);
+        self.initialize_memory(addr, bytes.len());
+        self.mem.write(addr, &bytes);
+    }
+
+    // Reads a value from guest memory at `addr`.
+    pub fn read_value<T: Marshal>(&self, addr: Address) -> T {
+        decode(&self.mem, addr, self.endian)
+    }
+
+    pub(crate) fn call_host_function(&mut self, number: Word) -> Result<StepOutcome> {
+        let Some(mut function) = self.host_functions.0.remove(&number) else {
+            return Err(self.syscall_trap(Trap::UnknownSyscall { number }));
+        };
+        let mut call = HostCall {
+            args: [RegisterID::A0, RegisterID::A1, RegisterID::A2].map(|reg| self.regs.get(&reg)),
+            mem: &mut self.mem,
+            endian: self.endian,
+            written: Vec::new(),
+        };
+        let result = function(&mut call);
+        for (addr, len) in std::mem::take(&mut call.written) {
+            self.initialize_memory(addr, len);
+        }
+        self.host_functions.0.insert(number, function);
+        self.set_register(RegisterID::A0, result);
+        Ok(StepOutcome::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, HaltReason};
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    struct Order {
+        quantity: u16,
+        price: Word,
+        rush: bool,
+    }
+
+    impl Marshal for Order {
+        const SIZE: usize = 7;
+
+        fn to_guest(&self, endian: Endian, out: &mut Vec<u8>) {
+            self.quantity.to_guest(endian, out);
+            self.price.to_guest(endian, out);
+            self.rush.to_guest(endian, out);
+        }
+
+        fn from_guest(endian: Endian, bytes: &mut &[u8]) -> Self {
+            Order {
+                quantity: u16::from_guest(endian, bytes),
+                price: Word::from_guest(endian, bytes),
+                rush: bool::from_guest(endian, bytes),
+            }
+        }
+    }
+
+    #[test]
+    fn structs_are_laid_out_field_by_field() {
+        let order = Order {
+            quantity: 3,
+            price: 250,
+            rush: true,
+        };
+        for (endian, bytes) in [
+            (Endian::Big, [0, 3, 0, 0, 0, 250, 1]),
+            (Endian::Little, [3, 0, 250, 0, 0, 0, 1]),
+        ] {
+            let mut machine: Machine<Vec<u8>> = Machine::builder().endian(endian).build().unwrap();
+            machine.write_value(0x100, &order);
+            assert_eq!(machine.mem.read(0x100, 7), bytes);
+            assert_eq!(machine.read_value::<Order>(0x100), order);
+        }
+    }
+
+    #[test]
+    fn scripts_call_host_functions() {
+        // Asks the host for the order at 0x100, then for the total of the
+        // one it was given, and exits with that.
+        let mut machine = Machine::scripting(
+            &assemble(
+                "li a0, 0x100
+                 li a7, 0x1000
+                 ecall
+                 li a7, 0x1001
+                 ecall
+                 li a7, 93
+                 ecall",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        machine
+            .bind_host_function(0x1000, |call| {
+                let order = Order {
+                    quantity: 3,
+                    price: 250,
+                    rush: false,
+                };
+                call.write_value(call.args[0], &order);
+                call.args[0]
+            })
+            .unwrap();
+        machine
+            .bind_host_function(0x1001, |call| {
+                let order: Order = call.read_value(call.args[0]);
+                Word::from(order.quantity) * order.price
+            })
+            .unwrap();
+
+        assert_ok_eq!(machine.run(), HaltReason::Exit { code: 750 });
+        assert_err_eq!(
+            machine.bind_host_function(64, |_| 0),
+            Error::HostFunctionInvalid(64)
+        );
+    }
+
+    #[test]
+    fn scripts_are_confined() {
+        let mut machine = Machine::scripting(&assemble("lw a0, 0(a1)\nebreak").unwrap()).unwrap();
+        machine.regs.set(RegisterID::A1, SCRIPT_MEMORY - 2);
+        assert_err_eq!(
+            machine.run(),
+            Error::Trap {
+                pc: 0,
+                trap: Trap::MemoryFault {
+                    addr: SCRIPT_MEMORY - 2,
+                    len: 4,
+                },
+            }
+        );
+
+        let mut machine = Machine::scripting(&assemble("spin: j spin").unwrap()).unwrap();
+        assert_ok_eq!(machine.run(), HaltReason::ResourceExhausted { pc: 0 });
+
+        let mut machine = Machine::scripting(
+            &assemble(
+                "li a2, 0x1000
+                 li a7, 64
+                 loop: li a0, 1
+                 ecall
+                 j loop",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_ok_eq!(
+            machine.run(),
+            HaltReason::OutputLimit {
+                pc: 12,
+                limit: SCRIPT_OUTPUT,
+            }
+        );
+        assert_eq!(machine.stdout().unwrap().len() as u64, SCRIPT_OUTPUT);
+
+        let mut machine = Machine::scripting(&assemble("li a7, 0x1002\necall").unwrap()).unwrap();
+        assert_err_eq!(
+            machine.run(),
+            Error::Trap {
+                pc: 4,
+                trap: Trap::UnknownSyscall { number: 0x1002 },
+            }
+        );
+    }
+}
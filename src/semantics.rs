@@ -0,0 +1,721 @@
+use crate::{
+    effect::{Control, Effect, MemoryWrite, RegisterWrite},
+    endian::Endian,
+    heap::AccessKind,
+    Address, Instruction, Memory, Opcode, RegisterID, Registers, Word,
+};
+
+// The meaning of an instruction, described as data so that tools such as
+// symbolic executors can interpret it without running a machine. `Pc` is
+// the address the instruction was fetched from.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Value {
+    Imm(Word),
+    Reg(RegisterID),
+    Pc,
+    Add(Box<Value>, Box<Value>),
+    Alu(AluOp, Box<Value>, Box<Value>),
+}
+
+// The ALU operations other than addition, on 32-bit words.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AluOp {
+    Sub,
+    Mul,
+    // Signed division, rounding towards zero.
+    Div,
+    Rem,
+    And,
+    Or,
+    Xor,
+    // Shifts by the low five bits of the right-hand side.
+    Sll,
+    Srl,
+    Sra,
+    // 1 if the left-hand side is less than the right, otherwise 0.
+    Slt,
+    Sltu,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Condition {
+    Eq,
+    Ne,
+    // Signed comparisons.
+    Lt,
+    Ge,
+}
+
+// The size of a memory access. Values are stored in the machine's byte
+// order, and loads narrower than a word are sign-extended.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Width {
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::Byte => 1,
+            Width::Half => 2,
+            Width::Word => 4,
+        }
+    }
+
+    // Assembles big-endian bytes into a sign-extended word.
+    fn extend(self, bytes: &[u8]) -> Word {
+        let value = bytes
+            .iter()
+            .fold(0, |value, byte| value << 8 | Word::from(*byte));
+        let unused = 32 - 8 * self.bytes() as u32;
+        (((value << unused) as i32) >> unused) as Word
+    }
+}
+
+// A state update made by an instruction. The updates of an instruction are
+// applied in order; values are always evaluated against the registers as
+// they are when the update is applied.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Update {
+    SetReg(RegisterID, Value),
+    // Continue at `target` if `lhs` and `rhs` satisfy the condition.
+    Branch {
+        condition: Condition,
+        lhs: Value,
+        rhs: Value,
+        target: Value,
+    },
+    // Continue at `target`.
+    Jump(Value),
+    Load {
+        rd: RegisterID,
+        addr: Value,
+        width: Width,
+    },
+    Store {
+        addr: Value,
+        value: Value,
+        width: Width,
+    },
+    // Hand control to the environment to service the syscall in a7.
+    Syscall,
+    Breakpoint,
+    // Continue where the interrupt being handled was taken, which only the
+    // environment knows.
+    InterruptReturn,
+}
+
+// Everything an instruction can read or change, without any of a machine's
+// I/O or bookkeeping.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ArchState {
+    pub pc: Word,
+    pub regs: Registers,
+    pub mem: Memory,
+    pub endian: Endian,
+}
+
+// Executes `instruction`, fetched from `state.pc`, leaving `state.pc` at the
+// next instruction to execute, and returns the changes it made.
+pub fn step(state: &mut ArchState, instruction: &Instruction) -> Effect {
+    let mut effect = Effect::default();
+    step_into(state, instruction, &mut effect);
+    effect
+}
+
+// As step, but recording the changes in `effect`, whose buffers are reused
+// so that a machine executing instruction after instruction allocates
+// nothing. This matches on the opcode rather than interpreting the
+// instruction's semantics, which agree with it.
+pub fn step_into(state: &mut ArchState, instruction: &Instruction, effect: &mut Effect) {
+    let pc = state.pc;
+    let spare = effect.mem.pop();
+    effect.mem.clear();
+    effect.regs.clear();
+    effect.pc = pc;
+    effect.control = Control::Continue;
+
+    let rs1 = state.regs.get(&instruction.rs1);
+    let rs2 = state.regs.get(&instruction.rs2);
+    let offset = instruction.offset() as Word;
+    // The second operand of an ALU operation is rs2 plus the immediate.
+    let operand = rs2.wrapping_add(offset);
+    let addr = rs1.wrapping_add(offset);
+    let link = pc.wrapping_add(4);
+    let mut next_pc = link;
+    let result = match instruction.opcode {
+        Opcode::LoadImmediate => Some(instruction.imm as Word),
+        Opcode::Auipc => Some(pc.wrapping_add(offset << 15)),
+        Opcode::Lui => Some(offset << 15),
+        Opcode::Add => Some(rs1.wrapping_add(operand)),
+        Opcode::Sub => Some(AluOp::Sub.apply(rs1, operand)),
+        Opcode::Mul => Some(AluOp::Mul.apply(rs1, operand)),
+        Opcode::Div => Some(AluOp::Div.apply(rs1, operand)),
+        Opcode::Rem => Some(AluOp::Rem.apply(rs1, operand)),
+        Opcode::And => Some(AluOp::And.apply(rs1, operand)),
+        Opcode::Or => Some(AluOp::Or.apply(rs1, operand)),
+        Opcode::Xor => Some(AluOp::Xor.apply(rs1, operand)),
+        Opcode::Sll => Some(AluOp::Sll.apply(rs1, operand)),
+        Opcode::Srl => Some(AluOp::Srl.apply(rs1, operand)),
+        Opcode::Sra => Some(AluOp::Sra.apply(rs1, operand)),
+        Opcode::Slt => Some(AluOp::Slt.apply(rs1, operand)),
+        Opcode::Sltu => Some(AluOp::Sltu.apply(rs1, operand)),
+        Opcode::Jal => {
+            next_pc = pc.wrapping_add(offset);
+            Some(link)
+        }
+        Opcode::Jalr => {
+            next_pc = addr;
+            Some(link)
+        }
+        Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge => {
+            let condition = match instruction.opcode {
+                Opcode::Beq => Condition::Eq,
+                Opcode::Bne => Condition::Ne,
+                Opcode::Blt => Condition::Lt,
+                _ => Condition::Ge,
+            };
+            if condition.holds(rs1, rs2) {
+                next_pc = pc.wrapping_add(offset);
+            }
+            None
+        }
+        Opcode::Lb => Some(load(state, addr, Width::Byte)),
+        Opcode::Lh => Some(load(state, addr, Width::Half)),
+        Opcode::Lw => Some(load(state, addr, Width::Word)),
+        Opcode::Sb | Opcode::Sh | Opcode::Sw => {
+            let width = match instruction.opcode {
+                Opcode::Sb => Width::Byte,
+                Opcode::Sh => Width::Half,
+                _ => Width::Word,
+            };
+            effect.mem.push(store(state, addr, rs2, width, spare));
+            None
+        }
+        Opcode::ECall => {
+            effect.control = Control::Syscall;
+            None
+        }
+        Opcode::EBreak => {
+            effect.control = Control::Breakpoint;
+            None
+        }
+        Opcode::Mret => {
+            effect.control = Control::InterruptReturn;
+            None
+        }
+    };
+    if let (Some(new), false) = (result, instruction.rd == RegisterID::X0) {
+        let old = state.regs.get(&instruction.rd);
+        state.regs.set(instruction.rd, new);
+        effect.regs.push(RegisterWrite {
+            reg: instruction.rd,
+            old,
+            new,
+        });
+    }
+    state.pc = next_pc;
+    effect.next_pc = next_pc;
+}
+
+fn load(state: &ArchState, addr: Address, width: Width) -> Word {
+    let mut bytes = [0; 4];
+    let bytes = &mut bytes[..width.bytes()];
+    for (offset, byte) in (0..).zip(bytes.iter_mut()) {
+        *byte = state.mem.get(addr.wrapping_add(offset));
+    }
+    state.endian.arrange(bytes);
+    width.extend(bytes)
+}
+
+// Stores the low bytes of `value`, reusing the buffers of `spare`.
+fn store(
+    state: &mut ArchState,
+    addr: Address,
+    value: Word,
+    width: Width,
+    spare: Option<MemoryWrite>,
+) -> MemoryWrite {
+    let mut write = spare.unwrap_or(MemoryWrite {
+        addr,
+        old: Vec::new(),
+        new: Vec::new(),
+    });
+    write.addr = addr;
+    write.new.clear();
+    write
+        .new
+        .extend_from_slice(&value.to_be_bytes()[4 - width.bytes()..]);
+    state.endian.arrange(&mut write.new);
+    write.old.clear();
+    write
+        .old
+        .extend((0..width.bytes() as Word).map(|offset| state.mem.get(addr.wrapping_add(offset))));
+    state.mem.write(addr, &write.new);
+    write
+}
+
+// The memory `instruction` would access against `regs`: its address,
+// length and whether it is read or written. The machine asks before every
+// instruction, so this matches on the opcode rather than building the
+// instruction's semantics, which agree with it.
+pub fn memory_access(
+    instruction: &Instruction,
+    regs: &Registers,
+) -> Option<(Address, usize, AccessKind)> {
+    let (width, kind) = match instruction.opcode {
+        Opcode::Lb => (Width::Byte, AccessKind::Read),
+        Opcode::Lh => (Width::Half, AccessKind::Read),
+        Opcode::Lw => (Width::Word, AccessKind::Read),
+        Opcode::Sb => (Width::Byte, AccessKind::Write),
+        Opcode::Sh => (Width::Half, AccessKind::Write),
+        Opcode::Sw => (Width::Word, AccessKind::Write),
+        _ => return None,
+    };
+    let addr = regs
+        .get(&instruction.rs1)
+        .wrapping_add(instruction.offset() as Word);
+    Some((addr, width.bytes(), kind))
+}
+
+impl Value {
+    pub fn eval(&self, pc: Address, regs: &Registers) -> Word {
+        match self {
+            Value::Imm(value) => *value,
+            Value::Reg(reg) => regs.get(reg),
+            Value::Pc => pc,
+            Value::Add(lhs, rhs) => lhs.eval(pc, regs).wrapping_add(rhs.eval(pc, regs)),
+            Value::Alu(op, lhs, rhs) => op.apply(lhs.eval(pc, regs), rhs.eval(pc, regs)),
+        }
+    }
+
+    fn add(lhs: Value, rhs: Value) -> Value {
+        Value::Add(Box::new(lhs), Box::new(rhs))
+    }
+}
+
+impl AluOp {
+    // Division never traps: dividing by zero gives all ones and leaves
+    // the dividend as the remainder, and the one quotient that overflows,
+    // i32::MIN / -1, wraps back to i32::MIN with a remainder of 0.
+    pub fn apply(self, lhs: Word, rhs: Word) -> Word {
+        let (signed_lhs, signed_rhs) = (lhs as i32, rhs as i32);
+        match self {
+            AluOp::Sub => lhs.wrapping_sub(rhs),
+            AluOp::Mul => lhs.wrapping_mul(rhs),
+            AluOp::Div if rhs == 0 => Word::MAX,
+            AluOp::Div => signed_lhs.wrapping_div(signed_rhs) as Word,
+            AluOp::Rem if rhs == 0 => lhs,
+            AluOp::Rem => signed_lhs.wrapping_rem(signed_rhs) as Word,
+            AluOp::And => lhs & rhs,
+            AluOp::Or => lhs | rhs,
+            AluOp::Xor => lhs ^ rhs,
+            AluOp::Sll => lhs << (rhs & 31),
+            AluOp::Srl => lhs >> (rhs & 31),
+            AluOp::Sra => (signed_lhs >> (rhs & 31)) as Word,
+            AluOp::Slt => Word::from(signed_lhs < signed_rhs),
+            AluOp::Sltu => Word::from(lhs < rhs),
+        }
+    }
+}
+
+impl Condition {
+    pub fn holds(self, lhs: Word, rhs: Word) -> bool {
+        match self {
+            Condition::Eq => lhs == rhs,
+            Condition::Ne => lhs != rhs,
+            Condition::Lt => (lhs as i32) < (rhs as i32),
+            Condition::Ge => (lhs as i32) >= (rhs as i32),
+        }
+    }
+}
+
+pub fn semantics(instruction: &Instruction) -> Vec<Update> {
+    let rs1 = Value::Reg(instruction.rs1);
+    let rs2 = Value::Reg(instruction.rs2);
+    let imm = Value::Imm(instruction.imm as Word);
+    let offset = || Value::Imm(instruction.offset() as Word);
+    // The second operand of an ALU operation is rs2 plus the immediate.
+    let operand = || Value::add(Value::Reg(instruction.rs2), offset());
+    let alu = |op| {
+        vec![Update::SetReg(
+            instruction.rd,
+            Value::Alu(
+                op,
+                Box::new(Value::Reg(instruction.rs1)),
+                Box::new(operand()),
+            ),
+        )]
+    };
+    // The jump is described first so that a link register that is also
+    // rs1 is read before it is overwritten.
+    let link = |target| {
+        vec![
+            Update::Jump(target),
+            Update::SetReg(instruction.rd, Value::add(Value::Pc, Value::Imm(4))),
+        ]
+    };
+    let branch = |condition| {
+        vec![Update::Branch {
+            condition,
+            lhs: Value::Reg(instruction.rs1),
+            rhs: Value::Reg(instruction.rs2),
+            target: Value::add(Value::Pc, offset()),
+        }]
+    };
+    let address = || Value::add(Value::Reg(instruction.rs1), offset());
+    let load = |width| {
+        vec![Update::Load {
+            rd: instruction.rd,
+            addr: address(),
+            width,
+        }]
+    };
+    let store = |width| {
+        vec![Update::Store {
+            addr: address(),
+            value: Value::Reg(instruction.rs2),
+            width,
+        }]
+    };
+    match instruction.opcode {
+        Opcode::LoadImmediate => vec![Update::SetReg(instruction.rd, imm)],
+        Opcode::Auipc => vec![Update::SetReg(
+            instruction.rd,
+            Value::add(Value::Pc, Value::Imm((instruction.offset() as Word) << 15)),
+        )],
+        Opcode::Lui => vec![Update::SetReg(
+            instruction.rd,
+            Value::Imm((instruction.offset() as Word) << 15),
+        )],
+        Opcode::Add => vec![Update::SetReg(instruction.rd, Value::add(rs1, operand()))],
+        Opcode::Sub => alu(AluOp::Sub),
+        Opcode::Mul => alu(AluOp::Mul),
+        Opcode::Div => alu(AluOp::Div),
+        Opcode::Rem => alu(AluOp::Rem),
+        Opcode::And => alu(AluOp::And),
+        Opcode::Or => alu(AluOp::Or),
+        Opcode::Xor => alu(AluOp::Xor),
+        Opcode::Sll => alu(AluOp::Sll),
+        Opcode::Srl => alu(AluOp::Srl),
+        Opcode::Sra => alu(AluOp::Sra),
+        Opcode::Slt => alu(AluOp::Slt),
+        Opcode::Sltu => alu(AluOp::Sltu),
+        Opcode::Jal => link(Value::add(Value::Pc, offset())),
+        Opcode::Jalr => link(Value::add(rs1, offset())),
+        Opcode::Beq => branch(Condition::Eq),
+        Opcode::Bne => branch(Condition::Ne),
+        Opcode::Blt => branch(Condition::Lt),
+        Opcode::Bge => branch(Condition::Ge),
+        Opcode::Lb => load(Width::Byte),
+        Opcode::Lh => load(Width::Half),
+        Opcode::Lw => load(Width::Word),
+        Opcode::Sb => store(Width::Byte),
+        Opcode::Sh => store(Width::Half),
+        Opcode::Sw => store(Width::Word),
+        Opcode::ECall => vec![Update::Syscall],
+        Opcode::EBreak => vec![Update::Breakpoint],
+        Opcode::Mret => vec![Update::InterruptReturn],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branches_are_described_relative_to_the_instruction() {
+        // bne a1, a2, -8
+        let instruction = Instruction::try_from(0xfff0_6410).unwrap();
+
+        let updates = semantics(&instruction);
+
+        assert_eq!(
+            updates,
+            vec![Update::Branch {
+                condition: Condition::Ne,
+                lhs: Value::Reg(RegisterID::A1),
+                rhs: Value::Reg(RegisterID::A2),
+                target: Value::add(Value::Pc, Value::Imm(-8i32 as Word)),
+            }]
+        );
+        let Update::Branch { target, .. } = &updates[0] else {
+            unreachable!();
+        };
+        assert_eq!(target.eval(0x20, &Registers::default()), 0x18);
+    }
+
+    #[test]
+    fn memory_accesses_agree_with_the_semantics() {
+        let regs = Registers::from([(RegisterID::A1, 0x1000)]);
+        for opcode in Opcode::ALL {
+            let instruction = Instruction {
+                opcode,
+                rd: RegisterID::A2,
+                rs1: RegisterID::A1,
+                rs2: RegisterID::A3,
+                imm: 0x7ffc,
+            };
+            let described = semantics(&instruction)
+                .into_iter()
+                .find_map(|update| match update {
+                    Update::Load { addr, width, .. } => {
+                        Some((addr.eval(0, &regs), width.bytes(), AccessKind::Read))
+                    }
+                    Update::Store { addr, width, .. } => {
+                        Some((addr.eval(0, &regs), width.bytes(), AccessKind::Write))
+                    }
+                    _ => None,
+                });
+            assert_eq!(memory_access(&instruction, &regs), described, "{opcode:?}");
+        }
+    }
+
+    // Runs `instruction` by interpreting its semantics, which is what step
+    // must agree with.
+    fn interpret(state: &mut ArchState, instruction: &Instruction) -> Effect {
+        let pc = state.pc;
+        state.pc = pc.wrapping_add(4);
+        let mut effect = Effect {
+            pc,
+            ..Default::default()
+        };
+        for update in semantics(instruction) {
+            match update {
+                Update::SetReg(RegisterID::X0, _) => {}
+                Update::SetReg(reg, value) => {
+                    let new = value.eval(pc, &state.regs);
+                    let old = state.regs.get(&reg);
+                    state.regs.set(reg, new);
+                    effect.regs.push(RegisterWrite { reg, old, new });
+                }
+                Update::Branch {
+                    condition,
+                    lhs,
+                    rhs,
+                    target,
+                } => {
+                    if condition.holds(lhs.eval(pc, &state.regs), rhs.eval(pc, &state.regs)) {
+                        state.pc = target.eval(pc, &state.regs);
+                    }
+                }
+                Update::Jump(target) => state.pc = target.eval(pc, &state.regs),
+                Update::Load { rd, addr, width } => {
+                    let addr = addr.eval(pc, &state.regs);
+                    let mut bytes = state.mem.read(addr, width.bytes());
+                    state.endian.arrange(&mut bytes);
+                    let new = width.extend(&bytes);
+                    if rd != RegisterID::X0 {
+                        let old = state.regs.get(&rd);
+                        state.regs.set(rd, new);
+                        effect.regs.push(RegisterWrite { reg: rd, old, new });
+                    }
+                }
+                Update::Store { addr, value, width } => {
+                    let addr = addr.eval(pc, &state.regs);
+                    let value = value.eval(pc, &state.regs).to_be_bytes();
+                    let mut new = value[4 - width.bytes()..].to_vec();
+                    state.endian.arrange(&mut new);
+                    let old = state.mem.read(addr, new.len());
+                    state.mem.write(addr, &new);
+                    effect.mem.push(MemoryWrite { addr, old, new });
+                }
+                Update::Syscall => effect.control = Control::Syscall,
+                Update::Breakpoint => effect.control = Control::Breakpoint,
+                Update::InterruptReturn => effect.control = Control::InterruptReturn,
+            }
+        }
+        effect.next_pc = state.pc;
+        effect
+    }
+
+    #[test]
+    fn step_agrees_with_the_semantics() {
+        let mut effect = Effect::default();
+        for opcode in Opcode::ALL {
+            for (rd, rs2, imm, endian) in [
+                (RegisterID::A2, RegisterID::A3, 0x3ffc, Endian::Big),
+                (RegisterID::A1, RegisterID::A1, 0x7ff8, Endian::Little),
+                (RegisterID::X0, RegisterID::A2, 6, Endian::Big),
+                (RegisterID::A3, RegisterID::A2, 0x7fff, Endian::Little),
+            ] {
+                let instruction = Instruction {
+                    opcode,
+                    rd,
+                    rs1: RegisterID::A1,
+                    rs2,
+                    imm,
+                };
+                let state = ArchState {
+                    pc: 0x40,
+                    regs: Registers::from([
+                        (RegisterID::A1, 0x1001),
+                        (RegisterID::A2, 0x8000_0003),
+                        (RegisterID::A3, 0x1234_5678),
+                    ]),
+                    mem: Memory::from([(0x1000, 0x81), (0x1001, 0x82), (0x1002, 0x83)]),
+                    endian,
+                };
+                let (mut want, mut got) = (state.clone(), state);
+                let expected = interpret(&mut want, &instruction);
+                step_into(&mut got, &instruction, &mut effect);
+
+                assert_eq!((got, &effect), (want, &expected), "{instruction:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn jalr_reads_its_base_before_linking() {
+        let mut state = ArchState {
+            pc: 0x10,
+            regs: Registers::from([(RegisterID::RA, 0x40)]),
+            ..Default::default()
+        };
+        // jalr ra, ra, 4
+        let jalr = Instruction::try_from(0x0008_1dcd).unwrap();
+
+        let effect = step(&mut state, &jalr);
+
+        assert_eq!(effect.next_pc, 0x44);
+        assert_eq!(state.regs.get(&RegisterID::RA), 0x14);
+    }
+
+    #[test]
+    fn step_executes_against_a_bare_state() {
+        let mut state = ArchState {
+            pc: 0x10,
+            regs: Registers::from([(RegisterID::A1, 3), (RegisterID::A2, 4)]),
+            ..Default::default()
+        };
+        // add a0, a1, a2
+        let add = Instruction::try_from(0b0110_0100_0010_0010).unwrap();
+        // bne a1, a2, -8
+        let bne = Instruction::try_from(0xfff0_6410).unwrap();
+        let ecall = Instruction::try_from(0b1_0111).unwrap();
+
+        assert_eq!(
+            step(&mut state, &add).regs,
+            vec![RegisterWrite {
+                reg: RegisterID::A0,
+                old: 0,
+                new: 7,
+            }]
+        );
+        assert_eq!(state.pc, 0x14);
+        assert_eq!(step(&mut state, &bne).next_pc, 0x0c);
+        assert_eq!(state.pc, 0x0c);
+        assert_eq!(step(&mut state, &ecall).control, Control::Syscall);
+        assert_eq!(state.pc, 0x10);
+    }
+
+    #[test]
+    fn loads_and_stores_are_big_endian_and_loads_sign_extend() {
+        let mut state = ArchState {
+            pc: 0x10,
+            regs: Registers::from([(RegisterID::A1, 0x104), (RegisterID::A2, 0x1234_8680)]),
+            ..Default::default()
+        };
+        let instruction = |opcode, rd, rs2, offset: i32| Instruction {
+            opcode,
+            rd,
+            rs1: RegisterID::A1,
+            rs2,
+            imm: (offset & 0x7fff) as u16,
+        };
+
+        // sw a2, -4(a1)
+        let effect = step(
+            &mut state,
+            &instruction(Opcode::Sw, RegisterID::X0, RegisterID::A2, -4),
+        );
+        assert_eq!(
+            effect.mem,
+            vec![MemoryWrite {
+                addr: 0x100,
+                old: vec![0; 4],
+                new: vec![0x12, 0x34, 0x86, 0x80],
+            }]
+        );
+        // sb a2, 0(a1)
+        step(
+            &mut state,
+            &instruction(Opcode::Sb, RegisterID::X0, RegisterID::A2, 0),
+        );
+        assert_eq!(state.mem.read(0x100, 5), [0x12, 0x34, 0x86, 0x80, 0x80]);
+
+        let cases = [
+            (Opcode::Lw, -4, 0x1234_8680),
+            (Opcode::Lh, -2, 0xffff_8680),
+            (Opcode::Lh, -4, 0x1234),
+            (Opcode::Lb, -1, 0xffff_ff80),
+            (Opcode::Lb, -3, 0x34),
+        ];
+        for (opcode, offset, want) in cases {
+            step(
+                &mut state,
+                &instruction(opcode, RegisterID::A0, RegisterID::X0, offset),
+            );
+            assert_eq!(state.regs.get(&RegisterID::A0), want, "{opcode:?} {offset}");
+        }
+    }
+
+    #[test]
+    fn alu_operations_wrap_and_never_trap() {
+        let min = i32::MIN as Word;
+        let cases = [
+            (AluOp::Sub, 0, 1, Word::MAX),
+            (AluOp::Mul, 0x1_0001, 0x1_0001, 0x2_0001),
+            (AluOp::Mul, min, -1i32 as Word, min),
+            (AluOp::Div, -7i32 as Word, 2, -3i32 as Word),
+            (AluOp::Rem, -7i32 as Word, 2, -1i32 as Word),
+            (AluOp::Div, 7, 0, Word::MAX),
+            (AluOp::Rem, 7, 0, 7),
+            (AluOp::Div, min, -1i32 as Word, min),
+            (AluOp::Rem, min, -1i32 as Word, 0),
+            (AluOp::And, 0b1100, 0b1010, 0b1000),
+            (AluOp::Or, 0b1100, 0b1010, 0b1110),
+            (AluOp::Xor, 0b1100, 0b1010, 0b0110),
+            (AluOp::Sll, 1, 33, 2),
+            (AluOp::Srl, min, 31, 1),
+            (AluOp::Sra, min, 31, Word::MAX),
+            (AluOp::Slt, -1i32 as Word, 0, 1),
+            (AluOp::Sltu, -1i32 as Word, 0, 0),
+        ];
+        for (op, lhs, rhs, want) in cases {
+            assert_eq!(op.apply(lhs, rhs), want, "{op:?} {lhs:#x} {rhs:#x}");
+        }
+    }
+
+    #[test]
+    fn alu_immediates_are_sign_extended_onto_rs2() {
+        let mut state = ArchState {
+            regs: Registers::from([(RegisterID::A1, 10), (RegisterID::A2, 3)]),
+            ..Default::default()
+        };
+        let instruction = |opcode, imm: i32| Instruction {
+            opcode,
+            rd: RegisterID::A0,
+            rs1: RegisterID::A1,
+            rs2: RegisterID::A2,
+            imm: (imm & 0x7fff) as u16,
+        };
+
+        // a0 = 10 - (3 + -4)
+        step(&mut state, &instruction(Opcode::Sub, -4));
+        assert_eq!(state.regs.get(&RegisterID::A0), 11);
+        // a0 = 10 + 3 + -20
+        step(&mut state, &instruction(Opcode::Add, -20));
+        assert_eq!(state.regs.get(&RegisterID::A0), -7i32 as Word);
+    }
+
+    #[test]
+    fn conditions_compare_signed_values() {
+        assert!(Condition::Lt.holds(-1i32 as Word, 1));
+        assert!(!Condition::Ge.holds(-1i32 as Word, 1));
+        assert!(Condition::Ge.holds(1, 1));
+        assert!(Condition::Ne.holds(1, 2));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::Address;
+use std::collections::HashMap;
+
+const PAGE_SIZE: usize = 4096;
+
+// A value for every byte of guest memory, kept alongside it by the analyses
+// that follow something about each byte: taint tracking, the check for
+// uninitialized reads, and any sanitizer to come. Bytes never set hold the
+// default, and pages of shadow are only allocated once something other
+// than the default is stored in them, so memory an analysis never touches
+// costs nothing. Addresses wrap around the top of memory, as the guest's do.
+#[derive(Debug, Clone, Default)]
+pub struct ShadowMemory<T> {
+    pages: HashMap<Address, Box<[T; PAGE_SIZE]>>,
+}
+
+impl<T: Copy + Default + PartialEq> ShadowMemory<T> {
+    pub fn new() -> Self {
+        Self {
+            pages: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, addr: Address) -> T {
+        self.pages
+            .get(&page_of(addr))
+            .map_or_else(T::default, |page| page[offset_in_page(addr)])
+    }
+
+    pub fn set(&mut self, addr: Address, value: T) {
+        let page = match self.pages.get_mut(&page_of(addr)) {
+            Some(page) => page,
+            None if value == T::default() => return,
+            None => self
+                .pages
+                .entry(page_of(addr))
+                .or_insert_with(|| Box::new([T::default(); PAGE_SIZE])),
+        };
+        page[offset_in_page(addr)] = value;
+    }
+
+    // The values of the `len` bytes from `addr`.
+    pub fn range(&self, addr: Address, len: usize) -> impl Iterator<Item = T> + '_ {
+        bytes(addr, len).map(|addr| self.get(addr))
+    }
+
+    pub fn fill(&mut self, addr: Address, len: usize, value: T) {
+        for addr in bytes(addr, len) {
+            self.set(addr, value);
+        }
+    }
+
+    // Replaces the value of each of the `len` bytes from `addr` with what
+    // `f` makes of it.
+    pub fn update(&mut self, addr: Address, len: usize, mut f: impl FnMut(T) -> T) {
+        for addr in bytes(addr, len) {
+            self.set(addr, f(self.get(addr)));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+
+    // How many pages of shadow are allocated.
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+fn page_of(addr: Address) -> Address {
+    addr & !(PAGE_SIZE as Address - 1)
+}
+
+fn offset_in_page(addr: Address) -> usize {
+    addr as usize & (PAGE_SIZE - 1)
+}
+
+fn bytes(addr: Address, len: usize) -> impl Iterator<Item = Address> {
+    (0..len).map(move |offset| addr.wrapping_add(offset as Address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_are_only_allocated_for_values_other_than_the_default() {
+        let mut shadow = ShadowMemory::new();
+        shadow.fill(0x1000, 0x2000, false);
+        assert_eq!(shadow.pages(), 0);
+
+        shadow.fill(0x1ffe, 4, true);
+        assert_eq!(shadow.pages(), 2);
+        assert_eq!(
+            shadow.range(0x1ffd, 6).collect::<Vec<_>>(),
+            [false, true, true, true, true, false]
+        );
+        assert!(!shadow.get(0x5000));
+
+        shadow.clear();
+        assert!(!shadow.get(0x1fff));
+    }
+
+    #[test]
+    fn ranges_wrap_around_the_top_of_memory() {
+        let mut shadow = ShadowMemory::new();
+        shadow.fill(0xffff_fffe, 4, 1u8);
+        shadow.update(0xffff_ffff, 2, |value| value + 1);
+
+        assert_eq!(
+            shadow.range(0xffff_fffe, 4).collect::<Vec<_>>(),
+            [1, 2, 2, 1]
+        );
+    }
+}
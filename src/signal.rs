@@ -0,0 +1,286 @@
+use crate::{
+    output::{errno, EINVAL},
+    state, Address, HaltReason, Machine, RegisterID, StepOutcome, Word,
+};
+use std::{collections::BTreeMap, io::Write};
+
+// Signals are numbered from 1 to MAX_SIGNAL.
+pub const MAX_SIGNAL: Word = 31;
+
+// The signal `rmachine run` posts when the user presses Ctrl-C.
+pub const SIGINT: Word = 2;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Frame {
+    // Where the interrupted code carries on once the handler returns.
+    pub(crate) epc: Address,
+    // The registers as the interrupted code had them.
+    pub(crate) regs: [Word; 16],
+}
+
+// Posts a signal every `period` cycles.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Alarm {
+    pub(crate) period: u64,
+    pub(crate) next: u64,
+}
+
+// Signals posted to the guest by the host or by an alarm, for teaching
+// asynchronous control flow with less machinery than interrupts. A signal
+// the guest has a handler for stays pending until it is delivered between
+// instructions: the machine saves the pc and every register, enters the
+// handler with the signal in a0, and sigreturn puts them back, so the
+// handler may use any register. Signals are not delivered while a handler
+// runs, and the lowest numbered pending signal goes first. A signal posted
+// with no handler installed is discarded.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Signals {
+    pub(crate) handlers: BTreeMap<Word, Address>,
+    // One bit per signal.
+    pub(crate) pending: u32,
+    pub(crate) alarms: BTreeMap<Word, Alarm>,
+    pub(crate) frame: Option<Frame>,
+}
+
+impl Signals {
+    fn post(&mut self, signal: Word) {
+        if self.handlers.contains_key(&signal) {
+            self.pending |= 1 << signal;
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+fn valid(signal: Word) -> bool {
+    (1..=MAX_SIGNAL).contains(&signal)
+}
+
+impl<W: Write> Machine<W> {
+    // Posts `signal` to the guest, returning whether it has a handler for
+    // it; signals out of range are never handled.
+    pub fn post_signal(&mut self, signal: Word) -> bool {
+        if !valid(signal) {
+            return false;
+        }
+        self.signals.post(signal);
+        self.signal_pending(signal)
+    }
+
+    pub fn signal_pending(&self, signal: Word) -> bool {
+        valid(signal) && self.signals.pending & 1 << signal != 0
+    }
+
+    // Posts `signal` every `period` cycles from now, or with a period of 0
+    // stops doing so.
+    pub fn set_alarm(&mut self, signal: Word, period: u64) {
+        if period == 0 {
+            self.signals.alarms.remove(&signal);
+        } else {
+            let next = self.cycles + period;
+            self.signals.alarms.insert(signal, Alarm { period, next });
+        }
+    }
+
+    pub(crate) fn any_signal_pending(&self) -> bool {
+        self.signals.pending != 0
+    }
+
+    // The cycle the soonest alarm for a signal with a handler goes off at.
+    pub(crate) fn next_alarm(&self) -> Option<u64> {
+        self.signals
+            .alarms
+            .iter()
+            .filter(|(signal, _)| self.signals.handlers.contains_key(signal))
+            .map(|(_, alarm)| alarm.next)
+            .min()
+    }
+
+    // Whether a signal handler is running.
+    pub fn in_signal_handler(&self) -> bool {
+        self.signals.frame.is_some()
+    }
+
+    // Posts the signals alarms are due to, then enters the handler for the
+    // lowest pending signal if no handler is running.
+    pub(crate) fn deliver_signal(&mut self) {
+        let now = self.cycles;
+        for (&signal, alarm) in &mut self.signals.alarms {
+            while alarm.next <= now {
+                if self.signals.handlers.contains_key(&signal) {
+                    self.signals.pending |= 1 << signal;
+                }
+                alarm.next += alarm.period;
+            }
+        }
+        if self.signals.frame.is_some() || self.signals.pending == 0 {
+            return;
+        }
+        let signal = self.signals.pending.trailing_zeros();
+        self.signals.pending &= !(1 << signal);
+        let Some(&handler) = self.signals.handlers.get(&signal) else {
+            return;
+        };
+        self.signals.frame = Some(Frame {
+            epc: self.pc,
+            regs: std::array::from_fn(|id| self.regs.get(&state::register(id))),
+        });
+        self.set_register(RegisterID::A0, signal);
+        self.set_pc(handler);
+    }
+
+    // sigaction(signal, handler) installs the handler for `signal`, or with
+    // 0 removes it and discards the signal if it is pending, returning the
+    // previous handler, or -EINVAL for a signal out of range.
+    pub(crate) fn sigaction_syscall(&mut self) {
+        let signal = self.regs.get(&RegisterID::A0);
+        let handler = self.regs.get(&RegisterID::A1);
+        let result = if !valid(signal) {
+            errno(EINVAL)
+        } else if handler == 0 {
+            self.signals.pending &= !(1 << signal);
+            self.signals.handlers.remove(&signal).unwrap_or_default()
+        } else {
+            self.signals
+                .handlers
+                .insert(signal, handler)
+                .unwrap_or_default()
+        };
+        self.set_register(RegisterID::A0, result);
+    }
+
+    // sigreturn() returns from the signal handler, restoring the pc and
+    // registers of the code it interrupted.
+    pub(crate) fn sigreturn_syscall(&mut self) -> StepOutcome {
+        let Some(frame) = self.signals.frame.take() else {
+            return StepOutcome::Halted(HaltReason::StraySignalReturn {
+                pc: self.pc.wrapping_sub(4),
+            });
+        };
+        for (id, value) in frame.regs.into_iter().enumerate().skip(1) {
+            self.set_register(state::register(id), value);
+        }
+        self.set_pc(frame.epc);
+        StepOutcome::Continue
+    }
+
+    // alarm(signal, period) posts `signal` every `period` cycles from now,
+    // or with a period of 0 stops, returning 0, or -EINVAL for a signal out
+    // of range.
+    pub(crate) fn alarm_syscall(&mut self) {
+        let signal = self.regs.get(&RegisterID::A0);
+        let period = self.regs.get(&RegisterID::A1);
+        let result = if valid(signal) {
+            self.set_alarm(signal, period.into());
+            0
+        } else {
+            errno(EINVAL)
+        };
+        self.set_register(RegisterID::A0, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use claims::assert_ok_eq;
+
+    // Installs the handler at 56 for signal 10, sets an alarm for it every
+    // a5 cycles unless a5 is 0, and spins until the handler has counted a2
+    // signals at 0x2000. The handler clobbers a2 and a3, which sigreturn
+    // restores, and keeps the signal it served at 0x2004.
+    const SPIN: &str = "
+                li a0, 10
+                li a1, 56
+                li a7, 275
+                ecall
+                mv a1, x0
+                li a3, 5
+                beq a5, x0, spin
+                li a0, 10
+                mv a1, a5
+                li a7, 277
+                ecall
+        spin:   lw a1, 0x2000
+                blt a1, a2, spin
+                ebreak
+        handler:
+                lw a3, 0x2000
+                add a3, a3, x0, 1
+                sw a3, 0x2000
+                sw a0, 0x2004
+                li a2, 99
+                li a7, 276
+                ecall
+    ";
+
+    fn machine(count: Word, period: Word) -> Machine<Vec<u8>> {
+        Machine::builder()
+            .program(&assemble(SPIN).unwrap())
+            .register(RegisterID::A2, count)
+            .register(RegisterID::A5, period)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn host_signals_run_the_handler_and_resume() {
+        let mut machine = machine(1, 0);
+        while machine.pc != 44 {
+            machine.step().unwrap();
+        }
+        assert!(machine.post_signal(10));
+        assert!(!machine.post_signal(11));
+        assert!(machine.signal_pending(10));
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert!(!machine.in_signal_handler());
+        assert_eq!(machine.mem.read(0x2000, 8), [0, 0, 0, 1, 0, 0, 0, 10]);
+        assert_eq!(machine.register(RegisterID::A2), 1);
+        assert_eq!(machine.register(RegisterID::A3), 5);
+    }
+
+    #[test]
+    fn the_instruction_a_signal_follows_records_entering_the_handler() {
+        let mut machine = machine(1, 0);
+        while machine.pc != 44 {
+            machine.step().unwrap();
+        }
+        machine.record_effects(true);
+        machine.post_signal(10);
+
+        machine.step().unwrap();
+
+        let effect = machine.last_effect().unwrap();
+        assert_eq!((effect.pc, effect.next_pc), (44, 56));
+        assert_eq!(
+            effect.regs.last().map(|write| (write.reg, write.new)),
+            Some((RegisterID::A0, 10))
+        );
+    }
+
+    #[test]
+    fn alarms_post_signals_periodically() {
+        let mut machine = machine(3, 50);
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.register(RegisterID::A1), 3);
+        assert_eq!(machine.register(RegisterID::A2), 3);
+        assert!(machine.cycles >= 150);
+    }
+
+    #[test]
+    fn sigreturn_outside_a_handler_halts() {
+        let mut machine = Machine::<Vec<u8>>::builder()
+            .program(&assemble("li a7, 276\necall").unwrap())
+            .build()
+            .unwrap();
+
+        assert_ok_eq!(machine.run(), HaltReason::StraySignalReturn { pc: 4 });
+    }
+}
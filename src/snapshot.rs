@@ -0,0 +1,539 @@
+use crate::{
+    endian::Endian,
+    fd::Description,
+    limits::LimitUsage,
+    loader::Reader,
+    manifest::Digest,
+    regbank::RegisterBanks,
+    signal::{Alarm, Frame, Signals},
+    state::register,
+    Address, Error, HaltReason, Machine, Result, StepOutcome, Word,
+};
+use std::{collections::BTreeMap, io::Write, time::Duration};
+
+const MAGIC: &[u8; 4] = b"RSN3";
+// Snapshots that end after the pages.
+const MAGIC_PAGES_LAST: &[u8; 4] = b"RSN2";
+// Snapshots from before the byte order was saved, all of big-endian
+// machines.
+const MAGIC_BIG_ENDIAN: &[u8; 4] = b"RSN1";
+
+// The state of a run, saved so that it can be resumed in a new process
+// after the host goes down. The format, big-endian throughout:
+//
+//   magic          "RSN3"
+//   endian         u8, 0 for big-endian or 1 for little-endian
+//   program hash   u64 digest of the program image
+//   retired        u64
+//   cycles         u64
+//   pc             u32
+//   registers      16 u32 values, x0 to sp
+//   break          u32 program break
+//   exit handlers  u32 count, each: u32 address
+//   input          u64 bytes read from stdin, u64 digest of them
+//   pages          u32 count, each: u32 address, then a page of bytes
+//   register banks u8 count, 0 if the register file is not banked, then
+//                  u32 select address, u16 banked registers one bit each,
+//                  u8 selected bank, and per bank 16 u32 values
+//   fuel           u8 1 then u64 fuel left, or u8 0 for unlimited fuel
+//   limit usage    u64 steps, u64 bytes of output, u64 nanoseconds
+//   fds            u32 count, each: u32 fd, u8 0 for stdin, 1 for stdout,
+//                  2 for a file the host attached or 3 for a file or pipe
+//                  the guest opened
+//   signals        u32 pending, one bit each; u32 handler count, each:
+//                  u32 signal, u32 address; u32 alarm count, each: u32
+//                  signal, u64 period, u64 next cycle; then u8 1, u32 pc
+//                  and 16 u32 registers of the code a running handler
+//                  interrupted, or u8 0
+//
+// Snapshots with the magic "RSN2" end after the pages, with no register
+// banks, no fuel, nothing used of the limits, only stdin and stdout open
+// and no signal handlers, and those with "RSN1" also have no endian byte
+// and are big-endian. The files the guest opens are host handles a new
+// process cannot get back, so a snapshot taken with any open can be parsed
+// but not resumed.
+// Memory is saved as the guest sees it, so bank-switched windows hold only
+// the selected bank and mapped files are saved as copies.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Snapshot {
+    pub endian: Endian,
+    pub program_hash: u64,
+    pub retired: u64,
+    pub cycles: u64,
+    pub pc: Word,
+    pub regs: [Word; 16],
+    pub brk: Address,
+    pub exit_handlers: Vec<Address>,
+    pub input_read: u64,
+    pub input_hash: u64,
+    pub pages: BTreeMap<Address, Vec<u8>>,
+    pub register_banks: Option<RegisterBanks>,
+    pub fuel: Option<u64>,
+    pub limit_usage: LimitUsage,
+    pub fds: BTreeMap<Word, SavedFd>,
+    pub signals: Signals,
+}
+
+// What an fd referred to when the snapshot was taken.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SavedFd {
+    Stdin,
+    Stdout,
+    // A reader or writer the embedder attached, which it attaches again
+    // before resuming.
+    Attached,
+    // A file or pipe the guest opened.
+    Opened,
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::SnapshotInvalid(reason.to_string())
+}
+
+impl Snapshot {
+    pub fn take<W: Write>(machine: &Machine<W>, image: &[u8]) -> Self {
+        Self {
+            endian: machine.endian,
+            program_hash: Digest::of(image),
+            retired: machine.retired,
+            cycles: machine.cycles,
+            pc: machine.pc,
+            regs: std::array::from_fn(|id| machine.regs.get(&register(id))),
+            brk: machine.brk(),
+            exit_handlers: machine.exit_handlers.clone(),
+            input_read: machine.input_read,
+            input_hash: machine.input_hash(),
+            pages: machine.saved_pages(),
+            register_banks: machine.saved_register_banks(),
+            fuel: machine.fuel(),
+            limit_usage: machine.limit_usage(),
+            fds: machine.files.saved_fds(),
+            signals: machine.signals.clone(),
+        }
+    }
+
+    pub fn parse(bytes: &[u8], page_size: u32) -> Result<Self> {
+        let mut reader = Reader { bytes, invalid };
+        let magic = reader.take(4)?;
+        let endian = match magic {
+            magic if magic == MAGIC || magic == MAGIC_PAGES_LAST => match reader.take(1)? {
+                [0] => Endian::Big,
+                [1] => Endian::Little,
+                _ => return Err(invalid("bad byte order")),
+            },
+            magic if magic == MAGIC_BIG_ENDIAN => Endian::Big,
+            _ => return Err(invalid("bad magic")),
+        };
+        let program_hash = reader.u64()?;
+        let retired = reader.u64()?;
+        let cycles = reader.u64()?;
+        let pc = reader.u32()?;
+        let mut regs = [0; 16];
+        for reg in &mut regs {
+            *reg = reader.u32()?;
+        }
+        let brk = reader.u32()?;
+        let mut exit_handlers = Vec::new();
+        for _ in 0..reader.u32()? {
+            exit_handlers.push(reader.u32()?);
+        }
+        let input_read = reader.u64()?;
+        let input_hash = reader.u64()?;
+        let mut pages = BTreeMap::new();
+        for _ in 0..reader.u32()? {
+            let page = reader.u32()?;
+            pages.insert(page, reader.take(page_size as usize)?.to_vec());
+        }
+        let mut snapshot = Self {
+            endian,
+            program_hash,
+            retired,
+            cycles,
+            pc,
+            regs,
+            brk,
+            exit_handlers,
+            input_read,
+            input_hash,
+            pages,
+            register_banks: None,
+            fuel: None,
+            limit_usage: LimitUsage::default(),
+            fds: BTreeMap::from([(0, SavedFd::Stdin), (1, SavedFd::Stdout)]),
+            signals: Signals::default(),
+        };
+        if magic == MAGIC {
+            snapshot.parse_runtime(&mut reader)?;
+        }
+        if !reader.bytes.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(snapshot)
+    }
+
+    // The sections after the pages.
+    fn parse_runtime(&mut self, reader: &mut Reader) -> Result<()> {
+        self.register_banks = parse_register_banks(reader)?;
+        self.fuel = match reader.u8()? {
+            0 => None,
+            1 => Some(reader.u64()?),
+            _ => return Err(invalid("bad fuel")),
+        };
+        self.limit_usage = LimitUsage {
+            steps: reader.u64()?,
+            output_bytes: reader.u64()?,
+            elapsed: Duration::from_nanos(reader.u64()?),
+        };
+        self.fds.clear();
+        for _ in 0..reader.u32()? {
+            let fd = reader.u32()?;
+            let saved = match reader.u8()? {
+                0 => SavedFd::Stdin,
+                1 => SavedFd::Stdout,
+                2 => SavedFd::Attached,
+                3 => SavedFd::Opened,
+                _ => return Err(invalid("bad fd")),
+            };
+            self.fds.insert(fd, saved);
+        }
+        self.signals = parse_signals(reader)?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(u8::from(self.endian == Endian::Little));
+        out.extend(self.program_hash.to_be_bytes());
+        out.extend(self.retired.to_be_bytes());
+        out.extend(self.cycles.to_be_bytes());
+        out.extend(self.pc.to_be_bytes());
+        for reg in self.regs {
+            out.extend(reg.to_be_bytes());
+        }
+        out.extend(self.brk.to_be_bytes());
+        out.extend((self.exit_handlers.len() as u32).to_be_bytes());
+        for handler in &self.exit_handlers {
+            out.extend(handler.to_be_bytes());
+        }
+        out.extend(self.input_read.to_be_bytes());
+        out.extend(self.input_hash.to_be_bytes());
+        out.extend((self.pages.len() as u32).to_be_bytes());
+        for (page, data) in &self.pages {
+            out.extend(page.to_be_bytes());
+            out.extend(data);
+        }
+        self.write_runtime(&mut out);
+        out
+    }
+
+    // The sections after the pages.
+    fn write_runtime(&self, out: &mut Vec<u8>) {
+        match &self.register_banks {
+            Some(banks) => {
+                out.push(banks.contents.len() as u8);
+                out.extend(banks.select.to_be_bytes());
+                out.extend(banks.banked.to_be_bytes());
+                out.push(banks.selected);
+                for bank in &banks.contents {
+                    for value in bank {
+                        out.extend(value.to_be_bytes());
+                    }
+                }
+            }
+            None => out.push(0),
+        }
+        match self.fuel {
+            Some(fuel) => {
+                out.push(1);
+                out.extend(fuel.to_be_bytes());
+            }
+            None => out.push(0),
+        }
+        out.extend(self.limit_usage.steps.to_be_bytes());
+        out.extend(self.limit_usage.output_bytes.to_be_bytes());
+        let elapsed = u64::try_from(self.limit_usage.elapsed.as_nanos()).unwrap_or(u64::MAX);
+        out.extend(elapsed.to_be_bytes());
+        out.extend((self.fds.len() as u32).to_be_bytes());
+        for (fd, saved) in &self.fds {
+            out.extend(fd.to_be_bytes());
+            out.push(*saved as u8);
+        }
+        let signals = &self.signals;
+        out.extend(signals.pending.to_be_bytes());
+        out.extend((signals.handlers.len() as u32).to_be_bytes());
+        for (signal, handler) in &signals.handlers {
+            out.extend(signal.to_be_bytes());
+            out.extend(handler.to_be_bytes());
+        }
+        out.extend((signals.alarms.len() as u32).to_be_bytes());
+        for (signal, alarm) in &signals.alarms {
+            out.extend(signal.to_be_bytes());
+            out.extend(alarm.period.to_be_bytes());
+            out.extend(alarm.next.to_be_bytes());
+        }
+        match &signals.frame {
+            Some(frame) => {
+                out.push(1);
+                out.extend(frame.epc.to_be_bytes());
+                for reg in frame.regs {
+                    out.extend(reg.to_be_bytes());
+                }
+            }
+            None => out.push(0),
+        }
+    }
+}
+
+fn parse_register_banks(reader: &mut Reader) -> Result<Option<RegisterBanks>> {
+    let count = reader.u8()?;
+    if count == 0 {
+        return Ok(None);
+    }
+    let select = reader.u32()?;
+    let banked = reader.u16()?;
+    let selected = reader.u8()?;
+    let mut contents = vec![[0; 16]; count.into()];
+    for value in contents.iter_mut().flatten() {
+        *value = reader.u32()?;
+    }
+    let banks = RegisterBanks {
+        select,
+        banked,
+        selected,
+        contents,
+    };
+    if !banks.is_valid() {
+        return Err(invalid("bad register banks"));
+    }
+    Ok(Some(banks))
+}
+
+fn parse_signals(reader: &mut Reader) -> Result<Signals> {
+    let mut signals = Signals {
+        pending: reader.u32()?,
+        ..Signals::default()
+    };
+    for _ in 0..reader.u32()? {
+        signals.handlers.insert(reader.u32()?, reader.u32()?);
+    }
+    for _ in 0..reader.u32()? {
+        let signal = reader.u32()?;
+        let alarm = Alarm {
+            period: reader.u64()?,
+            next: reader.u64()?,
+        };
+        signals.alarms.insert(signal, alarm);
+    }
+    signals.frame = match reader.u8()? {
+        0 => None,
+        1 => {
+            let epc = reader.u32()?;
+            let mut regs = [0; 16];
+            for reg in &mut regs {
+                *reg = reader.u32()?;
+            }
+            Some(Frame { epc, regs })
+        }
+        _ => return Err(invalid("bad signal frame")),
+    };
+    Ok(signals)
+}
+
+impl<W: Write> Machine<W> {
+    // Puts a machine built for the same program back into the state the
+    // snapshot was taken in. Its stdin should already have skipped the
+    // `input_read` bytes the guest had consumed, and the limits to go on
+    // enforcing and the readers and writers to attach should already be
+    // set. Fails, leaving the machine as it was, if the guest had opened a
+    // file or pipe.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.files.restore_saved_fds(&snapshot.fds)?;
+        self.signals.clone_from(&snapshot.signals);
+        if snapshot.fuel.is_some() {
+            self.set_fuel(snapshot.fuel);
+        }
+        self.resume_limit_usage(snapshot.limit_usage);
+        self.restore_pages(&snapshot.pages);
+        self.pc = snapshot.pc;
+        self.endian = snapshot.endian;
+        self.restore_register_banks(snapshot.register_banks.as_ref());
+        for (id, value) in snapshot.regs.iter().enumerate() {
+            self.regs.set(register(id), *value);
+        }
+        self.set_brk(snapshot.brk);
+        self.exit_handlers.clone_from(&snapshot.exit_handlers);
+        self.retired = snapshot.retired;
+        self.cycles = snapshot.cycles;
+        self.input_read = snapshot.input_read;
+        self.input_digest = Digest::resume(snapshot.input_hash);
+        Ok(())
+    }
+
+    // Runs to completion like run, calling `save` every `every` retired
+    // instructions so the run can be resumed from the latest save.
+    pub fn run_with_snapshots(
+        &mut self,
+        every: u64,
+        mut save: impl FnMut(&Self) -> std::io::Result<()>,
+    ) -> Result<HaltReason> {
+        let every = every.max(1);
+        loop {
+            if let (_, StepOutcome::Halted(reason)) = self.step()? {
+                return self.finalize(reason);
+            }
+            if self.retired.is_multiple_of(every) {
+                save(self).map_err(|err| Error::SnapshotFailed(err.to_string()))?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limits;
+    use claims::{assert_err_eq, assert_ok, assert_ok_eq};
+    use std::{cell::RefCell, io::Cursor, rc::Rc};
+
+    // Reads a byte to 0x100, counts a3 up to 6 and stops.
+    fn machine() -> Machine<Vec<u8>> {
+        let mut program = vec![
+            // li a7, 63; li a1, 0x100; li a2, 1; ecall
+            [0b0000_0000, 0b0111_1110, 0b0000_0001, 0b0000_0001],
+            [0b0000_0010, 0b0000_0000, 0b0000_0000, 0b0100_0001],
+            [0b0000_0000, 0b0000_0010, 0b0000_0000, 0b0110_0001],
+            [0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111],
+        ];
+        // add a3, a3, x0, 1
+        program.extend([[0b0000_0000, 0b0000_0010, 0b0000_1000, 0b1000_0010]; 6]);
+        // ebreak
+        program.push([0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        Machine::builder()
+            .stdout(Vec::new())
+            .stdin(Cursor::new(b"xy".to_vec()))
+            .program(&program.concat())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn snapshots_round_trip_through_bytes() {
+        let mut machine = machine();
+        for _ in 0..5 {
+            assert_ok!(machine.step());
+        }
+        let mut snapshot = Snapshot::take(&machine, b"image");
+
+        assert_eq!(snapshot.input_read, 1);
+        assert_ok_eq!(
+            Snapshot::parse(&snapshot.to_bytes(), 4096),
+            snapshot.clone()
+        );
+        snapshot.endian = Endian::Little;
+        assert_ok_eq!(
+            Snapshot::parse(&snapshot.to_bytes(), 4096),
+            snapshot.clone()
+        );
+        // Older snapshots end after the pages, and the oldest are of
+        // big-endian machines.
+        let mut runtime = Vec::new();
+        snapshot.write_runtime(&mut runtime);
+        let mut pages_last = snapshot.to_bytes();
+        pages_last.truncate(pages_last.len() - runtime.len());
+        pages_last.splice(..4, *b"RSN2");
+        assert_ok_eq!(Snapshot::parse(&pages_last, 4096), snapshot.clone());
+        let mut old = pages_last;
+        old.splice(..5, *b"RSN1");
+        snapshot.endian = Endian::Big;
+        assert_ok_eq!(Snapshot::parse(&old, 4096), snapshot);
+        assert_err_eq!(
+            Snapshot::parse(b"RSO1", 4096),
+            Error::SnapshotInvalid("bad magic".to_string())
+        );
+    }
+
+    #[test]
+    fn a_resumed_run_finishes_in_the_same_state_as_an_uninterrupted_one() {
+        let mut uninterrupted = machine();
+        assert_ok_eq!(uninterrupted.run(), HaltReason::Breakpoint);
+
+        let saved = Rc::new(RefCell::new(Vec::new()));
+        let mut interrupted = machine();
+        let sink = saved.clone();
+        let halted = interrupted.run_with_snapshots(4, move |machine| {
+            sink.borrow_mut().push(Snapshot::take(machine, b""));
+            Ok(())
+        });
+        assert_ok_eq!(halted, HaltReason::Breakpoint);
+        assert_eq!(saved.borrow().len(), 2);
+        let latest = saved.borrow()[1].clone();
+
+        let mut resumed = machine();
+        assert_ok!(resumed.restore_snapshot(&latest));
+        assert_ok_eq!(resumed.run(), HaltReason::Breakpoint);
+
+        assert_eq!(resumed.retired, uninterrupted.retired);
+        assert_eq!(resumed.state_hash(), uninterrupted.state_hash());
+        assert_eq!(resumed.input_hash(), uninterrupted.input_hash());
+    }
+
+    #[test]
+    fn resumed_runs_keep_their_fuel_limits_and_signals() {
+        let limits = Limits {
+            max_steps: Some(100),
+            ..Limits::default()
+        };
+        let mut machine = machine();
+        machine.set_fuel(Some(100));
+        machine.set_limits(Some(limits));
+        machine.signals.handlers.insert(10, 0x40);
+        machine.set_alarm(10, 50);
+        for _ in 0..3 {
+            assert_ok!(machine.step());
+        }
+        let snapshot = Snapshot::take(&machine, b"");
+        assert_ok_eq!(
+            Snapshot::parse(&snapshot.to_bytes(), 4096),
+            snapshot.clone()
+        );
+
+        let mut resumed = self::machine();
+        resumed.set_limits(Some(limits));
+        assert_ok!(resumed.restore_snapshot(&snapshot));
+        assert_eq!(resumed.fuel(), Some(97));
+        assert_eq!(resumed.limit_usage().steps, 3);
+        assert!(resumed.limit_usage().elapsed >= snapshot.limit_usage.elapsed);
+        assert_eq!(resumed.signals, machine.signals);
+    }
+
+    #[test]
+    fn snapshots_taken_with_files_the_guest_opened_cannot_be_resumed() {
+        let mut machine = machine();
+        machine.attach_writer(2, Vec::new());
+        let snapshot = Snapshot::take(&machine, b"");
+        assert_eq!(snapshot.fds.get(&2), Some(&SavedFd::Attached));
+
+        // The host attaches its files again before resuming.
+        let mut resumed = self::machine();
+        assert_err_eq!(
+            resumed.restore_snapshot(&snapshot),
+            Error::SnapshotInvalid(
+                "fd 2 was attached by the host, which has not attached it again".to_string()
+            )
+        );
+        resumed.attach_writer(2, Vec::new());
+        assert_ok!(resumed.restore_snapshot(&snapshot));
+
+        let mut opened = snapshot.clone();
+        opened.fds.insert(2, SavedFd::Opened);
+        let mut resumed = self::machine();
+        assert_err_eq!(
+            resumed.restore_snapshot(&Snapshot::parse(&opened.to_bytes(), 4096).unwrap()),
+            Error::SnapshotInvalid(
+                "fd 2 refers to a file the guest opened, which a resumed run cannot reopen"
+                    .to_string()
+            )
+        );
+        assert_eq!(resumed, self::machine());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{
+    endian::Endian, regbank::RegisterBanks, Address, Error, Layout, Machine, Memory, RegisterID,
+    Result, Word,
+};
+use std::{collections::BTreeMap, io::Write};
+
+// The architectural state of a machine: what the guest can observe, and
+// what two machines compare equal on. It leaves out the machine's stdout
+// and everything else attached by the host, so a machine can be rebuilt
+// from it with from_state and tests can compare runs against a saved
+// dump. With the `serde` feature it can be serialized.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct State {
+    pub pc: Word,
+    // x0 to sp, by register number, with banked registers as the selected
+    // bank holds them.
+    pub regs: [Word; 16],
+    // Every bank of a banked register file.
+    pub register_banks: Option<RegisterBanks>,
+    pub layout: Layout,
+    // The byte order the machine fetches and loads and stores in.
+    pub endian: Endian,
+    // Every page holding data, by base address, as the guest sees it.
+    pub pages: BTreeMap<Address, Vec<u8>>,
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::StateInvalid(reason.to_string())
+}
+
+pub(crate) fn register(id: usize) -> RegisterID {
+    RegisterID::try_from(id as Word).expect("register ids are 4 bits")
+}
+
+impl State {
+    // Checks that the pages are whole pages of the layout's size.
+    pub fn validate(&self) -> Result<()> {
+        self.layout.validate()?;
+        if self
+            .register_banks
+            .as_ref()
+            .is_some_and(|banks| !banks.is_valid())
+        {
+            return Err(invalid("register banks are invalid"));
+        }
+        let page_size = self.layout.page_size;
+        for (page, data) in &self.pages {
+            if page % page_size != 0 {
+                return Err(invalid("page is not aligned"));
+            }
+            if data.len() != page_size as usize {
+                return Err(invalid("page is not the page size"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn state(&self) -> State {
+        State {
+            pc: self.pc,
+            regs: std::array::from_fn(|id| self.regs.get(&register(id))),
+            register_banks: self.saved_register_banks(),
+            layout: self.layout,
+            endian: self.endian,
+            pages: self.saved_pages(),
+        }
+    }
+
+    // A machine in `state`, with nothing attached: no stdout, stdin or
+    // devices, and no program beyond what is in its memory.
+    pub fn from_state(state: &State) -> Result<Self> {
+        state.validate()?;
+        let mut machine = Self {
+            layout: state.layout,
+            mem: Memory::with_page_size(state.layout.page_size),
+            ..Default::default()
+        };
+        machine.restore_state(state);
+        Ok(machine)
+    }
+
+    // Puts the machine back into `state`, which should have the same page
+    // size, keeping everything the host has attached.
+    pub fn restore_state(&mut self, state: &State) {
+        self.restore_pages(&state.pages);
+        self.pc = state.pc;
+        self.endian = state.endian;
+        self.restore_register_banks(state.register_banks.as_ref());
+        for (id, value) in state.regs.iter().enumerate() {
+            self.regs.set(register(id), *value);
+        }
+    }
+
+    // Memory as the guest sees it, so bank-switched windows hold only the
+    // selected bank and mapped files are copies.
+    pub(crate) fn saved_pages(&self) -> BTreeMap<Address, Vec<u8>> {
+        self.mem
+            .pages()
+            .into_iter()
+            .map(|page| (page, self.mem.page(page)))
+            .collect()
+    }
+
+    // Replaces every page of memory with `pages`, clearing those missing
+    // from it.
+    pub(crate) fn restore_pages(&mut self, pages: &BTreeMap<Address, Vec<u8>>) {
+        let mut all = self.mem.pages();
+        all.extend(pages.keys());
+        for page in all {
+            self.mem
+                .restore_page(page, pages.get(&page).map(Vec::as_slice));
+        }
+        self.mem.clear_dirty();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, pool::BaseImage, HaltReason};
+    use claims::{assert_err_eq, assert_ok, assert_ok_eq};
+
+    fn machine() -> Machine<Vec<u8>> {
+        Machine::builder()
+            .stdout(Vec::new())
+            .program(&assemble("li a0, 7\nsw a0, 0x100(x0)\nadd a0, a0, a0\nebreak").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn machines_rebuilt_from_a_state_carry_on_where_it_was_taken() {
+        let mut uninterrupted = machine();
+        assert_ok_eq!(uninterrupted.run(), HaltReason::Breakpoint);
+
+        let mut original = machine();
+        assert_ok!(original.step());
+        assert_ok!(original.step());
+        let state = original.state();
+        assert_eq!(state.regs[RegisterID::A0 as usize], 7);
+        let mut resumed: Machine<Vec<u8>> = assert_ok!(Machine::from_state(&state));
+        assert_eq!(resumed.state(), state);
+        assert_ok_eq!(resumed.run(), HaltReason::Breakpoint);
+
+        assert_eq!(resumed.state(), uninterrupted.state());
+        // Output is not part of the state, so the machines are equal
+        // though only one has a stdout.
+        assert_eq!(resumed, uninterrupted);
+    }
+
+    #[test]
+    fn little_endian_machines_round_trip_through_a_state() {
+        let little = || {
+            let mut image = assemble("li a0, 7\nadd a0, a0, a0\nebreak").unwrap();
+            image.chunks_mut(4).for_each(<[u8]>::reverse);
+            Machine::<Vec<u8>>::builder()
+                .endian(Endian::Little)
+                .program(&image)
+                .build()
+                .unwrap()
+        };
+        let mut original = little();
+        assert_ok!(original.step());
+        let state = original.state();
+        assert_eq!(state.endian, Endian::Little);
+
+        let mut resumed: Machine<Vec<u8>> = assert_ok!(Machine::from_state(&state));
+        assert_ok_eq!(resumed.run(), HaltReason::Breakpoint);
+        assert_eq!(resumed.register(RegisterID::A0), 14);
+        let mut based =
+            Machine::from_base_image(&assert_ok!(BaseImage::from_state(&state)), Vec::new());
+        assert_ok_eq!(based.run(), HaltReason::Breakpoint);
+        assert_eq!(based.register(RegisterID::A0), 14);
+    }
+
+    #[test]
+    fn states_must_hold_whole_pages() {
+        let mut state = machine().state();
+        state.pages.insert(0x2000, vec![0; 16]);
+
+        assert_err_eq!(
+            Machine::<Vec<u8>>::from_state(&state),
+            Error::StateInvalid("page is not the page size".to_string())
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use crate::{
+    disasm::mnemonic,
+    observer::{Event, Observer},
+    Opcode, Word,
+};
+use std::{collections::BTreeMap, io};
+
+// The number of bits needed to hold `value` in two's complement.
+fn signed_width(value: i64) -> u32 {
+    let magnitude = if value < 0 { !value } else { value };
+    65 - magnitude.leading_zeros()
+}
+
+// The distribution of one instruction field over a run, by how many bits
+// each value needs as a signed number.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct FieldStats {
+    pub samples: u64,
+    pub min: i64,
+    pub max: i64,
+    widths: BTreeMap<u32, u64>,
+}
+
+impl FieldStats {
+    fn record(&mut self, value: i64) {
+        if self.samples == 0 {
+            (self.min, self.max) = (value, value);
+        }
+        self.samples += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        *self.widths.entry(signed_width(value)).or_default() += 1;
+    }
+
+    // How many values would not fit in a signed field of `bits` bits.
+    pub fn exceeding(&self, bits: u32) -> u64 {
+        self.widths.range(bits + 1..).map(|(_, count)| count).sum()
+    }
+
+    pub fn widths(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.widths.iter().map(|(bits, count)| (*bits, *count))
+    }
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct OpcodeStats {
+    pub count: u64,
+    // The immediate as the instruction uses it: sign-extended for branch
+    // offsets, as encoded otherwise.
+    pub imm: FieldStats,
+    // The values read from rs1 and rs2, as signed words.
+    pub rs1: FieldStats,
+    pub rs2: FieldStats,
+}
+
+// Operand and immediate statistics per opcode, gathered from the
+// instructions a run retires, to inform encoding decisions with data from
+// real programs. Every field is recorded for every instruction, so fields
+// an opcode does not use show up as zeros.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct IsaStats {
+    opcodes: BTreeMap<&'static str, OpcodeStats>,
+}
+
+impl IsaStats {
+    pub fn opcode(&self, opcode: &Opcode) -> Option<&OpcodeStats> {
+        self.opcodes.get(mnemonic(opcode))
+    }
+
+    // Writes the statistics as CSV with one row per opcode, field and
+    // width: `opcode,field,bits,count`.
+    pub fn write_csv(&self, out: &mut impl io::Write) -> io::Result<()> {
+        writeln!(out, "opcode,field,bits,count")?;
+        for (name, stats) in &self.opcodes {
+            for (field, values) in [
+                ("imm", &stats.imm),
+                ("rs1", &stats.rs1),
+                ("rs2", &stats.rs2),
+            ] {
+                for (bits, count) in values.widths() {
+                    writeln!(out, "{name},{field},{bits},{count}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Observer for IsaStats {
+    fn observe(&mut self, event: &Event) {
+        let Event::Retired {
+            instruction,
+            operands,
+            ..
+        } = event
+        else {
+            return;
+        };
+        let imm = match instruction.opcode {
+            Opcode::LoadImmediate | Opcode::ECall | Opcode::EBreak | Opcode::Mret => {
+                i64::from(instruction.imm)
+            }
+            _ => i64::from(instruction.offset()),
+        };
+        let stats = self
+            .opcodes
+            .entry(mnemonic(&instruction.opcode))
+            .or_default();
+        stats.count += 1;
+        stats.imm.record(imm);
+        stats.rs1.record(i64::from(operands[0] as i32));
+        stats.rs2.record(i64::from(operands[1] as i32));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Machine, Memory};
+    use claims::assert_ok;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn values_are_bucketed_by_signed_width() {
+        assert_eq!(signed_width(0), 1);
+        assert_eq!(signed_width(-1), 1);
+        assert_eq!(signed_width(1), 2);
+        assert_eq!(signed_width(2047), 12);
+        assert_eq!(signed_width(-2048), 12);
+        assert_eq!(signed_width(2048), 13);
+    }
+
+    #[test]
+    fn retired_instructions_are_aggregated_per_opcode() {
+        let mut mem = Memory::default();
+        // li a0, 5000; li a1, 3; add a2, a0, a1; ebreak
+        mem.write(0, &((5000 << 17) | (1 << 5) | 1u32).to_be_bytes());
+        mem.write(4, &((3 << 17) | (2 << 5) | 1u32).to_be_bytes());
+        mem.write(8, &((2 << 13) | (1 << 9) | (3 << 5) | 2u32).to_be_bytes());
+        mem.write(12, &0b1_1000u32.to_be_bytes());
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem,
+            ..Default::default()
+        };
+        let stats = Rc::new(RefCell::new(IsaStats::default()));
+        machine.add_observer(stats.clone());
+
+        assert_ok!(machine.run());
+
+        let stats = stats.borrow();
+        let li = stats.opcode(&Opcode::LoadImmediate).unwrap();
+        assert_eq!((li.count, li.imm.min, li.imm.max), (2, 3, 5000));
+        assert_eq!(li.imm.exceeding(12), 1);
+        let add = stats.opcode(&Opcode::Add).unwrap();
+        assert_eq!((add.rs1.max, add.rs2.max), (5000, 3));
+
+        let mut csv = Vec::new();
+        assert_ok!(stats.write_csv(&mut csv));
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("opcode,field,bits,count\nadd,imm,1,1\n"));
+        assert!(csv.contains("li,imm,3,1\nli,imm,14,1\n"));
+    }
+}
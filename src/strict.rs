@@ -0,0 +1,157 @@
+use crate::{trap::Trap, Error, Instruction, Machine, Opcode, RegisterID, Result, Word};
+use std::io::Write;
+
+// The fields an instruction with `opcode` ignores, which a canonical
+// encoding leaves zero.
+pub fn unused_fields(opcode: Opcode) -> &'static [&'static str] {
+    match opcode {
+        Opcode::Add
+        | Opcode::Sub
+        | Opcode::Mul
+        | Opcode::Div
+        | Opcode::Rem
+        | Opcode::And
+        | Opcode::Or
+        | Opcode::Xor
+        | Opcode::Sll
+        | Opcode::Srl
+        | Opcode::Sra
+        | Opcode::Slt
+        | Opcode::Sltu => &[],
+        Opcode::LoadImmediate | Opcode::Auipc | Opcode::Lui | Opcode::Jal => &["rs1", "rs2"],
+        Opcode::Jalr | Opcode::Lb | Opcode::Lh | Opcode::Lw => &["rs2"],
+        Opcode::Beq
+        | Opcode::Bne
+        | Opcode::Blt
+        | Opcode::Bge
+        | Opcode::Sb
+        | Opcode::Sh
+        | Opcode::Sw => &["rd"],
+        Opcode::ECall | Opcode::EBreak | Opcode::Mret => &["rd", "rs1", "rs2", "imm"],
+    }
+}
+
+impl Instruction {
+    // Decodes `word` as try_from does, and also rejects it unless it is
+    // canonical, so that an encoder setting fields the instruction ignores
+    // is caught rather than running as if it had not.
+    pub fn decode_strict(word: Word) -> Result<Self> {
+        let instruction = Self::try_from(word)?;
+        let set = |field: &&&str| match **field {
+            "rd" => instruction.rd != RegisterID::X0,
+            "rs1" => instruction.rs1 != RegisterID::X0,
+            "rs2" => instruction.rs2 != RegisterID::X0,
+            _ => instruction.imm != 0,
+        };
+        match unused_fields(instruction.opcode).iter().find(set) {
+            Some(field) => Err(Error::EncodingNonCanonical { word, field }),
+            None => Ok(instruction),
+        }
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Makes fetching a non-canonical instruction trap with
+    // NonCanonicalInstruction instead of running it. Off by default.
+    pub fn set_strict_decoding(&mut self, strict: bool) {
+        self.strict_decoding = strict;
+    }
+
+    pub fn strict_decoding(&self) -> bool {
+        self.strict_decoding
+    }
+
+    // Decodes the word fetched from pc, trapping if it is not an
+    // instruction the machine will run.
+    pub(crate) fn decode(&self, word: Word) -> Result<Instruction> {
+        let decoded = if self.strict_decoding {
+            Instruction::decode_strict(word)
+        } else {
+            Instruction::try_from(word)
+        };
+        decoded.map_err(|err| {
+            self.trap(match err {
+                Error::EncodingNonCanonical { field, .. } => {
+                    Trap::NonCanonicalInstruction { word, field }
+                }
+                _ => Trap::IllegalInstruction { word },
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, HaltReason};
+    use claims::{assert_err_eq, assert_ok, assert_ok_eq};
+
+    #[test]
+    fn fields_an_instruction_ignores_must_be_zero() {
+        let cases = [
+            // li a0, 1 with rs1 set to a0.
+            (0x0002_0221, Some("rs1")),
+            // beq a0, a1, 0 with rd set to a0.
+            (0x0000_422f, Some("rd")),
+            // ebreak with an immediate.
+            (0x0002_0018, Some("imm")),
+            // lw a0, 4(a1) with rs2 set to a0.
+            (0x0008_2435, Some("rs2")),
+            (0x0000_0018, None),
+        ];
+        for (word, field) in cases {
+            match field {
+                Some(field) => {
+                    assert_err_eq!(
+                        Instruction::decode_strict(word),
+                        Error::EncodingNonCanonical { word, field }
+                    );
+                }
+                None => {
+                    assert_ok_eq!(
+                        Instruction::decode_strict(word),
+                        assert_ok!(Instruction::try_from(word))
+                    );
+                }
+            }
+        }
+        assert_err_eq!(Instruction::decode_strict(0), Error::OpcodeUnknown(0));
+    }
+
+    #[test]
+    fn the_assembler_only_emits_canonical_encodings() {
+        let image = assert_ok!(assemble(
+            "start: li a0, 5\nauipc a1, -1\nadd a2, a0, a1, 3\nsltu a3, a0, a1\n\
+             jal ra, start\njalr x0, ra, 4\nbge a0, a1, start\nlh a4, 2(a0)\n\
+             sw a4, -4(sp)\nnop\nret\necall\nebreak\nmret",
+        ));
+
+        for word in image.chunks(4) {
+            let word = Word::from_be_bytes(word.try_into().unwrap());
+            assert_ok!(Instruction::decode_strict(word), "{word:#010x}");
+        }
+    }
+
+    #[test]
+    fn strict_machines_trap_on_non_canonical_instructions() {
+        // ebreak, with rd set to a0.
+        let program = 0x0000_0038u32.to_be_bytes();
+        let machine =
+            || -> Machine<Vec<u8>> { Machine::builder().program(&program).build().unwrap() };
+
+        assert_ok_eq!(machine().run(), HaltReason::Breakpoint);
+
+        let mut strict = machine();
+        strict.set_strict_decoding(true);
+        assert_err_eq!(
+            strict.run(),
+            Error::Trap {
+                pc: 0,
+                trap: Trap::NonCanonicalInstruction {
+                    word: 0x38,
+                    field: "rd",
+                },
+            }
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use crate::{
+    alignment::Alignment, disasm::mnemonic, irq::Latency, json, profile::Region, HaltReason,
+    Machine, Opcode, Result, Word,
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+// Counts kept while a run is being measured.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct RunCounters {
+    instructions: BTreeMap<&'static str, u64>,
+    syscalls: BTreeMap<Word, u64>,
+    interrupts: BTreeMap<u8, Latency>,
+    regions: BTreeMap<String, Region>,
+    pub(crate) accesses: Alignment,
+}
+
+// A summary of a run for benchmarking. Its Display is a stable format for
+// scripts: a header line, then one `key value` line per figure, with the
+// instruction mix, syscall counts, interrupt latencies and profiled
+// regions in mnemonic, number and name order:
+//
+//   rmachine-stats 1
+//   retired 11
+//   cycles 11
+//   wall-time-us 52
+//   memory-pages 2
+//   memory-bytes 8192
+//   aligned-accesses 30
+//   misaligned-accesses 2
+//   instruction add 6
+//   syscall 63 1
+//   interrupt 0 4 0 1.5 3 3
+//   region parse 2 40 38
+//
+// where each `interrupt` line gives the line number, how many interrupts
+// were delivered, and the minimum, mean and maximum latency and jitter,
+// and each `region` line the name, how many times the region was left,
+// and the cycles and instructions spent in it.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct RunStats {
+    pub retired: u64,
+    pub cycles: u64,
+    pub instructions: BTreeMap<&'static str, u64>,
+    pub syscalls: BTreeMap<Word, u64>,
+    pub interrupts: BTreeMap<u8, Latency>,
+    pub regions: BTreeMap<String, Region>,
+    // Loads and stores, by whether they were aligned to their width.
+    pub accesses: Alignment,
+    // Pages holding data when the run ended, and their size in bytes.
+    pub memory_pages: usize,
+    pub memory_bytes: u64,
+    pub wall_time: Duration,
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rmachine-stats 1")?;
+        writeln!(f, "retired {}", self.retired)?;
+        writeln!(f, "cycles {}", self.cycles)?;
+        writeln!(f, "wall-time-us {}", self.wall_time.as_micros())?;
+        writeln!(f, "memory-pages {}", self.memory_pages)?;
+        writeln!(f, "memory-bytes {}", self.memory_bytes)?;
+        writeln!(f, "aligned-accesses {}", self.accesses.aligned)?;
+        writeln!(f, "misaligned-accesses {}", self.accesses.misaligned)?;
+        for (mnemonic, count) in &self.instructions {
+            writeln!(f, "instruction {mnemonic} {count}")?;
+        }
+        for (number, count) in &self.syscalls {
+            writeln!(f, "syscall {number} {count}")?;
+        }
+        for (line, latency) in &self.interrupts {
+            writeln!(
+                f,
+                "interrupt {line} {} {} {:.1} {} {}",
+                latency.delivered,
+                latency.min,
+                latency.mean(),
+                latency.max,
+                latency.jitter()
+            )?;
+        }
+        for (name, region) in &self.regions {
+            writeln!(
+                f,
+                "region {name} {} {} {}",
+                region.entries, region.cycles, region.retired
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl RunStats {
+    // The same figures as a JSON object, under the names the `key value`
+    // lines give them, with the instruction mix, syscalls, interrupts and
+    // regions as objects keyed by mnemonic, number, line and name:
+    //
+    //   {"rmachine-stats": 1, "retired": 11, ..., "instructions": {"add": 6},
+    //    "syscalls": {"63": 1}, "interrupts": {"0": {"delivered": 4, "min": 0,
+    //    "mean": 1.5, "max": 3, "jitter": 3}}, "regions": {"parse":
+    //    {"entries": 2, "cycles": 40, "retired": 38}}}
+    pub fn to_json(&self) -> String {
+        let object = |members: Vec<String>| format!("{{{}}}", members.join(", "));
+        let mut members = vec![
+            "\"rmachine-stats\": 1".to_string(),
+            format!("\"retired\": {}", self.retired),
+            format!("\"cycles\": {}", self.cycles),
+            format!("\"wall-time-us\": {}", self.wall_time.as_micros()),
+            format!("\"memory-pages\": {}", self.memory_pages),
+            format!("\"memory-bytes\": {}", self.memory_bytes),
+            format!("\"aligned-accesses\": {}", self.accesses.aligned),
+            format!("\"misaligned-accesses\": {}", self.accesses.misaligned),
+        ];
+        let instructions = self
+            .instructions
+            .iter()
+            .map(|(mnemonic, count)| format!("{}: {count}", json::string(mnemonic)))
+            .collect();
+        members.push(format!("\"instructions\": {}", object(instructions)));
+        let syscalls = self
+            .syscalls
+            .iter()
+            .map(|(number, count)| format!("\"{number}\": {count}"))
+            .collect();
+        members.push(format!("\"syscalls\": {}", object(syscalls)));
+        let interrupts = self
+            .interrupts
+            .iter()
+            .map(|(line, latency)| {
+                format!(
+                    "\"{line}\": {{\"delivered\": {}, \"min\": {}, \"mean\": {:.1}, \"max\": {}, \"jitter\": {}}}",
+                    latency.delivered,
+                    latency.min,
+                    latency.mean(),
+                    latency.max,
+                    latency.jitter()
+                )
+            })
+            .collect();
+        members.push(format!("\"interrupts\": {}", object(interrupts)));
+        let regions = self
+            .regions
+            .iter()
+            .map(|(name, region)| {
+                format!(
+                    "{}: {{\"entries\": {}, \"cycles\": {}, \"retired\": {}}}",
+                    json::string(name),
+                    region.entries,
+                    region.cycles,
+                    region.retired
+                )
+            })
+            .collect();
+        members.push(format!("\"regions\": {}", object(regions)));
+        object(members) + "\n"
+    }
+}
+
+// How a measured run ended, and what it cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOutcome {
+    pub reason: HaltReason,
+    stats: RunStats,
+}
+
+impl RunOutcome {
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Runs like run, counting what the run does along the way.
+    pub fn run_with_stats(&mut self) -> Result<RunOutcome> {
+        self.measure(Self::run)
+    }
+
+    // Measures `run`, which runs the machine to completion in some way.
+    pub fn measure(
+        &mut self,
+        run: impl FnOnce(&mut Self) -> Result<HaltReason>,
+    ) -> Result<RunOutcome> {
+        let (retired, cycles) = (self.retired, self.cycles);
+        let started = Instant::now();
+        self.run_counters = Some(RunCounters::default());
+        let halted = run(self);
+        let counters = self.run_counters.take().unwrap_or_default();
+        let memory_pages = self.mem.pages().len();
+        Ok(RunOutcome {
+            reason: halted?,
+            stats: RunStats {
+                retired: self.retired - retired,
+                cycles: self.cycles - cycles,
+                instructions: counters.instructions,
+                syscalls: counters.syscalls,
+                interrupts: counters.interrupts,
+                regions: counters.regions,
+                accesses: counters.accesses,
+                memory_pages,
+                memory_bytes: memory_pages as u64 * u64::from(self.layout.page_size),
+                wall_time: started.elapsed(),
+            },
+        })
+    }
+
+    pub(crate) fn count_instruction(&mut self, opcode: &Opcode) {
+        self.execution.count_instruction(opcode);
+        if let Some(counters) = &mut self.run_counters {
+            *counters.instructions.entry(mnemonic(opcode)).or_default() += 1;
+        }
+    }
+
+    pub(crate) fn count_syscall(&mut self, number: Word) {
+        self.execution.count_syscall(number);
+        if let Some(counters) = &mut self.run_counters {
+            *counters.syscalls.entry(number).or_default() += 1;
+        }
+    }
+
+    pub(crate) fn count_irq(&mut self, line: u8, latency: u64) {
+        if let Some(counters) = &mut self.run_counters {
+            counters.interrupts.entry(line).or_default().record(latency);
+        }
+    }
+
+    pub(crate) fn count_region(&mut self, name: String, cycles: u64, retired: u64) {
+        if let Some(counters) = &mut self.run_counters {
+            counters
+                .regions
+                .entry(name)
+                .or_default()
+                .record(cycles, retired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, RegisterID, Registers};
+    use claims::assert_ok;
+
+    #[test]
+    fn measured_runs_report_the_instruction_mix_and_syscalls() {
+        let mut mem = Memory::default();
+        // add a3, a3, x0, 1 twice, a format_unsigned ecall, then ebreak.
+        mem.write(0, &[0b0000_0000, 0b0000_0010, 0b0000_1000, 0b1000_0010]);
+        mem.write(4, &[0b0000_0000, 0b0000_0010, 0b0000_1000, 0b1000_0010]);
+        mem.write(8, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111]);
+        mem.write(12, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem,
+            regs: Registers::from([
+                (RegisterID::A1, 0x1000),
+                (RegisterID::A2, 4),
+                (RegisterID::A7, 257),
+            ]),
+            ..Default::default()
+        };
+
+        let outcome = assert_ok!(machine.run_with_stats());
+
+        assert_eq!(outcome.reason, HaltReason::Breakpoint);
+        let stats = outcome.stats();
+        assert_eq!((stats.retired, stats.cycles), (4, 4));
+        assert_eq!(
+            stats.instructions,
+            BTreeMap::from([("add", 2), ("ebreak", 1), ("ecall", 1)])
+        );
+        assert_eq!(stats.syscalls, BTreeMap::from([(257, 1)]));
+        assert_eq!((stats.memory_pages, stats.memory_bytes), (2, 8192));
+
+        let text = stats.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[..3], ["rmachine-stats 1", "retired 4", "cycles 4"]);
+        assert_eq!(
+            lines[4..],
+            [
+                "memory-pages 2",
+                "memory-bytes 8192",
+                "aligned-accesses 0",
+                "misaligned-accesses 0",
+                "instruction add 2",
+                "instruction ebreak 1",
+                "instruction ecall 1",
+                "syscall 257 1",
+            ]
+        );
+        let json = stats.to_json();
+        let wall_time = format!("\"wall-time-us\": {}", stats.wall_time.as_micros());
+        assert_eq!(
+            json.replace(&wall_time, "\"wall-time-us\": 0"),
+            concat!(
+                r#"{"rmachine-stats": 1, "retired": 4, "cycles": 4, "wall-time-us": 0, "#,
+                r#""memory-pages": 2, "memory-bytes": 8192, "aligned-accesses": 0, "#,
+                r#""misaligned-accesses": 0, "instructions": {"add": 2, "ebreak": 1, "#,
+                r#""ecall": 1}, "syscalls": {"257": 1}, "interrupts": {}, "regions": {}}"#,
+                "\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{Address, Error, Machine, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::Write,
+};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: Address,
+    pub size: u32,
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct SymbolTable {
+    by_addr: BTreeMap<Address, Symbol>,
+    by_name: HashMap<String, Address>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A size of zero marks a plain label, which covers every address up to
+    // the next symbol.
+    pub fn insert(&mut self, name: &str, addr: Address, size: u32) {
+        if let Some(old) = self.by_name.insert(name.to_string(), addr) {
+            self.by_addr.remove(&old);
+        }
+        self.by_addr.insert(
+            addr,
+            Symbol {
+                name: name.to_string(),
+                addr,
+                size,
+            },
+        );
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.by_name
+            .get(name)
+            .and_then(|addr| self.by_addr.get(addr))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_addr.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.by_addr.values()
+    }
+
+    // The symbol containing `addr` and the offset of `addr` within it.
+    pub fn resolve(&self, addr: Address) -> Option<(&Symbol, u32)> {
+        let (_, symbol) = self.by_addr.range(..=addr).next_back()?;
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+
+    // Where `addr` is in `new`, a table for a rebuilt program: at the same
+    // offset into the symbol of the same name, or the same address when no
+    // symbol covers it. None if its symbol is not in `new`.
+    pub fn relocate(&self, addr: Address, new: &SymbolTable) -> Option<Address> {
+        match self.resolve(addr) {
+            Some((symbol, offset)) => Some(new.get(&symbol.name)?.addr.wrapping_add(offset)),
+            None => Some(addr),
+        }
+    }
+
+    // Formats `addr` as `label` or `label+offset` when a symbol covers it.
+    pub fn label(&self, addr: Address) -> Option<String> {
+        self.resolve(addr).map(|(symbol, offset)| match offset {
+            0 => symbol.name.clone(),
+            _ => format!("{}+{offset:#x}", symbol.name),
+        })
+    }
+
+    // Reads a symbol file, as written by Display: a line per symbol giving
+    // its address in hex, its size, and its name.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut table = Self::new();
+        for (index, line) in text.lines().enumerate() {
+            let invalid = || Error::SymbolsInvalid(format!("line {}: {line:?}", index + 1));
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(addr), Some(size), Some(name), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            let addr = Address::from_str_radix(addr, 16).map_err(|_| invalid())?;
+            let size = size.parse().map_err(|_| invalid())?;
+            table.insert(name, addr, size);
+        }
+        Ok(table)
+    }
+}
+
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for symbol in self.iter() {
+            writeln!(f, "{:08x} {} {}", symbol.addr, symbol.size, symbol.name)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    // Replaces the symbols used to name addresses, as debug info for the
+    // program.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+}
+
+impl<const N: usize> From<[(&str, Address, u32); N]> for SymbolTable {
+    fn from(values: [(&str, Address, u32); N]) -> Self {
+        let mut table = Self::new();
+        for (name, addr, size) in values {
+            table.insert(name, addr, size);
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_none, assert_some_eq};
+
+    #[test]
+    fn addresses_resolve_to_the_enclosing_symbol() {
+        let table = SymbolTable::from([("main", 0x0, 0), ("buffer", 0x100, 16)]);
+
+        assert_some_eq!(table.label(0x0), "main".to_string());
+        assert_some_eq!(table.label(0x8), "main+0x8".to_string());
+        assert_some_eq!(table.label(0x10f), "buffer+0xf".to_string());
+        assert_none!(table.label(0x110));
+    }
+
+    #[test]
+    fn symbols_can_be_looked_up_by_name() {
+        let mut table = SymbolTable::from([("counter", 0x200, 4)]);
+        assert_some_eq!(
+            table.get("counter"),
+            &Symbol {
+                name: "counter".to_string(),
+                addr: 0x200,
+                size: 4,
+            }
+        );
+
+        table.insert("counter", 0x300, 4);
+        assert_eq!(table.get("counter").map(|s| s.addr), Some(0x300));
+        assert_none!(table.resolve(0x200));
+    }
+
+    #[test]
+    fn symbol_files_round_trip() {
+        let table = SymbolTable::from([("main", 0x0, 0), ("buffer", 0x8000_0100, 16)]);
+        let text = table.to_string();
+
+        assert_eq!(text, "00000000 0 main\n80000100 16 buffer\n");
+        assert_eq!(SymbolTable::parse(&text), Ok(table));
+        assert_eq!(
+            SymbolTable::parse("100 main"),
+            Err(Error::SymbolsInvalid("line 1: \"100 main\"".to_string()))
+        );
+    }
+}
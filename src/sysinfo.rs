@@ -0,0 +1,126 @@
+use crate::{Machine, Opcode, RegisterID, Result, Word};
+use std::io::Write;
+
+// ISA feature flags, set for each group of instructions the machine
+// implements.
+pub const FEATURE_BRANCH: Word = 1 << 0;
+pub const FEATURE_JUMP: Word = 1 << 1;
+pub const FEATURE_LOAD_STORE: Word = 1 << 2;
+pub const FEATURE_INTERRUPTS: Word = 1 << 3;
+pub const FEATURE_MULDIV: Word = 1 << 4;
+pub const FEATURE_PCREL: Word = 1 << 5;
+
+fn features() -> Word {
+    let has = |opcode| Opcode::ALL.contains(&opcode);
+    let mut features = 0;
+    if has(Opcode::Beq) {
+        features |= FEATURE_BRANCH;
+    }
+    if has(Opcode::Jal) {
+        features |= FEATURE_JUMP;
+    }
+    if has(Opcode::Lw) {
+        features |= FEATURE_LOAD_STORE;
+    }
+    if has(Opcode::Mret) {
+        features |= FEATURE_INTERRUPTS;
+    }
+    if has(Opcode::Mul) {
+        features |= FEATURE_MULDIV;
+    }
+    if has(Opcode::Auipc) {
+        features |= FEATURE_PCREL;
+    }
+    features
+}
+
+// The length of the sysinfo record in bytes.
+pub const SYSINFO_LEN: usize = 28;
+
+// The VM version as 0x00MMmmpp.
+fn version() -> Word {
+    let part = |s: &str| s.parse::<Word>().unwrap_or_default();
+    part(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+        | part(env!("CARGO_PKG_VERSION_MINOR")) << 8
+        | part(env!("CARGO_PKG_VERSION_PATCH"))
+}
+
+impl<W: Write> Machine<W> {
+    pub fn hart_id(&self) -> Word {
+        self.hart_id
+    }
+
+    // The record the sysinfo syscall gives the guest, as words in the
+    // machine's byte order:
+    // VM version, hart ID, ISA feature flags, page size, load address,
+    // heap base and stack top, the last being the size of the address
+    // space the guest may use.
+    pub fn sysinfo(&self) -> Vec<u8> {
+        [
+            version(),
+            self.hart_id,
+            features(),
+            self.layout.page_size,
+            self.layout.load_address,
+            self.layout.heap_base,
+            self.layout.stack_top,
+        ]
+        .iter()
+        .flat_map(|word| self.endian.bytes(*word))
+        .collect()
+    }
+
+    // sysinfo(buf, len) copies up to `len` bytes of the record to `buf`
+    // and returns the record's full length, so guests built against an
+    // older, shorter record keep working.
+    pub(crate) fn sysinfo_syscall(&mut self) -> Result<()> {
+        let buf = self.regs.get(&RegisterID::A0);
+        let len = self.regs.get(&RegisterID::A1);
+        let info = self.sysinfo();
+        let len = self.guest_buffer(buf, u64::from(len).min(info.len() as u64))?;
+        self.store(buf, &info[..len]);
+        self.set_register(RegisterID::A0, info.len() as Word);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Layout, Memory};
+    use claims::assert_ok;
+
+    #[test]
+    fn guests_can_read_the_machine_configuration() {
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .layout(Layout::microcontroller())
+            .hart_id(3)
+            .program(&[0, 0, 0, 0b0001_0111, 0, 0, 0, 0b0001_1000])
+            .build()
+            .unwrap();
+        machine.regs.set(RegisterID::A0, 0x100);
+        machine.regs.set(RegisterID::A1, 16);
+        machine.regs.set(RegisterID::A7, 265);
+
+        assert_ok!(machine.run());
+
+        assert_eq!(machine.regs.get(&RegisterID::A0), SYSINFO_LEN as Word);
+        assert_eq!(
+            machine.mem.read(0x100, 20),
+            [
+                version().to_be_bytes(),
+                3u32.to_be_bytes(),
+                (FEATURE_BRANCH
+                    | FEATURE_JUMP
+                    | FEATURE_LOAD_STORE
+                    | FEATURE_INTERRUPTS
+                    | FEATURE_MULDIV
+                    | FEATURE_PCREL)
+                    .to_be_bytes(),
+                256u32.to_be_bytes(),
+                [0; 4],
+            ]
+            .concat()
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use crate::{
+    boot::RESET_KEEP_MEMORY, console::TERMINFO_LEN, semantics::memory_access, shadow::ShadowMemory,
+    sysinfo::SYSINFO_LEN, Address, Instruction, Machine, Opcode, RegisterID, Syscall, Word,
+};
+use std::{collections::BTreeSet, fmt, io::Write, ops::Range};
+
+// The set of reasons a value is tracked. Values derived from several
+// sources carry the union of their labels.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Taint(u8);
+
+impl Taint {
+    pub const NONE: Taint = Taint(0);
+    // Data that came from the guest's input.
+    pub const INPUT: Taint = Taint(1);
+    // Data the guest must not let influence its timing.
+    pub const SECRET: Taint = Taint(2);
+
+    pub fn union(self, other: Taint) -> Taint {
+        Taint(self.0 | other.0)
+    }
+
+    pub fn contains(self, other: Taint) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_tainted(self) -> bool {
+        self != Taint::NONE
+    }
+}
+
+// Shadow state recording the taint of every register and memory byte, and
+// where tainted data influenced the guest's observable behaviour.
+#[derive(Debug, Default)]
+pub struct TaintTracker {
+    regs: [Taint; 16],
+    mem: ShadowMemory<Taint>,
+    written: usize,
+    output: Vec<Range<usize>>,
+    branches: BTreeSet<Address>,
+    violations: Vec<Violation>,
+}
+
+// A place where secret data could change how long the guest takes to run.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Violation {
+    // The branch at `pc` depended on secret data.
+    Branch { pc: Address },
+    // The instruction at `pc` accessed memory at a secret-dependent address.
+    MemoryAddress { pc: Address, addr: Address },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Branch { pc } => {
+                write!(f, "branch at pc {pc:#010x} depends on secret data")
+            }
+            Violation::MemoryAddress { pc, addr } => write!(
+                f,
+                "access to {addr:#010x} at pc {pc:#010x} uses a secret-dependent address"
+            ),
+        }
+    }
+}
+
+impl TaintTracker {
+    fn reg(&self, reg: RegisterID) -> Taint {
+        self.regs[reg as usize]
+    }
+
+    fn set_reg(&mut self, reg: RegisterID, taint: Taint) {
+        if reg != RegisterID::X0 {
+            self.regs[reg as usize] = taint;
+        }
+    }
+
+    fn mem(&self, addr: Address, len: usize) -> Taint {
+        self.mem.range(addr, len).fold(Taint::NONE, Taint::union)
+    }
+
+    // Records a branch at `pc` whose condition carries `taint`.
+    fn branch(&mut self, pc: Address, taint: Taint) {
+        if taint.is_tainted() {
+            self.branches.insert(pc);
+        }
+        if taint.contains(Taint::SECRET) {
+            self.violations.push(Violation::Branch { pc });
+        }
+    }
+
+    // Records a memory access at `pc` whose address carries `taint`.
+    fn address(&mut self, pc: Address, addr: Address, taint: Taint) {
+        if taint.contains(Taint::SECRET) {
+            self.violations.push(Violation::MemoryAddress { pc, addr });
+        }
+    }
+
+    fn set_mem(&mut self, addr: Address, len: usize, taint: Taint) {
+        self.mem.fill(addr, len, taint);
+    }
+}
+
+impl<W: Write> Machine<W> {
+    pub fn enable_taint_tracking(&mut self) {
+        self.taint = Some(TaintTracker::default());
+    }
+
+    pub fn disable_taint_tracking(&mut self) {
+        self.taint = None;
+    }
+
+    // Adds `taint` to memory, for example to mark a buffer the host filled
+    // with untrusted data.
+    pub fn taint_memory(&mut self, addr: Address, len: usize, taint: Taint) {
+        if let Some(tracker) = &mut self.taint {
+            tracker.mem.update(addr, len, |old| old.union(taint));
+        }
+    }
+
+    pub fn register_taint(&self, reg: RegisterID) -> Taint {
+        self.taint
+            .as_ref()
+            .map_or(Taint::NONE, |tracker| tracker.reg(reg))
+    }
+
+    pub fn memory_taint(&self, addr: Address, len: usize) -> Taint {
+        self.taint
+            .as_ref()
+            .map_or(Taint::NONE, |tracker| tracker.mem(addr, len))
+    }
+
+    // Byte ranges of everything written to stdout that was influenced by
+    // tainted data.
+    pub fn tainted_output(&self) -> &[Range<usize>] {
+        self.taint
+            .as_ref()
+            .map_or(&[], |tracker| tracker.output.as_slice())
+    }
+
+    // Runs the constant-time checker: branches and memory addresses that
+    // depend on data marked secret, with the mark_secret syscall or
+    // taint_memory, are reported as violations. Enables taint tracking.
+    pub fn enable_constant_time_check(&mut self) {
+        if self.taint.is_none() {
+            self.enable_taint_tracking();
+        }
+    }
+
+    pub fn constant_time_violations(&self) -> &[Violation] {
+        self.taint
+            .as_ref()
+            .map_or(&[], |tracker| tracker.violations.as_slice())
+    }
+
+    // Addresses of branches whose condition depended on tainted data.
+    pub fn tainted_branches(&self) -> Option<&BTreeSet<Address>> {
+        self.taint.as_ref().map(|tracker| &tracker.branches)
+    }
+
+    // Bytes read into guest memory from an input stream are tainted with
+    // Taint::INPUT.
+    pub(crate) fn taint_input(&mut self, addr: Address, len: usize) {
+        self.taint_memory(addr, len, Taint::INPUT);
+    }
+
+    // Updates the shadow state for an instruction that is about to execute,
+    // using the register values it will read.
+    pub(crate) fn propagate_taint(&mut self, instruction: &Instruction) {
+        let Some(tracker) = &mut self.taint else {
+            return;
+        };
+        let pc = self.pc;
+        match instruction.opcode {
+            Opcode::LoadImmediate | Opcode::Auipc | Opcode::Lui => {
+                tracker.set_reg(instruction.rd, Taint::NONE);
+            }
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Rem
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Xor
+            | Opcode::Sll
+            | Opcode::Srl
+            | Opcode::Sra
+            | Opcode::Slt
+            | Opcode::Sltu => {
+                let taint = tracker
+                    .reg(instruction.rs1)
+                    .union(tracker.reg(instruction.rs2));
+                tracker.set_reg(instruction.rd, taint);
+            }
+            Opcode::Jal => tracker.set_reg(instruction.rd, Taint::NONE),
+            // Where the jump goes depends on rs1.
+            Opcode::Jalr => {
+                tracker.branch(pc, tracker.reg(instruction.rs1));
+                tracker.set_reg(instruction.rd, Taint::NONE);
+            }
+            // A loaded value is as tainted as the bytes it came from.
+            Opcode::Lb | Opcode::Lh | Opcode::Lw => {
+                let (addr, len, _) =
+                    memory_access(instruction, &self.regs).expect("loads access memory");
+                tracker.address(pc, addr, tracker.reg(instruction.rs1));
+                tracker.set_reg(instruction.rd, tracker.mem(addr, len));
+            }
+            Opcode::Sb | Opcode::Sh | Opcode::Sw => {
+                let (addr, len, _) =
+                    memory_access(instruction, &self.regs).expect("stores access memory");
+                tracker.address(pc, addr, tracker.reg(instruction.rs1));
+                tracker.set_mem(addr, len, tracker.reg(instruction.rs2));
+            }
+            Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge => {
+                let taint = tracker
+                    .reg(instruction.rs1)
+                    .union(tracker.reg(instruction.rs2));
+                tracker.branch(pc, taint);
+            }
+            Opcode::ECall => {
+                let arg = |reg| self.regs.get(&reg);
+                let (a0, a1, a2) = (
+                    arg(RegisterID::A0),
+                    arg(RegisterID::A1),
+                    arg(RegisterID::A2),
+                );
+                match Syscall::try_from(arg(RegisterID::A7)) {
+                    // The bytes read are tainted when the read is serviced.
+                    Ok(
+                        Syscall::Read
+                        | Syscall::DlOpen
+                        | Syscall::DlSym
+                        | Syscall::Poll
+                        | Syscall::IrqHandler
+                        | Syscall::IrqClaim
+                        | Syscall::IrqPriority
+                        | Syscall::IrqStatus
+                        | Syscall::RegionBegin
+                        | Syscall::RegionEnd
+                        | Syscall::Open
+                        | Syscall::Close
+                        | Syscall::Dup
+                        | Syscall::SigAction
+                        | Syscall::Alarm
+                        | Syscall::Log,
+                    ) => {
+                        tracker.set_reg(RegisterID::A0, Taint::NONE);
+                    }
+                    Ok(Syscall::ClockGetTime) => {
+                        tracker.address(pc, a1, tracker.reg(RegisterID::A1));
+                        tracker.set_mem(a1, 12, Taint::NONE);
+                        tracker.set_reg(RegisterID::A0, Taint::NONE);
+                    }
+                    Ok(Syscall::TermInfo) => {
+                        tracker.address(pc, a0, tracker.reg(RegisterID::A0));
+                        tracker.set_mem(a0, TERMINFO_LEN, Taint::NONE);
+                        tracker.set_reg(RegisterID::A0, Taint::NONE);
+                    }
+                    Ok(Syscall::Pipe) => {
+                        tracker.address(pc, a0, tracker.reg(RegisterID::A0));
+                        tracker.set_mem(a0, 8, Taint::NONE);
+                        tracker.set_reg(RegisterID::A0, Taint::NONE);
+                    }
+                    Ok(Syscall::SysInfo) => {
+                        tracker.address(pc, a0, tracker.reg(RegisterID::A0));
+                        tracker.set_mem(a0, (a1 as usize).min(SYSINFO_LEN), Taint::NONE);
+                        tracker.set_reg(RegisterID::A0, Taint::NONE);
+                    }
+                    // Only output to stdout is checked.
+                    Ok(Syscall::Write) if a0 != 1 => tracker.set_reg(RegisterID::A0, Taint::NONE),
+                    Ok(Syscall::Write) => {
+                        tracker.set_reg(RegisterID::A0, Taint::NONE);
+                        tracker.address(pc, a1, tracker.reg(RegisterID::A1));
+                        let taint = tracker.mem(a1, a2 as usize);
+                        let start = tracker.written;
+                        tracker.written += a2 as usize;
+                        if taint.is_tainted() && a2 > 0 {
+                            tracker.output.push(start..tracker.written);
+                        }
+                    }
+                    Ok(Syscall::FormatSigned | Syscall::FormatUnsigned | Syscall::FormatHex) => {
+                        tracker.address(pc, a1, tracker.reg(RegisterID::A1));
+                        let taint = tracker.reg(RegisterID::A0);
+                        let len = formatted_len(arg(RegisterID::A7), a0).min(a2 as usize);
+                        tracker.set_mem(a1, len, taint);
+                    }
+                    Ok(Syscall::Brk) => tracker.set_reg(RegisterID::A0, Taint::NONE),
+                    Ok(Syscall::Abort) => tracker.address(pc, a0, tracker.reg(RegisterID::A0)),
+                    // The hart comes out of reset holding nothing secret, and
+                    // so does memory after a cold reset.
+                    Ok(Syscall::Reset) => {
+                        tracker.regs = [Taint::NONE; 16];
+                        if a0 & RESET_KEEP_MEMORY == 0 {
+                            tracker.mem.clear();
+                        }
+                    }
+                    // The registers sigreturn restores keep the taint the
+                    // handler left them with.
+                    Ok(
+                        Syscall::MarkSecret
+                        | Syscall::AtExit
+                        | Syscall::Exit
+                        | Syscall::SigReturn
+                        | Syscall::WaitForInterrupt,
+                    )
+                    | Err(_) => {}
+                }
+            }
+            Opcode::EBreak | Opcode::Mret => {}
+        }
+    }
+}
+
+// The number of bytes a formatting syscall produces for `value`.
+fn formatted_len(number: Word, value: Word) -> usize {
+    match Syscall::try_from(number) {
+        Ok(Syscall::FormatSigned) => (value as i32).to_string().len(),
+        Ok(Syscall::FormatHex) => format!("{value:x}").len(),
+        _ => value.to_string().len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, Registers};
+    use claims::assert_ok;
+
+    // a0 = a3 + a4, formatted into the buffer at 0x100 and written to
+    // stdout, then a4 is reloaded with an immediate.
+    fn machine() -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        // add a0, a3, a4
+        mem.write(0, &[0b0000_0000, 0b0000_0000, 0b1010_1000, 0b0010_0010]);
+        // ecall (format_unsigned)
+        mem.write(4, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111]);
+        // add a7, a5, x0 (switch to write)
+        mem.write(8, &[0b0000_0000, 0b0000_0000, 0b0000_1101, 0b0000_0010]);
+        // add a0, a6, x0 (fd 1)
+        mem.write(12, &[0b0000_0000, 0b0000_0000, 0b0000_1110, 0b0010_0010]);
+        // ecall (write)
+        mem.write(16, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111]);
+        // li a4, 0
+        mem.write(20, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b1010_0001]);
+        // ebreak
+        mem.write(24, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        Machine {
+            mem,
+            stdout: Some(Vec::new()),
+            regs: Registers::from([
+                (RegisterID::A1, 0x100),
+                (RegisterID::A2, 2),
+                (RegisterID::A3, 40),
+                (RegisterID::A5, 64),
+                (RegisterID::A6, 1),
+                (RegisterID::A7, 257),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn input_taint_flows_through_registers_memory_and_output() {
+        let mut machine = machine();
+        machine.enable_taint_tracking();
+        machine.mem.write(0x200, &[2]);
+        machine.taint_input(0x200, 1);
+        // As if a4 had been loaded from the input buffer.
+        machine.regs.set(RegisterID::A4, 2);
+        machine
+            .taint
+            .as_mut()
+            .unwrap()
+            .set_reg(RegisterID::A4, Taint::INPUT);
+
+        assert_ok!(machine.run());
+
+        assert_eq!(machine.stdout, Some(b"42".to_vec()));
+        assert!(machine.memory_taint(0x200, 1).contains(Taint::INPUT));
+        assert!(machine.memory_taint(0x100, 2).contains(Taint::INPUT));
+        assert_eq!(machine.tainted_output().to_vec(), vec![0..2]);
+        assert_eq!(machine.register_taint(RegisterID::A4), Taint::NONE);
+        assert_eq!(machine.register_taint(RegisterID::A7), Taint::NONE);
+    }
+
+    #[test]
+    fn loads_and_stores_carry_taint_through_memory() {
+        let image =
+            crate::asm::assemble("lw a1, 0x200\nsw a1, 0x300\nlw a2, 0x300\nebreak").unwrap();
+        let mut machine: Machine<Vec<u8>> = Machine::builder().program(&image).build().unwrap();
+        machine.enable_taint_tracking();
+        machine.taint_input(0x202, 1);
+
+        assert_ok!(machine.run());
+
+        assert_eq!(machine.register_taint(RegisterID::A1), Taint::INPUT);
+        assert_eq!(machine.memory_taint(0x300, 4), Taint::INPUT);
+        assert_eq!(machine.register_taint(RegisterID::A2), Taint::INPUT);
+    }
+
+    #[test]
+    fn secret_dependent_addresses_and_branches_are_violations() {
+        // mark_secret(0x200, 4); format_unsigned(a0, a1, a2); ebreak
+        let mut machine: Machine<Vec<u8>> = Machine {
+            mem: Memory::default(),
+            regs: Registers::from([
+                (RegisterID::A0, 0x200),
+                (RegisterID::A1, 4),
+                (RegisterID::A5, 257),
+                (RegisterID::A7, 260),
+            ]),
+            ..Default::default()
+        };
+        machine
+            .mem
+            .write(0, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111]);
+        // add a7, a5, x0
+        machine
+            .mem
+            .write(4, &[0b0000_0000, 0b0000_0000, 0b0000_1101, 0b0000_0010]);
+        machine
+            .mem
+            .write(8, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111]);
+        machine
+            .mem
+            .write(12, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        machine.enable_constant_time_check();
+
+        assert_ok!(machine.step());
+        assert!(machine.memory_taint(0x200, 4).contains(Taint::SECRET));
+        assert!(!machine.memory_taint(0x204, 1).is_tainted());
+
+        // As if the buffer address had been computed from the secret.
+        let tracker = machine.taint.as_mut().unwrap();
+        tracker.set_reg(RegisterID::A1, Taint::SECRET);
+        tracker.branch(0x40, Taint::SECRET.union(Taint::INPUT));
+        assert_ok!(machine.run());
+
+        let violations: Vec<String> = machine
+            .constant_time_violations()
+            .iter()
+            .map(Violation::to_string)
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                "branch at pc 0x00000040 depends on secret data",
+                "access to 0x00000004 at pc 0x00000008 uses a secret-dependent address",
+            ]
+        );
+        assert_eq!(
+            machine
+                .tainted_branches()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![&0x40]
+        );
+    }
+
+    #[test]
+    fn untainted_runs_have_no_tainted_output() {
+        let mut machine = machine();
+        machine.enable_taint_tracking();
+
+        assert_ok!(machine.run());
+
+        assert_eq!(machine.stdout, Some(b"40".to_vec()));
+        assert!(machine.tainted_output().is_empty());
+        assert!(!machine.memory_taint(0x100, 2).is_tainted());
+    }
+}
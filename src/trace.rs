@@ -0,0 +1,335 @@
+use crate::{
+    disasm::Disassembler, expr::Expr, Address, Instruction, Machine, Opcode, RegisterID, Word,
+};
+use std::{fmt, io::Write, ops::Range};
+
+// Conditions that switch tracing on or off. Each is checked before an
+// instruction executes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Trigger {
+    // The instruction's address falls within the range.
+    Pc(Range<Address>),
+    // Execution reaches the first instruction of the symbol.
+    SymbolEntry(String),
+    // Execution leaves the range covered by the symbol.
+    SymbolExit(String),
+    // At least this many instructions have retired.
+    After(u64),
+    // The instruction makes a syscall; `None` matches any syscall number.
+    Syscall(Option<Word>),
+}
+
+// Logs executed instructions to a sink. With no start triggers tracing is
+// on from the first instruction; otherwise it switches on when any start
+// trigger fires and off again when any stop trigger does. An optional
+// filter expression further limits the logged instructions to those for
+// which it evaluates to non-zero. With effects shown, each line ends with
+// the changes the instruction made.
+pub struct Tracer {
+    sink: Box<dyn Write>,
+    start: Vec<Trigger>,
+    stop: Vec<Trigger>,
+    filter: Option<Expr>,
+    enabled: bool,
+    inside: Vec<bool>,
+    effects: bool,
+    pending: Option<String>,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("start", &self.start)
+            .field("stop", &self.stop)
+            .field("filter", &self.filter)
+            .field("enabled", &self.enabled)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    pub fn new(sink: impl Write + 'static) -> Self {
+        Self {
+            sink: Box::new(sink),
+            start: Vec::new(),
+            stop: Vec::new(),
+            filter: None,
+            enabled: true,
+            inside: Vec::new(),
+            effects: false,
+            pending: None,
+        }
+    }
+
+    pub fn start_on(mut self, trigger: Trigger) -> Self {
+        self.start.push(trigger);
+        self.enabled = false;
+        self
+    }
+
+    pub fn stop_on(mut self, trigger: Trigger) -> Self {
+        self.stop.push(trigger);
+        self
+    }
+
+    pub(crate) fn filter(mut self, filter: Expr) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn show_effects(mut self) -> Self {
+        self.effects = true;
+        self
+    }
+
+    fn update<W: Write>(&mut self, machine: &Machine<W>, instruction: &Instruction) {
+        if self.inside.len() != self.start.len() + self.stop.len() {
+            self.inside = vec![false; self.start.len() + self.stop.len()];
+        }
+        let (start, stop) = self.inside.split_at_mut(self.start.len());
+        let started = fired(&self.start, start, machine, instruction);
+        let stopped = fired(&self.stop, stop, machine, instruction);
+        if stopped {
+            self.enabled = false;
+        } else if started {
+            self.enabled = true;
+        }
+    }
+
+    fn record<W: Write>(&mut self, machine: &Machine<W>, instruction: &Instruction) {
+        self.update(machine, instruction);
+        if !self.enabled {
+            return;
+        }
+        if let Some(filter) = &self.filter {
+            if filter.eval(machine).unwrap_or_default() == 0 {
+                return;
+            }
+        }
+        let text = Disassembler::with_symbols(&machine.symbols).format(instruction, machine.pc);
+        let line = format!(
+            "{:#010x}{}: {text}",
+            machine.pc,
+            machine.memory_map.annotate(machine.pc)
+        );
+        if self.effects {
+            self.pending = Some(line);
+        } else {
+            self.write_line(&line);
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        // A broken trace sink must not bring down the guest.
+        writeln!(self.sink, "{line}").ok();
+    }
+}
+
+// Whether any of the triggers fires for the instruction about to execute.
+// `inside` keeps per-trigger state so that symbol exits are detected.
+fn fired<W: Write>(
+    triggers: &[Trigger],
+    inside: &mut [bool],
+    machine: &Machine<W>,
+    instruction: &Instruction,
+) -> bool {
+    let pc = machine.pc;
+    let mut any = false;
+    for (trigger, inside) in triggers.iter().zip(inside) {
+        any |= match trigger {
+            Trigger::Pc(range) => range.contains(&pc),
+            Trigger::SymbolEntry(name) => machine
+                .symbols
+                .get(name)
+                .is_some_and(|symbol| symbol.addr == pc),
+            Trigger::SymbolExit(name) => {
+                let now = machine
+                    .symbols
+                    .resolve(pc)
+                    .is_some_and(|(symbol, _)| &symbol.name == name);
+                let left = *inside && !now;
+                *inside = now;
+                left
+            }
+            Trigger::After(count) => machine.retired >= *count,
+            Trigger::Syscall(number) => {
+                instruction.opcode == Opcode::ECall
+                    && number.is_none_or(|number| machine.regs.get(&RegisterID::A7) == number)
+            }
+        };
+    }
+    any
+}
+
+impl<W: Write> Machine<W> {
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        if tracer.effects && !self.recording_effects() {
+            self.record_effects(true);
+        }
+        self.tracer = Some(tracer);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    pub(crate) fn trace_instruction(&mut self, instruction: &Instruction) {
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.record(self, instruction);
+            self.tracer = Some(tracer);
+        }
+    }
+
+    pub(crate) fn trace_effect(&mut self) {
+        let Some(mut tracer) = self.tracer.take() else {
+            return;
+        };
+        if let Some(mut line) = tracer.pending.take() {
+            let changes = self
+                .last_effect()
+                .map(|effect| effect.describe(&self.memory_map));
+            if let Some(changes) = changes.filter(|changes| !changes.is_empty()) {
+                line.push_str(&format!("  ; {changes}"));
+            }
+            tracer.write_line(&line);
+        }
+        self.tracer = Some(tracer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{symbols::SymbolTable, Memory, Registers};
+    use claims::assert_ok;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedSink {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone())
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    // Six Adds bumping a0 (two in `main`, two in `work`, two more in
+    // `main`), a formatting syscall and an EBreak.
+    fn machine() -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        for i in 0..6 {
+            mem.write(i * 4, &[0b0000_0000, 0b0000_0010, 0b0000_0010, 0b0010_0010]);
+        }
+        mem.write(24, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_0111]);
+        mem.write(28, &[0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0001_1000]);
+        Machine {
+            mem,
+            regs: Registers::from([(RegisterID::A1, 0x100), (RegisterID::A7, 257)]),
+            symbols: SymbolTable::from([("main", 0, 0), ("work", 8, 8), ("tail", 16, 0)]),
+            ..Default::default()
+        }
+    }
+
+    fn trace(tracer: impl FnOnce(Tracer) -> Tracer) -> Vec<String> {
+        let sink = SharedSink::default();
+        let mut machine = machine();
+        machine.set_tracer(tracer(Tracer::new(sink.clone())));
+        assert_ok!(machine.run());
+        sink.lines()
+    }
+
+    #[test]
+    fn tracing_without_triggers_logs_every_instruction() {
+        let lines = trace(|tracer| tracer);
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "0x00000000: add a0, a0, x0, 1");
+        assert_eq!(lines[7], "0x0000001c: ebreak");
+    }
+
+    #[test]
+    fn traced_lines_can_show_effects() {
+        let lines = trace(Tracer::show_effects);
+        assert_eq!(
+            lines[0],
+            "0x00000000: add a0, a0, x0, 1  ; a0 = 0x1 (was 0x0)"
+        );
+        assert_eq!(lines[6], "0x00000018: ecall  ; a0 = 0x1 (was 0x6)");
+        assert_eq!(lines[7], "0x0000001c: ebreak");
+    }
+
+    #[test]
+    fn traced_lines_name_the_regions_they_touch() {
+        let sink = SharedSink::default();
+        let mut machine: Machine<Vec<u8>> = Machine::builder()
+            .program(&crate::asm::assemble("sw a0, 0x3ffc\nebreak").unwrap())
+            .region("code", 0, 8)
+            .region("stack", 0x3000, 0x1000)
+            .build()
+            .unwrap();
+        machine.set_tracer(Tracer::new(sink.clone()).show_effects());
+        assert_ok!(machine.run());
+
+        assert_eq!(
+            sink.lines()[0],
+            "0x00000000 (code+0x0): sw a0, 0x3ffc  ; mem[0x3ffc] (stack+0xffc) = [00, 00, 00, 00]"
+        );
+    }
+
+    #[test]
+    fn triggers_limit_tracing_to_a_region() {
+        struct TestCase {
+            tracer: fn(Tracer) -> Tracer,
+            want: Vec<Address>,
+        }
+        let cases = [
+            TestCase {
+                tracer: |t| t.start_on(Trigger::Pc(4..12)).stop_on(Trigger::Pc(12..16)),
+                want: vec![4, 8],
+            },
+            TestCase {
+                tracer: |t| {
+                    t.start_on(Trigger::SymbolEntry("work".to_string()))
+                        .stop_on(Trigger::SymbolExit("work".to_string()))
+                },
+                want: vec![8, 12],
+            },
+            TestCase {
+                tracer: |t| t.start_on(Trigger::After(5)),
+                want: vec![20, 24, 28],
+            },
+            TestCase {
+                tracer: |t| t.start_on(Trigger::Syscall(Some(257))),
+                want: vec![24, 28],
+            },
+            TestCase {
+                tracer: |t| t.start_on(Trigger::Syscall(Some(64))),
+                want: vec![],
+            },
+            TestCase {
+                tracer: |t| t.filter("a0 % 2 == 1".parse().unwrap()),
+                want: vec![4, 12, 20, 28],
+            },
+        ];
+        for case in cases {
+            let got: Vec<Address> = trace(case.tracer)
+                .iter()
+                .map(|line| Address::from_str_radix(&line[2..10], 16).unwrap())
+                .collect();
+            assert_eq!(got, case.want);
+        }
+    }
+}
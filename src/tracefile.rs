@@ -0,0 +1,516 @@
+use crate::{effect::Effect, Address, Error, Machine, RegisterID, Result, Word};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+};
+
+const MAGIC: &[u8; 4] = b"RTR1";
+
+const PC: u8 = 0x01;
+const NEXT_PC: u8 = 0x02;
+const COUNT_FOLLOWS: u8 = 3;
+
+// One retired instruction of a full trace: where it was, where execution
+// went next, and the registers and memory it changed, in the order it
+// changed them.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct TraceRecord {
+    pub pc: Address,
+    pub next_pc: Address,
+    pub regs: Vec<(RegisterID, Word)>,
+    pub mem: Vec<(Address, Vec<u8>)>,
+}
+
+impl From<&Effect> for TraceRecord {
+    fn from(effect: &Effect) -> Self {
+        Self {
+            pc: effect.pc,
+            next_pc: effect.next_pc,
+            regs: effect
+                .regs
+                .iter()
+                .map(|write| (write.reg, write.new))
+                .collect(),
+            mem: effect
+                .mem
+                .iter()
+                .map(|write| (write.addr, write.new.clone()))
+                .collect(),
+        }
+    }
+}
+
+// The same form the tracer gives effects, so that text dumps of two traces
+// can be compared with diff.
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut changes: Vec<String> = self
+            .regs
+            .iter()
+            .map(|(reg, value)| format!("{reg} = {value:#x}"))
+            .collect();
+        changes.extend(
+            self.mem
+                .iter()
+                .map(|(addr, data)| format!("mem[{addr:#x}] = {data:02x?}")),
+        );
+        if self.next_pc != self.pc.wrapping_add(4) {
+            changes.push(format!("pc = {:#x}", self.next_pc));
+        }
+        write!(f, "{:#010x}:", self.pc)?;
+        if !changes.is_empty() {
+            write!(f, " {}", changes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// What writer and reader both track so that records need only hold what
+// the other side cannot work out: the pc the previous record went to, the
+// distinct pcs seen so far, the last value written to each register and
+// the last address written to.
+#[derive(Debug, Default)]
+struct Context {
+    next: Address,
+    pcs: Vec<Address>,
+    regs: [Word; 16],
+    addr: Address,
+}
+
+fn zigzag(delta: Word) -> u64 {
+    let delta = delta as i32;
+    u64::from(((delta << 1) ^ (delta >> 31)) as u32)
+}
+
+fn unzigzag(value: u64) -> Word {
+    let value = value as u32;
+    (value >> 1) ^ (value & 1).wrapping_neg()
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Writes a full trace, one record per retired instruction, in a format
+// that usually takes a few bytes per instruction:
+//
+//   magic     "RTR1"
+//   records, until the end of the file, each:
+//     tag       u8: 0x01 the pc is given, rather than being where the
+//               previous record went; 0x02 the next pc is given, rather
+//               than being the following instruction; bits 2-3 and 4-5
+//               the number of register and memory writes, with 3 meaning
+//               a varint count follows
+//     pc        if given, a pc reference
+//     next pc   if given, a pc reference
+//     register writes, each: u8 register id, then a varint delta from
+//               the last value the trace wrote to the register
+//     memory writes, each: varint delta from the last address written,
+//               varint length, then the bytes written
+//
+// Varints are LEB128, and deltas are zigzag encoded. A pc reference is a
+// varint holding either twice the index of a pc seen before, or a delta
+// from the record's pc (the previous record's next pc, for the pc itself)
+// times two plus one, which adds the pc to the dictionary.
+pub struct TraceWriter<W: Write> {
+    sink: W,
+    context: Context,
+    index: HashMap<Address, u64>,
+    buf: Vec<u8>,
+    records: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> fmt::Debug for TraceWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceWriter")
+            .field("records", &self.records)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut sink: W) -> io::Result<Self> {
+        sink.write_all(MAGIC)?;
+        Ok(Self {
+            sink,
+            context: Context::default(),
+            index: HashMap::new(),
+            buf: Vec::new(),
+            records: 0,
+            error: None,
+        })
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    fn pc_ref(&mut self, pc: Address, from: Address) {
+        if let Some(&index) = self.index.get(&pc) {
+            put_varint(&mut self.buf, index << 1);
+        } else {
+            self.index.insert(pc, self.context.pcs.len() as u64);
+            self.context.pcs.push(pc);
+            put_varint(&mut self.buf, zigzag(pc.wrapping_sub(from)) << 1 | 1);
+        }
+    }
+
+    pub fn write(&mut self, effect: &Effect) -> io::Result<()> {
+        let count = |len: usize| (len as u8).min(COUNT_FOLLOWS);
+        let mut tag = count(effect.regs.len()) << 2 | count(effect.mem.len()) << 4;
+        if effect.pc != self.context.next {
+            tag |= PC;
+        }
+        if effect.next_pc != effect.pc.wrapping_add(4) {
+            tag |= NEXT_PC;
+        }
+        self.buf.clear();
+        self.buf.push(tag);
+        if tag & PC != 0 {
+            self.pc_ref(effect.pc, self.context.next);
+        }
+        if tag & NEXT_PC != 0 {
+            self.pc_ref(effect.next_pc, effect.pc);
+        }
+        if effect.regs.len() >= COUNT_FOLLOWS.into() {
+            put_varint(&mut self.buf, effect.regs.len() as u64);
+        }
+        if effect.mem.len() >= COUNT_FOLLOWS.into() {
+            put_varint(&mut self.buf, effect.mem.len() as u64);
+        }
+        for write in &effect.regs {
+            let last = &mut self.context.regs[write.reg as usize];
+            self.buf.push(write.reg as u8);
+            put_varint(&mut self.buf, zigzag(write.new.wrapping_sub(*last)));
+            *last = write.new;
+        }
+        for write in &effect.mem {
+            put_varint(
+                &mut self.buf,
+                zigzag(write.addr.wrapping_sub(self.context.addr)),
+            );
+            put_varint(&mut self.buf, write.new.len() as u64);
+            self.buf.extend(&write.new);
+            self.context.addr = write.addr;
+        }
+        self.context.next = effect.next_pc;
+        self.records += 1;
+        self.sink.write_all(&self.buf)
+    }
+
+    // Writes the effect unless an earlier write failed, keeping the first
+    // failure for `finish`, since a trace with a record missing cannot be
+    // read past it.
+    pub(crate) fn record(&mut self, effect: &Effect) {
+        if self.error.is_none() {
+            self.error = self.write(effect).err();
+        }
+    }
+
+    // Flushes the trace, returning the sink, or the error that stopped the
+    // trace being written.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::TraceInvalid(reason.to_string())
+}
+
+// Reads back the records of a full trace in order. Wrap files in a
+// BufReader, as the trace is read a byte at a time.
+#[derive(Debug)]
+pub struct TraceReader<R: Read> {
+    source: R,
+    context: Context,
+    done: bool,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut source: R) -> Result<Self> {
+        let mut magic = [0; 4];
+        source
+            .read_exact(&mut magic)
+            .map_err(|_| invalid("bad magic"))?;
+        if &magic != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        Ok(Self {
+            source,
+            context: Context::default(),
+            done: false,
+        })
+    }
+
+    // The next byte, or None at the end of the trace.
+    fn next_byte(&mut self) -> Result<Option<u8>> {
+        let mut byte = [0];
+        loop {
+            return match self.source.read(&mut byte) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(byte[0])),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(invalid(&err.to_string())),
+            };
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        self.next_byte()?.ok_or_else(|| invalid("truncated"))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    fn count(&mut self, count: u8) -> Result<usize> {
+        match count {
+            COUNT_FOLLOWS => Ok(self.varint()? as usize),
+            count => Ok(count.into()),
+        }
+    }
+
+    fn pc_ref(&mut self, from: Address) -> Result<Address> {
+        let value = self.varint()?;
+        if value & 1 == 0 {
+            return self
+                .context
+                .pcs
+                .get((value >> 1) as usize)
+                .copied()
+                .ok_or_else(|| invalid("unknown pc index"));
+        }
+        let pc = from.wrapping_add(unzigzag(value >> 1));
+        self.context.pcs.push(pc);
+        Ok(pc)
+    }
+
+    fn record(&mut self, tag: u8) -> Result<TraceRecord> {
+        if tag & 0xc0 != 0 {
+            return Err(invalid("unknown tag"));
+        }
+        let pc = if tag & PC != 0 {
+            self.pc_ref(self.context.next)?
+        } else {
+            self.context.next
+        };
+        let next_pc = if tag & NEXT_PC != 0 {
+            self.pc_ref(pc)?
+        } else {
+            pc.wrapping_add(4)
+        };
+        let regs = self.count(tag >> 2 & 3)?;
+        let mem = self.count(tag >> 4 & 3)?;
+        let mut record = TraceRecord {
+            pc,
+            next_pc,
+            ..Default::default()
+        };
+        for _ in 0..regs {
+            let reg = RegisterID::try_from(Word::from(self.byte()?))
+                .map_err(|_| invalid("unknown register"))?;
+            let delta = unzigzag(self.varint()?);
+            let last = &mut self.context.regs[reg as usize];
+            *last = last.wrapping_add(delta);
+            record.regs.push((reg, *last));
+        }
+        for _ in 0..mem {
+            let addr = self.context.addr.wrapping_add(unzigzag(self.varint()?));
+            let len = self.varint()?;
+            let mut data = Vec::new();
+            (&mut self.source)
+                .take(len)
+                .read_to_end(&mut data)
+                .map_err(|err| invalid(&err.to_string()))?;
+            if data.len() as u64 != len {
+                return Err(invalid("truncated"));
+            }
+            record.mem.push((addr, data));
+            self.context.addr = addr;
+        }
+        self.context.next = next_pc;
+        Ok(record)
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = match self.next_byte() {
+            Ok(Some(tag)) => self.record(tag),
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(err) => Err(err),
+        };
+        self.done = record.is_err();
+        Some(record)
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Writes a full trace of every instruction retired from now on to
+    // `sink`.
+    pub fn record_trace(&mut self, sink: impl Write + 'static) -> io::Result<()> {
+        let writer: Box<dyn Write> = Box::new(sink);
+        self.trace_writer = Some(TraceWriter::new(writer)?);
+        if !self.recording_effects() {
+            self.record_effects(true);
+        }
+        Ok(())
+    }
+
+    // Stops recording, returning the writer to finish.
+    pub fn take_trace_writer(&mut self) -> Option<TraceWriter<Box<dyn Write>>> {
+        self.trace_writer.take()
+    }
+
+    pub(crate) fn write_trace_record(&mut self) {
+        if let (Some(writer), Some(effect)) = (&mut self.trace_writer, self.effects.last()) {
+            writer.record(effect);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::assemble,
+        effect::{MemoryWrite, RegisterWrite},
+        output::SharedBuffer,
+        StepOutcome,
+    };
+    use claims::{assert_err_eq, assert_ok, assert_ok_eq};
+
+    fn read(bytes: &[u8]) -> Result<Vec<TraceRecord>> {
+        TraceReader::new(bytes)?.collect()
+    }
+
+    #[test]
+    fn runs_read_back_as_they_were_recorded() {
+        let program = assemble(
+            "
+                    li a1, 1
+                    li a2, 100
+            loop:   add a0, a0, a1
+                    sw a0, 0x100(x0)
+                    blt a0, a2, loop
+                    ebreak
+            ",
+        )
+        .unwrap();
+        let trace = SharedBuffer::new();
+        let mut machine: Machine<Vec<u8>> = Machine::builder().program(&program).build().unwrap();
+        assert_ok!(machine.record_trace(trace.clone()));
+        let mut recorded = Vec::new();
+        loop {
+            let (_, outcome) = machine.step().unwrap();
+            recorded.push(TraceRecord::from(machine.last_effect().unwrap()));
+            if outcome != StepOutcome::Continue {
+                break;
+            }
+        }
+        assert_ok!(machine.take_trace_writer().unwrap().finish());
+
+        let bytes = trace.contents();
+        assert_ok_eq!(read(&bytes), recorded);
+        assert_eq!(recorded.len(), 2 + 100 * 3 + 1);
+        // Four bytes an instruction, stored words included, where writing
+        // out each pc and value in full would take at least eight.
+        assert!(bytes.len() <= 4 * recorded.len(), "{} bytes", bytes.len());
+    }
+
+    #[test]
+    fn jumps_and_long_records_survive_the_round_trip() {
+        let write = |reg, old, new| RegisterWrite { reg, old, new };
+        let effects = [
+            Effect {
+                pc: 0x1000,
+                next_pc: 0x2000,
+                regs: vec![write(RegisterID::SP, 0xfffc, 0xfff0)],
+                ..Default::default()
+            },
+            Effect {
+                pc: 0x1000,
+                next_pc: 0x1004,
+                regs: (1..=4)
+                    .map(|id| write(RegisterID::try_from(id).unwrap(), 0, id))
+                    .collect(),
+                ..Default::default()
+            },
+            Effect {
+                pc: 0x2000,
+                next_pc: 0x1000,
+                mem: vec![MemoryWrite {
+                    addr: 0xfff0,
+                    old: vec![0; 2],
+                    new: vec![1, 2],
+                }],
+                ..Default::default()
+            },
+        ];
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        for effect in &effects {
+            assert_ok!(writer.write(effect));
+        }
+
+        let records = read(&writer.finish().unwrap()).unwrap();
+
+        assert_eq!(
+            records,
+            effects.iter().map(TraceRecord::from).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            records[2].to_string(),
+            "0x00002000: mem[0xfff0] = [01, 02], pc = 0x1000"
+        );
+    }
+
+    #[test]
+    fn malformed_traces_are_rejected() {
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        let effect = Effect {
+            pc: 0x40,
+            next_pc: 0x44,
+            ..Default::default()
+        };
+        writer.write(&effect).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        assert_err_eq!(read(b"nope"), invalid("bad magic"));
+        assert_err_eq!(read(&bytes[..bytes.len() - 1]), invalid("truncated"));
+        assert_err_eq!(
+            read(&[&MAGIC[..], &[0x40]].concat()),
+            invalid("unknown tag")
+        );
+        assert_err_eq!(
+            read(&[&MAGIC[..], &[PC, 2]].concat()),
+            invalid("unknown pc index")
+        );
+    }
+}
@@ -0,0 +1,329 @@
+use crate::{
+    disasm::mnemonic, memmap::MemoryMap, uninit::Uninitialized, Address, Error, Machine, Opcode,
+    Result, Word,
+};
+use std::{fmt, io::Write};
+
+// The most a syscall copies between guest memory and the host at once.
+// Reads and writes asked for more transfer this much, as they may be
+// short anyway; other syscalls given a larger buffer trap.
+pub const MAX_TRANSFER: usize = 1 << 20;
+
+// Ways a guest can misbehave that stop the machine. They come back from
+// step and run as Error::Trap, with the pc of the instruction that caused
+// them, so that an embedder can report them and carry on rather than the
+// host process going down with the guest.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Trap {
+    // The word fetched is not an instruction.
+    IllegalInstruction {
+        word: Word,
+    },
+    // The word fetched sets a field its instruction ignores, with strict
+    // decoding on.
+    NonCanonicalInstruction {
+        word: Word,
+        field: &'static str,
+    },
+    // ecall with a number in a7 that is not a syscall.
+    UnknownSyscall {
+        number: Word,
+    },
+    InvalidSyscallArgument {
+        number: Word,
+        reason: &'static str,
+    },
+    // The host failed to pass on output the guest had already been told
+    // was written.
+    IoError {
+        message: String,
+    },
+    // A load or store reaching the memory limit.
+    MemoryFault {
+        addr: Address,
+        len: usize,
+    },
+    // Fetching the `len` byte instruction at `addr` ran past the end of the
+    // address space, or of the memory limit.
+    FetchFault {
+        addr: Address,
+        len: usize,
+    },
+    // A read of a register or memory nothing has written, with the check
+    // for them on.
+    UninitializedRead {
+        value: Uninitialized,
+    },
+    // The heap's break and the stack pointer crossed, growing either one
+    // into the other.
+    HeapStackCollision {
+        brk: Address,
+        sp: Address,
+    },
+    // With the overflow trap on, an add, sub or mul whose signed result
+    // does not fit in a word.
+    ArithmeticOverflow {
+        opcode: Opcode,
+        lhs: Word,
+        rhs: Word,
+    },
+    // With the image check on, the run executed code from parts of the
+    // program image it had written over.
+    ImageModified {
+        modified: Vec<(Address, usize)>,
+    },
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.describe(&MemoryMap::default()))
+    }
+}
+
+impl Trap {
+    // The trap's message, with the addresses in it placed in the regions of `map`.
+    pub fn describe(&self, map: &MemoryMap) -> String {
+        let at = |addr: &Address| format!("{addr:#010x}{}", map.annotate(*addr));
+        match self {
+            Trap::IllegalInstruction { word } => format!("illegal instruction {word:#010x}"),
+            Trap::NonCanonicalInstruction { word, field } => {
+                format!("non-canonical instruction {word:#010x}: {field} is not zero")
+            }
+            Trap::UnknownSyscall { number } => format!("unknown syscall {number}"),
+            Trap::InvalidSyscallArgument { number, reason } => {
+                format!("syscall {number} given {reason}")
+            }
+            Trap::IoError { message } => format!("I/O error: {message}"),
+            Trap::MemoryFault { addr, len } => {
+                format!(
+                    "{len} byte access at {} runs past the end of memory",
+                    at(addr)
+                )
+            }
+            Trap::FetchFault { addr, len } => {
+                format!(
+                    "{len} byte fetch at {} runs past the end of memory",
+                    at(addr)
+                )
+            }
+            Trap::UninitializedRead { value } => format!("read of uninitialized {value}"),
+            Trap::HeapStackCollision { brk, sp } => format!(
+                "heap and stack collide, with the break at {} and sp at {}",
+                at(brk),
+                at(sp)
+            ),
+            Trap::ArithmeticOverflow { opcode, lhs, rhs } => format!(
+                "{} of {} and {} overflows a word",
+                mnemonic(opcode),
+                *lhs as i32,
+                *rhs as i32
+            ),
+            Trap::ImageModified { modified } => {
+                let mut message = "executed code modified since it was loaded:".to_string();
+                for (i, (addr, len)) in modified.iter().enumerate() {
+                    let sep = if i == 0 { " " } else { ", " };
+                    let end = u64::from(*addr) + *len as u64;
+                    message.push_str(&format!("{sep}{}..{end:#010x}", at(addr)));
+                }
+                message
+            }
+        }
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // A trap raised by the instruction at pc.
+    pub(crate) fn trap(&self, trap: Trap) -> Error {
+        Error::Trap { pc: self.pc, trap }
+    }
+
+    // Confines the guest to the memory below `limit`: loads and stores
+    // reaching it trap with MemoryFault, fetches with FetchFault, and
+    // syscalls given buffers reaching it with InvalidSyscallArgument. None
+    // gives the guest the whole address space again.
+    pub fn set_memory_limit(&mut self, limit: Option<Address>) {
+        self.memory_limit = limit;
+    }
+
+    pub fn memory_limit(&self) -> Option<Address> {
+        self.memory_limit
+    }
+
+    // Whether the `len` bytes from `addr` lie within the memory the guest
+    // may use.
+    pub(crate) fn within_memory(&self, addr: Address, len: u64) -> bool {
+        u64::from(addr) + len <= self.memory_limit.map_or(1 << 32, u64::from)
+    }
+
+    // Traps if the instruction at pc would load or store memory beyond the
+    // limit. Without one, accesses wrap around the top of memory.
+    pub(crate) fn check_data_access(&self, access: Option<(Address, usize)>) -> Result<()> {
+        match access {
+            Some((addr, len))
+                if self.memory_limit.is_some() && !self.within_memory(addr, len as u64) =>
+            {
+                Err(self.trap(Trap::MemoryFault { addr, len }))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // A trap raised by the ecall just executed.
+    pub(crate) fn syscall_trap(&self, trap: Trap) -> Error {
+        Error::Trap {
+            pc: self.pc.wrapping_sub(4),
+            trap,
+        }
+    }
+
+    // Checks a buffer the guest passed to the syscall in a7, returning its
+    // length.
+    pub(crate) fn guest_buffer(&self, addr: Address, len: u64) -> Result<usize> {
+        let invalid = |reason| {
+            let number = self.regs.get(&crate::RegisterID::A7);
+            self.syscall_trap(Trap::InvalidSyscallArgument { number, reason })
+        };
+        if len > MAX_TRANSFER as u64 {
+            return Err(invalid("a buffer larger than the transfer limit"));
+        }
+        if !self.within_memory(addr, len) {
+            return Err(invalid("a buffer running past the end of memory"));
+        }
+        Ok(len as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::machine, HaltReason, RegisterID};
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    // Sets `reg` to 2 MiB, more than a syscall will transfer.
+    fn two_mib(reg: &str) -> String {
+        format!(
+            "li {reg}, 0x4000\n{}",
+            format!("add {reg}, {reg}, {reg}\n").repeat(7)
+        )
+    }
+
+    #[test]
+    fn guest_misbehaviour_traps_with_the_pc_it_happened_at() {
+        let cases = [
+            (
+                ".word 0".to_string(),
+                0,
+                Trap::IllegalInstruction { word: 0 },
+            ),
+            (
+                "li a7, 7\necall".to_string(),
+                4,
+                Trap::UnknownSyscall { number: 7 },
+            ),
+            (
+                format!("{}li a7, 259\necall", two_mib("a1")),
+                36,
+                Trap::InvalidSyscallArgument {
+                    number: 259,
+                    reason: "a buffer larger than the transfer limit",
+                },
+            ),
+        ];
+        for (program, pc, trap) in cases {
+            assert_err_eq!(machine(&program).run(), Error::Trap { pc, trap });
+        }
+    }
+
+    #[test]
+    fn syscalls_check_the_buffers_they_fill() {
+        let cases = [
+            ("li a0, -1\nli a1, -4\nli a2, 16\nli a7, 257", 16, 257),
+            ("li a0, 0\nli a1, -4\nli a7, 113", 12, 113),
+            ("li a0, -4\nli a1, 64\nli a7, 265", 12, 265),
+            ("li a0, -4\nli a7, 273", 8, 273),
+        ];
+        for (setup, pc, number) in cases {
+            assert_err_eq!(
+                machine(&format!("{setup}\necall\nebreak")).run(),
+                Error::Trap {
+                    pc,
+                    trap: Trap::InvalidSyscallArgument {
+                        number,
+                        reason: "a buffer running past the end of memory",
+                    },
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn buffers_may_not_wrap_around_memory() {
+        let machine = machine("ebreak");
+
+        assert_ok_eq!(machine.guest_buffer(0xffff_fff0, 16), 16);
+        assert_err_eq!(
+            machine.guest_buffer(0xffff_fff0, 17),
+            Error::Trap {
+                pc: 0xffff_fffc,
+                trap: Trap::InvalidSyscallArgument {
+                    number: 0,
+                    reason: "a buffer running past the end of memory",
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn fetching_past_the_end_of_memory_faults() {
+        let mut machine = machine("ebreak");
+        machine.pc = 0xffff_fffe;
+
+        assert_err_eq!(
+            machine.step(),
+            Error::Trap {
+                pc: 0xffff_fffe,
+                trap: Trap::FetchFault {
+                    addr: 0xffff_fffe,
+                    len: 4,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn large_reads_and_writes_are_short() {
+        let mut machine = machine(&format!(
+            "li a0, 1\n{}li a7, 64\necall\nebreak",
+            two_mib("a2")
+        ));
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+
+        assert_eq!(machine.register(RegisterID::A0), MAX_TRANSFER as Word);
+    }
+
+    #[test]
+    fn output_the_host_cannot_pass_on_traps() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut machine = machine("li a0, 1\nli a2, 1\nli a7, 64\necall\nebreak");
+        machine.add_stdout_sink(Broken);
+
+        assert_err_eq!(
+            machine.run(),
+            Error::Trap {
+                pc: 12,
+                trap: Trap::IoError {
+                    message: "disk full".to_string(),
+                },
+            }
+        );
+    }
+}
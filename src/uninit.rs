@@ -0,0 +1,241 @@
+use crate::{
+    heap::AccessKind,
+    semantics::memory_access,
+    shadow::ShadowMemory,
+    verify::{reads, writes},
+    Address, Instruction, Machine, RegisterID, Result, Trap,
+};
+use std::{fmt, io::Write};
+
+// What to do about a read of a value nothing has written.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UninitCheck {
+    // Record the read and carry on with the zero the machine holds.
+    Warn,
+    // Stop with Trap::UninitializedRead before the instruction runs.
+    Trap,
+}
+
+// A value read before anything wrote it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Uninitialized {
+    Register(RegisterID),
+    Memory(Address),
+}
+
+impl fmt::Display for Uninitialized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Uninitialized::Register(reg) => write!(f, "{reg}"),
+            Uninitialized::Memory(addr) => write!(f, "memory at {addr:#010x}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct UninitializedRead {
+    pub pc: Address,
+    pub value: Uninitialized,
+}
+
+impl fmt::Display for UninitializedRead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pc {:#010x} reads uninitialized {}", self.pc, self.value)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct UninitTracker {
+    check: UninitCheck,
+    // The registers written so far, one bit each.
+    regs: u16,
+    // Whether each byte of memory has been written.
+    mem: ShadowMemory<bool>,
+    reads: Vec<UninitializedRead>,
+}
+
+impl UninitTracker {
+    fn has_reg(&self, reg: RegisterID) -> bool {
+        self.regs & 1 << reg as u16 != 0
+    }
+}
+
+impl<W: Write> Machine<W> {
+    // Watches for instructions reading registers or memory that nothing has
+    // written, which would otherwise quietly read as zero. Registers the
+    // loader or the embedder set, x0, and memory on pages the machine
+    // already holds, such as the program image, count as written; the rest
+    // must be written by an instruction or a syscall first. Each value is
+    // reported once, and only instructions' reads are checked, not the
+    // buffers syscalls read. None stops watching.
+    pub fn set_uninit_check(&mut self, check: Option<UninitCheck>) {
+        self.uninit = check.map(|check| {
+            let mut mem = ShadowMemory::new();
+            for page in self.mem.pages() {
+                mem.fill(page, self.mem.page_size as usize, true);
+            }
+            UninitTracker {
+                check,
+                regs: self
+                    .regs
+                    .inner
+                    .keys()
+                    .fold(1, |bits, reg| bits | 1 << *reg as u16),
+                mem,
+                reads: Vec::new(),
+            }
+        });
+    }
+
+    // The reads recorded with UninitCheck::Warn, in the order they happened.
+    pub fn uninitialized_reads(&self) -> &[UninitializedRead] {
+        self.uninit
+            .as_ref()
+            .map_or(&[], |tracker| tracker.reads.as_slice())
+    }
+
+    // Checks what the instruction at pc is about to read, then notes what it
+    // writes.
+    pub(crate) fn check_uninitialized(&mut self, instruction: &Instruction) -> Result<()> {
+        let Some(tracker) = &self.uninit else {
+            return Ok(());
+        };
+        let access = memory_access(instruction, &self.regs);
+        let mut found: Vec<Uninitialized> = reads(instruction)
+            .into_iter()
+            .filter(|reg| !tracker.has_reg(*reg))
+            .map(Uninitialized::Register)
+            .collect();
+        found.dedup();
+        if let Some((addr, len, AccessKind::Read)) = access {
+            found.extend(
+                tracker
+                    .mem
+                    .range(addr, len)
+                    .position(|written| !written)
+                    .map(|offset| Uninitialized::Memory(addr.wrapping_add(offset as Address))),
+            );
+        }
+        if let (UninitCheck::Trap, Some(&value)) = (tracker.check, found.first()) {
+            return Err(self.trap(Trap::UninitializedRead { value }));
+        }
+        let pc = self.pc;
+        let Some(tracker) = &mut self.uninit else {
+            return Ok(());
+        };
+        tracker
+            .reads
+            .extend(found.iter().map(|&value| UninitializedRead { pc, value }));
+        for reg in reads(instruction).into_iter().chain(writes(instruction)) {
+            tracker.regs |= 1 << reg as u16;
+        }
+        if let Some((addr, len, _)) = access {
+            tracker.mem.fill(addr, len, true);
+        }
+        Ok(())
+    }
+
+    // Notes a register or memory write made by a syscall.
+    pub(crate) fn initialize_register(&mut self, reg: RegisterID) {
+        if let Some(tracker) = &mut self.uninit {
+            tracker.regs |= 1 << reg as u16;
+        }
+    }
+
+    pub(crate) fn initialize_memory(&mut self, addr: Address, len: usize) {
+        if let Some(tracker) = &mut self.uninit {
+            tracker.mem.fill(addr, len, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, Error, HaltReason};
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    fn machine(program: &str, check: UninitCheck) -> Machine<Vec<u8>> {
+        let mut machine = Machine::builder()
+            .program(&assemble(program).unwrap())
+            .build()
+            .unwrap();
+        machine.set_uninit_check(Some(check));
+        machine
+    }
+
+    #[test]
+    fn reading_a_register_nothing_wrote_traps() {
+        let mut machine = machine("li a2, 1\nadd a3, a2, a4\nebreak", UninitCheck::Trap);
+
+        assert_err_eq!(
+            machine.run(),
+            Error::Trap {
+                pc: 4,
+                trap: Trap::UninitializedRead {
+                    value: Uninitialized::Register(RegisterID::A4),
+                },
+            }
+        );
+        // The instruction did not run.
+        assert_eq!(machine.register(RegisterID::A3), 0);
+    }
+
+    #[test]
+    fn warnings_report_each_value_once() {
+        let mut machine = machine(
+            "add a2, a5, a5
+             add a2, a5, x0
+             sw a2, 0x2000
+             lw a3, 0x2000
+             lw a4, 0x2004
+             lw a4, 0x2004
+             lw a6, 0(sp)
+             ebreak",
+            UninitCheck::Warn,
+        );
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        let sp = machine.register(RegisterID::SP);
+        assert_eq!(
+            machine.uninitialized_reads(),
+            [
+                UninitializedRead {
+                    pc: 0,
+                    value: Uninitialized::Register(RegisterID::A5),
+                },
+                UninitializedRead {
+                    pc: 16,
+                    value: Uninitialized::Memory(0x2004),
+                },
+                UninitializedRead {
+                    pc: 24,
+                    value: Uninitialized::Memory(sp),
+                },
+            ]
+        );
+        assert_eq!(
+            machine.uninitialized_reads()[1].to_string(),
+            "pc 0x00000010 reads uninitialized memory at 0x00002004"
+        );
+    }
+
+    #[test]
+    fn syscalls_and_the_image_initialize_what_they_write() {
+        let mut machine = machine(
+            "li a0, 0
+             li a1, 0x2000
+             li a7, 113
+             ecall
+             lw a2, 0x2008
+             lw a3, 0(a1)
+             lw a4, 32
+             ebreak
+             .word 7",
+            UninitCheck::Trap,
+        );
+
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+        assert_eq!(machine.register(RegisterID::A4), 7);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::{
+    disasm::mnemonic, state, strict::unused_fields, Address, Error, Instruction, Machine, Memory,
+    Opcode, RegisterID, Registers, Result, Word,
+};
+use std::fmt;
+
+// Where each vector's instruction is fetched from, and where the memory its
+// loads and stores use starts.
+const PC: Address = 0x1000;
+const DATA: Address = 0x2000;
+
+// How many vectors each opcode gets.
+const PER_OPCODE: usize = 8;
+
+// Operand values that find the usual mistakes, tried before random ones.
+const EDGES: [Word; 6] = [0, 1, Word::MAX, 0x8000_0000, 0x7fff_ffff, 0x0000_8000];
+
+const HEADER: &str = "rmachine-vectors 1";
+
+// The state a vector starts from or must end in: the pc, the registers that
+// are not zero, and chosen ranges of memory.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct VectorState {
+    pub pc: Address,
+    pub regs: Vec<(RegisterID, Word)>,
+    pub mem: Vec<(Address, Vec<u8>)>,
+}
+
+// A single instruction's worth of behaviour to check an implementation
+// against: put the machine in the initial state, with the instruction word
+// at the pc, execute one instruction, and compare with the expected state.
+// Registers the expected state leaves out must be zero.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TestVector {
+    pub name: String,
+    pub word: Word,
+    pub initial: VectorState,
+    pub expected: VectorState,
+}
+
+impl TestVector {
+    // Runs the vector on a fresh machine, returning how the outcome differs
+    // from the expected state; nothing if it passes.
+    pub fn check(&self) -> Vec<String> {
+        let machine = self.execute();
+        let mut differences = Vec::new();
+        if machine.pc != self.expected.pc {
+            differences.push(format!(
+                "pc is {:#010x}, not {:#010x}",
+                machine.pc, self.expected.pc
+            ));
+        }
+        for id in 0..16 {
+            let reg = state::register(id);
+            let want = self
+                .expected
+                .regs
+                .iter()
+                .find(|(expected, _)| *expected == reg)
+                .map_or(0, |(_, value)| *value);
+            let got = machine.regs.get(&reg);
+            if got != want {
+                differences.push(format!("{reg} is {got:#010x}, not {want:#010x}"));
+            }
+        }
+        for (addr, want) in &self.expected.mem {
+            let got = machine.mem.read(*addr, want.len());
+            if got != *want {
+                differences.push(format!(
+                    "memory at {addr:#010x} is {}, not {}",
+                    hex(&got),
+                    hex(want)
+                ));
+            }
+        }
+        differences
+    }
+
+    fn execute(&self) -> Machine<Vec<u8>> {
+        let mut mem = Memory::default();
+        for (addr, data) in &self.initial.mem {
+            mem.write(*addr, data);
+        }
+        mem.write(self.initial.pc, &self.word.to_be_bytes());
+        let mut regs = Registers::default();
+        for (reg, value) in &self.initial.regs {
+            regs.set(*reg, *value);
+        }
+        let mut machine = Machine {
+            pc: self.initial.pc,
+            mem,
+            regs,
+            ..Default::default()
+        };
+        // A vector whose word is not an instruction fails on the pc.
+        let _ = machine.step();
+        machine
+    }
+}
+
+// A small xorshift generator, so that the suite is the same every time it
+// is generated.
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    // A register other than x0.
+    fn register(&mut self) -> RegisterID {
+        state::register(1 + self.next() as usize % 15)
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+fn width(opcode: Opcode) -> Option<usize> {
+    match opcode {
+        Opcode::Lb | Opcode::Sb => Some(1),
+        Opcode::Lh | Opcode::Sh => Some(2),
+        Opcode::Lw | Opcode::Sw => Some(4),
+        _ => None,
+    }
+}
+
+fn vector(opcode: Opcode, index: usize, rng: &mut Rng) -> TestVector {
+    let mut instruction = Instruction {
+        opcode,
+        rd: rng.register(),
+        rs1: rng.register(),
+        rs2: rng.register(),
+        imm: rng.next() as u16 & 0x7fff,
+    };
+    // Every other ALU vector uses the immediate form, with rs2 as x0.
+    if index % 2 == 1 && width(opcode).is_none() {
+        instruction.rs2 = RegisterID::X0;
+    }
+    for field in unused_fields(opcode) {
+        match *field {
+            "rd" => instruction.rd = RegisterID::X0,
+            "rs1" => instruction.rs1 = RegisterID::X0,
+            "rs2" => instruction.rs2 = RegisterID::X0,
+            _ => instruction.imm = 0,
+        }
+    }
+    let mut value = |which: usize| match EDGES.get(index + which) {
+        Some(edge) if index < EDGES.len() / 2 => *edge,
+        _ => rng.next(),
+    };
+    let mut initial = VectorState {
+        pc: PC,
+        ..Default::default()
+    };
+    let (rs1, rs2) = (value(0), value(3));
+    let rd = rng.next();
+    let mut set = |reg: RegisterID, value: Word| {
+        if reg != RegisterID::X0 && value != 0 {
+            initial.regs.retain(|(set, _)| *set != reg);
+            initial.regs.push((reg, value));
+        }
+    };
+    set(instruction.rd, rd);
+    set(instruction.rs2, rs2);
+    match width(opcode) {
+        // Loads and stores address a few bytes either side of a point in
+        // the data, which starts out random.
+        Some(len) => {
+            let base = DATA + 0x40 + rng.next() % 0x40;
+            instruction.imm = (rng.next() % 0x40) as u16;
+            instruction.imm = instruction.imm.wrapping_sub(0x20) & 0x7fff;
+            set(instruction.rs1, base);
+            let addr = base.wrapping_add_signed(instruction.offset());
+            initial.mem.push((addr, rng.bytes(len)));
+        }
+        None => set(instruction.rs1, rs1),
+    }
+    initial.regs.sort_by_key(|(reg, _)| *reg as u16);
+
+    let mut vector = TestVector {
+        name: format!("{}.{index}", mnemonic(&opcode)),
+        word: instruction.encode(),
+        expected: VectorState::default(),
+        initial,
+    };
+    let machine = vector.execute();
+    vector.expected = VectorState {
+        pc: machine.pc,
+        regs: (0..16)
+            .map(state::register)
+            .map(|reg| (reg, machine.regs.get(&reg)))
+            .filter(|(_, value)| *value != 0)
+            .collect(),
+        mem: vector
+            .initial
+            .mem
+            .iter()
+            .map(|(addr, data)| (*addr, machine.mem.read(*addr, data.len())))
+            .collect(),
+    };
+    vector
+}
+
+// The suite of vectors for every instruction but ecall and mret, whose
+// effects depend on the environment rather than the instruction alone. It
+// is generated the same way every time, so the copy shipped in
+// vectors/isa.txt can be checked against the machine as it is now.
+pub fn generate() -> Vec<TestVector> {
+    let mut rng = Rng(0x2545_f491);
+    Opcode::ALL
+        .iter()
+        .filter(|opcode| !matches!(opcode, Opcode::ECall | Opcode::Mret))
+        .flat_map(|opcode| {
+            (0..PER_OPCODE)
+                .map(|index| vector(*opcode, index, &mut rng))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// Writes vectors in the text format parse reads, which is simple enough to
+// load into another implementation's test harness:
+//
+//   rmachine-vectors 1
+//
+//   vector lw.0
+//   word 0x00140b35
+//   pc 0x00001000
+//   reg a4 0x00002053
+//   reg a8 0xb8802cb8
+//   mem 0x0000205d dd3496c0
+//   expect-pc 0x00001004
+//   expect-reg a4 0x00002053
+//   expect-reg a8 0xdd3496c0
+//   expect-mem 0x0000205d dd3496c0
+//
+// Each vector starts with its name; the fields after it may come in any
+// order. Numbers are hex, and memory is given as bytes in address order.
+pub fn to_text(vectors: &[TestVector]) -> String {
+    let mut text = format!("{HEADER}\n");
+    for vector in vectors {
+        text.push_str(&format!("\n{vector}"));
+    }
+    text
+}
+
+impl fmt::Display for TestVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "vector {}", self.name)?;
+        writeln!(f, "word {:#010x}", self.word)?;
+        for (prefix, state) in [("", &self.initial), ("expect-", &self.expected)] {
+            writeln!(f, "{prefix}pc {:#010x}", state.pc)?;
+            for (reg, value) in &state.regs {
+                writeln!(f, "{prefix}reg {reg} {value:#010x}")?;
+            }
+            for (addr, data) in &state.mem {
+                writeln!(f, "{prefix}mem {addr:#010x} {}", hex(data))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<TestVector>> {
+    let mut lines = text.lines().enumerate();
+    if lines.next().map(|(_, line)| line) != Some(HEADER) {
+        return Err(Error::VectorsInvalid("missing header".to_string()));
+    }
+    let mut vectors: Vec<TestVector> = Vec::new();
+    for (index, line) in lines {
+        let invalid = |reason: &str| Error::VectorsInvalid(format!("line {}: {reason}", index + 1));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let number = |text: &str| {
+            Word::from_str_radix(text.trim_start_matches("0x"), 16)
+                .map_err(|_| invalid(&format!("{text:?} is not a hex number")))
+        };
+        let (key, values) = match fields.split_first() {
+            None => continue,
+            Some((key, _)) if key.starts_with('#') => continue,
+            Some((key, values)) => (*key, values),
+        };
+        if key == "vector" {
+            let [name] = values else {
+                return Err(invalid("a vector needs a name"));
+            };
+            vectors.push(TestVector {
+                name: name.to_string(),
+                word: 0,
+                initial: VectorState::default(),
+                expected: VectorState::default(),
+            });
+            continue;
+        }
+        let vector = vectors
+            .last_mut()
+            .ok_or_else(|| invalid(&format!("{key} before the first vector")))?;
+        if let ("word", [word]) = (key, values) {
+            vector.word = number(word)?;
+            continue;
+        }
+        let (state, field) = match key.strip_prefix("expect-") {
+            Some(field) => (&mut vector.expected, field),
+            None => (&mut vector.initial, key),
+        };
+        match (field, values) {
+            ("pc", [pc]) => state.pc = number(pc)?,
+            ("reg", [reg, value]) => {
+                let reg = reg
+                    .parse()
+                    .map_err(|_| invalid(&format!("unknown register {reg:?}")))?;
+                state.regs.push((reg, number(value)?));
+            }
+            ("mem", [addr, data]) => {
+                let bytes = (0..data.len())
+                    .step_by(2)
+                    .map(|at| {
+                        data.get(at..at + 2)
+                            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    })
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(|| invalid(&format!("{data:?} is not hex bytes")))?;
+                state.mem.push((number(addr)?, bytes));
+            }
+            _ => return Err(invalid(&format!("unexpected {line:?}"))),
+        }
+    }
+    Ok(vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err_eq, assert_ok_eq};
+
+    const SHIPPED: &str = include_str!("../vectors/isa.txt");
+
+    #[test]
+    fn the_shipped_vectors_pass() {
+        let vectors = parse(SHIPPED).unwrap();
+        assert!(!vectors.is_empty());
+        for vector in &vectors {
+            assert_eq!(vector.check(), Vec::<String>::new(), "{}", vector.name);
+        }
+    }
+
+    // Regenerate with `rmachine vectors generate -o vectors/isa.txt` when
+    // instructions are added or their behaviour is meant to change.
+    #[test]
+    fn the_shipped_vectors_are_up_to_date() {
+        assert_eq!(to_text(&generate()), SHIPPED);
+        assert_ok_eq!(parse(SHIPPED), generate());
+    }
+
+    #[test]
+    fn every_instruction_but_ecall_and_mret_is_covered() {
+        let vectors = generate();
+        for opcode in Opcode::ALL {
+            let covered = vectors
+                .iter()
+                .filter(|vector| Instruction::try_from(vector.word).unwrap().opcode == opcode)
+                .count();
+            let want = match opcode {
+                Opcode::ECall | Opcode::Mret => 0,
+                _ => PER_OPCODE,
+            };
+            assert_eq!(covered, want, "{opcode:?}");
+        }
+    }
+
+    #[test]
+    fn mismatches_are_reported() {
+        let vector = parse(
+            "rmachine-vectors 1
+             vector add.wrong
+             word 0x00024222
+             pc 0x1000
+             reg a1 0x2
+             expect-pc 0x1008
+             expect-reg a0 0x4
+             expect-reg a1 0x2",
+        )
+        .unwrap()
+        .remove(0);
+
+        // add a0, a0, a1, 1
+        assert_eq!(
+            vector.check(),
+            [
+                "pc is 0x00001004, not 0x00001008",
+                "a0 is 0x00000003, not 0x00000004",
+            ]
+        );
+        assert_err_eq!(
+            parse("rmachine-vectors 1\npc 0x0"),
+            Error::VectorsInvalid("line 2: pc before the first vector".to_string())
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use crate::{Address, Instruction, Opcode, RegisterID, Word};
+use std::fmt;
+
+// Something about a program worth a look before it runs.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Problem {
+    // An ebreak no path from the entry point reaches.
+    UnreachableBreakpoint,
+    // A branch or jump to somewhere outside the image.
+    JumpOutsideImage { target: Address },
+    // An instruction computing a result only to discard it in x0.
+    WriteToX0,
+    SuspiciousImmediate { reason: String },
+    // A register read on some path from the entry point before anything
+    // sets it.
+    UndefinedRegister { reg: RegisterID },
+    // Execution can reach a word that is not an instruction.
+    IllegalInstruction { word: Word },
+    // Execution can run on past the last word of the image.
+    RunsOffTheEnd,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UnreachableBreakpoint => write!(f, "ebreak is unreachable"),
+            Problem::JumpOutsideImage { target } => {
+                write!(f, "target {target:#010x} is outside the image")
+            }
+            Problem::WriteToX0 => write!(f, "result is written to x0 and lost"),
+            Problem::SuspiciousImmediate { reason } => write!(f, "{reason}"),
+            Problem::UndefinedRegister { reg } => write!(f, "{reg} may be read before it is set"),
+            Problem::IllegalInstruction { word } => {
+                write!(
+                    f,
+                    "execution reaches {word:#010x}, which is not an instruction"
+                )
+            }
+            Problem::RunsOffTheEnd => write!(f, "execution runs off the end of the image"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Finding {
+    pub addr: Address,
+    pub problem: Problem,
+}
+
+// A set of registers, one bit each.
+type Registers = u16;
+
+const ALL: Registers = Registers::MAX;
+
+fn bit(reg: RegisterID) -> Registers {
+    1 << reg as u16
+}
+
+// The registers the loader sets before the first instruction.
+fn at_entry() -> Registers {
+    bit(RegisterID::X0) | bit(RegisterID::SP) | bit(RegisterID::A0) | bit(RegisterID::A1)
+}
+
+fn is_alu(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Rem
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Xor
+            | Opcode::Sll
+            | Opcode::Srl
+            | Opcode::Sra
+            | Opcode::Slt
+            | Opcode::Sltu
+    )
+}
+
+// The registers an instruction reads. An ecall reads its arguments too,
+// but which depends on the syscall, so only a7 is counted.
+pub(crate) fn reads(instruction: &Instruction) -> Vec<RegisterID> {
+    match instruction.opcode {
+        opcode if is_alu(opcode) => vec![instruction.rs1, instruction.rs2],
+        Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge => {
+            vec![instruction.rs1, instruction.rs2]
+        }
+        Opcode::Sb | Opcode::Sh | Opcode::Sw => vec![instruction.rs1, instruction.rs2],
+        Opcode::Jalr | Opcode::Lb | Opcode::Lh | Opcode::Lw => vec![instruction.rs1],
+        Opcode::ECall => vec![RegisterID::A7],
+        _ => Vec::new(),
+    }
+}
+
+// The register an instruction writes, if any.
+pub(crate) fn writes(instruction: &Instruction) -> Option<RegisterID> {
+    match instruction.opcode {
+        Opcode::Beq
+        | Opcode::Bne
+        | Opcode::Blt
+        | Opcode::Bge
+        | Opcode::Sb
+        | Opcode::Sh
+        | Opcode::Sw
+        | Opcode::EBreak
+        | Opcode::Mret => None,
+        Opcode::ECall => Some(RegisterID::A0),
+        _ => Some(instruction.rd),
+    }
+}
+
+// Where a branch or jal goes.
+fn target(instruction: &Instruction, addr: Address) -> Option<Address> {
+    match instruction.opcode {
+        Opcode::Jal | Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge => {
+            Some(addr.wrapping_add_signed(instruction.offset()))
+        }
+        _ => None,
+    }
+}
+
+// Whether execution can carry on to the next instruction. A call, linking
+// through ra, is expected to return to it; a jump linking through any other
+// register is not, that being how code takes the address of data after it.
+fn is_call(instruction: &Instruction) -> bool {
+    matches!(instruction.opcode, Opcode::Jal | Opcode::Jalr) && instruction.rd == RegisterID::RA
+}
+
+fn falls_through(instruction: &Instruction) -> bool {
+    match instruction.opcode {
+        Opcode::Jal | Opcode::Jalr => is_call(instruction),
+        Opcode::EBreak | Opcode::Mret => false,
+        _ => true,
+    }
+}
+
+fn suspicious_immediate(instruction: &Instruction, addr: Address) -> Option<String> {
+    let imm = instruction.offset();
+    let constant = instruction.rs2 == RegisterID::X0;
+    match instruction.opcode {
+        Opcode::Sll | Opcode::Srl | Opcode::Sra if constant && !(0..32).contains(&imm) => {
+            Some(format!("shift by {imm} is taken modulo 32"))
+        }
+        Opcode::Div | Opcode::Rem if constant && imm == 0 => Some("divides by zero".to_string()),
+        Opcode::Lh | Opcode::Sh | Opcode::Lw | Opcode::Sw if instruction.rs1 == RegisterID::X0 => {
+            let width = if matches!(instruction.opcode, Opcode::Lh | Opcode::Sh) {
+                2
+            } else {
+                4
+            };
+            (imm % width != 0).then(|| format!("address {imm:#x} is not aligned to {width} bytes"))
+        }
+        _ => target(instruction, addr)
+            .filter(|target| !target.is_multiple_of(4))
+            .map(|target| format!("target {target:#010x} is not word-aligned")),
+    }
+}
+
+// Scans a program image loaded at `base`, entered at its first word, for
+// likely mistakes. It follows the direct branches and jumps from the entry
+// point, and assumes calls return and may set any register; code reached
+// only through jalr, such as interrupt handlers, is not followed.
+pub fn verify(image: &[u8], base: Address) -> Vec<Finding> {
+    let words: Vec<Word> = image
+        .chunks(4)
+        .map(|chunk| {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            Word::from_be_bytes(word)
+        })
+        .collect();
+    let index = |addr: Address| {
+        let offset = addr.wrapping_sub(base) as usize;
+        (addr.is_multiple_of(4) && offset / 4 < words.len()).then_some(offset / 4)
+    };
+    let addr = |index: usize| base.wrapping_add(4 * index as Address);
+
+    // The registers set on every path to each instruction reached.
+    let mut defined: Vec<Option<Registers>> = vec![None; words.len()];
+    let mut pending = Vec::new();
+    if !words.is_empty() {
+        defined[0] = Some(at_entry());
+        pending.push(0);
+    }
+    while let Some(at) = pending.pop() {
+        let Ok(instruction) = Instruction::try_from(words[at]) else {
+            continue;
+        };
+        let before = defined[at].expect("pending instructions are reached");
+        let after = before | writes(&instruction).map_or(0, bit);
+        let mut next = Vec::new();
+        if let Some(to) = target(&instruction, addr(at)) {
+            next.push((to, after));
+        }
+        if falls_through(&instruction) {
+            let after = if is_call(&instruction) { ALL } else { after };
+            next.push((addr(at + 1), after));
+        }
+        for (to, registers) in next {
+            let Some(to) = index(to) else {
+                continue;
+            };
+            let merged = defined[to].map_or(registers, |known| known & registers);
+            if defined[to] != Some(merged) {
+                defined[to] = Some(merged);
+                pending.push(to);
+            }
+        }
+    }
+
+    let mut findings = Vec::new();
+    for (at, &word) in words.iter().enumerate() {
+        let mut found = |problem| {
+            findings.push(Finding {
+                addr: addr(at),
+                problem,
+            });
+        };
+        let decoded = Instruction::try_from(word);
+        let Some(before) = defined[at] else {
+            if decoded.is_ok_and(|instruction| instruction.opcode == Opcode::EBreak) {
+                found(Problem::UnreachableBreakpoint);
+            }
+            continue;
+        };
+        let Ok(instruction) = decoded else {
+            found(Problem::IllegalInstruction { word });
+            continue;
+        };
+        if let Some(reason) = suspicious_immediate(&instruction, addr(at)) {
+            found(Problem::SuspiciousImmediate { reason });
+        } else if let Some(to) = target(&instruction, addr(at)).filter(|to| index(*to).is_none()) {
+            found(Problem::JumpOutsideImage { target: to });
+        }
+        let nop = instruction.opcode == Opcode::Add
+            && instruction.rs1 == RegisterID::X0
+            && instruction.rs2 == RegisterID::X0
+            && instruction.imm == 0;
+        let linking = matches!(instruction.opcode, Opcode::Jal | Opcode::Jalr);
+        if writes(&instruction) == Some(RegisterID::X0) && !nop && !linking {
+            found(Problem::WriteToX0);
+        }
+        let mut undefined: Vec<RegisterID> = reads(&instruction)
+            .into_iter()
+            .filter(|reg| before & bit(*reg) == 0)
+            .collect();
+        undefined.dedup();
+        for reg in undefined {
+            found(Problem::UndefinedRegister { reg });
+        }
+        if falls_through(&instruction) && at + 1 == words.len() {
+            found(Problem::RunsOffTheEnd);
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, assemble_file};
+
+    fn problems(source: &str) -> Vec<(Address, Problem)> {
+        let image = assemble(source).unwrap();
+        verify(&image, 0x100)
+            .into_iter()
+            .map(|finding| (finding.addr - 0x100, finding.problem))
+            .collect()
+    }
+
+    #[test]
+    fn clean_programs_have_no_findings() {
+        let program = "
+            li a2, 3
+            li a3, 0
+            loop: add a3, a3, a2
+            call double
+            sub a2, a2, x0, 1
+            bne a2, x0, loop
+            mv a0, a3
+            li a7, 93
+            ecall
+            ebreak
+            double: add a0, a0, a0
+            ret";
+
+        assert_eq!(problems(program), []);
+    }
+
+    #[test]
+    fn likely_mistakes_are_found() {
+        let program = "
+            add x0, a0, a1
+            beq a0, a1, 0x400
+            sll a2, a0, x0, 40
+            bge a4, a0, skip
+            li a4, 1
+            skip: add a5, a4, x0
+            lw a6, 6
+            j 2
+            ebreak";
+
+        assert_eq!(
+            problems(program),
+            [
+                (0, Problem::WriteToX0),
+                (4, Problem::JumpOutsideImage { target: 0x504 }),
+                (
+                    8,
+                    Problem::SuspiciousImmediate {
+                        reason: "shift by 40 is taken modulo 32".to_string()
+                    }
+                ),
+                (
+                    12,
+                    Problem::UndefinedRegister {
+                        reg: RegisterID::A4
+                    }
+                ),
+                // a4 is only set when the branch is not taken.
+                (
+                    20,
+                    Problem::UndefinedRegister {
+                        reg: RegisterID::A4
+                    }
+                ),
+                (
+                    24,
+                    Problem::SuspiciousImmediate {
+                        reason: "address 0x6 is not aligned to 4 bytes".to_string()
+                    }
+                ),
+                (
+                    28,
+                    Problem::SuspiciousImmediate {
+                        reason: "target 0x0000011e is not word-aligned".to_string()
+                    }
+                ),
+                (32, Problem::UnreachableBreakpoint),
+            ]
+        );
+    }
+
+    #[test]
+    fn execution_must_stay_on_instructions() {
+        assert_eq!(
+            problems("beq a0, a1, data\nebreak\ndata: .word 0"),
+            [(8, Problem::IllegalInstruction { word: 0 })]
+        );
+        assert_eq!(problems("li a2, 1"), [(0, Problem::RunsOffTheEnd)]);
+    }
+
+    #[test]
+    fn the_examples_have_no_findings() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "s") {
+                let image = assemble_file(&path).unwrap();
+                assert_eq!(verify(&image, 0), [], "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn only_jumps_linking_through_ra_return() {
+        // The jal takes the address of the word after it, as print_int does.
+        assert_eq!(
+            problems("jal a1, next\n.word 0\nnext: lw a2, 0(a1)\nebreak"),
+            []
+        );
+    }
+}
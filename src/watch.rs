@@ -0,0 +1,156 @@
+use crate::{Address, Error, HaltReason, Machine, Result};
+use std::io::Write;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Watchpoint {
+    addr: Address,
+    len: usize,
+    label: String,
+}
+
+impl<W: Write> Machine<W> {
+    // Halts execution after any instruction that changes one of the `len`
+    // bytes starting at `addr`. Run the machine again to continue.
+    pub fn watch(&mut self, addr: Address, len: usize) {
+        let label = self
+            .symbols
+            .label(addr)
+            .unwrap_or_else(|| format!("{addr:#x}"));
+        self.watchpoints.push(Watchpoint { addr, len, label });
+    }
+
+    // Watches the whole of the named data symbol from the loaded symbol table.
+    pub fn watch_symbol(&mut self, name: &str) -> Result<()> {
+        let symbol = self
+            .symbols
+            .get(name)
+            .ok_or_else(|| Error::SymbolUnknown(name.to_string()))?;
+        self.watchpoints.push(Watchpoint {
+            addr: symbol.addr,
+            len: symbol.size.max(1) as usize,
+            label: symbol.name.clone(),
+        });
+        Ok(())
+    }
+
+    pub fn unwatch(&mut self, addr: Address) {
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.addr != addr);
+    }
+
+    pub(crate) fn watched_values(&self) -> Vec<Vec<u8>> {
+        self.watchpoints
+            .iter()
+            .map(|watchpoint| self.mem.read(watchpoint.addr, watchpoint.len))
+            .collect()
+    }
+
+    pub(crate) fn triggered_watchpoint(
+        &self,
+        pc: Address,
+        before: Vec<Vec<u8>>,
+    ) -> Option<HaltReason> {
+        self.watchpoints
+            .iter()
+            .zip(before)
+            .find_map(|(watchpoint, old)| {
+                let new = self.mem.read(watchpoint.addr, watchpoint.len);
+                (new != old).then(|| HaltReason::Watchpoint {
+                    pc,
+                    label: watchpoint.label.clone(),
+                    old,
+                    new,
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{symbols::SymbolTable, Error, HaltReason, Machine, Memory, RegisterID, Registers};
+    use claims::{assert_err_eq, assert_ok, assert_ok_eq};
+
+    // Formats a3 into the buffer at a1 twice, then breaks.
+    fn formatting_machine() -> Machine<Vec<u8>> {
+        Machine {
+            regs: Registers::from([
+                (RegisterID::A1, 0x100),
+                (RegisterID::A2, 4),
+                (RegisterID::A3, 7),
+                (RegisterID::A7, 257),
+            ]),
+            mem: Memory::from([
+                // add a0, a3, x0, 0
+                (0, 0b0000_0000),
+                (1, 0b0000_0000),
+                (2, 0b0000_1000),
+                (3, 0b0010_0010),
+                // ECall
+                (4, 0b0000_0000),
+                (5, 0b0000_0000),
+                (6, 0b0000_0000),
+                (7, 0b0001_0111),
+                // add a0, a3, x0, 0
+                (8, 0b0000_0000),
+                (9, 0b0000_0000),
+                (10, 0b0000_1000),
+                (11, 0b0010_0010),
+                // ECall
+                (12, 0b0000_0000),
+                (13, 0b0000_0000),
+                (14, 0b0000_0000),
+                (15, 0b0001_0111),
+                // EBreak
+                (16, 0b0000_0000),
+                (17, 0b0000_0000),
+                (18, 0b0000_0000),
+                (19, 0b0001_1000),
+            ]),
+            symbols: SymbolTable::from([("counter", 0x100, 4)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn watch_symbol_halts_when_the_symbol_is_written() {
+        let mut machine = formatting_machine();
+        assert_ok!(machine.watch_symbol("counter"));
+
+        assert_ok_eq!(
+            machine.run(),
+            HaltReason::Watchpoint {
+                pc: 4,
+                label: "counter".to_string(),
+                old: vec![0, 0, 0, 0],
+                new: vec![b'7', 0, 0, 0],
+            }
+        );
+        assert_eq!(machine.pc, 8);
+
+        // The second write stores the same bytes, so execution runs on.
+        assert_ok_eq!(machine.run(), HaltReason::Breakpoint);
+    }
+
+    #[test]
+    fn watch_labels_raw_addresses_with_the_enclosing_symbol() {
+        let mut machine = formatting_machine();
+        machine.regs.set(RegisterID::A3, 17);
+        machine.watch(0x101, 1);
+
+        let reason = machine.run().unwrap();
+
+        assert_eq!(
+            reason.to_string(),
+            "watchpoint on counter+0x1 hit at pc 0x00000004: [00] -> [37]"
+        );
+    }
+
+    #[test]
+    fn watching_an_unknown_symbol_returns_an_error() {
+        let mut machine = formatting_machine();
+        assert_err_eq!(
+            machine.watch_symbol("missing"),
+            Error::SymbolUnknown("missing".to_string())
+        );
+    }
+}